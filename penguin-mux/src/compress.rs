@@ -0,0 +1,156 @@
+//! Compression of `Psh` payloads on streams that negotiated it.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use std::io;
+
+/// Stream compression algorithms, each a bit in
+/// [`Capabilities::compression`](crate::Capabilities::compression)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Raw DEFLATE (RFC 1951), with the `deflate` feature
+    Deflate,
+    /// Zstandard (RFC 8878), with the `zstd` feature
+    Zstd,
+}
+
+impl Compression {
+    /// Most preferred first
+    const ALL: [Self; 2] = [Self::Zstd, Self::Deflate];
+
+    /// Bit of the algorithm in the bit sets
+    #[must_use]
+    pub const fn bit(self) -> u8 {
+        match self {
+            Self::Deflate => 0x01,
+            Self::Zstd => 0x02,
+        }
+    }
+
+    /// Bit set of the algorithms this build supports
+    #[must_use]
+    pub const fn supported() -> u8 {
+        let mut bits = 0;
+        if cfg!(feature = "deflate") {
+            bits |= Self::Deflate.bit();
+        }
+        if cfg!(feature = "zstd") {
+            bits |= Self::Zstd.bit();
+        }
+        bits
+    }
+
+    /// The most preferred algorithm in the bit set `bits` that this build
+    /// supports
+    pub(crate) fn choose(bits: u8) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algo| bits & Self::supported() & algo.bit() != 0)
+    }
+
+    /// The algorithm of `bit`, if this build supports it
+    pub(crate) fn from_bit(bit: u8) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algo| algo.bit() == bit)
+            .filter(|algo| Self::supported() & algo.bit() != 0)
+    }
+
+    /// Compress `data`, or return `None` if that does not save at least an
+    /// eighth, e.g. because it is already compressed.
+    #[cfg_attr(
+        not(any(feature = "deflate", feature = "zstd")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn compress(self, data: &[u8]) -> Option<Vec<u8>> {
+        let compressed: io::Result<Vec<u8>> = match self {
+            #[cfg(feature = "deflate")]
+            Self::Deflate => {
+                use std::io::Write;
+                let mut encoder = flate2::write::DeflateEncoder::new(
+                    Vec::with_capacity(data.len()),
+                    flate2::Compression::fast(),
+                );
+                encoder.write_all(data).and_then(|()| encoder.finish())
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::compress(data, crate::config::ZSTD_LEVEL),
+            #[allow(unreachable_patterns)]
+            _ => Err(io::ErrorKind::Unsupported.into()),
+        };
+        compressed
+            .ok()
+            .filter(|compressed| compressed.len() <= data.len() - data.len() / 8)
+    }
+
+    /// Decompress `data`, failing if it is corrupt or expands to more than
+    /// `config::MAX_DECOMPRESSED_SIZE` bytes.
+    #[cfg_attr(
+        not(any(feature = "deflate", feature = "zstd")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            #[cfg(feature = "deflate")]
+            Self::Deflate => {
+                use std::io::Read;
+                let mut decompressed = Vec::new();
+                flate2::read::DeflateDecoder::new(data)
+                    .take(crate::config::MAX_DECOMPRESSED_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)?;
+                if decompressed.len() > crate::config::MAX_DECOMPRESSED_SIZE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "decompressed payload too large",
+                    ));
+                }
+                Ok(decompressed)
+            }
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::bulk::decompress(data, crate::config::MAX_DECOMPRESSED_SIZE),
+            #[allow(unreachable_patterns)]
+            _ => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        assert_eq!(Compression::choose(0), None);
+        assert_eq!(Compression::choose(0x80), None);
+        if cfg!(feature = "zstd") {
+            assert_eq!(Compression::choose(0b11), Some(Compression::Zstd));
+        }
+        if cfg!(feature = "deflate") {
+            assert_eq!(Compression::choose(0b01), Some(Compression::Deflate));
+            assert_eq!(Compression::from_bit(0b01), Some(Compression::Deflate));
+        }
+        assert_eq!(Compression::from_bit(0b11), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let text = b"penguin ".repeat(1000);
+        let random: Vec<u8> = (0..8000).map(|_| rand::random()).collect();
+        for algo in Compression::ALL {
+            if Compression::supported() & algo.bit() == 0 {
+                continue;
+            }
+            let compressed = algo.compress(&text).unwrap();
+            assert!(compressed.len() < text.len());
+            assert_eq!(algo.decompress(&compressed).unwrap(), text);
+            // Incompressible data is sent as is
+            assert!(algo.compress(&random).is_none());
+            // Bombs are refused
+            let bomb = algo
+                .compress(&vec![0; crate::config::MAX_DECOMPRESSED_SIZE + 1])
+                .unwrap();
+            assert!(algo.decompress(&bomb).is_err());
+            assert!(algo.decompress(b"not compressed").is_err());
+        }
+    }
+}
@@ -0,0 +1,115 @@
+//! Default configuration parameters of the multiplexor.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use std::time::Duration;
+
+/// Number of datagram frames to buffer in the channels on the receiving end.
+/// If the buffer is not read fast enough, excess datagrams are dropped or
+/// wait as `Options::datagram_overflow` says.
+pub const DATAGRAM_BUFFER_SIZE: usize = 1 << 9;
+/// Number of `MuxStream`s to buffer in the channels on the receiving end.
+/// Since there is a handshake to obtain `MuxStream`s, there should be no
+/// need to have a crazy high buffer size.
+pub const STREAM_BUFFER_SIZE: usize = 1 << 5;
+
+/// With `Options::cork`, a stream sends the data held back as soon as it
+/// reaches this many bytes. Larger writes are never held back.
+pub const CORK_BYTES: usize = 1 << 14;
+
+/// Number of frames a `MuxStream` can queue for the transport before its
+/// writes block. Streams take turns sending them one at a time.
+pub const STREAM_QUEUED_FRAMES: usize = 1 << 4;
+/// Bytes the `MuxStream`s can queue for the transport in total before
+/// writes block, unless the stream has nothing queued.
+pub const QUEUED_BYTES: usize = 1 << 20;
+
+/// Smallest `max_frame_size` a peer may announce, so that every frame,
+/// including a `Syn` to a long host name, still fits.
+pub const MIN_FRAME_SIZE_LIMIT: u32 = 1 << 10;
+
+/// Streams that negotiated compression only compress `Psh` payloads of at
+/// least this many bytes.
+pub const COMPRESS_MIN_BYTES: usize = 1 << 8;
+/// A stream stops compressing after this many payloads in a row did not
+/// get smaller, e.g. because the data is already compressed.
+pub const COMPRESS_GIVE_UP: u32 = 8;
+/// Largest payload a compressed `Psh` frame may expand to. Larger writes
+/// are sent uncompressed.
+pub const MAX_DECOMPRESSED_SIZE: usize = 1 << 20;
+/// Zstandard level for stream compression: fast rather than small
+#[cfg(feature = "zstd")]
+pub const ZSTD_LEVEL: i32 = 1;
+
+/// With `Options::obfs_traffic`, messages are padded to the next power of
+/// two from this size
+pub const PAD_MIN_SIZE: usize = 1 << 8;
+/// ... up to this size, and to the next multiple of it after that.
+pub const PAD_STEP: usize = 1 << 14;
+/// Dummy frames are `PAD_MIN_SIZE` shifted left by up to this much.
+pub const PAD_DUMMY_SIZES: u32 = 6;
+/// With `Options::obfs_traffic`, dummy frames are sent at random intervals
+/// in this range.
+pub const DUMMY_FRAME_INTERVAL: std::ops::RangeInclusive<Duration> =
+    Duration::from_millis(50)..=Duration::from_secs(2);
+
+/// Number of maps the open streams are split into by port, so that frames
+/// of different streams are seldom dispatched under the same lock.
+pub const STREAM_MAP_SHARDS: usize = 1 << 4;
+
+/// How long the port of a closed stream is not handed out again, so that
+/// late frames of the old stream do not reach the new one.
+pub const PORT_QUARANTINE: Duration = Duration::from_secs(30);
+
+/// Largest encoded datagram that is fragmented and reassembled. Enough for
+/// any UDP payload plus the datagram header.
+pub const MAX_FRAGMENTED_SIZE: usize = 1 << 17;
+
+/// How long the fragments of a datagram are kept waiting for the rest
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of datagrams reassembled at a time. The oldest is dropped to make
+/// room for a new one.
+pub const MAX_PARTIAL_DATAGRAMS: usize = 1 << 4;
+
+/// How long to wait for the acknowledgement of a reliable datagram before
+/// sending it again. Doubles with each try.
+pub const RELIABLE_RTO: Duration = Duration::from_millis(200);
+
+/// How often reliable datagrams are sent before giving up on them
+pub const RELIABLE_MAX_TRIES: u32 = 8;
+
+/// How often to look for reliable datagrams to send again
+pub const RELIABLE_TICK: Duration = Duration::from_millis(50);
+
+/// Number of reliable datagrams waiting for acknowledgement. The oldest is
+/// given up on to make room for a new one.
+pub const RELIABLE_WINDOW: usize = 1 << 10;
+
+/// Number of received sequence numbers remembered to drop duplicates
+pub const RELIABLE_DEDUP_WINDOW: usize = 1 << 12;
+
+/// Number of source IDs of reliable datagrams remembered to send the
+/// replies reliably too. The oldest is forgotten to make room.
+pub const RELIABLE_MAX_SIDS: usize = 1 << 10;
+
+/// Needs to be the same as `STREAM_FRAME_BUFFER_SIZE` but as `u64`
+pub const RWND: u64 = STREAM_FRAME_BUFFER_SIZE as u64;
+
+/// Number of `StreamFrame`s to buffer in `MuxStream`'s channels before blocking
+#[cfg(not(test))]
+pub const STREAM_FRAME_BUFFER_SIZE: usize = 1 << 9;
+/// Number of `Psh` frames between `Ack`s:
+/// If too low, `Ack`s will consume too much bandwidth;
+/// If too high, writers may block.
+#[cfg(not(test))]
+pub const RWND_THRESHOLD: u64 = 1 << 8;
+
+/// Number of `StreamFrame`s to buffer in `MuxStream`'s channels before blocking
+#[cfg(test)]
+pub const STREAM_FRAME_BUFFER_SIZE: usize = 1 << 2;
+/// Number of `Psh` frames between `Ack`s. In tests, we want to be able to
+/// test the `Ack` mechanism, so we set this to be the same as the buffer size.
+/// The downside is that tests will be slower.
+#[cfg(test)]
+pub const RWND_THRESHOLD: u64 = RWND;
@@ -0,0 +1,482 @@
+//! Running the multiplexor over a lean WebSocket implementation of its own.
+//!
+//! Unlike [`ws::WebSocket`](crate::ws::WebSocket), which receives each
+//! message into a new `Vec`, this one parses frames in place in its read
+//! buffer and hands out their payloads as [`Bytes`] sharing that buffer.
+//! Outgoing frames are encoded straight into one write buffer. It speaks
+//! only what the multiplexor needs: `Binary` messages, which may be
+//! fragmented, `Ping`, `Pong`, and `Close`, without extensions. The HTTP
+//! upgrade is left to the caller.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::transport::{Message, Transport};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{Sink, Stream};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::io::poll_read_buf;
+
+pub use crate::ws::Role;
+
+/// Bytes to make room for before each read
+const READ_SIZE: usize = 1 << 16;
+/// Buffered bytes above which `poll_ready` writes them out first
+const WRITE_BUFFER_SIZE: usize = 1 << 17;
+/// Largest message accepted by default, as in `tungstenite`
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// A WebSocket over `RW`, whose HTTP upgrade is complete, as a
+/// [`Transport`]. Each frame is sent in a `Binary` message.
+#[derive(Debug)]
+pub struct WebSocket<RW> {
+    io: RW,
+    role: Role,
+    max_message_size: usize,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    /// A fragmented `Binary` message being put back together
+    partial: Option<BytesMut>,
+    close_sent: bool,
+    close_received: bool,
+}
+
+impl<RW> WebSocket<RW> {
+    /// Wrap a connection whose HTTP upgrade is complete. Messages larger
+    /// than `max_message_size` are rejected.
+    #[inline]
+    pub fn new(io: RW, role: Role, max_message_size: Option<usize>) -> Self {
+        Self::from_partially_read(io, role, max_message_size, BytesMut::new())
+    }
+
+    /// Like [`new`](Self::new), where `read_buf` is what was read past the
+    /// HTTP upgrade.
+    pub fn from_partially_read(
+        io: RW,
+        role: Role,
+        max_message_size: Option<usize>,
+        read_buf: BytesMut,
+    ) -> Self {
+        Self {
+            io,
+            role,
+            max_message_size: max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+            read_buf,
+            write_buf: BytesMut::new(),
+            partial: None,
+            close_sent: false,
+            close_received: false,
+        }
+    }
+
+    /// Get the connection back.
+    #[inline]
+    pub fn into_inner(self) -> RW {
+        self.io
+    }
+
+    /// Take the next whole frame out of the read buffer.
+    /// Returns its FIN bit, opcode, and unmasked payload.
+    fn parse_frame(&mut self) -> io::Result<Option<(bool, u8, Bytes)>> {
+        let buf = &self.read_buf[..];
+        let available = buf.len();
+        if available < 2 {
+            return Ok(None);
+        }
+        if buf[0] & 0x70 != 0 {
+            return Err(protocol_error("reserved bits set without an extension"));
+        }
+        let fin = buf[0] & 0x80 != 0;
+        let opcode = buf[0] & 0x0f;
+        let masked = buf[1] & 0x80 != 0;
+        let (len, mut header_len) = match buf[1] & 0x7f {
+            126 if buf.len() < 4 => return Ok(None),
+            126 => (u64::from(u16::from_be_bytes([buf[2], buf[3]])), 4),
+            127 if buf.len() < 10 => return Ok(None),
+            127 => {
+                let len: [u8; 8] = buf[2..10].try_into().expect("Slice of 8 bytes");
+                (u64::from_be_bytes(len), 10)
+            }
+            len => (u64::from(len), 2),
+        };
+        // Clients mask their frames and servers do not
+        if masked != (self.role == Role::Server) {
+            return Err(protocol_error("wrong masking"));
+        }
+        if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+            return Err(protocol_error("fragmented or long control frame"));
+        }
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|len| *len <= self.max_message_size)
+            .ok_or_else(|| protocol_error("message too long"))?;
+        let mask = if masked {
+            if buf.len() < header_len + 4 {
+                return Ok(None);
+            }
+            let mask: [u8; 4] = buf[header_len..header_len + 4]
+                .try_into()
+                .expect("Slice of 4 bytes");
+            header_len += 4;
+            Some(mask)
+        } else {
+            None
+        };
+        if available < header_len + len {
+            self.read_buf.reserve(header_len + len - available);
+            return Ok(None);
+        }
+        self.read_buf.advance(header_len);
+        let mut payload = self.read_buf.split_to(len);
+        if let Some(mask) = mask {
+            apply_mask(&mut payload, mask);
+        }
+        Ok(Some((fin, opcode, payload.freeze())))
+    }
+
+    /// Take the next whole message out of the read buffer.
+    fn parse_message(&mut self) -> io::Result<Option<Message>> {
+        while let Some((fin, opcode, payload)) = self.parse_frame()? {
+            match opcode {
+                OPCODE_BINARY if self.partial.is_some() => {
+                    return Err(protocol_error("new message before the last one ended"));
+                }
+                OPCODE_BINARY if fin => return Ok(Some(Message::Frame(payload))),
+                OPCODE_BINARY => self.partial = Some(BytesMut::from(&payload[..])),
+                OPCODE_CONTINUATION => {
+                    let partial = self
+                        .partial
+                        .as_mut()
+                        .ok_or_else(|| protocol_error("continuation without a message"))?;
+                    if partial.len() + payload.len() > self.max_message_size {
+                        return Err(protocol_error("message too long"));
+                    }
+                    partial.extend_from_slice(&payload);
+                    if fin {
+                        let message = self.partial.take().expect("Checked above").freeze();
+                        return Ok(Some(Message::Frame(message)));
+                    }
+                }
+                // "The client and server MUST NOT use other WebSocket data frame types"
+                OPCODE_TEXT => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "received `Text` message",
+                    ));
+                }
+                OPCODE_CLOSE => {
+                    self.close_received = true;
+                    if !self.close_sent {
+                        // Echo the status code, if any
+                        self.write_frame(OPCODE_CLOSE, &payload[..payload.len().min(2)]);
+                        self.close_sent = true;
+                    }
+                    return Ok(Some(Message::Close));
+                }
+                OPCODE_PING => return Ok(Some(Message::Ping(payload))),
+                OPCODE_PONG => return Ok(Some(Message::Pong(payload))),
+                _ => return Err(protocol_error("unknown opcode")),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Encode a frame into the write buffer.
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) {
+        let buf = &mut self.write_buf;
+        let mask_bit = if self.role == Role::Client { 0x80 } else { 0 };
+        buf.reserve(14 + payload.len());
+        buf.put_u8(0x80 | opcode);
+        match payload.len() {
+            // `as`: checked by the match
+            len @ 0..=125 => buf.put_u8(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                buf.put_u8(mask_bit | 126);
+                buf.put_u16(len as u16);
+            }
+            len => {
+                buf.put_u8(mask_bit | 127);
+                buf.put_u64(len as u64);
+            }
+        }
+        if mask_bit == 0 {
+            buf.put_slice(payload);
+        } else {
+            let mask: [u8; 4] = rand::random();
+            buf.put_slice(&mask);
+            let start = buf.len();
+            buf.put_slice(payload);
+            apply_mask(&mut buf[start..], mask);
+        }
+    }
+}
+
+impl<RW> WebSocket<RW>
+where
+    RW: AsyncWrite + Unpin,
+{
+    /// Write out the write buffer.
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let written = ready!(Pin::new(&mut self.io).poll_write(cx, &self.write_buf))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// XOR `payload` with `mask`, which masks and unmasks it.
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    let mask_word = u32::from_ne_bytes(mask);
+    let mut chunks = payload.chunks_exact_mut(4);
+    for chunk in &mut chunks {
+        let word = u32::from_ne_bytes(chunk.try_into().expect("Chunk of 4 bytes")) ^ mask_word;
+        chunk.copy_from_slice(&word.to_ne_bytes());
+    }
+    for (byte, mask) in chunks.into_remainder().iter_mut().zip(mask) {
+        *byte ^= mask;
+    }
+}
+
+/// An error for a peer that breaks RFC 6455.
+fn protocol_error(reason: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason)
+}
+
+impl<RW> Stream for WebSocket<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = io::Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if this.close_received {
+                return Poll::Ready(None);
+            }
+            match this.parse_message() {
+                Ok(Some(Message::Close)) => {
+                    // Best effort to send our `Close` right away; `poll_close`
+                    // finishes it otherwise.
+                    if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+                        tracing::debug!("Failed to reply to `Close`: {err}");
+                    }
+                    return Poll::Ready(Some(Ok(Message::Close)));
+                }
+                Ok(Some(msg)) => return Poll::Ready(Some(Ok(msg))),
+                Ok(None) => {}
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+            this.read_buf.reserve(READ_SIZE);
+            match ready!(poll_read_buf(
+                Pin::new(&mut this.io),
+                cx,
+                &mut this.read_buf
+            )) {
+                Ok(0) => {
+                    this.close_received = true;
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed without a `Close` frame",
+                    ))));
+                }
+                Ok(_) => {}
+                Err(err) => return Poll::Ready(Some(Err(err))),
+            }
+        }
+    }
+}
+
+impl<RW> Sink<Message> for WebSocket<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_buf.len() >= WRITE_BUFFER_SIZE {
+            ready!(self.poll_write_buf(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> io::Result<()> {
+        if self.close_sent {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        match msg {
+            Message::Frame(data) => self.write_frame(OPCODE_BINARY, &data),
+            Message::Ping(data) => self.write_frame(OPCODE_PING, &data),
+            Message::Pong(data) => self.write_frame(OPCODE_PONG, &data),
+            Message::Close => {
+                self.write_frame(OPCODE_CLOSE, &[]);
+                self.close_sent = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_buf(cx))?;
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.close_sent {
+            self.write_frame(OPCODE_CLOSE, &[]);
+            self.close_sent = true;
+        }
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+impl<RW> Transport for WebSocket<RW> where RW: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+    use tokio_tungstenite::tungstenite;
+    use tokio_tungstenite::WebSocketStream;
+
+    /// A pair of these WebSockets over a `tokio` `DuplexStream`.
+    pub(crate) fn get_pair() -> (WebSocket<DuplexStream>, WebSocket<DuplexStream>) {
+        let (client, server) = tokio::io::duplex(10);
+        (
+            WebSocket::new(client, Role::Client, None),
+            WebSocket::new(server, Role::Server, None),
+        )
+    }
+
+    #[test]
+    fn test_apply_mask() {
+        let mut payload = *b"hello, world";
+        apply_mask(&mut payload, [1, 2, 3, 4]);
+        assert_eq!(payload[0], b'h' ^ 1);
+        assert_eq!(payload[5], b',' ^ 2);
+        assert_eq!(payload[11], b'd' ^ 4);
+        apply_mask(&mut payload, [1, 2, 3, 4]);
+        assert_eq!(&payload, b"hello, world");
+    }
+
+    #[tokio::test]
+    async fn test_messages() {
+        let (mut client, mut server) = get_pair();
+        for len in [0, 125, 126, 0xffff, 0x10000] {
+            let data = Bytes::from(vec![0x55; len]);
+            let (sent, received) =
+                tokio::join!(client.send(Message::Frame(data.clone())), server.next());
+            sent.unwrap();
+            assert_eq!(received.unwrap().unwrap(), Message::Frame(data.clone()));
+            let (sent, received) =
+                tokio::join!(server.send(Message::Frame(data.clone())), client.next());
+            sent.unwrap();
+            assert_eq!(received.unwrap().unwrap(), Message::Frame(data));
+        }
+        let ping = Message::Ping(Bytes::from_static(b"hi"));
+        client.send(ping.clone()).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), ping);
+        // Closing is answered
+        client.send(Message::Close).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap(), Message::Close);
+        assert!(server.next().await.is_none());
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::Close);
+        assert!(client.send(Message::Close).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tungstenite_interop() {
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut client = WebSocket::new(client, Role::Client, None);
+        let mut server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        client
+            .send(Message::Frame(Bytes::from_static(b"to server")))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            tungstenite::Message::Binary(b"to server".to_vec())
+        );
+        server
+            .send(tungstenite::Message::Binary(b"to client".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Message::Frame(Bytes::from_static(b"to client"))
+        );
+        server.close(None).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Message::Close);
+        // Our reply
+        assert!(matches!(
+            server.next().await,
+            Some(Ok(tungstenite::Message::Close(_)))
+        ));
+
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let mut client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let mut server = WebSocket::new(server, Role::Server, None);
+        client
+            .send(tungstenite::Message::Ping(b"ping".to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Ping(Bytes::from_static(b"ping"))
+        );
+        server
+            .send(Message::Frame(Bytes::from_static(b"to client")))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            tungstenite::Message::Binary(b"to client".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fragments() {
+        let (mut client, server) = tokio::io::duplex(1 << 10);
+        let mut server = WebSocket::new(server, Role::Server, Some(8));
+        // Masked with zeros: "hel", a ping, then "lo"
+        client
+            .write_all(b"\x02\x83\0\0\0\0hel\x89\x80\0\0\0\0\x80\x82\0\0\0\0lo")
+            .await
+            .unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Ping(Bytes::new())
+        );
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Frame(Bytes::from_static(b"hello"))
+        );
+        // Too long once put together
+        client
+            .write_all(b"\x02\x85\0\0\0\0hello\x80\x84\0\0\0\0 you")
+            .await
+            .unwrap();
+        assert!(server.next().await.unwrap().is_err());
+
+        // Unmasked frames from a client
+        let (mut client, server) = tokio::io::duplex(1 << 10);
+        let mut server = WebSocket::new(server, Role::Server, None);
+        client.write_all(b"\x82\x02hi").await.unwrap();
+        assert!(server.next().await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,163 @@
+//! Splitting datagrams that are too large for the peer into `Fragment`
+//! frames, and putting them back together.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::config;
+use crate::frame::{FragmentFrame, FRAGMENT_HEADER_LEN};
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use tokio::time::Instant;
+use tracing::{trace, warn};
+
+/// Split the encoded datagram `payload`, including its type byte, into
+/// fragments that fit in frames of `max_frame` bytes.
+pub fn split(id: u32, payload: &Bytes, max_frame: usize) -> Vec<FragmentFrame> {
+    // Fragments carry the datagram without its type byte
+    let datagram = payload.slice(1..);
+    let total = u32::try_from(datagram.len()).expect("datagram larger than 4 GiB (this is a bug)");
+    let chunk = max_frame.saturating_sub(FRAGMENT_HEADER_LEN).max(1);
+    (0..datagram.len())
+        .step_by(chunk)
+        .map(|start| FragmentFrame {
+            id,
+            offset: u32::try_from(start).expect("offset larger than 4 GiB (this is a bug)"),
+            total,
+            data: datagram.slice(start..datagram.len().min(start + chunk)),
+        })
+        .collect()
+}
+
+/// A datagram whose fragments are still arriving
+#[derive(Debug)]
+struct Partial {
+    /// Fragments received so far
+    buf: BytesMut,
+    /// Length of the whole datagram
+    total: usize,
+    /// When the first fragment arrived
+    started: Instant,
+}
+
+/// Puts fragmented datagrams back together
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    partials: HashMap<u32, Partial>,
+}
+
+impl Reassembler {
+    /// Add a fragment. Returns the datagram, without its type byte, once
+    /// all of its fragments have arrived. Fragments that do not follow
+    /// the previous one are dropped together with the datagram.
+    pub fn push(&mut self, frame: FragmentFrame) -> Option<Bytes> {
+        let now = Instant::now();
+        self.partials.retain(|id, partial| {
+            let keep = now.duration_since(partial.started) < config::FRAGMENT_TIMEOUT;
+            if !keep {
+                warn!("datagram {id} timed out waiting for fragments");
+            }
+            keep
+        });
+        let total = frame.total as usize;
+        let offset = frame.offset as usize;
+        if total > config::MAX_FRAGMENTED_SIZE || offset + frame.data.len() > total {
+            warn!("dropped invalid fragment: {frame:?}");
+            self.partials.remove(&frame.id);
+            return None;
+        }
+        if offset == 0 {
+            if self.partials.len() >= config::MAX_PARTIAL_DATAGRAMS {
+                let oldest = self
+                    .partials
+                    .iter()
+                    .min_by_key(|(_, partial)| partial.started)
+                    .map(|(id, _)| *id);
+                if let Some(oldest) = oldest {
+                    warn!("dropped partial datagram {oldest} to make room");
+                    self.partials.remove(&oldest);
+                }
+            }
+            self.partials.insert(
+                frame.id,
+                Partial {
+                    buf: BytesMut::with_capacity(total),
+                    total,
+                    started: now,
+                },
+            );
+        }
+        let Some(partial) = self.partials.get_mut(&frame.id) else {
+            trace!("dropped fragment of an unknown datagram: {frame:?}");
+            return None;
+        };
+        if partial.total != total || partial.buf.len() != offset {
+            warn!("dropped out-of-order fragment: {frame:?}");
+            self.partials.remove(&frame.id);
+            return None;
+        }
+        partial.buf.extend_from_slice(&frame.data);
+        if partial.buf.len() < total {
+            return None;
+        }
+        self.partials
+            .remove(&frame.id)
+            .map(|partial| partial.buf.freeze())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_and_reassemble() {
+        let payload = Bytes::from((0..=255u8).cycle().take(5000).collect::<Vec<_>>());
+        let fragments = split(7, &payload, 1024);
+        assert_eq!(fragments.len(), 5);
+        assert!(fragments
+            .iter()
+            .all(|f| f.data.len() + FRAGMENT_HEADER_LEN <= 1024));
+        let mut reassembler = Reassembler::default();
+        let (last, rest) = fragments.split_last().unwrap();
+        for fragment in rest {
+            assert!(reassembler.push(fragment.clone()).is_none());
+        }
+        assert_eq!(reassembler.push(last.clone()).unwrap(), payload.slice(1..));
+        assert!(reassembler.partials.is_empty());
+    }
+
+    #[test]
+    fn test_out_of_order_fragment_drops_datagram() {
+        let payload = Bytes::from(vec![3; 3000]);
+        let fragments = split(1, &payload, 1024);
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.push(fragments[0].clone()).is_none());
+        assert!(reassembler.push(fragments[2].clone()).is_none());
+        assert!(reassembler.partials.is_empty());
+        assert!(reassembler.push(fragments[1].clone()).is_none());
+        assert!(reassembler.partials.is_empty());
+    }
+
+    #[test]
+    fn test_too_many_partials_evicts_oldest() {
+        let payload = Bytes::from(vec![3; 3000]);
+        let mut reassembler = Reassembler::default();
+        for id in 0..=config::MAX_PARTIAL_DATAGRAMS as u32 {
+            assert!(reassembler
+                .push(split(id, &payload, 1024).remove(0))
+                .is_none());
+        }
+        assert_eq!(reassembler.partials.len(), config::MAX_PARTIAL_DATAGRAMS);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_partial_times_out() {
+        let payload = Bytes::from(vec![3; 2000]);
+        let fragments = split(1, &payload, 1024);
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.push(fragments[0].clone()).is_none());
+        tokio::time::advance(config::FRAGMENT_TIMEOUT).await;
+        assert!(reassembler.push(fragments[1].clone()).is_none());
+        assert!(reassembler.partials.is_empty());
+    }
+}
@@ -0,0 +1,1735 @@
+//! Frames.
+//! Each frame strictly fits in a `Message`.
+//!
+//! Architecture:
+//! The system is similar to a traditional SOCKS5 proxy, but the protocol
+//! allows for UDP to be transmitted over the same WebSocket connection.
+//! It is essentially a SOCKS5 forwarder over a WebSocket link.
+//!
+//! All `Message`s carry a complete frame:
+//! - 1 byte: type (1 for TCP, 2 for `Hello`, 3 for UDP, 4 for padding,
+//!   5 for `GoAway`, 6 for a UDP fragment, 7 for reliable UDP, 8 for
+//!   acknowledging reliable UDP)
+//! - variable: dependent on the type (see `StreamFrame` and `DatagramFrame`).
+//!
+//! `Stream` messages are connection-based.
+//! The frames are in the following format ([`FrameVersion::V1`]):
+//! - 2 bytes: source port in network byte order.
+//! - 2 bytes: destination port in network byte order.
+//! - 1 byte: type (see below)
+//! - variable: payload
+//!
+//! Or, with [`FrameVersion::V2`]:
+//! - 1 byte: flags. The low 4 bits are the type, the others are reserved.
+//! - 1 byte: length of the header extensions.
+//! - 4 bytes: source port (stream ID) in network byte order.
+//! - 4 bytes: destination port (stream ID) in network byte order.
+//! - variable: header extensions, each a 1-byte ID, a 1-byte length, and
+//!   the value. Receivers skip the ones they do not know.
+//! - variable: payload
+//!
+//! There are six types of frames:
+//! - `Syn`: the client sends this frame to request a connection to a target:
+//!   - 4 bytes: initial receive window size in network byte order.
+//!   - 2 bytes: forwarding destination port in network byte order.
+//!   - variable: (0..256) bytes: (forwarding destination domain name or IP).
+//! - `SynAck`: the server replies with this frame to confirm the connection.
+//!   It is in the same format as `Ack`. Using two types of frames is to
+//!   avoid having to implement a state machine.
+//! - `Ack`: the server replies with this frame to confirm the data reception:
+//!   - 4 bytes: number of `Psh` frames processed since the last `Ack` frame.
+//! - `Rst`: one side sends this frame to indicate that the connection should
+//!   be closed. It may carry a UTF-8 reason, e.g. why a `Syn` was rejected.
+//!   With [`FrameVersion::V2`], a header extension also carries a
+//!   [`RstReason`] code.
+//! - `Psh`: one side sends this frame to send data.
+//! - `Fin`: one side sends this frame to indicate that it has no more data to
+//!   send.
+//!
+//! The handshake is simpler than a TCP handshake:
+//! The client sends a `Syn` frame, then the server replies an `SynAck`.
+//! To reduce overhead, in `Syn`, the `dport` is 0, and the server replies
+//! with a usable port in the `sport` of the `SynAck` frame.
+//! The client asks for a target to `connect` in the `Syn` payload (see above).
+//!
+//! After a certain amount of `Psh` frames are received, the mux will send
+//! a `Ack` frame to confirm the data. The `Ack` frame carries the current
+//! remaining space in the receive buffer as a `u64`. (Similar to TCP receiving
+//! window). One side should never write more frames than the other side's
+//! receive window size before receiving the next `Ack` frame.
+//!
+//! `Datagram` messages are connectionless. On the client side,
+//! each forwarder client (i.e. (port, host) tuple) is assigned a unique
+//! Source ID, and the frame also carries its intended target.
+//! When the server receives datagrams from that target, it will
+//! send them back to the client with the same Source ID.
+//!
+//! With [`FrameVersion::V2`], both sides start with a `Hello` message
+//! listing their `Capabilities`, so that features can be added without
+//! breaking older peers.
+//!
+//! A datagram with an empty host and port 0 carries an IP packet instead,
+//! but only to peers that announced [`Capabilities::ip_packets`].
+//!
+//! Padding frames wrap another frame and fill up the message to hide its
+//! size:
+//! - 4 bytes: length of the wrapped frame in network byte order. 0 for a
+//!   dummy frame that wraps nothing.
+//! - variable: the wrapped frame.
+//! - variable: padding, ignored.
+//!
+//! A `GoAway` frame has no payload. Its sender accepts no new streams from
+//! now on, but lets the open ones finish before closing the connection.
+//!
+//! A datagram too large for the peer's `max_frame_size` is encoded without
+//! its type byte and split into `Fragment` frames:
+//! - 4 bytes: ID of the datagram, unique among the sender's fragmented
+//!   datagrams in flight.
+//! - 4 bytes: offset of this fragment in the encoded datagram.
+//! - 4 bytes: total length of the encoded datagram.
+//! - variable: the fragment.
+//!
+//! Fragments of a datagram are sent in order.
+//!
+//! A reliable datagram is a 4-byte sequence number in network byte order
+//! followed by a datagram frame without its type byte. It is sent again
+//! until a `DatagramAck` frame, a list of 4-byte sequence numbers,
+//! acknowledges it.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+#![allow(clippy::similar_names)]
+
+use crate::transport::Message;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::{fmt::Debug, io::ErrorKind, num::TryFromIntError};
+use thiserror::Error;
+use tracing::{trace, warn};
+
+/// Errors that can occur when parsing a frame.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Frame is invalid or incomplete")]
+    FrameTooShort,
+    #[error("Invalid frame type: {0}")]
+    InvalidFrameType(u8),
+    #[error("Invalid stream flag: {0}")]
+    InvalidStreamFlag(u8),
+    #[error("Invalid value for capability {0}")]
+    InvalidCapability(u8),
+    #[error("Invalid value for header extension {0}")]
+    InvalidExtension(u8),
+}
+
+/// Format of stream frames. Datagram frames are the same in all versions.
+/// Newer versions compare greater.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FrameVersion {
+    /// 16-bit ports, spoken by `penguin-v6` peers
+    #[default]
+    V1,
+    /// 32-bit ports, reserved flag bits, and header extensions
+    V2,
+}
+
+impl FrameVersion {
+    /// Largest port the version can encode
+    #[must_use]
+    pub const fn max_port(self) -> u32 {
+        match self {
+            Self::V1 => u16::MAX as u32,
+            Self::V2 => u32::MAX,
+        }
+    }
+}
+
+/// Stream frame types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StreamFlag {
+    /// Initiating connection by the client.
+    Syn = 0,
+    /// Confirming connection by the server.
+    SynAck = 1,
+    /// Confirming data reception.
+    Ack = 2,
+    /// Aborting connection when `dport` does not exist.
+    Rst = 3,
+    /// Closing connection.
+    Fin = 4,
+    /// Sending data.
+    Psh = 5,
+}
+
+/// Why a stream was reset, carried by `Rst` frames in V2.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RstReason {
+    /// No reason given, or one we do not know
+    #[default]
+    Unspecified = 0,
+    /// The destination refused the connection
+    Refused = 1,
+    /// The destination is not allowed
+    Denied = 2,
+    /// Connecting to the destination timed out
+    TimedOut = 3,
+    /// There is no route to the destination
+    NoRoute = 4,
+}
+
+impl From<u8> for RstReason {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => Self::Refused,
+            2 => Self::Denied,
+            3 => Self::TimedOut,
+            4 => Self::NoRoute,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+impl From<&std::io::Error> for RstReason {
+    /// The reason to give the peer when connecting failed with this error
+    fn from(err: &std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::ConnectionRefused => Self::Refused,
+            ErrorKind::PermissionDenied => Self::Denied,
+            ErrorKind::TimedOut => Self::TimedOut,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => Self::NoRoute,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
+impl From<RstReason> for ErrorKind {
+    fn from(reason: RstReason) -> Self {
+        match reason {
+            RstReason::Unspecified => Self::ConnectionReset,
+            RstReason::Refused => Self::ConnectionRefused,
+            RstReason::Denied => Self::PermissionDenied,
+            RstReason::TimedOut => Self::TimedOut,
+            RstReason::NoRoute => Self::HostUnreachable,
+        }
+    }
+}
+
+impl std::fmt::Display for RstReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Unspecified => "unspecified",
+            Self::Refused => "connection refused",
+            Self::Denied => "not allowed",
+            Self::TimedOut => "timed out",
+            Self::NoRoute => "no route to host",
+        })
+    }
+}
+
+/// TCP options that the client asks the server to set on its connection to
+/// the destination of a stream, carried by `Syn` frames in V2. `None`
+/// keeps the server's default.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TcpOptions {
+    /// Whether to disable Nagle's algorithm (`TCP_NODELAY`)
+    pub nodelay: Option<bool>,
+    /// Seconds of idleness before the first keepalive probe
+    pub keepalive_idle: Option<u32>,
+    /// Seconds between keepalive probes
+    pub keepalive_interval: Option<u32>,
+    /// Unanswered keepalive probes before the connection is dropped
+    pub keepalive_count: Option<u32>,
+    /// Seconds that sent data may stay unacknowledged before the
+    /// connection is dropped (`TCP_USER_TIMEOUT`)
+    pub user_timeout: Option<u32>,
+}
+
+impl TcpOptions {
+    /// No options
+    pub const NONE: Self = Self {
+        nodelay: None,
+        keepalive_idle: None,
+        keepalive_interval: None,
+        keepalive_count: None,
+        user_timeout: None,
+    };
+
+    /// Whether no option is set
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        *self == Self::NONE
+    }
+
+    /// Whether a keepalive parameter is set, which turns keepalive on
+    #[must_use]
+    pub const fn keepalive(&self) -> bool {
+        self.keepalive_idle.is_some()
+            || self.keepalive_interval.is_some()
+            || self.keepalive_count.is_some()
+    }
+
+    /// The 32-bit options, in the order they are encoded
+    const fn u32_options(&self) -> [Option<u32>; 4] {
+        [
+            self.keepalive_idle,
+            self.keepalive_interval,
+            self.keepalive_count,
+            self.user_timeout,
+        ]
+    }
+
+    /// Length of the encoded options
+    fn encoded_len(&self) -> usize {
+        1 + usize::from(self.nodelay.is_some()) + 4 * self.u32_options().iter().flatten().count()
+    }
+
+    /// Encode the options as a bit set of the ones present, followed by
+    /// their values in the order of the bits.
+    fn encode(&self, encoded: &mut BytesMut) {
+        let mut present = u8::from(self.nodelay.is_some());
+        for (bit, option) in self.u32_options().iter().enumerate() {
+            if option.is_some() {
+                present |= 2 << bit;
+            }
+        }
+        encoded.put_u8(present);
+        if let Some(nodelay) = self.nodelay {
+            encoded.put_u8(u8::from(nodelay));
+        }
+        for value in self.u32_options().into_iter().flatten() {
+            encoded.put_u32(value);
+        }
+    }
+
+    /// Decode the options, or `None` if they are invalid.
+    fn decode(mut value: Bytes) -> Option<Self> {
+        if !value.has_remaining() {
+            return None;
+        }
+        let present = value.get_u8();
+        if present & !0x1f != 0 {
+            return None;
+        }
+        let mut options = Self::NONE;
+        if present & 1 != 0 {
+            if !value.has_remaining() {
+                return None;
+            }
+            options.nodelay = Some(value.get_u8() != 0);
+        }
+        let u32_options = [
+            &mut options.keepalive_idle,
+            &mut options.keepalive_interval,
+            &mut options.keepalive_count,
+            &mut options.user_timeout,
+        ];
+        for (bit, option) in u32_options.into_iter().enumerate() {
+            if present & (2 << bit) != 0 {
+                if value.remaining() < 4 {
+                    return None;
+                }
+                *option = Some(value.get_u32());
+            }
+        }
+        (!value.has_remaining()).then_some(options)
+    }
+}
+
+/// Stream frame.
+///
+/// See PROTOCOL.md for details.
+#[derive(Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct StreamFrame {
+    /// Source port (2 bytes in V1, 4 bytes in V2)
+    pub sport: u32,
+    /// Destination port (2 bytes in V1, 4 bytes in V2)
+    pub dport: u32,
+    /// Frame type (1 byte)
+    pub flag: StreamFlag,
+    /// Compression extension, 0 if absent. In `Syn`, the algorithms the
+    /// sender accepts for the stream; in `SynAck`, the one chosen; in
+    /// `Psh`, the one `data` is compressed with. Only sent in V2.
+    pub compression: u8,
+    /// Reason extension of `Rst`, [`RstReason::Unspecified`] if absent.
+    /// Only sent in V2.
+    pub reason: RstReason,
+    /// TCP options extension of `Syn`, [`TcpOptions::NONE`] if absent.
+    /// Only sent in V2.
+    pub tcp_options: TcpOptions,
+    /// Trace context extension of `Syn`, empty if absent: an opaque value,
+    /// e.g. a W3C `traceparent`, that links the server's work on the stream
+    /// to the client's. Only sent in V2, and left out if it does not fit in
+    /// the header extensions.
+    pub trace_context: Bytes,
+    /// Data
+    pub data: Bytes,
+}
+
+impl Debug for StreamFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StreamFrame")
+            .field("sport", &self.sport)
+            .field("dport", &self.dport)
+            .field("flag", &self.flag)
+            .field("compression", &self.compression)
+            .field("reason", &self.reason)
+            .field("tcp_options", &self.tcp_options)
+            .field("trace_context", &self.trace_context)
+            .field("data.len", &self.data.len())
+            .finish()
+    }
+}
+
+impl StreamFrame {
+    /// Length of the encoded frame type, ports, and flag in V1
+    const V1_HEADER_LEN: usize =
+        1 + std::mem::size_of::<u16>() * 2 + std::mem::size_of::<StreamFlag>();
+    /// Length of the encoded frame type, flags, extension length, and ports
+    /// in V2
+    const V2_HEADER_LEN: usize = 1 + 1 + 1 + std::mem::size_of::<u32>() * 2;
+    /// Bits of the V2 flags byte that carry the [`StreamFlag`]. The others
+    /// are reserved: sent as 0 and ignored when received.
+    const V2_FLAG_MASK: u8 = 0x0f;
+    /// Header extension carrying [`StreamFrame::compression`]
+    const EXT_COMPRESSION: u8 = 0x01;
+    /// Header extension carrying [`StreamFrame::reason`]
+    const EXT_RST_REASON: u8 = 0x02;
+    /// Header extension carrying [`StreamFrame::tcp_options`]
+    const EXT_TCP_OPTIONS: u8 = 0x03;
+    /// Header extension carrying [`StreamFrame::trace_context`]
+    const EXT_TRACE_CONTEXT: u8 = 0x04;
+
+    /// Allocate a buffer for a frame with `data_len` bytes of data and
+    /// write the header. Fails if a port does not fit in `version`.
+    #[allow(clippy::too_many_arguments)]
+    #[inline]
+    fn encode_header(
+        version: FrameVersion,
+        sport: u32,
+        dport: u32,
+        flag: StreamFlag,
+        compression: u8,
+        reason: RstReason,
+        tcp_options: &TcpOptions,
+        trace_context: &[u8],
+        data_len: usize,
+    ) -> Result<BytesMut, TryFromIntError> {
+        let encoded = match version {
+            FrameVersion::V1 => {
+                let mut encoded = BytesMut::with_capacity(Self::V1_HEADER_LEN + data_len);
+                encoded.put_u8(1);
+                encoded.put_u16(u16::try_from(sport)?);
+                encoded.put_u16(u16::try_from(dport)?);
+                encoded.put_u8(flag as u8);
+                encoded
+            }
+            FrameVersion::V2 => {
+                let ext_len = if compression == 0 { 0 } else { 3 }
+                    + if reason == RstReason::Unspecified {
+                        0
+                    } else {
+                        3
+                    }
+                    + if tcp_options.is_empty() {
+                        0
+                    } else {
+                        2 + tcp_options.encoded_len()
+                    };
+                // A context that does not fit is left out rather than cut
+                let trace_context = if ext_len + 2 + trace_context.len() <= usize::from(u8::MAX) {
+                    trace_context
+                } else {
+                    &[]
+                };
+                let ext_len = ext_len
+                    + if trace_context.is_empty() {
+                        0
+                    } else {
+                        2 + trace_context.len()
+                    };
+                let mut encoded = BytesMut::with_capacity(Self::V2_HEADER_LEN + ext_len + data_len);
+                encoded.put_u8(1);
+                encoded.put_u8(flag as u8);
+                encoded.put_u8(ext_len as u8);
+                encoded.put_u32(sport);
+                encoded.put_u32(dport);
+                if compression != 0 {
+                    encoded.put_u8(Self::EXT_COMPRESSION);
+                    encoded.put_u8(1);
+                    encoded.put_u8(compression);
+                }
+                if reason != RstReason::Unspecified {
+                    encoded.put_u8(Self::EXT_RST_REASON);
+                    encoded.put_u8(1);
+                    encoded.put_u8(reason as u8);
+                }
+                if !tcp_options.is_empty() {
+                    encoded.put_u8(Self::EXT_TCP_OPTIONS);
+                    encoded.put_u8(tcp_options.encoded_len() as u8);
+                    tcp_options.encode(&mut encoded);
+                }
+                if !trace_context.is_empty() {
+                    encoded.put_u8(Self::EXT_TRACE_CONTEXT);
+                    encoded.put_u8(trace_context.len() as u8);
+                    encoded.extend_from_slice(trace_context);
+                }
+                encoded
+            }
+        };
+        Ok(encoded)
+    }
+
+    /// Length of the encoded header in `version` without extensions
+    #[inline]
+    pub(crate) const fn header_len(version: FrameVersion) -> usize {
+        match version {
+            FrameVersion::V1 => Self::V1_HEADER_LEN,
+            FrameVersion::V2 => Self::V2_HEADER_LEN,
+        }
+    }
+
+    /// Encode the frame in `version`.
+    ///
+    /// # Errors
+    /// Fails if a port does not fit in `version`.
+    #[inline]
+    pub fn encode(self, version: FrameVersion) -> Result<Bytes, TryFromIntError> {
+        let mut encoded = Self::encode_header(
+            version,
+            self.sport,
+            self.dport,
+            self.flag,
+            self.compression,
+            self.reason,
+            &self.tcp_options,
+            &self.trace_context,
+            self.data.len(),
+        )?;
+        encoded.extend_from_slice(&self.data);
+        Ok(encoded.freeze())
+    }
+
+    /// Encode a [`StreamFlag::Psh`] frame carrying `data`. This is the same
+    /// as encoding [`StreamFrame::new_psh`], but copies `data` only once.
+    ///
+    /// # Errors
+    /// Fails if a port does not fit in `version`.
+    #[inline]
+    pub fn encode_psh(
+        version: FrameVersion,
+        sport: u32,
+        dport: u32,
+        data: &[u8],
+    ) -> Result<Bytes, TryFromIntError> {
+        let mut encoded = Self::encode_header(
+            version,
+            sport,
+            dport,
+            StreamFlag::Psh,
+            0,
+            RstReason::Unspecified,
+            &TcpOptions::NONE,
+            &[],
+            data.len(),
+        )?;
+        encoded.extend_from_slice(data);
+        Ok(encoded.freeze())
+    }
+
+    /// Encode the frame into a `Message` for a mux speaking `version`. The
+    /// mux only hands out ports that fit.
+    #[inline]
+    pub(crate) fn into_message(self, version: FrameVersion) -> Message {
+        Message::Frame(
+            self.encode(version)
+                .expect("port does not fit in the frame version (this is a bug)"),
+        )
+    }
+
+    /// Decode a frame in `version`, without the frame type.
+    ///
+    /// # Errors
+    /// Fails if the frame is too short or of an unknown type.
+    #[inline]
+    pub fn decode(mut data: Bytes, version: FrameVersion) -> Result<Self, Error> {
+        let mut compression = 0;
+        let mut reason = RstReason::Unspecified;
+        let mut tcp_options = TcpOptions::NONE;
+        let mut trace_context = Bytes::new();
+        let (sport, dport, flag) = match version {
+            FrameVersion::V1 => {
+                if data.remaining() < Self::V1_HEADER_LEN - 1 {
+                    return Err(Error::FrameTooShort);
+                }
+                let sport = u32::from(data.get_u16());
+                let dport = u32::from(data.get_u16());
+                (sport, dport, data.get_u8())
+            }
+            FrameVersion::V2 => {
+                if data.remaining() < Self::V2_HEADER_LEN - 1 {
+                    return Err(Error::FrameTooShort);
+                }
+                let flag = data.get_u8() & Self::V2_FLAG_MASK;
+                let ext_len = usize::from(data.get_u8());
+                let sport = data.get_u32();
+                let dport = data.get_u32();
+                if data.remaining() < ext_len {
+                    return Err(Error::FrameTooShort);
+                }
+                let mut ext = data.split_to(ext_len);
+                while ext.remaining() >= 2 {
+                    let id = ext.get_u8();
+                    let len = usize::from(ext.get_u8());
+                    if ext.remaining() < len {
+                        // Not one of ours, so skipped like an unknown one
+                        break;
+                    }
+                    let mut value = ext.split_to(len);
+                    match (id, len) {
+                        (Self::EXT_COMPRESSION, 1) => compression = value.get_u8(),
+                        (Self::EXT_COMPRESSION, _) => return Err(Error::InvalidExtension(id)),
+                        (Self::EXT_RST_REASON, 1) => reason = RstReason::from(value.get_u8()),
+                        (Self::EXT_RST_REASON, _) => return Err(Error::InvalidExtension(id)),
+                        (Self::EXT_TCP_OPTIONS, _) => {
+                            tcp_options =
+                                TcpOptions::decode(value).ok_or(Error::InvalidExtension(id))?;
+                        }
+                        (Self::EXT_TRACE_CONTEXT, _) => trace_context = value,
+                        _ => trace!("ignoring unknown header extension {id}"),
+                    }
+                }
+                (sport, dport, flag)
+            }
+        };
+        let flag = match flag {
+            0 => StreamFlag::Syn,
+            1 => StreamFlag::SynAck,
+            2 => StreamFlag::Ack,
+            3 => StreamFlag::Rst,
+            4 => StreamFlag::Fin,
+            5 => StreamFlag::Psh,
+            other => return Err(Error::InvalidStreamFlag(other)),
+        };
+        Ok(Self {
+            sport,
+            dport,
+            flag,
+            compression,
+            reason,
+            tcp_options,
+            trace_context,
+            data,
+        })
+    }
+
+    /// Create a new [`StreamFlag::Syn`] frame.
+    ///
+    /// # Arguments
+    /// * `dest_host`: The destination host to forward to.
+    /// * `dest_port`: The destination port to forward to.
+    /// * `sport`: The source port of this stream.
+    /// * `rwnd`: Number of frames buffered in the client receive buffer.
+    #[must_use]
+    #[inline]
+    pub fn new_syn(dest_host: &[u8], dest_port: u16, sport: u32, rwnd: u64) -> Self {
+        let host_len = dest_host.len();
+        let mut syn_payload =
+            Vec::with_capacity(std::mem::size_of::<u64>() + std::mem::size_of::<u16>() + host_len);
+        syn_payload.put_u64(rwnd);
+        syn_payload.put_u16(dest_port);
+        syn_payload.extend(dest_host);
+        Self {
+            sport,
+            dport: 0,
+            flag: StreamFlag::Syn,
+            compression: 0,
+            reason: RstReason::Unspecified,
+            tcp_options: TcpOptions::NONE,
+            trace_context: Bytes::new(),
+            data: Bytes::from(syn_payload),
+        }
+    }
+    /// Create a new [`StreamFlag::SynAck`] frame.
+    ///
+    /// # Arguments
+    /// * `sport`: The source port of this stream.
+    /// * `dport`: The destination port of this stream (i.e. the source port
+    ///   of the `Syn` frame).
+    /// * `rwnd`: Number of frames buffered in the server receive buffer.
+    #[must_use]
+    #[inline]
+    pub fn new_synack(sport: u32, dport: u32, rwnd: u64) -> Self {
+        Self {
+            sport,
+            dport,
+            flag: StreamFlag::SynAck,
+            compression: 0,
+            reason: RstReason::Unspecified,
+            tcp_options: TcpOptions::NONE,
+            trace_context: Bytes::new(),
+            data: Bytes::copy_from_slice(&rwnd.to_be_bytes()),
+        }
+    }
+    /// Create a new [`StreamFlag::Ack`] frame.
+    ///
+    /// # Arguments
+    /// * `sport`: The source port of this stream.
+    /// * `dport`: The destination port of this stream.
+    /// * `psh_recvd_since`: The number of `Psh` frames received since the
+    ///   previous `Ack` frame.
+    #[must_use]
+    #[inline]
+    pub fn new_ack(sport: u32, dport: u32, psh_recvd_since: u64) -> Self {
+        Self {
+            sport,
+            dport,
+            flag: StreamFlag::Ack,
+            compression: 0,
+            reason: RstReason::Unspecified,
+            tcp_options: TcpOptions::NONE,
+            trace_context: Bytes::new(),
+            data: Bytes::copy_from_slice(&psh_recvd_since.to_be_bytes()),
+        }
+    }
+    /// Create a new [`StreamFlag::Rst`] frame.
+    ///
+    /// # Arguments
+    /// * `sport`: The destination port of the offending frame.
+    /// * `dport`: The source port of the offending frame.
+    /// * `reason`: Why the stream is reset.
+    #[must_use]
+    #[inline]
+    pub const fn new_rst(sport: u32, dport: u32, reason: RstReason) -> Self {
+        Self {
+            sport,
+            dport,
+            flag: StreamFlag::Rst,
+            compression: 0,
+            reason,
+            tcp_options: TcpOptions::NONE,
+            trace_context: Bytes::new(),
+            data: Bytes::new(),
+        }
+    }
+    /// Create a new [`StreamFlag::Rst`] frame that also tells the peer why
+    /// in words.
+    ///
+    /// # Arguments
+    /// * `sport`: The destination port of the offending frame.
+    /// * `dport`: The source port of the offending frame.
+    /// * `reason`: Why the stream is reset.
+    /// * `message`: Human-readable reason.
+    #[must_use]
+    #[inline]
+    pub fn new_rst_with_message(sport: u32, dport: u32, reason: RstReason, message: &str) -> Self {
+        Self {
+            data: Bytes::copy_from_slice(message.as_bytes()),
+            ..Self::new_rst(sport, dport, reason)
+        }
+    }
+    /// Create a new [`StreamFlag::Fin`] frame.
+    ///
+    /// # Arguments
+    /// * `sport`: The source port of this stream.
+    /// * `dport`: The destination port of this stream.
+    #[must_use]
+    #[inline]
+    pub const fn new_fin(sport: u32, dport: u32) -> Self {
+        Self {
+            sport,
+            dport,
+            flag: StreamFlag::Fin,
+            compression: 0,
+            reason: RstReason::Unspecified,
+            tcp_options: TcpOptions::NONE,
+            trace_context: Bytes::new(),
+            data: Bytes::new(),
+        }
+    }
+    /// Create a new [`StreamFlag::Psh`] frame.
+    ///
+    /// # Arguments
+    /// * `sport`: The source port of this stream.
+    /// * `dport`: The destination port of this stream.
+    /// * `data`: The data to send.
+    #[must_use]
+    #[inline]
+    pub const fn new_psh(sport: u32, dport: u32, data: Bytes) -> Self {
+        Self {
+            sport,
+            dport,
+            flag: StreamFlag::Psh,
+            compression: 0,
+            reason: RstReason::Unspecified,
+            tcp_options: TcpOptions::NONE,
+            trace_context: Bytes::new(),
+            data,
+        }
+    }
+}
+
+/// Datagram frame.
+///
+/// See PROTOCOL.md for details.
+#[derive(Clone, PartialEq, Eq)]
+#[repr(C)]
+pub struct DatagramFrame {
+    /// Target host:
+    /// Host of the forwarding target
+    /// host of the "remote" if sent from client;
+    /// host of the "from" if sent from server.
+    pub host: Bytes,
+    /// Target port:
+    /// Port of the forwarding target
+    pub port: u16,
+    /// User ID (4 bytes)
+    pub sid: u32,
+    /// Data
+    pub data: Bytes,
+}
+
+impl Debug for DatagramFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DatagramFrame")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("sid", &self.sid)
+            .field("data.len", &self.data.len())
+            .finish()
+    }
+}
+
+/// Fragment of a datagram too large for one frame.
+///
+/// See PROTOCOL.md for details.
+#[derive(Clone, PartialEq, Eq)]
+pub struct FragmentFrame {
+    /// ID of the datagram
+    pub id: u32,
+    /// Offset of `data` in the encoded datagram
+    pub offset: u32,
+    /// Length of the encoded datagram
+    pub total: u32,
+    /// Data
+    pub data: Bytes,
+}
+
+impl Debug for FragmentFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FragmentFrame")
+            .field("id", &self.id)
+            .field("offset", &self.offset)
+            .field("total", &self.total)
+            .field("data.len", &self.data.len())
+            .finish()
+    }
+}
+
+/// Length of the type and header of a fragment frame
+pub(crate) const FRAGMENT_HEADER_LEN: usize = 1 + 3 * std::mem::size_of::<u32>();
+
+impl FragmentFrame {
+    fn encode(&self) -> Bytes {
+        let mut encoded = BytesMut::with_capacity(FRAGMENT_HEADER_LEN + self.data.len());
+        encoded.put_u8(6);
+        encoded.put_u32(self.id);
+        encoded.put_u32(self.offset);
+        encoded.put_u32(self.total);
+        encoded.extend_from_slice(&self.data);
+        encoded.freeze()
+    }
+
+    fn decode(mut data: Bytes) -> Result<Self, Error> {
+        if data.remaining() < FRAGMENT_HEADER_LEN - 1 {
+            return Err(Error::FrameTooShort);
+        }
+        let id = data.get_u32();
+        let offset = data.get_u32();
+        let total = data.get_u32();
+        Ok(Self {
+            id,
+            offset,
+            total,
+            data,
+        })
+    }
+}
+
+/// Datagram that is sent again until the peer acknowledges it.
+///
+/// See PROTOCOL.md for details.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReliableDatagramFrame {
+    /// Sequence number, acknowledged in a `DatagramAck` frame
+    pub seq: u32,
+    /// The datagram
+    pub datagram: DatagramFrame,
+}
+
+impl ReliableDatagramFrame {
+    fn encode(self) -> Result<Bytes, TryFromIntError> {
+        let datagram = Bytes::try_from(self.datagram)?;
+        let mut encoded = BytesMut::with_capacity(std::mem::size_of::<u32>() + datagram.len());
+        encoded.put_u8(7);
+        encoded.put_u32(self.seq);
+        // Without the type byte of the datagram
+        encoded.extend_from_slice(&datagram[1..]);
+        Ok(encoded.freeze())
+    }
+
+    fn decode(mut data: Bytes) -> Result<Self, Error> {
+        if data.remaining() < std::mem::size_of::<u32>() {
+            return Err(Error::FrameTooShort);
+        }
+        let seq = data.get_u32();
+        Ok(Self {
+            seq,
+            datagram: DatagramFrame::try_from(data)?,
+        })
+    }
+}
+
+/// Features a side supports, sent in a `Hello` frame when the connection
+/// starts.
+///
+/// See PROTOCOL.md for details.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Largest frame the sender accepts, if it is limited. At least
+    /// 1024 bytes.
+    pub max_frame_size: Option<u32>,
+    /// Compression algorithms the sender supports, as a bit set
+    pub compression: u8,
+    /// Whether the sender honours receive windows and sends `Ack`s
+    pub flow_control: bool,
+    /// Whether the sender accepts datagram frames
+    pub datagrams: bool,
+    /// Whether the sender accepts padding frames
+    pub padding: bool,
+    /// Whether the sender understands `GoAway` frames
+    pub go_away: bool,
+    /// Whether the sender reassembles `Fragment` frames
+    pub fragments: bool,
+    /// Whether the sender acknowledges reliable datagram frames
+    pub reliable_datagrams: bool,
+    /// Whether the sender accepts IP packets in datagram frames
+    pub ip_packets: bool,
+    /// Whether the sender applies the TCP options of `Syn` frames
+    pub tcp_options: bool,
+}
+
+impl Default for Capabilities {
+    /// What this implementation supports
+    fn default() -> Self {
+        Self {
+            max_frame_size: None,
+            compression: 0,
+            flow_control: true,
+            datagrams: true,
+            padding: true,
+            go_away: true,
+            fragments: true,
+            reliable_datagrams: true,
+            // Only if the application has somewhere to send them
+            ip_packets: false,
+            // Only if the application dials TCP connections
+            tcp_options: false,
+        }
+    }
+}
+
+impl Capabilities {
+    const MAX_FRAME_SIZE: u8 = 0x01;
+    const COMPRESSION: u8 = 0x02;
+    const FLOW_CONTROL: u8 = 0x03;
+    const DATAGRAMS: u8 = 0x04;
+    const PADDING: u8 = 0x05;
+    const GO_AWAY: u8 = 0x06;
+    const FRAGMENTS: u8 = 0x07;
+    const RELIABLE_DATAGRAMS: u8 = 0x08;
+    const IP_PACKETS: u8 = 0x09;
+    const TCP_OPTIONS: u8 = 0x0a;
+
+    /// Encode the capabilities as a sequence of (id, length, value).
+    fn encode(&self) -> Bytes {
+        // Room for the type and all capabilities
+        let mut encoded = BytesMut::with_capacity(1 + 6 + 3 + 2 + 2 + 2 + 2 + 2 + 2 + 2 + 2);
+        encoded.put_u8(2);
+        if let Some(max_frame_size) = self.max_frame_size {
+            encoded.put_u8(Self::MAX_FRAME_SIZE);
+            encoded.put_u8(4);
+            encoded.put_u32(max_frame_size);
+        }
+        if self.compression != 0 {
+            encoded.put_u8(Self::COMPRESSION);
+            encoded.put_u8(1);
+            encoded.put_u8(self.compression);
+        }
+        if self.flow_control {
+            encoded.put_u8(Self::FLOW_CONTROL);
+            encoded.put_u8(0);
+        }
+        if self.datagrams {
+            encoded.put_u8(Self::DATAGRAMS);
+            encoded.put_u8(0);
+        }
+        if self.padding {
+            encoded.put_u8(Self::PADDING);
+            encoded.put_u8(0);
+        }
+        if self.go_away {
+            encoded.put_u8(Self::GO_AWAY);
+            encoded.put_u8(0);
+        }
+        if self.fragments {
+            encoded.put_u8(Self::FRAGMENTS);
+            encoded.put_u8(0);
+        }
+        if self.reliable_datagrams {
+            encoded.put_u8(Self::RELIABLE_DATAGRAMS);
+            encoded.put_u8(0);
+        }
+        if self.ip_packets {
+            encoded.put_u8(Self::IP_PACKETS);
+            encoded.put_u8(0);
+        }
+        if self.tcp_options {
+            encoded.put_u8(Self::TCP_OPTIONS);
+            encoded.put_u8(0);
+        }
+        encoded.freeze()
+    }
+
+    /// Decode the capabilities. Unknown ones are skipped, so that new
+    /// features can be announced to older peers.
+    fn decode(mut data: Bytes) -> Result<Self, Error> {
+        let mut caps = Self {
+            max_frame_size: None,
+            compression: 0,
+            flow_control: false,
+            datagrams: false,
+            padding: false,
+            go_away: false,
+            fragments: false,
+            reliable_datagrams: false,
+            ip_packets: false,
+            tcp_options: false,
+        };
+        while data.has_remaining() {
+            if data.remaining() < 2 {
+                return Err(Error::FrameTooShort);
+            }
+            let id = data.get_u8();
+            let len = usize::from(data.get_u8());
+            if data.remaining() < len {
+                return Err(Error::FrameTooShort);
+            }
+            let mut value = data.split_to(len);
+            match (id, len) {
+                (Self::MAX_FRAME_SIZE, 4) => {
+                    let max_frame_size = value.get_u32();
+                    if max_frame_size < crate::config::MIN_FRAME_SIZE_LIMIT {
+                        return Err(Error::InvalidCapability(id));
+                    }
+                    caps.max_frame_size = Some(max_frame_size);
+                }
+                (Self::COMPRESSION, 1) => caps.compression = value.get_u8(),
+                (Self::FLOW_CONTROL, 0) => caps.flow_control = true,
+                (Self::DATAGRAMS, 0) => caps.datagrams = true,
+                (Self::PADDING, 0) => caps.padding = true,
+                (Self::GO_AWAY, 0) => caps.go_away = true,
+                (Self::FRAGMENTS, 0) => caps.fragments = true,
+                (Self::RELIABLE_DATAGRAMS, 0) => caps.reliable_datagrams = true,
+                (Self::IP_PACKETS, 0) => caps.ip_packets = true,
+                (Self::TCP_OPTIONS, 0) => caps.tcp_options = true,
+                (Self::MAX_FRAME_SIZE..=Self::TCP_OPTIONS, _) => {
+                    return Err(Error::InvalidCapability(id));
+                }
+                _ => warn!("ignoring unknown capability {id}"),
+            }
+        }
+        Ok(caps)
+    }
+}
+
+/// Frame.
+///
+/// See PROTOCOL.md for details.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum Frame {
+    /// Stream frame, encoded with `Type=0x01`
+    Stream(StreamFrame),
+    /// Capabilities of the sender, encoded with `Type=0x02`
+    Hello(Capabilities),
+    /// Datagram frame, encoded with `Type=0x03`
+    Datagram(DatagramFrame),
+    /// Dummy frame, encoded with `Type=0x04`. Frames wrapped in padding
+    /// decode as themselves.
+    Padding,
+    /// No new streams, the sender is draining. Encoded with `Type=0x05`
+    GoAway,
+    /// Part of a datagram, encoded with `Type=0x06`
+    Fragment(FragmentFrame),
+    /// Datagram to acknowledge, encoded with `Type=0x07`
+    ReliableDatagram(ReliableDatagramFrame),
+    /// Sequence numbers of the reliable datagrams received, encoded with
+    /// `Type=0x08`
+    DatagramAck(Vec<u32>),
+}
+
+impl Frame {
+    /// Encode the frame, with stream frames in `version`.
+    ///
+    /// # Errors
+    /// Fails if a port does not fit in `version`, or if
+    /// [`DatagramFrame::host`] is longer than 255 octets.
+    #[inline]
+    pub fn encode(self, version: FrameVersion) -> Result<Bytes, TryFromIntError> {
+        match self {
+            Self::Stream(frame) => frame.encode(version),
+            Self::Hello(caps) => Ok(caps.encode()),
+            Self::Datagram(frame) => frame.try_into(),
+            Self::Padding => Ok(pad(&[], PADDING_HEADER_LEN)),
+            Self::GoAway => Ok(Bytes::from_static(&[5])),
+            Self::Fragment(frame) => Ok(frame.encode()),
+            Self::ReliableDatagram(frame) => frame.encode(),
+            Self::DatagramAck(seqs) => {
+                let mut encoded = BytesMut::with_capacity(1 + 4 * seqs.len());
+                encoded.put_u8(8);
+                for seq in seqs {
+                    encoded.put_u32(seq);
+                }
+                Ok(encoded.freeze())
+            }
+        }
+    }
+
+    /// Decode a frame, with stream frames in `version`.
+    ///
+    /// # Errors
+    /// Fails if the frame is too short or of an unknown type.
+    #[tracing::instrument(skip_all, level = "trace")]
+    #[inline]
+    pub fn decode(mut data: Bytes, version: FrameVersion) -> Result<Self, Error> {
+        if data.remaining() < 1 {
+            return Err(Error::FrameTooShort);
+        }
+        let frame_type = data.get_u8();
+        match frame_type {
+            1 => Ok(Self::Stream(StreamFrame::decode(data, version)?)),
+            2 => Ok(Self::Hello(Capabilities::decode(data)?)),
+            3 => Ok(Self::Datagram(DatagramFrame::try_from(data)?)),
+            4 => {
+                if data.remaining() < 4 {
+                    return Err(Error::FrameTooShort);
+                }
+                let len = data.get_u32() as usize;
+                if len == 0 {
+                    return Ok(Self::Padding);
+                }
+                if data.remaining() < len {
+                    return Err(Error::FrameTooShort);
+                }
+                let inner = data.split_to(len);
+                // Padding is not nested
+                if inner[0] == 4 {
+                    return Err(Error::InvalidFrameType(4));
+                }
+                Self::decode(inner, version)
+            }
+            // The payload is reserved
+            5 => Ok(Self::GoAway),
+            6 => Ok(Self::Fragment(FragmentFrame::decode(data)?)),
+            7 => Ok(Self::ReliableDatagram(ReliableDatagramFrame::decode(data)?)),
+            8 => {
+                if !data.remaining().is_multiple_of(4) {
+                    return Err(Error::FrameTooShort);
+                }
+                let mut seqs = Vec::with_capacity(data.remaining() / 4);
+                while data.has_remaining() {
+                    seqs.push(data.get_u32());
+                }
+                Ok(Self::DatagramAck(seqs))
+            }
+            other => Err(Error::InvalidFrameType(other)),
+        }
+    }
+}
+
+/// Length of the type and length of a padding frame
+pub(crate) const PADDING_HEADER_LEN: usize = 1 + std::mem::size_of::<u32>();
+
+/// Wrap the encoded `frame` in a padding frame of `size` bytes, or of just
+/// enough bytes if that is more.
+#[must_use]
+pub(crate) fn pad(frame: &[u8], size: usize) -> Bytes {
+    let size = size.max(PADDING_HEADER_LEN + frame.len());
+    let mut encoded = BytesMut::with_capacity(size);
+    encoded.put_u8(4);
+    encoded.put_u32(u32::try_from(frame.len()).expect("frame larger than 4 GiB (this is a bug)"));
+    encoded.extend_from_slice(frame);
+    encoded.resize(size, 0);
+    encoded.freeze()
+}
+
+// Frames are encoded into a buffer of exactly their size, so that turning
+// the `Bytes` into a `Vec<u8>`, as `tungstenite` wants, does not copy again.
+impl TryFrom<DatagramFrame> for Bytes {
+    type Error = TryFromIntError;
+
+    /// Convert a [`DatagramFrame`] to bytes. Gives an error when
+    /// [`DatagramFrame::host`] is longer than 255 octets.
+    #[inline]
+    fn try_from(frame: DatagramFrame) -> Result<Self, Self::Error> {
+        let size = 1
+            + 1
+            + frame.host.len()
+            + std::mem::size_of::<u16>()
+            + std::mem::size_of::<u32>()
+            + frame.data.len();
+        let mut encoded = BytesMut::with_capacity(size);
+        encoded.put_u8(3);
+        encoded.put_u8(u8::try_from(frame.host.len())?);
+        encoded.extend_from_slice(&frame.host);
+        encoded.put_u16(frame.port);
+        encoded.put_u32(frame.sid);
+        encoded.extend_from_slice(&frame.data);
+        Ok(encoded.freeze())
+    }
+}
+
+impl TryFrom<Frame> for Bytes {
+    type Error = TryFromIntError;
+
+    /// Encode a [`Frame`] in [`FrameVersion::V1`].
+    #[inline]
+    fn try_from(frame: Frame) -> Result<Self, Self::Error> {
+        frame.encode(FrameVersion::V1)
+    }
+}
+
+impl TryFrom<Frame> for Vec<u8> {
+    type Error = TryFromIntError;
+
+    #[inline]
+    fn try_from(frame: Frame) -> Result<Self, Self::Error> {
+        Bytes::try_from(frame).map(Self::from)
+    }
+}
+
+impl TryFrom<Bytes> for DatagramFrame {
+    type Error = Error;
+
+    #[inline]
+    fn try_from(mut data: Bytes) -> Result<Self, Self::Error> {
+        if data.remaining() < 1 {
+            return Err(Error::FrameTooShort);
+        }
+        let host_len = usize::from(data.get_u8());
+
+        if data.remaining() < host_len + 6 {
+            return Err(Error::FrameTooShort);
+        }
+        let host = data.split_to(host_len);
+        let port = data.get_u16();
+        let sid = data.get_u32();
+        Ok(Self {
+            host,
+            port,
+            sid,
+            data,
+        })
+    }
+}
+
+impl TryFrom<Bytes> for Frame {
+    type Error = Error;
+
+    /// Decode a [`Frame`] in [`FrameVersion::V1`].
+    #[inline]
+    fn try_from(data: Bytes) -> Result<Self, Self::Error> {
+        Self::decode(data, FrameVersion::V1)
+    }
+}
+
+impl TryFrom<Vec<u8>> for Frame {
+    type Error = <Self as TryFrom<Bytes>>::Error;
+
+    #[inline]
+    fn try_from(data: Vec<u8>) -> Result<Self, Self::Error> {
+        Self::try_from(Bytes::from(data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stream_frame() {
+        let frame = Frame::Stream(StreamFrame::new_syn(&[], 5678, 1234, 128));
+        assert_eq!(
+            frame,
+            Frame::Stream(StreamFrame {
+                sport: 1234,
+                dport: 0,
+                flag: StreamFlag::Syn,
+                compression: 0,
+                reason: RstReason::Unspecified,
+                tcp_options: TcpOptions::NONE,
+                trace_context: Bytes::new(),
+                data: Bytes::from_static(&[
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0x16, 0x2e
+                ]),
+            })
+        );
+        let bytes = Vec::try_from(frame.clone()).unwrap();
+        let decoded = Frame::try_from(bytes).unwrap();
+        assert_eq!(frame, decoded);
+    }
+
+    #[test]
+    fn test_encode_psh() {
+        let data = [1, 2, 3, 4];
+        let frame = StreamFrame::new_psh(1234, 5678, Bytes::copy_from_slice(&data));
+        let encoded = StreamFrame::encode_psh(FrameVersion::V1, 1234, 5678, &data).unwrap();
+        assert_eq!(encoded, frame.clone().encode(FrameVersion::V1).unwrap());
+        assert_eq!(Frame::try_from(encoded).unwrap(), Frame::Stream(frame));
+    }
+
+    #[test]
+    fn test_v2_stream_frame() {
+        let frame = StreamFrame::new_psh(0x0001_0000, 5678, Bytes::from_static(&[1, 2]));
+        assert!(frame.clone().encode(FrameVersion::V1).is_err());
+        let encoded = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(
+            encoded,
+            StreamFrame::encode_psh(FrameVersion::V2, 0x0001_0000, 5678, &[1, 2]).unwrap()
+        );
+        assert_eq!(
+            Frame::decode(encoded, FrameVersion::V2).unwrap(),
+            Frame::Stream(frame)
+        );
+    }
+
+    #[test]
+    fn test_v2_reserved_and_extensions() {
+        let bytes = Bytes::from_static(&[
+            0x01, // frame type (u8)
+            0xf4, // reserved bits and flag (u8)
+            0x02, // extension length (u8)
+            0x00, 0x00, 0x04, 0xd2, // sport (u32)
+            0x00, 0x00, 0x16, 0x2e, // dport (u32)
+            0xaa, 0xbb, // unknown extensions
+            0x01, // data (variable)
+        ]);
+        assert_eq!(
+            Frame::decode(bytes, FrameVersion::V2).unwrap(),
+            Frame::Stream(StreamFrame {
+                sport: 1234,
+                dport: 5678,
+                flag: StreamFlag::Fin,
+                compression: 0,
+                reason: RstReason::Unspecified,
+                tcp_options: TcpOptions::NONE,
+                trace_context: Bytes::new(),
+                data: Bytes::from_static(&[0x01]),
+            })
+        );
+        // Extensions longer than the frame
+        let bytes = Bytes::from_static(&[0x01, 0x04, 0x02, 0, 0, 0, 1, 0, 0, 0, 2, 0xaa]);
+        assert!(matches!(
+            Frame::decode(bytes, FrameVersion::V2),
+            Err(Error::FrameTooShort)
+        ));
+    }
+
+    #[test]
+    fn test_compression_extension() {
+        let frame = StreamFrame {
+            compression: 0b10,
+            ..StreamFrame::new_psh(1234, 5678, Bytes::from_static(&[1, 2]))
+        };
+        let encoded = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(
+            encoded,
+            vec![
+                0x01, // frame type (u8)
+                0x05, // flag (u8)
+                0x03, // extension length (u8)
+                0x00, 0x00, 0x04, 0xd2, // sport (u32)
+                0x00, 0x00, 0x16, 0x2e, // dport (u32)
+                0x01, 0x01, 0x02, // compression extension
+                0x01, 0x02, // data (variable)
+            ]
+        );
+        assert_eq!(
+            Frame::decode(encoded, FrameVersion::V2).unwrap(),
+            Frame::Stream(frame)
+        );
+        // Known extensions with the wrong length are rejected
+        let bytes = Bytes::from_static(&[0x01, 0x05, 0x02, 0, 0, 0, 1, 0, 0, 0, 2, 0x01, 0x00]);
+        assert!(matches!(
+            Frame::decode(bytes, FrameVersion::V2),
+            Err(Error::InvalidExtension(1))
+        ));
+    }
+
+    #[test]
+    fn test_tcp_options_extension() {
+        let frame = StreamFrame {
+            tcp_options: TcpOptions {
+                nodelay: Some(true),
+                keepalive_idle: Some(60),
+                user_timeout: Some(30),
+                ..TcpOptions::NONE
+            },
+            ..StreamFrame::new_syn(b"a", 80, 1234, 1)
+        };
+        let encoded = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(
+            encoded[11..23],
+            [
+                0x03, 0x0a, // TCP options extension
+                0x13, // present: nodelay, keepalive idle, and user timeout
+                0x01, // nodelay
+                0x00, 0x00, 0x00, 0x3c, // keepalive idle
+                0x00, 0x00, 0x00, 0x1e, // user timeout
+            ]
+        );
+        assert_eq!(
+            Frame::decode(encoded, FrameVersion::V2).unwrap(),
+            Frame::Stream(frame.clone())
+        );
+        // Not in V1
+        let encoded = frame.encode(FrameVersion::V1).unwrap();
+        let Frame::Stream(decoded) = Frame::decode(encoded, FrameVersion::V1).unwrap() else {
+            panic!("not a stream frame");
+        };
+        assert!(decoded.tcp_options.is_empty());
+        // Truncated values and unknown options are rejected
+        assert_eq!(TcpOptions::decode(Bytes::from_static(&[0x02, 0, 0])), None);
+        assert_eq!(TcpOptions::decode(Bytes::from_static(&[0x20])), None);
+        assert_eq!(TcpOptions::decode(Bytes::new()), None);
+    }
+
+    #[test]
+    fn test_trace_context_extension() {
+        let frame = StreamFrame {
+            trace_context: Bytes::from_static(b"00-ab-cd-01"),
+            ..StreamFrame::new_syn(b"a", 80, 1234, 1)
+        };
+        let encoded = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(encoded[2], 13);
+        assert_eq!(&encoded[11..24], b"\x04\x0b00-ab-cd-01");
+        assert_eq!(
+            Frame::decode(encoded, FrameVersion::V2).unwrap(),
+            Frame::Stream(frame)
+        );
+        // Left out if it does not fit
+        let frame = StreamFrame {
+            trace_context: Bytes::from(vec![b'x'; 254]),
+            ..StreamFrame::new_syn(b"a", 80, 1234, 1)
+        };
+        let encoded = frame.encode(FrameVersion::V2).unwrap();
+        let Frame::Stream(decoded) = Frame::decode(encoded, FrameVersion::V2).unwrap() else {
+            panic!("not a stream frame");
+        };
+        assert!(decoded.trace_context.is_empty());
+    }
+
+    #[test]
+    fn test_rst_reason_extension() {
+        let frame = StreamFrame::new_rst_with_message(1234, 5678, RstReason::Refused, "no");
+        let encoded = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(
+            encoded,
+            vec![
+                0x01, // frame type (u8)
+                0x03, // flag (u8)
+                0x03, // extension length (u8)
+                0x00, 0x00, 0x04, 0xd2, // sport (u32)
+                0x00, 0x00, 0x16, 0x2e, // dport (u32)
+                0x02, 0x01, 0x01, // reason extension
+                b'n', b'o', // data (variable)
+            ]
+        );
+        assert_eq!(
+            Frame::decode(encoded, FrameVersion::V2).unwrap(),
+            Frame::Stream(frame.clone())
+        );
+        // V1 has no room for the code, only the message
+        let decoded = Frame::try_from(frame.encode(FrameVersion::V1).unwrap()).unwrap();
+        let Frame::Stream(decoded) = decoded else {
+            panic!("not a stream frame");
+        };
+        assert_eq!(decoded.reason, RstReason::Unspecified);
+        assert_eq!(decoded.data, &b"no"[..]);
+        // Unknown codes are unspecified
+        assert_eq!(RstReason::from(0x7f), RstReason::Unspecified);
+        let refused = std::io::Error::from(ErrorKind::ConnectionRefused);
+        assert_eq!(RstReason::from(&refused), RstReason::Refused);
+        assert_eq!(
+            ErrorKind::from(RstReason::Refused),
+            ErrorKind::ConnectionRefused
+        );
+    }
+
+    #[test]
+    fn test_hello_frame() {
+        let frame = Frame::Hello(Capabilities {
+            max_frame_size: Some(1 << 20),
+            compression: 0b11,
+            ip_packets: true,
+            tcp_options: true,
+            ..Capabilities::default()
+        });
+        let bytes = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(Frame::decode(bytes, FrameVersion::V2).unwrap(), frame);
+        // Unknown capabilities are skipped
+        let bytes = Bytes::from_static(&[0x02, 0x7f, 0x02, 0xaa, 0xbb, 0x04, 0x00]);
+        let Frame::Hello(caps) = Frame::decode(bytes, FrameVersion::V2).unwrap() else {
+            panic!("not a `Hello` frame");
+        };
+        assert!(caps.datagrams);
+        assert!(!caps.flow_control);
+        assert_eq!(caps.max_frame_size, None);
+        // Known capabilities with the wrong length are not
+        let bytes = Bytes::from_static(&[0x02, 0x01, 0x02, 0xaa, 0xbb]);
+        assert!(matches!(
+            Frame::decode(bytes, FrameVersion::V2),
+            Err(Error::InvalidCapability(1))
+        ));
+    }
+
+    #[test]
+    fn test_padding_frame() {
+        let frame = Frame::Stream(StreamFrame::new_fin(1234, 5678));
+        let padded = pad(&frame.clone().encode(FrameVersion::V2).unwrap(), 256);
+        assert_eq!(padded.len(), 256);
+        assert_eq!(Frame::decode(padded, FrameVersion::V2).unwrap(), frame);
+        // Dummy frames
+        let padded = pad(&[], 100);
+        assert_eq!(
+            Frame::decode(padded, FrameVersion::V2).unwrap(),
+            Frame::Padding
+        );
+        let encoded = Frame::Padding.encode(FrameVersion::V2).unwrap();
+        assert_eq!(encoded, [0x04, 0x00, 0x00, 0x00, 0x00][..]);
+        // Nested padding
+        let nested = pad(&pad(&[0x02], 10), 20);
+        assert!(matches!(
+            Frame::decode(nested, FrameVersion::V2),
+            Err(Error::InvalidFrameType(4))
+        ));
+        // Wrapped frame longer than the padding frame
+        let bytes = Bytes::from_static(&[0x04, 0x00, 0x00, 0x00, 0x08, 0x02]);
+        assert!(matches!(
+            Frame::decode(bytes, FrameVersion::V2),
+            Err(Error::FrameTooShort)
+        ));
+    }
+
+    #[test]
+    fn test_go_away_frame() {
+        let encoded = Frame::GoAway.encode(FrameVersion::V2).unwrap();
+        assert_eq!(encoded, [0x05][..]);
+        assert_eq!(
+            Frame::decode(encoded, FrameVersion::V2).unwrap(),
+            Frame::GoAway
+        );
+        let bytes = Bytes::from_static(&[0x05, 0xaa]);
+        assert_eq!(
+            Frame::decode(bytes, FrameVersion::V2).unwrap(),
+            Frame::GoAway
+        );
+    }
+
+    #[test]
+    fn test_fragment_frame() {
+        let frame = Frame::Fragment(FragmentFrame {
+            id: 0x0102_0304,
+            offset: 5,
+            total: 8,
+            data: Bytes::from_static(b"abc"),
+        });
+        let encoded = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(
+            encoded,
+            [
+                0x06, 0x01, 0x02, 0x03, 0x04, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x08, b'a',
+                b'b', b'c'
+            ][..]
+        );
+        assert_eq!(Frame::decode(encoded, FrameVersion::V2).unwrap(), frame);
+        let bytes = Bytes::from_static(&[0x06, 0x00, 0x00, 0x00, 0x01, 0x00]);
+        assert!(matches!(
+            Frame::decode(bytes, FrameVersion::V2),
+            Err(Error::FrameTooShort)
+        ));
+    }
+
+    #[test]
+    fn test_reliable_datagram_frames() {
+        let frame = Frame::ReliableDatagram(ReliableDatagramFrame {
+            seq: 9,
+            datagram: DatagramFrame {
+                host: Bytes::from_static(b"a"),
+                port: 69,
+                sid: 2,
+                data: Bytes::from_static(b"xy"),
+            },
+        });
+        let encoded = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(
+            encoded,
+            [0x07, 0, 0, 0, 9, 1, b'a', 0, 69, 0, 0, 0, 2, b'x', b'y'][..]
+        );
+        assert_eq!(Frame::decode(encoded, FrameVersion::V2).unwrap(), frame);
+
+        let frame = Frame::DatagramAck(vec![1, 0x0102_0304]);
+        let encoded = frame.clone().encode(FrameVersion::V2).unwrap();
+        assert_eq!(encoded, [0x08, 0, 0, 0, 1, 1, 2, 3, 4][..]);
+        assert_eq!(Frame::decode(encoded, FrameVersion::V2).unwrap(), frame);
+        let bytes = Bytes::from_static(&[0x08, 0, 0, 1]);
+        assert!(matches!(
+            Frame::decode(bytes, FrameVersion::V2),
+            Err(Error::FrameTooShort)
+        ));
+    }
+
+    #[test]
+    fn test_datagram_frame() {
+        let frame = Frame::Datagram(DatagramFrame {
+            host: Bytes::from_static(&[1, 2, 3, 4]),
+            port: 1234,
+            sid: 5678,
+            data: Bytes::from_static(&[1, 2, 3, 4]),
+        });
+        let bytes = Vec::try_from(frame.clone()).unwrap();
+        let decoded = Frame::try_from(bytes).unwrap();
+        assert_eq!(frame, decoded);
+    }
+
+    /// These tests are to make sure that the binary representation of the
+    /// frames does not change without a protocol version bump.
+    #[test]
+    fn test_frame_repr() {
+        let frame = Frame::Stream(StreamFrame::new_syn(&[0x01, 0x02, 0x03], 5678, 1234, 512));
+        let bytes = Vec::try_from(frame).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x01, // frame type (u8)
+                0x04, 0xd2, // sport (u16)
+                0x00, 0x00, // dport (u16)
+                0x00, // flag (u8)
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, // rwnd (u64)
+                0x16, 0x2e, // dest_port (u16)
+                0x01, 0x02, 0x03, // data (variable)
+            ]
+        );
+
+        let frame = Frame::Stream(StreamFrame::new_synack(1234, 5678, 128));
+        let bytes = Vec::try_from(frame).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x01, // frame type (u8)
+                0x04, 0xd2, // sport (u16)
+                0x16, 0x2e, // dport (u16)
+                0x01, // flag (u8)
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, // rwnd (u64)
+            ]
+        );
+
+        let frame = Frame::Stream(StreamFrame::new_ack(5678, 1234, 128));
+        let bytes = Vec::try_from(frame).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x01, // frame type (u8)
+                0x16, 0x2e, // sport (u16)
+                0x04, 0xd2, // dport (u16)
+                0x02, // flag (u8)
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, // psh_recvd_since (u64)
+            ]
+        );
+
+        let frame = Frame::Stream(StreamFrame::new_rst(1234, 5678, RstReason::Unspecified));
+        let bytes = Vec::try_from(frame).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x01, // frame type (u8)
+                0x04, 0xd2, // sport (u16)
+                0x16, 0x2e, // dport (u16)
+                0x03, // flag (u8)
+            ]
+        );
+
+        let frame = Frame::Stream(StreamFrame::new_fin(5678, 1234));
+        let bytes = Vec::try_from(frame).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x01, // frame type (u8)
+                0x16, 0x2e, // sport (u16)
+                0x04, 0xd2, // dport (u16)
+                0x04  // flag (u8)
+            ]
+        );
+
+        let frame = Frame::Stream(StreamFrame::new_psh(
+            1234,
+            5678,
+            Bytes::from_static(&[1, 2, 3, 4]),
+        ));
+        let bytes = Vec::try_from(frame).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x01, // frame type (u8)
+                0x04, 0xd2, // sport (u16)
+                0x16, 0x2e, // dport (u16)
+                0x05, // flag (u8)
+                0x01, 0x02, 0x03, 0x04 // data (variable)
+            ]
+        );
+
+        let frame = Frame::Datagram(DatagramFrame {
+            host: Bytes::from_static(&[1, 2, 3, 4]),
+            port: 1234,
+            sid: 5678,
+            data: Bytes::from_static(&[1, 2, 3, 4]),
+        });
+        let bytes = Vec::try_from(frame).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x03, // frame type (u8)
+                0x04, // host len (u8)
+                0x01, 0x02, 0x03, 0x04, // host (variable)
+                0x04, 0xd2, // port (u16)
+                0x00, 0x00, 0x16, 0x2e, // sid (u32)
+                0x01, 0x02, 0x03, 0x04 // data (variable)
+            ]
+        );
+
+        let frame = Frame::Stream(StreamFrame::new_synack(1234, 5678, 128));
+        let bytes = frame.encode(FrameVersion::V2).unwrap();
+        assert_eq!(
+            bytes,
+            vec![
+                0x01, // frame type (u8)
+                0x01, // flag (u8)
+                0x00, // extension length (u8)
+                0x00, 0x00, 0x04, 0xd2, // sport (u32)
+                0x00, 0x00, 0x16, 0x2e, // dport (u32)
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, // rwnd (u64)
+            ]
+        );
+    }
+}
@@ -0,0 +1,1198 @@
+//! Client side of the multiplexor
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::compress::Compression;
+use super::config;
+use super::dupe::Dupe;
+use super::fragment::Reassembler;
+use super::frame::{
+    Capabilities, DatagramFrame, Frame, FrameVersion, ReliableDatagramFrame, RstReason, StreamFlag,
+    StreamFrame, TcpOptions,
+};
+use super::locked_sink::LockedWebSocket;
+use super::queue::{self, UnboundedReceiver, UnboundedSender};
+use super::reliable::ReliableDatagrams;
+use super::rtt::RttTracker;
+use super::sched::Scheduler;
+use super::stats::{MuxStats, StreamCounters};
+use super::stream::{Activity, MuxStream};
+use super::stream_map::StreamMap;
+use super::{DatagramOverflow, Error, Options, Result, Role};
+use crate::transport::{Message, Transport};
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::task::AtomicWaker;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch, RwLock};
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, trace, warn};
+
+#[derive(Debug)]
+pub struct MuxStreamData {
+    /// Channel for sending data to `MuxStream`'s `AsyncRead`
+    sender: mpsc::Sender<Bytes>,
+    /// Whether writes should succeed.
+    /// There are two cases for `false`:
+    /// 1. `Fin` has been sent.
+    /// 2. The stream has been removed from `inner.streams`.
+    // In general, our `Atomic*` types don't need more than `Relaxed` ordering
+    // because we are not protecting memory accesses, but rather counting the
+    // frames we have sent and received.
+    can_write: Arc<AtomicBool>,
+    /// Number of `Psh` frames we are allowed to send before waiting for a `Ack` frame.
+    psh_send_remaining: Arc<AtomicU64>,
+    /// Waker to wake up the task that sends frames because their `psh_send_remaining`
+    /// has increased.
+    writer_waker: Arc<AtomicWaker>,
+    /// Port of the other end
+    their_port: u32,
+    /// When the stream last sent or received data
+    activity: Arc<Activity>,
+    /// Forwarding destination
+    dest_host: Bytes,
+    /// Forwarding destination port
+    dest_port: u16,
+    /// Bytes and frames the stream sent and received
+    counters: Arc<StreamCounters>,
+    /// [`RstReason`] of the `Rst` the peer reset the stream with
+    peer_rst_reason: Arc<AtomicU8>,
+}
+
+#[derive(Debug)]
+pub enum MuxStreamSlot<S> {
+    /// The stream to `dest_host:dest_port` is requested by the `client`.
+    Requested {
+        sender: oneshot::Sender<Result<MuxStream<S>>>,
+        dest_host: Bytes,
+        dest_port: u16,
+    },
+    /// The stream is established.
+    Established(MuxStreamData),
+}
+
+impl<S> MuxStreamSlot<S> {
+    /// Take the sender and set the slot to `Established`.
+    /// Returns `None` if the slot is already established.
+    pub fn establish(
+        &mut self,
+        data: MuxStreamData,
+    ) -> Option<oneshot::Sender<Result<MuxStream<S>>>> {
+        // Make sure it is not replaced in the error case
+        if matches!(self, Self::Established(_)) {
+            return None;
+        }
+        let sender = match std::mem::replace(self, Self::Established(data)) {
+            Self::Requested { sender, .. } => sender,
+            Self::Established(_) => unreachable!(),
+        };
+        Some(sender)
+    }
+}
+
+/// Multiplexor inner
+pub struct MultiplexorInner<S> {
+    /// The role of this multiplexor
+    pub role: Role,
+    /// The underlying `Sink + Stream` of messages.
+    pub ws: LockedWebSocket<S>,
+    /// Whether `ws` answers `Ping`s by itself
+    pub ws_auto_pong: bool,
+    /// Takes turns feeding the frames of the streams to `ws`
+    pub sched: Arc<Scheduler>,
+    /// Settings such as the interval between keepalive `Ping`s
+    pub options: Arc<Options>,
+    /// When the last stream was open or the last datagram was sent or received
+    pub activity: Arc<Activity>,
+    /// Number of `Ping`s sent since the last `Pong` was received
+    pub unanswered_pings: Arc<AtomicU32>,
+    /// Round-trip times of the keepalive `Ping`s
+    pub rtt: Arc<RttTracker>,
+    /// What the peer said it supports in its `Hello`
+    pub peer_caps: Arc<Mutex<Option<Capabilities>>>,
+    /// Largest frame the peer accepts, `u32::MAX` if it did not say
+    pub peer_max_frame: Arc<AtomicU32>,
+    /// Whether we announced `GoAway`, so no new streams are opened
+    pub going_away: Arc<AtomicBool>,
+    /// Whether the peer sent `GoAway`
+    pub peer_going_away: Arc<watch::Sender<bool>>,
+    /// Channel of received datagram frames for processing
+    pub datagram_rx: Arc<RwLock<mpsc::Receiver<DatagramFrame>>>,
+    /// Number of received datagrams dropped because too many were waiting
+    pub datagrams_dropped: Arc<AtomicU64>,
+    /// Reliable datagrams waiting for acknowledgement, and the ones received
+    pub reliable: Arc<Mutex<ReliableDatagrams>>,
+    /// ID of the next datagram we fragment
+    pub fragment_id: Arc<AtomicU32>,
+    /// Datagrams the peer fragmented, waiting for the rest of their fragments
+    pub reassembler: Arc<Mutex<Reassembler>>,
+    /// Open stream channels: our_port -> `MuxStreamData`
+    pub streams: Arc<StreamMap<S>>,
+    /// Channel for notifying the task of a dropped `MuxStream`
+    /// (in the form (our_port, their_port, reason of the `Rst`)).
+    /// Sending (0, _) means that the multiplexor is being dropped and the
+    /// task should exit.
+    /// The reason we need `their_port` is to ensure the connection is `Rst`ed
+    /// if the user did not call `poll_shutdown` on the `MuxStream`.
+    pub dropped_ports_tx: UnboundedSender<(u32, u32, RstReason)>,
+    /// Channel for queuing `Ack` frames to be sent
+    /// (in the form (our_port, their_port, psh_recvd_since)).
+    pub ack_tx: UnboundedSender<(u32, u32, u64)>,
+}
+
+impl<S> std::fmt::Debug for MultiplexorInner<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MultiplexorInner")
+            .field("role", &self.role)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Dupe for MultiplexorInner<S> {
+    #[inline]
+    fn dupe(&self) -> Self {
+        Self {
+            role: self.role,
+            ws: self.ws.dupe(),
+            ws_auto_pong: self.ws_auto_pong,
+            sched: self.sched.dupe(),
+            options: self.options.dupe(),
+            activity: self.activity.dupe(),
+            unanswered_pings: self.unanswered_pings.dupe(),
+            rtt: self.rtt.dupe(),
+            peer_caps: self.peer_caps.dupe(),
+            peer_max_frame: self.peer_max_frame.dupe(),
+            going_away: self.going_away.dupe(),
+            peer_going_away: self.peer_going_away.dupe(),
+            datagram_rx: self.datagram_rx.dupe(),
+            datagrams_dropped: self.datagrams_dropped.dupe(),
+            reliable: self.reliable.dupe(),
+            fragment_id: self.fragment_id.dupe(),
+            reassembler: self.reassembler.dupe(),
+            streams: self.streams.dupe(),
+            dropped_ports_tx: self.dropped_ports_tx.dupe(),
+            ack_tx: self.ack_tx.dupe(),
+        }
+    }
+}
+
+impl<S: Transport> MultiplexorInner<S> {
+    /// Processing task
+    /// Does the following:
+    /// - Receives messages from `WebSocket` and processes them
+    /// - Sends received datagrams to the `datagram_tx` channel
+    /// - Sends received streams to the appropriate handler
+    /// - Responds to ping/pong messages
+    // It doesn't make sense to return a `Result` here because we can't propagate
+    // the error to the user from a spawned task.
+    // Instead, the user will notice when `rx` channels return `None`.
+    #[tracing::instrument(skip_all, level = "trace")]
+    pub async fn task(
+        mut self,
+        datagram_tx: mpsc::Sender<DatagramFrame>,
+        server_stream_tx: mpsc::Sender<MuxStream<S>>,
+        dropped_ports_rx: UnboundedReceiver<(u32, u32, RstReason)>,
+        ack_rx: UnboundedReceiver<(u32, u32, u64)>,
+    ) -> Result<()> {
+        let result = tokio::select! {
+            result = async {
+                tokio::try_join!(
+                    // Polled first, so that `Hello` usually goes out before
+                    // any other frame
+                    self.send_hello(),
+                    self.keepalive_task(),
+                    self.stream_idle_task(),
+                    self.idle_task(),
+                    self.dummy_frame_task(),
+                    self.retransmit_task(),
+                    self.send_ack_task(ack_rx),
+                    async { self.sched.run(&self.ws).await.map_err(Error::SendStreamFrame) },
+                )
+            } => result.map(|_| ()),
+            // Returns when the peer closes the connection
+            result = self.process_messages_task(datagram_tx, server_stream_tx) => result,
+            // Returns when the `Multiplexor` is dropped
+            result = self.close_port_task(dropped_ports_rx) => result,
+        };
+        self.shutdown().await;
+        result
+    }
+
+    /// Compression algorithms to offer in a `Syn`: the ones both sides
+    /// announced. None before the peer's `Hello` arrives.
+    pub fn compression_offer(&self) -> u8 {
+        self.peer_caps.lock().as_ref().map_or(0, |caps| {
+            caps.compression & self.options.capabilities.compression & Compression::supported()
+        })
+    }
+
+    /// Stop opening streams and tell the peer, if it understands `GoAway`.
+    pub async fn go_away(&self) -> Result<()> {
+        // Under the lock, so that exactly one of us and the `Hello`
+        // handler sends `GoAway`
+        let peer_knows = {
+            let peer_caps = self.peer_caps.lock();
+            if self.going_away.swap(true, Ordering::Relaxed) {
+                return Ok(());
+            }
+            peer_caps.as_ref().map(|caps| caps.go_away)
+        };
+        match peer_knows {
+            Some(true) => self.send_go_away().await,
+            Some(false) => {
+                debug!("peer does not understand `GoAway`");
+                Ok(())
+            }
+            // Sent when the `Hello` arrives
+            None => Ok(()),
+        }
+    }
+
+    async fn send_go_away(&self) -> Result<()> {
+        let go_away = Frame::GoAway
+            .encode(self.options.frame_version)
+            .expect("`GoAway` frames always encode (this is a bug)");
+        self.ws
+            .send_with(|| Message::Frame(go_away.dupe()))
+            .await
+            .map_err(Error::SendStreamFrame)
+    }
+
+    /// Tell the peer what we support. Peers speaking V1 frames do not know
+    /// `Hello`.
+    async fn send_hello(&self) -> Result<()> {
+        if self.options.frame_version < FrameVersion::V2 {
+            return Ok(());
+        }
+        let hello = Frame::Hello(self.options.capabilities.clone())
+            .encode(self.options.frame_version)
+            .expect("`Hello` frames always encode (this is a bug)");
+        self.ws
+            .send_with(|| Message::Frame(hello.dupe()))
+            .await
+            .map_err(Error::SendHello)
+    }
+
+    /// Keepalive subtask
+    async fn keepalive_task(&self) -> Result<()> {
+        if let Some(keepalive_interval) = self.options.keepalive_interval {
+            let mut interval = tokio::time::interval(keepalive_interval);
+            // If we missed a tick, it is probably doing networking, so we don't need to
+            // make up for it.
+            interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                interval.tick().await;
+                let unanswered = self.unanswered_pings.fetch_add(1, Ordering::Relaxed);
+                if self
+                    .options
+                    .max_missed_pongs
+                    .is_some_and(|max| unanswered >= max)
+                {
+                    warn!("peer did not answer {unanswered} pings");
+                    return Err(Error::PongTimeout(unanswered));
+                }
+                trace!("sending ping");
+                self.ws
+                    .send_with(|| Message::Ping(self.rtt.ping_payload().into()))
+                    .await
+                    .map_err(Error::PingPong)?;
+            }
+        } else {
+            futures_util::future::pending::<()>().await;
+            unreachable!("`futures_util::future::pending` never resolves")
+        }
+    }
+
+    /// Process closed ports subtask
+    async fn close_port_task(
+        &self,
+        mut dropped_ports_rx: UnboundedReceiver<(u32, u32, RstReason)>,
+    ) -> Result<()> {
+        while let Some((our_port, their_port, reason)) = dropped_ports_rx.recv().await {
+            if our_port == 0 {
+                debug!("mux dropped");
+                break;
+            }
+            self.close_port(our_port, their_port, false, reason).await;
+        }
+        // Only happens when the last sender (i.e. `dropped_ports_tx` in `MultiplexorInner`)
+        // is dropped or when the mux is dropped.
+        Ok(())
+    }
+    /// Send `Ack` subtask. `Ack`s queued together are written out together.
+    async fn send_ack_task(&self, mut ack_rx: UnboundedReceiver<(u32, u32, u64)>) -> Result<()> {
+        let mut acks = Vec::new();
+        while let Some(ack) = ack_rx.recv().await {
+            acks.push(ack);
+            while let Ok(ack) = ack_rx.try_recv() {
+                acks.push(ack);
+            }
+            trace!("sending {} `Ack`s", acks.len());
+            let frames = acks
+                .drain(..)
+                .map(|(our_port, their_port, psh_recvd_since)| {
+                    StreamFrame::new_ack(our_port, their_port, psh_recvd_since)
+                        .into_message(self.options.frame_version)
+                });
+            self.ws
+                .feed_all(frames)
+                .await
+                .map_err(Error::SendStreamFrame)?;
+            if let Some(cork) = self.options.cork {
+                // Stream writes flushing in the meantime take the `Ack`s along
+                tokio::time::sleep(cork).await;
+            }
+            self.ws.flush().await.map_err(Error::SendStreamFrame)?;
+        }
+        // Only happens when the last sender (i.e. `ack_tx` in `MultiplexorInner`)
+        // is dropped.
+        Ok(())
+    }
+
+    /// Subtask closing streams idle for longer than `stream_idle_timeout`
+    async fn stream_idle_task(&self) -> Result<()> {
+        let Some(timeout) = self.options.stream_idle_timeout else {
+            futures_util::future::pending::<()>().await;
+            unreachable!("`futures_util::future::pending` never resolves")
+        };
+        // Close idle streams at most a quarter of `timeout` late
+        let mut interval = tokio::time::interval((timeout / 4).max(Duration::from_millis(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let idle: Vec<(u32, u32)> = self
+                .streams
+                .filter_map(|our_port, slot| match slot {
+                    MuxStreamSlot::Established(data) if data.activity.idle_for() >= timeout => {
+                        Some((our_port, data.their_port))
+                    }
+                    _ => None,
+                })
+                .await;
+            for (our_port, their_port) in idle {
+                debug!("closing idle stream {our_port} -> {their_port}");
+                self.close_port(our_port, their_port, false, RstReason::Unspecified)
+                    .await;
+            }
+        }
+    }
+
+    /// Subtask that exits with `Error::Idle` when the connection has had no
+    /// streams or datagrams for `idle_timeout`
+    async fn idle_task(&self) -> Result<()> {
+        let Some(timeout) = self.options.idle_timeout else {
+            futures_util::future::pending::<()>().await;
+            unreachable!("`futures_util::future::pending` never resolves")
+        };
+        let mut interval = tokio::time::interval((timeout / 4).max(Duration::from_millis(1)));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if !self.streams.is_empty().await {
+                self.activity.touch();
+            } else if self.activity.idle_for() >= timeout {
+                debug!("closing idle connection");
+                return Err(Error::Idle);
+            }
+        }
+    }
+
+    /// Subtask sending dummy frames at random intervals once padding is on
+    async fn dummy_frame_task(&self) -> Result<()> {
+        if !self.options.obfs_traffic {
+            futures_util::future::pending::<()>().await;
+            unreachable!("`futures_util::future::pending` never resolves")
+        }
+        loop {
+            let delay = rand::thread_rng().gen_range(config::DUMMY_FRAME_INTERVAL);
+            tokio::time::sleep(delay).await;
+            if self.ws.padding().is_enabled() {
+                trace!("sending dummy frame");
+                self.ws
+                    .send_with(|| self.ws.padding().dummy())
+                    .await
+                    .map_err(Error::SendStreamFrame)?;
+            }
+        }
+    }
+
+    /// Subtask sending the reliable datagrams the peer has not acknowledged
+    /// again
+    async fn retransmit_task(&self) -> Result<()> {
+        let mut interval = tokio::time::interval(config::RELIABLE_TICK);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let due = self.reliable.lock().due();
+            if !due.is_empty() {
+                trace!("sending {} reliable datagrams again", due.len());
+                self.ws
+                    .feed_all(due.into_iter().map(Message::Frame))
+                    .await
+                    .map_err(Error::SendDatagram)?;
+                self.ws.flush().await.map_err(Error::SendDatagram)?;
+            }
+        }
+    }
+
+    /// Message processing subtask
+    async fn process_messages_task(
+        &self,
+        datagram_tx: mpsc::Sender<DatagramFrame>,
+        server_stream_tx: mpsc::Sender<MuxStream<S>>,
+    ) -> Result<()> {
+        while let Some(msg) = self.ws.next().await {
+            let msg = msg.map_err(Error::Next)?;
+            trace!("received message: {msg:?}");
+            // Messages cannot be processed concurrently
+            // because doing so will break stream ordering
+            if self
+                .process_message(msg, &datagram_tx, &server_stream_tx)
+                .await?
+            {
+                // `Close` message was received, so we can exit
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: Transport> MultiplexorInner<S> {
+    /// Hand a received datagram to `datagram_tx`, following
+    /// `options.datagram_overflow` if it is full. Returns whether it was
+    /// queued.
+    async fn deliver_datagram(
+        &self,
+        datagram_frame: DatagramFrame,
+        datagram_tx: &mpsc::Sender<DatagramFrame>,
+    ) -> Result<bool> {
+        if self.options.datagram_overflow == DatagramOverflow::Backpressure {
+            return datagram_tx
+                .send(datagram_frame)
+                .await
+                .map(|()| true)
+                .map_err(|_| Error::Closed);
+        }
+        // Only fails if the receiver is dropped or the queue is full.
+        // The first case means the multiplexor itself is dropped;
+        // In the second case, we drop a frame to avoid blocking.
+        // It is UDP, after all.
+        let datagram_frame = match datagram_tx.try_send(datagram_frame) {
+            Ok(()) => return Ok(true),
+            Err(TrySendError::Full(datagram_frame)) => datagram_frame,
+            Err(TrySendError::Closed(_)) => return Err(Error::Closed),
+        };
+        self.datagrams_dropped.fetch_add(1, Ordering::Relaxed);
+        if self.options.datagram_overflow == DatagramOverflow::DropOldest {
+            // If `get_datagram` holds the lock, it is taking one out anyway
+            if let Ok(mut datagram_rx) = self.datagram_rx.try_write() {
+                datagram_rx.try_recv().ok();
+            }
+            if datagram_tx.try_send(datagram_frame).is_ok() {
+                warn!("dropped the oldest datagram frame: queue full");
+                return Ok(true);
+            }
+        }
+        warn!("dropped datagram frame: queue full");
+        Ok(false)
+    }
+
+    /// Process an incoming message
+    /// Returns `Ok(true)` if a `Close` message was received.
+    #[tracing::instrument(skip_all, level = "debug")]
+    #[inline]
+    async fn process_message(
+        &self,
+        msg: Message,
+        datagram_tx: &mpsc::Sender<DatagramFrame>,
+        server_stream_tx: &mpsc::Sender<MuxStream<S>>,
+    ) -> Result<bool> {
+        match msg {
+            Message::Frame(data) => {
+                if let Some(max) = self.options.capabilities.max_frame_size {
+                    if data.len() > max as usize {
+                        return Err(Error::FrameTooLarge(data.len()));
+                    }
+                }
+                let frame = Frame::decode(data, self.options.frame_version)?;
+                match frame {
+                    Frame::Datagram(datagram_frame) => {
+                        trace!("received datagram frame: {:?}", datagram_frame);
+                        self.activity.touch();
+                        self.deliver_datagram(datagram_frame, datagram_tx).await?;
+                    }
+                    Frame::ReliableDatagram(ReliableDatagramFrame { seq, datagram }) => {
+                        trace!("received reliable datagram frame {seq}: {datagram:?}");
+                        self.activity.touch();
+                        let duplicate = self.reliable.lock().is_duplicate(seq);
+                        if !duplicate {
+                            let sid = datagram.sid;
+                            if !self.deliver_datagram(datagram, datagram_tx).await? {
+                                // Not acknowledged, so the peer sends it again
+                                return Ok(false);
+                            }
+                            self.reliable.lock().received(seq, sid);
+                        }
+                        let ack = Frame::DatagramAck(vec![seq])
+                            .encode(self.options.frame_version)
+                            .expect("`DatagramAck` frames always encode (this is a bug)");
+                        self.ws
+                            .send_with(|| Message::Frame(ack.dupe()))
+                            .await
+                            .map_err(Error::SendDatagram)?;
+                    }
+                    Frame::DatagramAck(seqs) => {
+                        trace!("received acknowledgement of {} datagrams", seqs.len());
+                        let mut reliable = self.reliable.lock();
+                        for seq in seqs {
+                            reliable.ack(seq);
+                        }
+                    }
+                    Frame::Fragment(fragment_frame) => {
+                        trace!("received fragment frame: {:?}", fragment_frame);
+                        self.activity.touch();
+                        let datagram = self.reassembler.lock().push(fragment_frame);
+                        if let Some(datagram) = datagram {
+                            match DatagramFrame::try_from(datagram) {
+                                Ok(datagram_frame) => {
+                                    self.deliver_datagram(datagram_frame, datagram_tx).await?;
+                                }
+                                Err(e) => warn!("dropped reassembled datagram: {e}"),
+                            }
+                        }
+                    }
+                    Frame::Hello(caps) => {
+                        debug!("peer capabilities: {caps:?}");
+                        let peer_max_frame = caps.max_frame_size.unwrap_or(u32::MAX);
+                        self.peer_max_frame.store(peer_max_frame, Ordering::Relaxed);
+                        if self.options.obfs_traffic && caps.padding {
+                            self.ws.padding().enable(peer_max_frame);
+                        }
+                        // `go_away` may have been called before we knew
+                        // whether the peer understands it
+                        let go_away = {
+                            let mut peer_caps = self.peer_caps.lock();
+                            let go_away = caps.go_away && self.going_away.load(Ordering::Relaxed);
+                            peer_caps.replace(caps);
+                            go_away
+                        };
+                        if go_away {
+                            self.send_go_away().await?;
+                        }
+                    }
+                    Frame::Padding => trace!("received dummy frame"),
+                    Frame::GoAway => {
+                        debug!("peer is going away");
+                        self.peer_going_away.send_replace(true);
+                    }
+                    Frame::Stream(stream_frame) => {
+                        trace!("received stream frame: {:?}", stream_frame);
+                        self.process_stream_frame(stream_frame, server_stream_tx)
+                            .await?;
+                    }
+                }
+                Ok(false)
+            }
+            Message::Ping(data) => {
+                trace!("received ping");
+                if self.ws_auto_pong {
+                    // The transport queued a `Pong`; make sure it goes out
+                    self.ws.flush_ignore_closed().await
+                } else {
+                    self.ws.send_with(|| Message::Pong(data.dupe())).await
+                }
+                .map_err(Error::PingPong)?;
+                Ok(false)
+            }
+            Message::Pong(data) => {
+                trace!("received pong");
+                self.rtt.record_pong(&data);
+                self.unanswered_pings.store(0, Ordering::Relaxed);
+                Ok(false)
+            }
+            Message::Close => {
+                debug!("received close");
+                Ok(true)
+            }
+        }
+    }
+
+    /// Process a stream frame
+    /// Does the following:
+    /// - If `flag` is `Syn`,
+    ///   - Find an available `dport` and send a `Ack`.
+    ///   - Create a new `MuxStream` and send it to the `stream_tx` channel.
+    /// - If `flag` is `Ack`,
+    ///   - Create a `MuxStream` and send it to the `stream_tx` channel.
+    /// - Otherwise, we find the sender with the matching `dport` and
+    ///   - Send the data to the sender.
+    ///   - If the receiver is closed or the port does not exist, send back a
+    ///     `Rst` frame.
+    #[tracing::instrument(skip_all, level = "trace")]
+    #[inline]
+    async fn process_stream_frame(
+        &self,
+        stream_frame: StreamFrame,
+        server_stream_tx: &mpsc::Sender<MuxStream<S>>,
+    ) -> Result<()> {
+        let StreamFrame {
+            dport: our_port,
+            sport: their_port,
+            flag,
+            compression,
+            reason: stream_frame_reason,
+            tcp_options,
+            trace_context,
+            mut data,
+        } = stream_frame;
+        let send_rst = || async {
+            self.ws
+                .send_with(|| {
+                    StreamFrame::new_rst(our_port, their_port, RstReason::Unspecified)
+                        .into_message(self.options.frame_version)
+                })
+                .await
+                .map_err(Error::SendStreamFrame)
+        };
+        match flag {
+            StreamFlag::Syn => {
+                if self.role == Role::Client {
+                    return Err(Error::ClientReceivedSyn);
+                }
+                // Decode Syn handshake
+                if data.remaining() < 10 {
+                    return Err(super::frame::Error::FrameTooShort.into());
+                }
+                let peer_rwnd = data.get_u64();
+                let dest_port = data.get_u16();
+                let dest_host = data;
+                if let Err((reason, message)) = self.check_syn(&dest_host, dest_port).await {
+                    debug!("rejecting `Syn` from {their_port}: {message}");
+                    self.ws
+                        .send_with(|| {
+                            StreamFrame::new_rst_with_message(
+                                our_port, their_port, reason, &message,
+                            )
+                            .into_message(self.options.frame_version)
+                        })
+                        .await
+                        .map_err(Error::SendStreamFrame)?;
+                    return Ok(());
+                }
+                let compression =
+                    Compression::choose(compression & self.options.capabilities.compression);
+                // "we" is `role == Server`
+                // "they" is `role == Client`
+                self.server_new_stream(
+                    our_port,
+                    their_port,
+                    dest_host,
+                    dest_port,
+                    peer_rwnd,
+                    compression,
+                    tcp_options,
+                    trace_context,
+                    server_stream_tx,
+                )
+                .await?;
+            }
+            StreamFlag::SynAck => {
+                if self.role == Role::Server {
+                    return Err(Error::ServerReceivedSynAck);
+                }
+                if data.remaining() < 8 {
+                    return Err(super::frame::Error::FrameTooShort.into());
+                }
+                // Decode `SynAck` handshake
+                let peer_rwnd = data.get_u64();
+                let compression = match compression {
+                    0 => None,
+                    chosen => Some(
+                        self.accepted_compression(chosen)
+                            .ok_or(Error::UnofferedCompression(chosen))?,
+                    ),
+                };
+                // "we" is `role == Client`
+                // "they" is `role == Server`
+                self.client_new_stream(our_port, their_port, peer_rwnd, compression)
+                    .await?;
+            }
+            StreamFlag::Ack => {
+                trace!("received `Ack` for {our_port}");
+                if data.remaining() < 8 {
+                    return Err(super::frame::Error::FrameTooShort.into());
+                }
+                let peer_processed = data.get_u64();
+                debug!("peer processed {peer_processed} frames");
+                let streams = self.streams.shard(our_port).read().await;
+                if let Some(MuxStreamSlot::Established(stream_data)) = streams.get(&our_port) {
+                    // Atomic ordering: as long as the value is incremented atomically,
+                    // whether a writer sees the new value or the old value is not
+                    // important. If it sees the old value and decides to return
+                    // `Poll::Pending`, it will be woken up by the `Waker` anyway.
+                    stream_data
+                        .psh_send_remaining
+                        .fetch_add(peer_processed, Ordering::Relaxed);
+                    stream_data.writer_waker.wake();
+                } else {
+                    // the port does not exist
+                    drop(streams);
+                    send_rst().await?;
+                }
+            }
+            StreamFlag::Rst => {
+                let mut streams = self.streams.shard(our_port).write().await;
+                if let Some(MuxStreamSlot::Requested { .. }) = streams.get(&our_port) {
+                    // Our `Syn` was rejected
+                    let error = if *self.peer_going_away.borrow() {
+                        // Probably crossed the `GoAway`; worth another connection
+                        Error::GoingAway
+                    } else {
+                        Error::StreamRejected(
+                            stream_frame_reason,
+                            String::from_utf8_lossy(&data).into_owned(),
+                        )
+                    };
+                    if let Some(MuxStreamSlot::Requested { sender, .. }) = streams.remove(&our_port)
+                    {
+                        sender.send(Err(error)).ok();
+                    }
+                    drop(streams);
+                    self.streams.release(our_port);
+                    return Ok(());
+                }
+                drop(streams);
+                // `true` because we don't want to reply `Rst` with `Rst`.
+                self.close_port(our_port, their_port, true, stream_frame_reason)
+                    .await;
+            }
+            StreamFlag::Fin => {
+                if let Some(MuxStreamSlot::Established(stream_data)) =
+                    self.streams.shard(our_port).read().await.get(&our_port)
+                {
+                    // Make sure the user receives `EOF`.
+                    stream_data.sender.send(Bytes::new()).await.ok();
+                }
+                // And our end can still send
+            }
+            StreamFlag::Psh => {
+                if compression != 0 {
+                    let decompressed = self.accepted_compression(compression).map_or_else(
+                        || Err(std::io::ErrorKind::Unsupported.into()),
+                        |algo| algo.decompress(&data),
+                    );
+                    match decompressed {
+                        Ok(decompressed) => data = Bytes::from(decompressed),
+                        Err(e) => {
+                            warn!("resetting stream {our_port}: bad compressed frame: {e}");
+                            self.close_port(our_port, their_port, false, RstReason::Unspecified)
+                                .await;
+                            return Ok(());
+                        }
+                    }
+                }
+                if let Some(MuxStreamSlot::Established(stream_data)) =
+                    self.streams.shard(our_port).read().await.get(&our_port)
+                {
+                    stream_data.activity.touch();
+                    if stream_data.sender.send(data).await.is_ok() {
+                        // The data is sent successfully
+                        stream_data.counters.received_frame();
+                        return Ok(());
+                    }
+                    // Else, the corresponding `MuxStream` is dropped
+                    // let it fall through to send `Rst`.
+                    // The job to remove the port from the map is done by `close_port_task`,
+                    // so not being able to send is the same as not finding the port;
+                    // just timing is different.
+                    trace!("dropped `MuxStream` not yet removed from the map");
+                }
+                // The port does not exist
+                send_rst().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The compression algorithm of `bit`, if we accept it.
+    fn accepted_compression(&self, bit: u8) -> Option<Compression> {
+        Compression::from_bit(bit).filter(|_| self.options.capabilities.compression & bit != 0)
+    }
+
+    /// Check if a `Syn` may open a new stream. Returns the reason if not.
+    async fn check_syn(
+        &self,
+        dest_host: &[u8],
+        dest_port: u16,
+    ) -> std::result::Result<(), (RstReason, String)> {
+        if self.going_away.load(Ordering::Relaxed) {
+            return Err((RstReason::Refused, "going away".to_string()));
+        }
+        let streams = self.streams.len().await;
+        if let Some(max_streams) = self.options.max_streams {
+            if streams >= max_streams {
+                return Err((
+                    RstReason::Refused,
+                    format!("too many streams (limit is {max_streams})"),
+                ));
+            }
+        }
+        if u32::try_from(streams).map_or(true, |n| n >= self.options.frame_version.max_port()) {
+            return Err((RstReason::Refused, "no free port".to_string()));
+        }
+        if let Some(syn_filter) = &self.options.syn_filter {
+            syn_filter(dest_host, dest_port).map_err(|message| (RstReason::Denied, message))?;
+        }
+        Ok(())
+    }
+
+    /// Create a new `MuxStream`, add it to the map, and send a `SynAck` frame.
+    /// If `our_port` is 0, a new port will be allocated.
+    #[allow(clippy::too_many_arguments)]
+    #[inline]
+    async fn server_new_stream(
+        &self,
+        our_port: u32,
+        their_port: u32,
+        dest_host: Bytes,
+        dest_port: u16,
+        peer_rwnd: u64,
+        compression: Option<Compression>,
+        tcp_options: TcpOptions,
+        trace_context: Bytes,
+        server_stream_tx: &mpsc::Sender<MuxStream<S>>,
+    ) -> Result<()> {
+        assert_eq!(self.role, Role::Server);
+        // `tx` is our end, `rx` is the user's end
+        let (frame_tx, frame_rx) = mpsc::channel(config::STREAM_FRAME_BUFFER_SIZE);
+        let can_write = Arc::new(AtomicBool::new(true));
+        let psh_send_remaining = Arc::new(AtomicU64::new(peer_rwnd));
+        let writer_waker = Arc::new(AtomicWaker::new());
+        let activity = Arc::new(Activity::new());
+        let counters = Arc::new(StreamCounters::default());
+        let peer_rst_reason = Arc::new(AtomicU8::new(0));
+        // Save the TX end of the stream so we can write to it when subsequent frames arrive
+        let slot = MuxStreamSlot::Established(MuxStreamData {
+            sender: frame_tx,
+            can_write: can_write.dupe(),
+            psh_send_remaining: psh_send_remaining.dupe(),
+            writer_waker: writer_waker.dupe(),
+            their_port,
+            dest_host: dest_host.dupe(),
+            dest_port,
+            activity: activity.dupe(),
+            counters: counters.dupe(),
+            peer_rst_reason: peer_rst_reason.dupe(),
+        });
+        let our_port = if our_port == 0 {
+            // Allocate a new port
+            let result = self
+                .streams
+                .insert_available(slot)
+                .await
+                .expect("no free port after `check_syn` (this is a bug)");
+            trace!("port {result} allocated");
+            result
+        } else {
+            let mut streams = self.streams.shard(our_port).write().await;
+            // Check if the port is available
+            if streams.contains_key(&our_port) {
+                return Err(Error::InvalidSynPort(our_port));
+            }
+            streams.insert(our_port, slot);
+            our_port
+        };
+        let mut stream = MuxStream {
+            frame_rx,
+            our_port,
+            their_port,
+            frame_version: self.options.frame_version,
+            peer_max_frame: self.peer_max_frame.dupe(),
+            compression,
+            incompressible: AtomicU32::new(0),
+            dest_host,
+            dest_port,
+            tcp_options,
+            trace_context,
+            can_write,
+            psh_send_remaining,
+            psh_recvd_since: AtomicU64::new(0),
+            ack_tx: self.ack_tx.dupe(),
+            writer_waker,
+            buf: Bytes::new(),
+            ws: self.ws.dupe(),
+            sched: self.sched.dupe(),
+            dropped_ports_tx: self.dropped_ports_tx.dupe(),
+            read_limits: Vec::new(),
+            write_limits: Vec::new(),
+            read_delay: None,
+            write_delay: None,
+            activity,
+            counters,
+            peer_rst_reason,
+            rst_reason: RstReason::Unspecified,
+            syn_ack: None,
+            cork: self.options.cork,
+            corked: BytesMut::new(),
+            cork_delay: None,
+        };
+        let syn_ack = || {
+            StreamFrame {
+                compression: compression.map_or(0, Compression::bit),
+                ..StreamFrame::new_synack(our_port, their_port, config::RWND)
+            }
+            .into_message(self.options.frame_version)
+        };
+        if self.options.defer_syn_ack {
+            // Sent by `MuxStream::accept`
+            stream.syn_ack = Some(syn_ack());
+        } else {
+            // Send a `SynAck`
+            // Make sure `SynAck` is sent before the stream is sent to the user
+            // so that the stream is `Established` when the user uses it.
+            trace!("sending `SynAck`");
+            self.ws
+                .send_with(syn_ack)
+                .await
+                .map_err(Error::SendStreamFrame)?;
+        }
+        // At the server side, we use `server_stream_tx` to send the new stream to the
+        // user.
+        trace!("sending stream to user");
+        // This goes to the user
+        server_stream_tx
+            .send(stream)
+            .await
+            .map_err(|_| Error::SendStreamToClient)?;
+        Ok(())
+    }
+
+    /// Create a new `MuxStream` and change the state of the port to `Established`.
+    #[inline]
+    async fn client_new_stream(
+        &self,
+        our_port: u32,
+        their_port: u32,
+        peer_rwnd: u64,
+        compression: Option<Compression>,
+    ) -> Result<()> {
+        assert_eq!(self.role, Role::Client);
+        // `tx` is our end, `rx` is the user's end
+        let (frame_tx, frame_rx) = mpsc::channel(config::STREAM_FRAME_BUFFER_SIZE);
+        let can_write = Arc::new(AtomicBool::new(true));
+        let psh_send_remaining = Arc::new(AtomicU64::new(peer_rwnd));
+        let writer_waker = Arc::new(AtomicWaker::new());
+        let activity = Arc::new(Activity::new());
+        let counters = Arc::new(StreamCounters::default());
+        let peer_rst_reason = Arc::new(AtomicU8::new(0));
+        let mut streams = self.streams.shard(our_port).write().await;
+        assert_ne!(our_port, 0);
+        // Our `Syn` recorded the destination
+        let (dest_host, dest_port) = match streams.get(&our_port) {
+            Some(MuxStreamSlot::Requested {
+                dest_host,
+                dest_port,
+                ..
+            }) => (dest_host, dest_port),
+            Some(MuxStreamSlot::Established(_)) => return Err(Error::BogusSynAck),
+            None => {
+                drop(streams);
+                // We gave up waiting for it (`Options::syn_timeout`)
+                debug!("resetting late `SynAck` to {our_port}");
+                self.ws
+                    .send_with(|| {
+                        StreamFrame::new_rst(our_port, their_port, RstReason::TimedOut)
+                            .into_message(self.options.frame_version)
+                    })
+                    .await
+                    .map_err(Error::SendStreamFrame)?;
+                return Ok(());
+            }
+        };
+        let (dest_host, dest_port) = (dest_host.dupe(), *dest_port);
+        let stream_data = MuxStreamData {
+            sender: frame_tx,
+            can_write: can_write.dupe(),
+            psh_send_remaining: psh_send_remaining.dupe(),
+            writer_waker: writer_waker.dupe(),
+            their_port,
+            dest_host: dest_host.dupe(),
+            dest_port,
+            activity: activity.dupe(),
+            counters: counters.dupe(),
+            peer_rst_reason: peer_rst_reason.dupe(),
+        };
+        let stream = MuxStream {
+            frame_rx,
+            our_port,
+            their_port,
+            frame_version: self.options.frame_version,
+            peer_max_frame: self.peer_max_frame.dupe(),
+            compression,
+            incompressible: AtomicU32::new(0),
+            dest_host,
+            dest_port,
+            tcp_options: TcpOptions::NONE,
+            trace_context: Bytes::new(),
+            can_write,
+            psh_send_remaining,
+            psh_recvd_since: AtomicU64::new(0),
+            ack_tx: self.ack_tx.dupe(),
+            writer_waker,
+            buf: Bytes::new(),
+            ws: self.ws.dupe(),
+            sched: self.sched.dupe(),
+            dropped_ports_tx: self.dropped_ports_tx.dupe(),
+            read_limits: Vec::new(),
+            write_limits: Vec::new(),
+            read_delay: None,
+            write_delay: None,
+            activity,
+            counters,
+            peer_rst_reason,
+            rst_reason: RstReason::Unspecified,
+            syn_ack: None,
+            cork: self.options.cork,
+            corked: BytesMut::new(),
+            cork_delay: None,
+        };
+        // Save the TX end of the stream so we can write to it when subsequent frames arrive
+        // by changing the state of the port to `Established`
+        let sender = streams
+            .get_mut(&our_port)
+            .and_then(|entry| entry.establish(stream_data))
+            .expect("The slot was `Requested` (this is a bug)");
+        drop(streams);
+        // Send the stream to the user
+        // At the client side, we use the associated oneshot channel to send the new stream
+        trace!("sending stream to user");
+        sender
+            .send(Ok(stream))
+            .map_err(|_| Error::SendStreamToClient)?;
+        Ok(())
+    }
+
+    /// Traffic of the established streams and the fill levels of the queues.
+    pub async fn stats(&self) -> MuxStats {
+        let mut streams = self
+            .streams
+            .filter_map(|our_port, slot| match slot {
+                MuxStreamSlot::Established(data) => Some(data.counters.snapshot(
+                    our_port,
+                    data.dest_host.dupe(),
+                    data.dest_port,
+                    queue::depth(&data.sender),
+                )),
+                MuxStreamSlot::Requested { .. } => None,
+            })
+            .await;
+        streams.sort_unstable_by_key(|stream| stream.id);
+        MuxStats {
+            streams,
+            // Filled in by `Multiplexor::stats`
+            datagrams_queued: 0,
+            datagrams_dropped: self.datagrams_dropped.load(Ordering::Relaxed),
+            datagrams_unacked: self.reliable.lock().unacked(),
+            dropped_ports_queued: self.dropped_ports_tx.queued(),
+            acks_queued: self.ack_tx.queued(),
+            bytes_unsent: self.sched.queued_bytes(),
+        }
+    }
+
+    /// Reset the established stream at `our_port`. Returns whether there was
+    /// one.
+    pub async fn reset_stream(&self, our_port: u32) -> bool {
+        let their_port = match self.streams.shard(our_port).read().await.get(&our_port) {
+            Some(MuxStreamSlot::Established(data)) => data.their_port,
+            _ => return false,
+        };
+        self.close_port(our_port, their_port, false, RstReason::Unspecified)
+            .await;
+        true
+    }
+
+    /// Close a port. That is, send `Rst` if `Fin` is not sent,
+    /// and remove it from the map. `reason` is that of the `Rst` we received
+    /// if `inhibit_rst`, or of the one we send otherwise.
+    #[tracing::instrument(skip_all, level = "debug")]
+    #[inline]
+    pub async fn close_port(
+        &self,
+        our_port: u32,
+        their_port: u32,
+        inhibit_rst: bool,
+        reason: RstReason,
+    ) {
+        // The idle timeout starts when the last stream is closed
+        self.activity.touch();
+        // Free the port for reuse
+        if let Some(MuxStreamSlot::Established(stream_data)) = self.streams.remove(our_port).await {
+            if inhibit_rst {
+                // Reads give an error instead of `EOF` if the peer said why
+                stream_data
+                    .peer_rst_reason
+                    .store(reason as u8, Ordering::Relaxed);
+            }
+            // Make sure the user receives `EOF`.
+            stream_data.sender.send(Bytes::new()).await.ok();
+            // Atomic ordering:
+            // Load part:
+            // If the user calls `poll_shutdown`, but we see `true` here,
+            // the other end will receive a bogus `Rst` frame, which is fine.
+            // Store part:
+            // It does not matter whether the user calls `poll_shutdown` or not,
+            // the stream is shut down and the final value of `can_write` is `false`.
+            let old = stream_data.can_write.swap(false, Ordering::Relaxed);
+            if inhibit_rst {
+                // The peer is not expecting any more frames
+                self.sched.discard(our_port);
+            } else if old {
+                // If the user did not call `poll_shutdown`, we need to send a `Rst` frame.
+                // It goes after the frames the stream has queued.
+                self.sched.push_last(
+                    our_port,
+                    StreamFrame::new_rst(our_port, their_port, reason)
+                        .into_message(self.options.frame_version),
+                );
+            }
+            // If there is a writer waiting for `Ack`, wake it up because it will never receive one.
+            // Waking it here and the user should receive a `BrokenPipe` error.
+            stream_data.writer_waker.wake();
+        }
+        debug!("freed connection {our_port} -> {their_port}");
+    }
+
+    /// Should really only be called when the mux is dropped.
+    #[tracing::instrument(skip_all, level = "trace")]
+    async fn shutdown(&mut self) {
+        debug!("closing all connections");
+        for (_, stream_data) in self.streams.drain().await {
+            // Make sure `self.streams` is not locked in loop body
+            if let MuxStreamSlot::Established(stream_data) = stream_data {
+                // Make sure the user receives `EOF`.
+                stream_data.sender.send(Bytes::new()).await.ok();
+                // Prevent the user from writing
+                // Atomic ordering: It does not matter whether the user calls `poll_shutdown` or not,
+                // the stream is shut down and the final value of `can_write` is `false`.
+                stream_data.can_write.store(false, Ordering::Relaxed);
+                // If there is a writer waiting for `Ack`, wake it up because it will never receive one.
+                // Waking it here and the user should receive a `BrokenPipe` error.
+                stream_data.writer_waker.wake();
+            }
+            // else: just drop the sender
+        }
+        // Frames the streams have queued so far still go out
+        self.sched.close(&self.ws).await.ok();
+        // This also effectively `Rst`s all streams on the other side
+        self.ws.close().await.ok();
+        self.ws.flush_ignore_closed().await.ok();
+        // Intentionally flushing twice: this time we should get a `ConnectionClosed` error
+        self.ws.flush_ignore_closed().await.ok();
+    }
+}
@@ -0,0 +1,821 @@
+//! Multiplexing streamed data and datagrams over a single WebSocket
+//! connection, or any other [`Transport`].
+//!
+//! This is not a general-purpose WebSocket multiplexing library.
+//! It is tailored to the needs of `penguin`, and speaks the protocol
+//! described in `PROTOCOL.md` of the `penguin` repository.
+//!
+//! Any [`Transport`], such as a `tokio_tungstenite::WebSocketStream` wrapped
+//! in [`ws::WebSocket`], can be multiplexed. The client side
+//! opens streams with [`Multiplexor::client_new_stream_channel`], and the
+//! server side accepts them with [`Multiplexor::server_new_stream_channel`].
+//! Both sides exchange UDP-like datagrams with
+//! [`Multiplexor::send_datagram_to`] and [`Multiplexor::datagrams`], which
+//! can carry any protocol, not just the UDP forwarded by `penguin`.
+//!
+//! ```
+//! use penguin_mux::{Multiplexor, Role, Transport};
+//! use tokio::io::{AsyncReadExt, AsyncWriteExt};
+//!
+//! /// Fetch a page from `example.com` through the server at the other end of `ws`.
+//! async fn fetch<S: Transport>(ws: S) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+//!     let mux = Multiplexor::new(ws, Role::Client, None, None);
+//!     let mut stream = mux.client_new_stream_channel(b"example.com", 80).await?;
+//!     stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+//!     let mut response = Vec::new();
+//!     stream.read_to_end(&mut response).await?;
+//!     Ok(response)
+//! }
+//!
+//! /// Accept streams from the client at the other end of `ws`.
+//! async fn serve<S: Transport>(ws: S) {
+//!     let mux = Multiplexor::new(ws, Role::Server, None, None);
+//!     while let Ok(stream) = mux.server_new_stream_channel().await {
+//!         println!("{:?}:{}", stream.dest_host, stream.dest_port);
+//!     }
+//! }
+//! ```
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+#![deny(missing_docs, missing_debug_implementations)]
+#![allow(clippy::module_name_repetitions)]
+
+mod compress;
+mod config;
+pub mod dupe;
+#[cfg(feature = "fast-ws")]
+pub mod fast_ws;
+mod fragment;
+mod frame;
+mod inner;
+mod locked_sink;
+mod obfs;
+mod queue;
+mod rate;
+mod reliable;
+mod rtt;
+mod sched;
+mod stats;
+mod stream;
+mod stream_map;
+#[cfg(test)]
+mod test;
+pub mod transport;
+pub mod ws;
+
+use crate::dupe::Dupe;
+use crate::inner::MultiplexorInner;
+use crate::rtt::RttTracker;
+use crate::stream::Activity;
+use crate::transport::Message;
+use bytes::Bytes;
+use futures_util::Stream;
+use rand::distributions::uniform::SampleUniform;
+use rand::Rng;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tokio::{
+    sync::{mpsc, watch, RwLock},
+    task::JoinSet,
+};
+use tracing::{debug, error, trace, warn};
+
+pub use crate::compress::Compression;
+pub use crate::frame::{
+    Capabilities, DatagramFrame, Frame, FrameVersion, ReliableDatagramFrame, RstReason, StreamFlag,
+    StreamFrame, TcpOptions,
+};
+pub use crate::rate::TokenBucket;
+pub use crate::rtt::RttStats;
+pub use crate::stats::{MuxStats, StreamStats};
+pub use crate::stream::MuxStream;
+pub use crate::transport::Transport;
+pub use crate::ws::Role;
+
+/// Multiplexor error
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Requester exited before receiving the stream
+    /// (i.e. the `Receiver` was dropped before the task could send the stream).
+    #[error("Requester exited before receiving the stream")]
+    SendStreamToClient,
+    /// The multiplexor is closed.
+    #[error("Mux is already closed")]
+    Closed,
+    /// All ports are taken by open streams.
+    #[error("No free port for a new stream")]
+    NoFreePort,
+
+    // These are transport errors separated by their origin
+    /// Transport error when polling the next message.
+    #[error("Failed to receive message: {0}")]
+    Next(std::io::Error),
+    /// Transport error when sending a datagram.
+    #[error("Failed to send datagram: {0}")]
+    SendDatagram(std::io::Error),
+    /// Transport error when sending a stream frame.
+    #[error("Failed to send stream frame: {0}")]
+    SendStreamFrame(std::io::Error),
+    /// Transport error when sending our `Hello`.
+    #[error("Failed to send hello: {0}")]
+    SendHello(std::io::Error),
+    /// Transport error when working with [Ping](Message::Ping)/[Pong](Message::Pong) frames.
+    #[error("Failed to send ping/pong: {0}")]
+    PingPong(std::io::Error),
+
+    // These are the ones that shouldn't normally happen
+    /// Datagram larger than the peer accepts.
+    #[error("Datagram of {0} bytes is larger than the peer accepts")]
+    DatagramTooLarge(usize),
+    /// Received a frame larger than our `max_frame_size`.
+    #[error("Received frame of {0} bytes, larger than the limit")]
+    FrameTooLarge(usize),
+    /// Datagram target host longer than 255 octets.
+    #[error("Datagram target host longer than 255 octets")]
+    DatagramHostTooLong(#[from] <Bytes as TryFrom<DatagramFrame>>::Error),
+    /// Received an invalid frame.
+    #[error("Invalid frame: {0}")]
+    InvalidFrame(#[from] frame::Error),
+    /// A `SynAck` frame was received by the server.
+    /// "clients MUST NOT send `SynAck` frames"
+    #[error("Server received `SynAck` frame")]
+    ServerReceivedSynAck,
+    /// A `Syn` frame was received by the client.
+    /// "Servers MUST NOT send `Syn` frames"
+    #[error("Client received `Syn` frame")]
+    ClientReceivedSyn,
+    /// A `Syn` frame carrying a non-zero-port ot aport that is already in use.
+    #[error("Invalid `Syn` port: {0}")]
+    InvalidSynPort(u32),
+    /// A `SynAck` frame that does not match any pending `Syn` request.
+    #[error("Bogus `SynAck` frame")]
+    BogusSynAck,
+    /// A `SynAck` frame choosing a compression algorithm we did not offer.
+    #[error("Peer chose compression {0:#04x}, which was not offered")]
+    UnofferedCompression(u8),
+    /// The peer did not answer our `Syn` within
+    /// [`Options::syn_timeout`].
+    #[error("Stream request timed out")]
+    SynTimeout,
+    /// The peer answered our `Syn` with `Rst`.
+    #[error("Stream rejected by the peer ({0}): {1}")]
+    StreamRejected(RstReason, String),
+    /// One side sent `GoAway`, so no new streams are opened on this
+    /// connection.
+    #[error("Connection is going away")]
+    GoingAway,
+    /// There were no streams or datagrams for `idle_timeout`.
+    #[error("Connection closed after being idle")]
+    Idle,
+    /// The peer did not answer this many keepalive `Ping`s in a row.
+    #[error("Peer did not answer {0} pings")]
+    PongTimeout(u32),
+}
+
+/// A variant of [`std::result::Result`] with [`enum@Error`] as the error type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Decides whether a server-side `Multiplexor` accepts a `Syn` to the given
+/// destination host and port. `Err` carries the reason sent in the `Rst`,
+/// with [`RstReason::Denied`].
+pub type SynFilter = Arc<dyn Fn(&[u8], u16) -> std::result::Result<(), String> + Send + Sync>;
+
+/// What to do with a received datagram when 512 datagrams are already
+/// waiting for [`Multiplexor::get_datagram`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DatagramOverflow {
+    /// Drop the received datagram
+    #[default]
+    DropNewest,
+    /// Drop the datagram that has waited the longest to make room
+    DropOldest,
+    /// Stop reading from the transport until there is room. Nothing is
+    /// dropped, but the streams stall as well.
+    Backpressure,
+}
+
+/// Settings of a `Multiplexor`.
+#[derive(Clone, Default)]
+pub struct Options {
+    /// The interval at which to send `Ping` frames.
+    pub keepalive_interval: Option<std::time::Duration>,
+    /// Close the connection when this many `Ping`s in a row get no `Pong`.
+    /// The task then exits with [`Error::PongTimeout`].
+    pub max_missed_pongs: Option<u32>,
+    /// If a server, only accept the `Syn`s allowed by this filter.
+    pub syn_filter: Option<SynFilter>,
+    /// If a server, the maximum number of streams open at the same time.
+    pub max_streams: Option<usize>,
+    /// If a server, do not answer `Syn`s with `SynAck` until
+    /// [`MuxStream::accept`] is called, e.g. after connecting to the
+    /// destination. Dropping the stream or calling [`MuxStream::reset`]
+    /// before that rejects it instead.
+    pub defer_syn_ack: bool,
+    /// If a client, give up on a `Syn` the server has not answered within
+    /// this long. [`Multiplexor::client_new_stream_channel`] then fails with
+    /// [`Error::SynTimeout`], and a late `SynAck` is answered with `Rst`.
+    pub syn_timeout: Option<std::time::Duration>,
+    /// Close streams that have not sent or received data for this long.
+    pub stream_idle_timeout: Option<std::time::Duration>,
+    /// Close the connection after it has had no streams or datagrams for
+    /// this long. The task then exits with [`Error::Idle`].
+    pub idle_timeout: Option<std::time::Duration>,
+    /// Hold back small writes to a stream and the `Ack`s for up to this
+    /// long, so that they go out in fewer and larger frames and writes.
+    /// Helps with chatty protocols at the cost of latency.
+    pub cork: Option<std::time::Duration>,
+    /// Format of the stream frames. Both ends must use the same one, e.g. as
+    /// agreed on in the WebSocket subprotocol. [`FrameVersion::V2`] allows
+    /// more than 65535 streams.
+    pub frame_version: FrameVersion,
+    /// Features announced to the peer when using [`FrameVersion::V2`].
+    /// Frames larger than `capabilities.max_frame_size` are rejected with
+    /// [`Error::FrameTooLarge`], which closes the connection. Streams are
+    /// compressed with an algorithm in `capabilities.compression` if the
+    /// peer supports it too; see [`Compression::supported`].
+    pub capabilities: Capabilities,
+    /// Pad frames to a few sizes and send dummy frames at random intervals
+    /// once the peer has announced `capabilities.padding`, so that the sizes
+    /// and timing of the messages tell less about the traffic.
+    pub obfs_traffic: bool,
+    /// What to do with received datagrams when too many are waiting to be
+    /// read. Dropped ones are counted in [`MuxStats::datagrams_dropped`].
+    pub datagram_overflow: DatagramOverflow,
+}
+
+impl std::fmt::Debug for Options {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Options")
+            .field("keepalive_interval", &self.keepalive_interval)
+            .field("max_missed_pongs", &self.max_missed_pongs)
+            .field("syn_filter", &self.syn_filter.is_some())
+            .field("max_streams", &self.max_streams)
+            .field("defer_syn_ack", &self.defer_syn_ack)
+            .field("syn_timeout", &self.syn_timeout)
+            .field("stream_idle_timeout", &self.stream_idle_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("cork", &self.cork)
+            .field("frame_version", &self.frame_version)
+            .field("capabilities", &self.capabilities)
+            .field("obfs_traffic", &self.obfs_traffic)
+            .field("datagram_overflow", &self.datagram_overflow)
+            .finish()
+    }
+}
+
+/// A multiplexor over a `WebSocket` connection.
+#[derive(Debug)]
+pub struct Multiplexor<S> {
+    inner: MultiplexorInner<S>,
+    /// The task's end of `datagram_rx`, to see how full it is
+    datagram_tx: mpsc::WeakSender<DatagramFrame>,
+    /// Channel for a server-side `Multiplexor` to receive newly
+    /// established streams.
+    server_stream_rx: RwLock<mpsc::Receiver<MuxStream<S>>>,
+}
+
+impl<S: Transport> Multiplexor<S> {
+    /// Create a new `Multiplexor`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ws`: The `WebSocket` connection to multiplex over.
+    ///
+    /// * `role`: The role of this side of the connection.
+    ///   (does not have to match the `WebSocket` role)
+    ///
+    /// * `keepalive_interval`: The interval at which to send `Ping` frames.
+    ///
+    /// * `task_joinset`: A `JoinSet` to spawn the multiplexor task into so
+    ///   that the caller can notice if the task exits. If it is `None`, the
+    ///   task will be spawned by `tokio::spawn` and errors will be logged.
+    #[tracing::instrument(skip_all, level = "debug")]
+    pub fn new(
+        ws: S,
+        role: Role,
+        keepalive_interval: Option<std::time::Duration>,
+        task_joinset: Option<&mut JoinSet<Result<()>>>,
+    ) -> Self {
+        let options = Options {
+            keepalive_interval,
+            ..Options::default()
+        };
+        Self::with_options(ws, role, options, task_joinset)
+    }
+
+    /// Create a new `Multiplexor` like [`Multiplexor::new`], but with more
+    /// [`Options`]. `Syn`s rejected by a server are answered with a `Rst`
+    /// carrying the reason.
+    #[tracing::instrument(skip_all, level = "debug")]
+    pub fn with_options(
+        ws: S,
+        role: Role,
+        options: Options,
+        task_joinset: Option<&mut JoinSet<Result<()>>>,
+    ) -> Self {
+        let (datagram_tx, datagram_rx) = mpsc::channel(config::DATAGRAM_BUFFER_SIZE);
+        let (server_stream_tx, server_stream_rx) = mpsc::channel(config::STREAM_BUFFER_SIZE);
+        let (dropped_ports_tx, dropped_ports_rx) = queue::unbounded_channel();
+        let (ack_tx, ack_rx) = queue::unbounded_channel();
+
+        let max_port = options.frame_version.max_port();
+        let inner = MultiplexorInner {
+            role,
+            ws_auto_pong: ws.ping_auto_pong(),
+            ws: locked_sink::LockedWebSocket::new(ws),
+            sched: Arc::new(sched::Scheduler::default()),
+            options: Arc::new(options),
+            activity: Arc::new(Activity::new()),
+            unanswered_pings: Arc::new(AtomicU32::new(0)),
+            rtt: Arc::new(RttTracker::new()),
+            peer_caps: Arc::new(parking_lot::Mutex::new(None)),
+            peer_max_frame: Arc::new(AtomicU32::new(u32::MAX)),
+            going_away: Arc::new(AtomicBool::new(false)),
+            peer_going_away: Arc::new(watch::channel(false).0),
+            datagram_rx: Arc::new(RwLock::new(datagram_rx)),
+            datagrams_dropped: Arc::new(AtomicU64::new(0)),
+            reliable: Arc::new(parking_lot::Mutex::new(
+                reliable::ReliableDatagrams::default(),
+            )),
+            fragment_id: Arc::new(AtomicU32::new(0)),
+            reassembler: Arc::new(parking_lot::Mutex::new(fragment::Reassembler::default())),
+            streams: Arc::new(stream_map::StreamMap::new(max_port)),
+            dropped_ports_tx,
+            ack_tx,
+        };
+        let weak_datagram_tx = datagram_tx.downgrade();
+        let task_future =
+            inner
+                .dupe()
+                .task(datagram_tx, server_stream_tx, dropped_ports_rx, ack_rx);
+        if let Some(task_joinset) = task_joinset {
+            task_joinset.spawn(task_future);
+        } else {
+            tokio::spawn(async move {
+                if let Err(e) = task_future.await {
+                    error!("Multiplexor task exited with error: {}", e);
+                }
+            });
+        }
+        trace!("Multiplexor task spawned");
+
+        Self {
+            inner,
+            datagram_tx: weak_datagram_tx,
+            server_stream_rx: RwLock::new(server_stream_rx),
+        }
+    }
+
+    /// Request a channel for `host` and `port`.
+    ///
+    /// # Arguments
+    /// * `host`: The host to forward to. While the current implementation
+    ///   supports a domain of arbitrary length, Section 3.2.2 of
+    ///   [RFC 3986](https://www.rfc-editor.org/rfc/rfc3986#section-3.2.2)
+    ///   specifies that the host component of a URI is limited to 255 octets.
+    /// * `port`: The port to forward to.
+    ///
+    /// # Errors
+    /// Returns [`Error::NoFreePort`] if all ports are taken by open streams,
+    /// [`Error::StreamRejected`] if the server rejects the stream,
+    /// [`Error::SynTimeout`] if it does not answer in time, or
+    /// [`Error::GoingAway`] if either side sent `GoAway`.
+    ///
+    /// # Panics
+    /// Panics if the `Multiplexor` is not a client.
+    ///
+    /// # Cancel safety
+    /// This function is not cancel safe. If the task is cancelled while waiting
+    /// for the channel to be established, that channel may be established but
+    /// inaccessible through normal means. Subsequent calls to this function
+    /// will result in a new channel being established.
+    pub async fn client_new_stream_channel(&self, host: &[u8], port: u16) -> Result<MuxStream<S>> {
+        self.client_new_stream_channel_with_options(host, port, TcpOptions::NONE)
+            .await
+    }
+
+    /// Request a channel for `host` and `port`, asking the server to set
+    /// `tcp_options` on its connection to them. Servers that did not
+    /// announce [`Capabilities::tcp_options`] ignore them.
+    ///
+    /// See [`client_new_stream_channel`](Self::client_new_stream_channel)
+    /// for the arguments and cancel safety.
+    ///
+    /// # Errors
+    /// The same as [`client_new_stream_channel`](Self::client_new_stream_channel).
+    ///
+    /// # Panics
+    /// Panics if the `Multiplexor` is not a client.
+    pub async fn client_new_stream_channel_with_options(
+        &self,
+        host: &[u8],
+        port: u16,
+        tcp_options: TcpOptions,
+    ) -> Result<MuxStream<S>> {
+        self.client_new_stream_channel_with_context(host, port, tcp_options, &[])
+            .await
+    }
+
+    /// Request a channel like
+    /// [`client_new_stream_channel_with_options`](Self::client_new_stream_channel_with_options),
+    /// also sending `trace_context`, e.g. a W3C `traceparent`, for the
+    /// server to link its work on the stream to the client's. It is left
+    /// out if it does not fit in the `Syn` frame or the connection speaks
+    /// [`FrameVersion::V1`].
+    ///
+    /// # Errors
+    /// The same as [`client_new_stream_channel`](Self::client_new_stream_channel).
+    ///
+    /// # Panics
+    /// Panics if the `Multiplexor` is not a client.
+    #[tracing::instrument(skip(self, trace_context), level = "debug")]
+    pub async fn client_new_stream_channel_with_context(
+        &self,
+        host: &[u8],
+        port: u16,
+        tcp_options: TcpOptions,
+        trace_context: &[u8],
+    ) -> Result<MuxStream<S>> {
+        assert_eq!(self.inner.role, Role::Client);
+        if self.inner.going_away.load(Ordering::Relaxed) || self.is_peer_going_away() {
+            return Err(Error::GoingAway);
+        }
+        let (stream_tx, mut stream_rx) = oneshot::channel();
+        // Allocate a new port
+        let sport = self
+            .inner
+            .streams
+            .insert_available(inner::MuxStreamSlot::Requested {
+                sender: stream_tx,
+                dest_host: Bytes::copy_from_slice(host),
+                dest_port: port,
+            })
+            .await
+            .ok_or(Error::NoFreePort)?;
+        trace!("sport = {sport}");
+        trace!("sending `Syn`");
+        self.inner
+            .ws
+            .feed_with(|| {
+                StreamFrame {
+                    compression: self.inner.compression_offer(),
+                    tcp_options,
+                    trace_context: Bytes::copy_from_slice(trace_context),
+                    ..StreamFrame::new_syn(host, port, sport, config::RWND)
+                }
+                .into_message(self.inner.options.frame_version)
+            })
+            .await
+            .map_err(Error::SendStreamFrame)?;
+        // The peer may answer and close the connection before our flush
+        // returns. `stream_rx` tells whether the stream was established.
+        self.inner
+            .ws
+            .flush_ignore_closed()
+            .await
+            .map_err(Error::SendStreamFrame)?;
+        if let Some(syn_timeout) = self.inner.options.syn_timeout {
+            if let Ok(result) = tokio::time::timeout(syn_timeout, &mut stream_rx).await {
+                trace!("sending stream to user");
+                // See below
+                return result.map_err(|_| Error::Closed)?;
+            }
+            let mut streams = self.inner.streams.shard(sport).write().await;
+            if matches!(
+                streams.get(&sport),
+                Some(inner::MuxStreamSlot::Requested { .. })
+            ) {
+                // The port is quarantined, so a late `SynAck` is not taken
+                // for that of a new stream
+                streams.remove(&sport);
+                drop(streams);
+                self.inner.streams.release(sport);
+                debug!("`Syn` from {sport} timed out");
+                return Err(Error::SynTimeout);
+            }
+            // Else, answered in the meantime
+        }
+        trace!("sending stream to user");
+        stream_rx
+            .await
+            // Happens if the task exits before sending the stream,
+            // thus `Closed` is the correct error
+            .map_err(|_| Error::Closed)?
+    }
+
+    /// Get the next available stream channel.
+    ///
+    /// # Errors
+    /// Returns [`Error::Closed`] if the connection is closed.
+    ///
+    /// # Panics
+    /// Panics if the `Multiplexor` is not a server.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe. If the task is cancelled while waiting
+    /// for a new connection, it is guaranteed that no connected stream will
+    /// be lost.
+    #[tracing::instrument(skip(self), level = "debug")]
+    pub async fn server_new_stream_channel(&self) -> Result<MuxStream<S>> {
+        assert_eq!(self.inner.role, Role::Server);
+        self.server_stream_rx
+            .write()
+            .await
+            .recv()
+            .await
+            .ok_or(Error::Closed)
+    }
+
+    /// Get the next available datagram.
+    ///
+    /// # Errors
+    /// Returns [`Error::Closed`] if the connection is closed.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe. If the task is cancelled while waiting
+    /// for a datagram, it is guaranteed that no datagram will be lost.
+    #[tracing::instrument(skip(self), level = "debug")]
+    #[inline]
+    pub async fn get_datagram(&self) -> Result<DatagramFrame> {
+        self.inner
+            .datagram_rx
+            .write()
+            .await
+            .recv()
+            .await
+            .ok_or(Error::Closed)
+    }
+
+    /// Receive the datagrams from the peer as a [`Stream`], which ends when
+    /// the connection is closed. Same as calling
+    /// [`get_datagram`](Self::get_datagram) in a loop.
+    ///
+    /// ```
+    /// use futures_util::StreamExt;
+    /// use penguin_mux::{Multiplexor, Transport};
+    ///
+    /// /// Answer each datagram with its reversed payload.
+    /// async fn echo<S: Transport>(mux: &Multiplexor<S>) {
+    ///     let mut datagrams = std::pin::pin!(mux.datagrams());
+    ///     while let Some(datagram) = datagrams.next().await {
+    ///         let reversed = datagram.data.iter().rev().copied().collect::<Vec<_>>();
+    ///         mux.send_datagram_to(&datagram.host, datagram.port, reversed.into())
+    ///             .await
+    ///             .ok();
+    ///     }
+    /// }
+    /// ```
+    pub fn datagrams(&self) -> impl Stream<Item = DatagramFrame> + '_ {
+        futures_util::stream::unfold(self, |mux| async move {
+            let datagram = mux.get_datagram().await.ok()?;
+            Some((datagram, mux))
+        })
+    }
+
+    /// Send `data` in a datagram to `host:port`. The client chooses the
+    /// meaning of the target; for `penguin`'s UDP forwarding, it is where the
+    /// server sends the payload. See [`send_datagram`](Self::send_datagram)
+    /// to also set [`DatagramFrame::sid`].
+    ///
+    /// # Errors
+    /// Same as [`send_datagram`](Self::send_datagram).
+    #[inline]
+    pub async fn send_datagram_to(&self, host: &[u8], port: u16, data: Bytes) -> Result<()> {
+        self.send_datagram(DatagramFrame {
+            host: Bytes::copy_from_slice(host),
+            port,
+            sid: 0,
+            data,
+        })
+        .await
+    }
+
+    /// Send a datagram
+    ///
+    /// # Errors
+    /// * Returns `Error::DatagramHostTooLong` if the destination host is
+    /// longer than 255 octets.
+    /// * Returns `Error::DatagramTooLarge` if the datagram is larger than
+    /// the peer accepts. Datagrams of up to 128 KiB are split into
+    /// fragments if the peer reassembles them.
+    /// * Returns `Error::SendDatagram` if the datagram could not be sent
+    /// due to a transport error.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe. If the task is cancelled, it is
+    /// guaranteed that the datagram has not been sent, or, if it was
+    /// fragmented, that the peer drops the fragments it got.
+    /// Datagrams for a [`DatagramFrame::sid`] the peer sent reliable
+    /// datagrams from are sent with
+    /// [`send_reliable_datagram`](Self::send_reliable_datagram) instead.
+    #[tracing::instrument(skip(self), level = "debug")]
+    #[inline]
+    pub async fn send_datagram(&self, frame: DatagramFrame) -> Result<()> {
+        let reliable = self.inner.reliable.lock().is_reliable_sid(frame.sid);
+        if reliable {
+            return self.send_reliable_datagram(frame).await;
+        }
+        self.send_unreliable_datagram(frame).await
+    }
+
+    /// Send a datagram, and send it again until the peer acknowledges it,
+    /// so that it survives the peer dropping datagrams it has no room for.
+    /// It may arrive out of order. The peer's replies to
+    /// [`DatagramFrame::sid`] are reliable too. Falls back to
+    /// [`send_datagram`](Self::send_datagram) if the peer does not
+    /// acknowledge datagrams.
+    ///
+    /// # Errors
+    /// Same as [`send_datagram`](Self::send_datagram), except that reliable
+    /// datagrams are never fragmented.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe, but the datagram may be sent later
+    /// even if the task is cancelled.
+    #[tracing::instrument(skip(self), level = "debug")]
+    pub async fn send_reliable_datagram(&self, frame: DatagramFrame) -> Result<()> {
+        let supported = self
+            .inner
+            .peer_caps
+            .lock()
+            .as_ref()
+            .is_some_and(|caps| caps.reliable_datagrams);
+        if !supported {
+            trace!("peer does not acknowledge datagrams");
+            return self.send_unreliable_datagram(frame).await;
+        }
+        self.inner.activity.touch();
+        let seq = self.inner.reliable.lock().next_seq();
+        let payload = Frame::ReliableDatagram(ReliableDatagramFrame {
+            seq,
+            datagram: frame,
+        })
+        .encode(self.inner.options.frame_version)?;
+        let peer_max_frame = self.inner.peer_max_frame.load(Ordering::Relaxed);
+        if payload.len() > peer_max_frame as usize {
+            return Err(Error::DatagramTooLarge(payload.len()));
+        }
+        // Before sending, so that the acknowledgement cannot come first
+        self.inner.reliable.lock().sent(seq, payload.dupe());
+        self.inner
+            .ws
+            .send_with(|| Message::Frame(payload.dupe()))
+            .await
+            .map_err(Error::SendDatagram)
+    }
+
+    /// Send a datagram once, fragmented if needed.
+    async fn send_unreliable_datagram(&self, frame: DatagramFrame) -> Result<()> {
+        self.inner.activity.touch();
+        let payload = Bytes::try_from(frame)?;
+        let peer_max_frame = self.inner.peer_max_frame.load(Ordering::Relaxed) as usize;
+        if payload.len() > peer_max_frame {
+            let fragments = self
+                .inner
+                .peer_caps
+                .lock()
+                .as_ref()
+                .is_some_and(|caps| caps.fragments);
+            if !fragments || payload.len() > config::MAX_FRAGMENTED_SIZE {
+                return Err(Error::DatagramTooLarge(payload.len()));
+            }
+            let id = self.inner.fragment_id.fetch_add(1, Ordering::Relaxed);
+            let frames = fragment::split(id, &payload, peer_max_frame)
+                .into_iter()
+                .map(|frame| {
+                    let encoded = Frame::Fragment(frame)
+                        .encode(self.inner.options.frame_version)
+                        .expect("fragment frames have no ports or hosts (this is a bug)");
+                    Message::Frame(encoded)
+                })
+                .collect::<Vec<_>>();
+            self.inner
+                .ws
+                .feed_all(frames)
+                .await
+                .map_err(Error::SendDatagram)?;
+            self.inner.ws.flush().await.map_err(Error::SendDatagram)?;
+            return Ok(());
+        }
+        // Always flush datagrams immediately
+        self.inner
+            .ws
+            .send_with(|| Message::Frame(payload.dupe()))
+            .await
+            .map_err(Error::SendDatagram)?;
+        Ok(())
+    }
+
+    /// Round-trip times of the recent keepalive `Ping`s, or `None` if no
+    /// `Pong` has arrived yet.
+    #[must_use]
+    pub fn rtt(&self) -> Option<RttStats> {
+        self.inner.rtt.stats()
+    }
+
+    /// Features the peer announced, or `None` if its `Hello` has not
+    /// arrived (yet). Peers speaking [`FrameVersion::V1`] never send one.
+    #[must_use]
+    pub fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.inner.peer_caps.lock().clone()
+    }
+
+    /// Announce that no new streams will be accepted or opened, so that
+    /// the peer can move new streams to another connection while the open
+    /// ones finish. The connection stays up until the `Multiplexor` is
+    /// dropped. Peers that do not understand `GoAway` are not told, but
+    /// their `Syn`s are rejected all the same.
+    ///
+    /// # Errors
+    /// Returns [`Error::SendStreamFrame`] if the frame could not be sent.
+    #[tracing::instrument(skip(self), level = "debug")]
+    pub async fn go_away(&self) -> Result<()> {
+        self.inner.go_away().await
+    }
+
+    /// Whether the peer sent `GoAway`.
+    #[must_use]
+    pub fn is_peer_going_away(&self) -> bool {
+        *self.inner.peer_going_away.borrow()
+    }
+
+    /// Wait until the peer sends `GoAway`.
+    ///
+    /// # Cancel Safety
+    /// This function is cancel safe.
+    pub async fn peer_going_away(&self) {
+        let mut going_away = self.inner.peer_going_away.subscribe();
+        while !*going_away.borrow_and_update() {
+            // `self.inner` holds the sender
+            going_away
+                .changed()
+                .await
+                .expect("`GoAway` sender dropped (this is a bug)");
+        }
+    }
+
+    /// Traffic of the open streams and the fill levels of the queues.
+    pub async fn stats(&self) -> MuxStats {
+        let mut stats = self.inner.stats().await;
+        stats.datagrams_queued = self
+            .datagram_tx
+            .upgrade()
+            .map_or(0, |datagram_tx| queue::depth(&datagram_tx));
+        stats
+    }
+
+    /// Reset the established stream with this [`MuxStream::id`] from outside
+    /// the task that holds it, e.g. for an operator. Its reads then see EOF
+    /// and its writes fail. Returns whether there was such a stream.
+    pub async fn reset_stream(&self, id: u32) -> bool {
+        self.inner.reset_stream(id).await
+    }
+}
+
+impl<S> Drop for Multiplexor<S> {
+    fn drop(&mut self) {
+        self.inner
+            .dropped_ports_tx
+            .send((0, 0, RstReason::Unspecified))
+            .ok();
+    }
+}
+
+/// Randomly generate a new number
+pub trait IntKey: Eq + Hash + Copy + SampleUniform + PartialOrd {
+    /// The minimum value of the key
+    const MIN: Self;
+    /// The maximum value of the key
+    const MAX: Self;
+
+    /// Generate a new key that is not in the map
+    #[inline]
+    #[must_use]
+    fn next_available_key<V>(map: &HashMap<Self, V>) -> Self {
+        loop {
+            let i = rand::thread_rng().gen_range(Self::MIN..Self::MAX);
+            if !map.contains_key(&i) {
+                break i;
+            }
+        }
+    }
+}
+
+macro_rules! impl_int_key {
+    ($($t:ty),*) => {
+        $(
+            impl IntKey for $t {
+                // 0 is for special use
+                const MIN : Self = 1;
+                const MAX : Self = Self::MAX;
+            }
+        )*
+    };
+}
+
+impl_int_key!(u8, u16, u32, u64, u128, usize);
@@ -3,26 +3,90 @@
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 #![deny(missing_docs)]
 
-use crate::ws::{Message, Result, WebSocketError, WebSocketStream};
+use crate::dupe::Dupe;
+use crate::obfs::Padding;
+use crate::transport::{because_closed, Message, Transport};
 use futures_util::{SinkExt, StreamExt};
 use parking_lot::Mutex;
 use std::future::poll_fn;
+use std::io::Result;
 use std::sync::Arc;
-use std::task::{ready, Context, Poll};
+use std::task::{ready, Context, Poll, Wake, Waker};
 use tracing::trace;
 
 /// A wrapper around `Sink + Stream` that can be cloned and shared between tasks.
-pub struct LockedWebSocket<S>(Arc<Mutex<S>>);
+pub struct LockedWebSocket<S> {
+    sink: Arc<Mutex<S>>,
+    /// Tasks waiting for the sink
+    waiters: Arc<SinkWaiters>,
+    /// Waker given to the sink, which wakes all of `waiters`
+    waker: Waker,
+    /// Pads the frames fed to the sink
+    padding: Arc<Padding>,
+}
+
+/// Tasks waiting to write to the sink. A sink usually only remembers the
+/// last task that polled it, so all of them are woken instead.
+#[derive(Debug, Default)]
+struct SinkWaiters(Mutex<Vec<Waker>>);
+
+impl SinkWaiters {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.0.lock();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+}
+
+impl Wake for SinkWaiters {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        let wakers = std::mem::take(&mut *self.0.lock());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
 
 impl<S> LockedWebSocket<S> {
-    /// Create a new `LockedWebSocket` from a `WebSocketStream`
+    /// Create a new `LockedWebSocket` from a `Transport`
     #[inline]
     pub fn new(websocket: S) -> Self {
-        Self(Arc::new(Mutex::new(websocket)))
+        let waiters = Arc::new(SinkWaiters::default());
+        Self {
+            sink: Arc::new(Mutex::new(websocket)),
+            waker: Waker::from(waiters.dupe()),
+            waiters,
+            padding: Arc::new(Padding::default()),
+        }
+    }
+
+    /// Padding applied to the frames fed to the sink
+    #[inline]
+    pub fn padding(&self) -> &Padding {
+        &self.padding
+    }
+
+    /// Lock the sink and poll it with a waker that wakes every task waiting
+    /// for it, including this one.
+    #[inline]
+    fn with_sink<T>(
+        &self,
+        cx: &mut Context<'_>,
+        f: impl FnOnce(&mut S, &mut Context<'_>) -> T,
+    ) -> T {
+        let mut sink = self.sink.lock();
+        // Registered before polling so that no wakeup is missed
+        self.waiters.register(cx.waker());
+        f(&mut sink, &mut Context::from_waker(&self.waker))
     }
 }
 
-impl<S: WebSocketStream> LockedWebSocket<S> {
+impl<S: Transport> LockedWebSocket<S> {
     /// Lock and feed the resulting `Message` from a computation into the sink.
     /// The computation is only executed if the sink is ready.
     /// The computation may return `Poll::Pending` to indicate that it is not
@@ -33,10 +97,11 @@ impl<S: WebSocketStream> LockedWebSocket<S> {
         cx: &mut Context<'_>,
         msg_fn: impl FnOnce(&mut Context<'_>) -> Poll<Message>,
     ) -> Poll<Result<()>> {
-        let mut sink = self.0.lock();
+        let mut sink = self.sink.lock();
+        self.waiters.register(cx.waker());
         // `ready`: if we return here, nothing happens
-        ready!(sink.poll_ready_unpin(cx))?;
-        let msg = ready!(msg_fn(cx));
+        ready!(sink.poll_ready_unpin(&mut Context::from_waker(&self.waker)))?;
+        let msg = self.padding.pad(ready!(msg_fn(cx)));
         let result = sink.start_send_unpin(msg);
         drop(sink);
         trace!("message sent");
@@ -60,7 +125,7 @@ impl<S: WebSocketStream> LockedWebSocket<S> {
     /// Lock and flush any remaining data in the sink.
     #[inline]
     pub fn poll_flush(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        self.0.lock().poll_flush_unpin(cx)
+        self.with_sink(cx, |sink, cx| sink.poll_flush_unpin(cx))
     }
 
     /// Lock and flush any remaining data in the sink.
@@ -81,6 +146,24 @@ impl<S: WebSocketStream> LockedWebSocket<S> {
         self.flush().await
     }
 
+    /// Feed all `msgs`, so that the transport can write them out together.
+    #[inline]
+    pub async fn feed_all<I>(&self, msgs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Message>,
+    {
+        for msg in msgs {
+            let mut msg = Some(msg);
+            poll_fn(|cx| {
+                self.poll_feed_with(cx, |_cx| {
+                    Poll::Ready(msg.take().expect("message fed twice (this is a bug)"))
+                })
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
     /// Lock and flush the sink, ignoring errors that indicate the connection
     /// is closed.
     /// It is sometimes acceptable when the other side closes the connection
@@ -90,7 +173,7 @@ impl<S: WebSocketStream> LockedWebSocket<S> {
     pub fn poll_flush_ignore_closed(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
         match ready!(self.poll_flush(cx)) {
             Ok(()) => Poll::Ready(Ok(())),
-            Err(e) if e.because_closed() => Poll::Ready(Ok(())),
+            Err(e) if because_closed(&e) => Poll::Ready(Ok(())),
             Err(e) => Poll::Ready(Err(e)),
         }
     }
@@ -103,7 +186,7 @@ impl<S: WebSocketStream> LockedWebSocket<S> {
     /// Lock and close the sink
     #[inline]
     pub fn poll_close(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        self.0.lock().poll_close_unpin(cx)
+        self.with_sink(cx, |sink, cx| sink.poll_close_unpin(cx))
     }
 
     #[inline]
@@ -113,7 +196,7 @@ impl<S: WebSocketStream> LockedWebSocket<S> {
 
     #[inline]
     pub fn poll_next(&self, cx: &mut Context<'_>) -> Poll<Option<Result<Message>>> {
-        self.0.lock().poll_next_unpin(cx)
+        self.sink.lock().poll_next_unpin(cx)
     }
 
     #[inline]
@@ -122,17 +205,22 @@ impl<S: WebSocketStream> LockedWebSocket<S> {
     }
 }
 
-impl<S> crate::dupe::Dupe for LockedWebSocket<S> {
+impl<S> Dupe for LockedWebSocket<S> {
     #[inline]
     fn dupe(&self) -> Self {
-        Self(self.0.dupe())
+        Self {
+            sink: self.sink.dupe(),
+            waiters: self.waiters.dupe(),
+            waker: self.waker.clone(),
+            padding: self.padding.dupe(),
+        }
     }
 }
 
 impl<S: std::fmt::Debug> std::fmt::Debug for LockedWebSocket<S> {
     #[inline]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.0.try_lock() {
+        match self.sink.try_lock() {
             Some(sink) => <S as std::fmt::Debug>::fmt(&*sink, f),
             None => f.write_str("WebSocket (locked)"),
         }
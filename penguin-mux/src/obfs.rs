@@ -0,0 +1,111 @@
+//! Traffic obfuscation: frames padded to a few sizes and dummy frames at
+//! random times, so that the sizes and timing of the messages tell less
+//! about the tunneled traffic.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::config;
+use crate::frame::{self, PADDING_HEADER_LEN};
+use crate::transport::Message;
+use rand::Rng;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Pads the frames fed to the transport once both sides agreed to it
+#[derive(Debug, Default)]
+pub struct Padding {
+    /// Largest message the peer accepts, 0 while padding is off
+    max: AtomicU32,
+}
+
+impl Padding {
+    /// Start padding messages, up to `peer_max_frame` bytes.
+    pub fn enable(&self, peer_max_frame: u32) {
+        self.max.store(peer_max_frame.max(1), Ordering::Relaxed);
+    }
+
+    /// Whether messages are being padded
+    pub fn is_enabled(&self) -> bool {
+        self.max.load(Ordering::Relaxed) != 0
+    }
+
+    /// Wrap a frame in `msg` in a padding frame of the next size up, unless
+    /// padding is off or the peer would not accept it.
+    pub fn pad(&self, msg: Message) -> Message {
+        let max = self.max.load(Ordering::Relaxed) as usize;
+        match msg {
+            // Dummy frames are padded already
+            Message::Frame(data) if max != 0 && data.first() != Some(&4) => {
+                let len = PADDING_HEADER_LEN + data.len();
+                let size = bucket(len).min(max);
+                if size < len {
+                    Message::Frame(data)
+                } else {
+                    Message::Frame(frame::pad(&data, size))
+                }
+            }
+            msg => msg,
+        }
+    }
+
+    /// A dummy frame of a random size
+    pub fn dummy(&self) -> Message {
+        let max = self.max.load(Ordering::Relaxed) as usize;
+        let size =
+            config::PAD_MIN_SIZE << rand::thread_rng().gen_range(0..=config::PAD_DUMMY_SIZES);
+        Message::Frame(frame::pad(&[], size.min(max)))
+    }
+}
+
+/// Size to pad a message of `len` bytes to: the next power of two up to
+/// `config::PAD_STEP`, then the next multiple of it.
+fn bucket(len: usize) -> usize {
+    if len <= config::PAD_STEP {
+        len.next_power_of_two().max(config::PAD_MIN_SIZE)
+    } else {
+        len.next_multiple_of(config::PAD_STEP)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(bucket(1), config::PAD_MIN_SIZE);
+        assert_eq!(bucket(config::PAD_MIN_SIZE + 1), config::PAD_MIN_SIZE * 2);
+        assert_eq!(bucket(config::PAD_STEP), config::PAD_STEP);
+        assert_eq!(bucket(config::PAD_STEP + 1), config::PAD_STEP * 2);
+    }
+
+    #[test]
+    fn test_pad() {
+        let padding = Padding::default();
+        let frame = Message::Frame(Bytes::from_static(&[1; 100]));
+        // Off until enabled
+        assert_eq!(padding.pad(frame.clone()), frame);
+        padding.enable(u32::MAX);
+        let Message::Frame(padded) = padding.pad(frame.clone()) else {
+            panic!("not a frame");
+        };
+        assert_eq!(padded.len(), config::PAD_MIN_SIZE);
+        // Control messages are left alone
+        assert_eq!(padding.pad(Message::Close), Message::Close);
+        // Messages too large to pad are sent as they are
+        padding.enable(102);
+        assert_eq!(padding.pad(frame.clone()), frame);
+        padding.enable(200);
+        let Message::Frame(padded) = padding.pad(frame) else {
+            panic!("not a frame");
+        };
+        assert_eq!(padded.len(), 200);
+        let Message::Frame(dummy) = padding.dummy() else {
+            panic!("not a frame");
+        };
+        assert_eq!(
+            padding.pad(Message::Frame(dummy.clone())),
+            Message::Frame(dummy)
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! Unbounded channels that know how many messages are waiting.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::dupe::Dupe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Create an unbounded channel whose sender can report its depth.
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, UnboundedReceiver<T>) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let queued = Arc::new(AtomicUsize::new(0));
+    (
+        UnboundedSender {
+            tx,
+            queued: queued.dupe(),
+        },
+        UnboundedReceiver { rx, queued },
+    )
+}
+
+/// Sending half of [`unbounded_channel`]
+#[derive(Debug)]
+pub struct UnboundedSender<T> {
+    tx: mpsc::UnboundedSender<T>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.dupe(),
+            queued: self.queued.dupe(),
+        }
+    }
+}
+
+impl<T> Dupe for UnboundedSender<T> {
+    #[inline]
+    fn dupe(&self) -> Self {
+        self.clone()
+    }
+}
+
+impl<T> UnboundedSender<T> {
+    /// See [`mpsc::UnboundedSender::send`].
+    pub fn send(&self, message: T) -> Result<(), mpsc::error::SendError<T>> {
+        // Counted first so that the receiver never takes it below zero
+        // Atomic ordering: we are only counting
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.tx.send(message).inspect_err(|_| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        })
+    }
+
+    /// Number of messages sent but not received yet.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+/// Receiving half of [`unbounded_channel`]
+#[derive(Debug)]
+pub struct UnboundedReceiver<T> {
+    rx: mpsc::UnboundedReceiver<T>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl<T> UnboundedReceiver<T> {
+    /// See [`mpsc::UnboundedReceiver::recv`].
+    pub async fn recv(&mut self) -> Option<T> {
+        let message = self.rx.recv().await?;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        Some(message)
+    }
+
+    /// See [`mpsc::UnboundedReceiver::try_recv`].
+    pub fn try_recv(&mut self) -> Result<T, mpsc::error::TryRecvError> {
+        let message = self.rx.try_recv()?;
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+        Ok(message)
+    }
+}
+
+/// Number of messages waiting in a bounded channel.
+pub fn depth<T>(tx: &mpsc::Sender<T>) -> usize {
+    tx.max_capacity() - tx.capacity()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unbounded_queued() {
+        let (tx, mut rx) = unbounded_channel();
+        tx.send(1).unwrap();
+        tx.dupe().send(2).unwrap();
+        assert_eq!(tx.queued(), 2);
+        assert_eq!(rx.recv().await, Some(1));
+        assert_eq!(tx.queued(), 1);
+        assert_eq!(rx.try_recv(), Ok(2));
+        assert_eq!(tx.queued(), 0);
+        rx.try_recv().unwrap_err();
+        tx.send(4).unwrap();
+        drop(rx);
+        tx.send(3).unwrap_err();
+        assert_eq!(tx.queued(), 1);
+    }
+}
@@ -0,0 +1,110 @@
+//! Token buckets for limiting the throughput of streams.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use parking_lot::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A token bucket of bytes that can be shared between streams.
+///
+/// Transfers are charged after they happen and may drive the bucket into
+/// debt, so that frames larger than the bucket never stall. A stream waits
+/// before its next transfer until the debt is paid off.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes added per second
+    rate: f64,
+    /// Maximum number of tokens
+    burst: f64,
+    /// Tokens and the time they were last refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Create a bucket that allows `rate` bytes per second, with bursts of
+    /// up to one second's worth.
+    #[must_use]
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1) as f64;
+        Self {
+            rate,
+            burst: rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Refill the bucket and return the number of tokens.
+    fn refill(&self, state: &mut (f64, Instant)) -> f64 {
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.1).as_secs_f64();
+        state.0 = (state.0 + elapsed * self.rate).min(self.burst);
+        state.1 = now;
+        state.0
+    }
+
+    /// Take `n` bytes out of the bucket.
+    pub fn consume(&self, n: usize) {
+        let mut state = self.state.lock();
+        self.refill(&mut state);
+        let n = n as f64;
+        state.0 -= n;
+    }
+
+    /// Take `n` bytes out of the bucket unless it is in debt, for traffic
+    /// that is dropped rather than delayed, like datagrams. Returns whether
+    /// they were taken.
+    pub fn try_consume(&self, n: usize) -> bool {
+        let mut state = self.state.lock();
+        if self.refill(&mut state) < 0.0 {
+            return false;
+        }
+        state.0 -= n as f64;
+        true
+    }
+
+    /// How long to wait until the bucket is out of debt.
+    #[must_use]
+    pub fn delay(&self) -> Duration {
+        let mut state = self.state.lock();
+        let tokens = self.refill(&mut state);
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let bucket = TokenBucket::new(10000);
+        assert_eq!(bucket.delay(), Duration::ZERO);
+        bucket.consume(10000);
+        bucket.consume(1000);
+        let delay = bucket.delay();
+        assert!(delay > Duration::from_millis(50) && delay <= Duration::from_millis(100));
+        tokio::time::sleep(delay).await;
+        assert_eq!(bucket.delay(), Duration::ZERO);
+        // Refills up to the burst size only
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        bucket.consume(10100);
+        assert!(bucket.delay() > Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_try_consume() {
+        let bucket = TokenBucket::new(10000);
+        // Like `consume`, one may go into debt
+        assert!(bucket.try_consume(6000));
+        assert!(bucket.try_consume(6000));
+        // But not further
+        assert!(!bucket.try_consume(1));
+        tokio::time::advance(bucket.delay()).await;
+        assert!(bucket.try_consume(1));
+    }
+}
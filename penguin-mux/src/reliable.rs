@@ -0,0 +1,201 @@
+//! Reliable datagrams: sending them again until the peer acknowledges
+//! them, and dropping the duplicates that causes.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::config;
+use crate::dupe::Dupe;
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// A reliable datagram waiting for its acknowledgement
+#[derive(Debug)]
+struct Unacked {
+    /// The encoded frame
+    frame: Bytes,
+    /// When to send it again
+    due: Instant,
+    /// How long to wait after the next try
+    rto: Duration,
+    /// Number of times it has been sent
+    tries: u32,
+}
+
+/// State of the reliable datagrams of a multiplexor
+#[derive(Debug, Default)]
+pub struct ReliableDatagrams {
+    /// Sequence number of the next datagram we send
+    next_seq: u32,
+    /// Sent datagrams by sequence number
+    unacked: BTreeMap<u32, Unacked>,
+    /// Sequence numbers received recently
+    seen: HashSet<u32>,
+    /// `seen` in the order they arrived, to forget the oldest
+    seen_order: VecDeque<u32>,
+    /// Source IDs the peer sent reliable datagrams from, so that the
+    /// replies are reliable too
+    sids: HashSet<u32>,
+    /// `sids` in the order they first arrived, to forget the oldest
+    sids_order: VecDeque<u32>,
+}
+
+impl ReliableDatagrams {
+    /// Take a sequence number for a new datagram.
+    pub fn next_seq(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        seq
+    }
+
+    /// Keep the encoded `frame` with `seq` to send again until acknowledged.
+    pub fn sent(&mut self, seq: u32, frame: Bytes) {
+        if self.unacked.len() >= config::RELIABLE_WINDOW {
+            if let Some((oldest, _)) = self.unacked.pop_first() {
+                warn!("gave up on reliable datagram {oldest} to make room");
+            }
+        }
+        self.unacked.insert(
+            seq,
+            Unacked {
+                frame,
+                due: Instant::now() + config::RELIABLE_RTO,
+                rto: config::RELIABLE_RTO * 2,
+                tries: 1,
+            },
+        );
+    }
+
+    /// The peer acknowledged `seq`.
+    pub fn ack(&mut self, seq: u32) {
+        self.unacked.remove(&seq);
+    }
+
+    /// Number of datagrams waiting for acknowledgement
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Frames to send again now. Gives up on those sent too often.
+    pub fn due(&mut self) -> Vec<Bytes> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        self.unacked.retain(|seq, unacked| {
+            if unacked.due > now {
+                return true;
+            }
+            if unacked.tries >= config::RELIABLE_MAX_TRIES {
+                warn!("gave up on reliable datagram {seq}");
+                return false;
+            }
+            unacked.tries += 1;
+            unacked.due = now + unacked.rto;
+            unacked.rto *= 2;
+            due.push(unacked.frame.dupe());
+            true
+        });
+        due
+    }
+
+    /// Whether `seq` has been received before.
+    pub fn is_duplicate(&self, seq: u32) -> bool {
+        self.seen.contains(&seq)
+    }
+
+    /// Record that `seq` from `sid` was received.
+    pub fn received(&mut self, seq: u32, sid: u32) {
+        if self.seen_order.len() >= config::RELIABLE_DEDUP_WINDOW {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(seq);
+        self.seen_order.push_back(seq);
+        if self.sids.contains(&sid) {
+            return;
+        }
+        if self.sids_order.len() >= config::RELIABLE_MAX_SIDS {
+            if let Some(oldest) = self.sids_order.pop_front() {
+                self.sids.remove(&oldest);
+            }
+        }
+        self.sids.insert(sid);
+        self.sids_order.push_back(sid);
+    }
+
+    /// Whether the peer sent reliable datagrams from `sid`.
+    pub fn is_reliable_sid(&self, sid: u32) -> bool {
+        self.sids.contains(&sid)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retransmit_until_acked() {
+        let mut reliable = ReliableDatagrams::default();
+        let seq = reliable.next_seq();
+        assert_eq!(reliable.next_seq(), seq + 1);
+        reliable.sent(seq, Bytes::from_static(b"frame"));
+        assert!(reliable.due().is_empty());
+        tokio::time::advance(config::RELIABLE_RTO).await;
+        assert_eq!(reliable.due(), vec![Bytes::from_static(b"frame")]);
+        // Backs off
+        tokio::time::advance(config::RELIABLE_RTO).await;
+        assert!(reliable.due().is_empty());
+        tokio::time::advance(config::RELIABLE_RTO).await;
+        assert_eq!(reliable.due().len(), 1);
+        reliable.ack(seq);
+        assert_eq!(reliable.unacked(), 0);
+        tokio::time::advance(config::RELIABLE_RTO * 100).await;
+        assert!(reliable.due().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_after_max_tries() {
+        let mut reliable = ReliableDatagrams::default();
+        reliable.sent(0, Bytes::from_static(b"frame"));
+        let mut tries = 1;
+        while reliable.unacked() != 0 {
+            tokio::time::advance(config::RELIABLE_RTO * (1 << tries)).await;
+            tries += reliable.due().len() as u32;
+        }
+        assert_eq!(tries, config::RELIABLE_MAX_TRIES);
+    }
+
+    #[test]
+    fn test_duplicates_are_detected() {
+        let mut reliable = ReliableDatagrams::default();
+        assert!(!reliable.is_duplicate(5));
+        reliable.received(5, 1);
+        assert!(reliable.is_duplicate(5));
+        assert!(reliable.is_reliable_sid(1));
+        assert!(!reliable.is_reliable_sid(2));
+        for seq in 6..6 + config::RELIABLE_DEDUP_WINDOW as u32 {
+            reliable.received(seq, 1);
+        }
+        assert!(!reliable.is_duplicate(5));
+        assert_eq!(reliable.seen.len(), config::RELIABLE_DEDUP_WINDOW);
+    }
+
+    #[test]
+    fn test_sids_are_capped() {
+        let mut reliable = ReliableDatagrams::default();
+        for sid in 0..config::RELIABLE_MAX_SIDS as u32 {
+            reliable.received(sid, sid);
+        }
+        // Known sids do not take more room
+        reliable.received(u32::MAX, 0);
+        assert!(reliable.is_reliable_sid(0));
+        reliable.received(u32::MAX - 1, config::RELIABLE_MAX_SIDS as u32);
+        assert!(!reliable.is_reliable_sid(0));
+        assert!(reliable.is_reliable_sid(1));
+        assert!(reliable.is_reliable_sid(config::RELIABLE_MAX_SIDS as u32));
+        assert_eq!(reliable.sids.len(), config::RELIABLE_MAX_SIDS);
+        assert_eq!(reliable.sids_order.len(), config::RELIABLE_MAX_SIDS);
+    }
+}
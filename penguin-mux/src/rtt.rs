@@ -0,0 +1,122 @@
+//! Round-trip times measured with keepalive `Ping`s.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Number of recent samples the statistics are computed over
+const WINDOW: usize = 16;
+
+/// Latency statistics over the most recent keepalive round trips.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RttStats {
+    /// The latest round-trip time
+    pub last: Duration,
+    /// The shortest round-trip time in the window
+    pub min: Duration,
+    /// The mean round-trip time in the window
+    pub mean: Duration,
+    /// The longest round-trip time in the window
+    pub max: Duration,
+    /// Number of samples in the window
+    pub samples: usize,
+}
+
+impl std::fmt::Display for RttStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "last {:?}, min {:?}, mean {:?}, max {:?} over {} pings",
+            self.last, self.min, self.mean, self.max, self.samples
+        )
+    }
+}
+
+/// Timestamps `Ping` payloads and records the samples from their `Pong`s.
+#[derive(Debug)]
+pub struct RttTracker {
+    /// Reference point of the timestamps
+    epoch: Instant,
+    /// Most recent samples, oldest first
+    samples: Mutex<VecDeque<Duration>>,
+}
+
+impl RttTracker {
+    pub fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            samples: Mutex::new(VecDeque::with_capacity(WINDOW)),
+        }
+    }
+
+    /// The payload of a `Ping` sent now.
+    pub fn ping_payload(&self) -> Vec<u8> {
+        let micros = u64::try_from(self.epoch.elapsed().as_micros()).unwrap_or(u64::MAX);
+        micros.to_be_bytes().to_vec()
+    }
+
+    /// Record the round-trip time of the `Ping` a `Pong` answers.
+    /// Payloads we did not send are ignored.
+    pub fn record_pong(&self, payload: &[u8]) {
+        let Ok(sent) = <[u8; 8]>::try_from(payload) else {
+            return;
+        };
+        let sent = Duration::from_micros(u64::from_be_bytes(sent));
+        let Some(rtt) = self.epoch.elapsed().checked_sub(sent) else {
+            return;
+        };
+        let mut samples = self.samples.lock();
+        if samples.len() == WINDOW {
+            samples.pop_front();
+        }
+        samples.push_back(rtt);
+    }
+
+    /// Statistics over the recent samples, or `None` if there are none.
+    pub fn stats(&self) -> Option<RttStats> {
+        let samples = self.samples.lock();
+        let last = *samples.back()?;
+        let total: Duration = samples.iter().sum();
+        Some(RttStats {
+            last,
+            min: *samples.iter().min()?,
+            // `as`: `WINDOW` fits in `u32`
+            mean: total / samples.len() as u32,
+            max: *samples.iter().max()?,
+            samples: samples.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rtt_tracker() {
+        let tracker = RttTracker::new();
+        assert!(tracker.stats().is_none());
+        let payload = tracker.ping_payload();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        tracker.record_pong(&payload);
+        // Not one of ours
+        tracker.record_pong(b"hello");
+        tracker.record_pong(&[]);
+        let stats = tracker.stats().unwrap();
+        assert_eq!(stats.samples, 1);
+        assert!(stats.last >= Duration::from_millis(20));
+        assert_eq!(stats.min, stats.last);
+        assert_eq!(stats.max, stats.last);
+        for _ in 0..WINDOW {
+            tracker.record_pong(&tracker.ping_payload());
+        }
+        let stats = tracker.stats().unwrap();
+        // The slow sample has left the window
+        assert_eq!(stats.samples, WINDOW);
+        assert!(stats.max < Duration::from_millis(20));
+        assert!(stats.min <= stats.mean && stats.mean <= stats.max);
+    }
+}
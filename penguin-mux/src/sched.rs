@@ -0,0 +1,324 @@
+//! Round-robin scheduling of stream frames onto the transport.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::config;
+use crate::locked_sink::LockedWebSocket;
+use crate::transport::{Message, Transport};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::task::{ready, Context, Poll, Waker};
+use tokio::sync::Notify;
+use tracing::trace;
+
+/// Frames queued by one stream
+#[derive(Debug, Default)]
+struct Slot {
+    frames: VecDeque<Message>,
+    /// Task waiting for room in `frames` or for them to be sent
+    waker: Option<Waker>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Streams (by our port) with queued frames. A slot only exists while
+    /// it has frames queued or one of them is being sent.
+    slots: HashMap<u32, Slot>,
+    /// Ports with queued frames, in the order they take turns
+    turns: VecDeque<u32>,
+    /// Port whose frame is being fed to the transport
+    in_flight: Option<u32>,
+    /// Size of all queued frames
+    bytes: usize,
+    /// Tasks waiting for `bytes` to drop below `config::QUEUED_BYTES`
+    blocked: Vec<Waker>,
+    /// Whether the mux task has exited
+    closed: bool,
+}
+
+/// Interleaves the frames of streams writing at the same time, so that one
+/// busy stream cannot hold up the others. Each stream with queued frames
+/// gets one frame sent per turn.
+///
+/// Frames only leave the queue as fast as the transport accepts them, so
+/// writers are held up once too much is queued. This keeps the memory use
+/// bounded on slow links.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    state: Mutex<State>,
+    /// Wakes `run` when a frame is queued
+    queued: Notify,
+}
+
+impl Scheduler {
+    /// Queue the resulting `Message` from a computation for stream `port`.
+    /// The computation is only executed if the stream has room for another
+    /// frame, and it may return `Poll::Pending` like in
+    /// [`LockedWebSocket::poll_feed_with`].
+    pub fn poll_push(
+        &self,
+        cx: &mut Context<'_>,
+        port: u32,
+        msg_fn: impl FnOnce(&mut Context<'_>) -> Poll<Message>,
+    ) -> Poll<io::Result<()>> {
+        let mut guard = self.state.lock();
+        let state = &mut *guard;
+        if state.closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if let Some(slot) = state.slots.get_mut(&port) {
+            if slot.frames.len() >= config::STREAM_QUEUED_FRAMES {
+                slot.waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+            // A stream's first frame is always taken, so that busy streams
+            // cannot starve the others of room
+            if state.bytes >= config::QUEUED_BYTES {
+                trace!("{} bytes queued, waiting for the transport", state.bytes);
+                state.blocked.push(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+        let msg = ready!(msg_fn(cx));
+        state.push(port, msg);
+        drop(guard);
+        self.queued.notify_one();
+        Poll::Ready(Ok(()))
+    }
+
+    /// Queue the last frame of stream `port` regardless of room, so that it is
+    /// sent after the frames already queued.
+    pub fn push_last(&self, port: u32, msg: Message) {
+        let mut state = self.state.lock();
+        if state.closed {
+            return;
+        }
+        state.push(port, msg);
+        drop(state);
+        self.queued.notify_one();
+    }
+
+    /// Wait until all frames queued for stream `port` have been fed to the
+    /// transport.
+    pub fn poll_sent(&self, cx: &mut Context<'_>, port: u32) -> Poll<()> {
+        let mut state = self.state.lock();
+        match state.slots.get_mut(&port) {
+            Some(slot) => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            None => Poll::Ready(()),
+        }
+    }
+
+    /// Drop the frames queued for stream `port`, e.g. after the peer reset it.
+    pub fn discard(&self, port: u32) {
+        let mut state = self.state.lock();
+        if let Some(slot) = state.slots.remove(&port) {
+            trace!("discarding {} frames of port {port}", slot.frames.len());
+            state.turns.retain(|p| *p != port);
+            state.bytes -= slot.frames.iter().map(frame_len).sum::<usize>();
+            state.wake_blocked();
+            if let Some(waker) = slot.waker {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Stop accepting frames, feed the ones already queued to `ws`, and wake
+    /// all waiting streams. Called when the mux task exits.
+    pub async fn close<S: Transport>(&self, ws: &LockedWebSocket<S>) -> io::Result<()> {
+        self.state.lock().closed = true;
+        let result = self.feed_queued(ws).await;
+        let mut state = self.state.lock();
+        state.turns.clear();
+        state.bytes = 0;
+        state.wake_blocked();
+        for (_, slot) in state.slots.drain() {
+            if let Some(waker) = slot.waker {
+                waker.wake();
+            }
+        }
+        result.map(|_| ())
+    }
+
+    /// Take the next frame in turn.
+    fn pop(&self) -> Option<Message> {
+        let mut state = self.state.lock();
+        let port = state.turns.pop_front()?;
+        let slot = state
+            .slots
+            .get_mut(&port)
+            .expect("port with a turn has no slot (this is a bug)");
+        let msg = slot
+            .frames
+            .pop_front()
+            .expect("port with a turn has no frames (this is a bug)");
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+        if !slot.frames.is_empty() {
+            state.turns.push_back(port);
+        }
+        state.in_flight = Some(port);
+        state.bytes -= frame_len(&msg);
+        if state.bytes < config::QUEUED_BYTES {
+            state.wake_blocked();
+        }
+        Some(msg)
+    }
+
+    /// Mark the frame taken by `pop` as fed.
+    fn done(&self) {
+        let mut state = self.state.lock();
+        let Some(port) = state.in_flight.take() else {
+            return;
+        };
+        if state
+            .slots
+            .get(&port)
+            .is_some_and(|slot| slot.frames.is_empty())
+        {
+            let slot = state
+                .slots
+                .remove(&port)
+                .expect("slot just seen (this is a bug)");
+            if let Some(waker) = slot.waker {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Feed frames to `ws` until none is queued. Returns whether any was fed.
+    async fn feed_queued<S: Transport>(&self, ws: &LockedWebSocket<S>) -> io::Result<bool> {
+        let mut fed = false;
+        while let Some(msg) = self.pop() {
+            let result = ws.feed_all(std::iter::once(msg)).await;
+            self.done();
+            result?;
+            fed = true;
+        }
+        Ok(fed)
+    }
+
+    /// Scheduler subtask: feed the queued frames to `ws`, and flush once
+    /// there are none left.
+    pub async fn run<S: Transport>(&self, ws: &LockedWebSocket<S>) -> io::Result<()> {
+        loop {
+            if self.feed_queued(ws).await? {
+                ws.flush().await?;
+            }
+            self.queued.notified().await;
+        }
+    }
+
+    /// Size of all frames waiting for the transport
+    pub fn queued_bytes(&self) -> usize {
+        self.state.lock().bytes
+    }
+}
+
+/// Size of `msg` on the transport, not counting framing
+fn frame_len(msg: &Message) -> usize {
+    match msg {
+        Message::Frame(data) | Message::Ping(data) | Message::Pong(data) => data.len(),
+        Message::Close => 0,
+    }
+}
+
+impl State {
+    fn wake_blocked(&mut self) {
+        for waker in self.blocked.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn push(&mut self, port: u32, msg: Message) {
+        self.bytes += frame_len(&msg);
+        let slot = self.slots.entry(port).or_default();
+        slot.frames.push_back(msg);
+        // Ports already in `turns` keep their place
+        if slot.frames.len() == 1 {
+            self.turns.push_back(port);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    use futures_util::task::noop_waker_ref;
+
+    fn push(sched: &Scheduler, port: u32, data: &'static [u8]) -> Poll<io::Result<()>> {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        sched.poll_push(&mut cx, port, |_| {
+            Poll::Ready(Message::Frame(Bytes::from_static(data)))
+        })
+    }
+
+    fn pop(sched: &Scheduler) -> Option<Message> {
+        let msg = sched.pop();
+        sched.done();
+        msg
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let sched = Scheduler::default();
+        for _ in 0..config::STREAM_QUEUED_FRAMES {
+            assert!(matches!(push(&sched, 1, b"a"), Poll::Ready(Ok(()))));
+        }
+        // The slot of port 1 is full
+        assert!(push(&sched, 1, b"a").is_pending());
+        assert!(matches!(push(&sched, 2, b"b"), Poll::Ready(Ok(()))));
+        assert!(matches!(push(&sched, 2, b"b"), Poll::Ready(Ok(()))));
+        sched.push_last(3, Message::Close);
+        let mut order = Vec::new();
+        while let Some(msg) = pop(&sched) {
+            order.push(msg);
+        }
+        let a = Message::Frame(Bytes::from_static(b"a"));
+        let b = Message::Frame(Bytes::from_static(b"b"));
+        assert_eq!(order[..5], [a.clone(), b.clone(), Message::Close, a, b]);
+        assert_eq!(order.len(), config::STREAM_QUEUED_FRAMES + 3);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(sched.poll_sent(&mut cx, 1).is_ready());
+    }
+
+    #[test]
+    fn test_bytes_bounded() {
+        static BIG: [u8; config::QUEUED_BYTES] = [0; config::QUEUED_BYTES];
+        let sched = Scheduler::default();
+        assert!(matches!(push(&sched, 1, &BIG), Poll::Ready(Ok(()))));
+        // No room left
+        assert!(push(&sched, 1, b"a").is_pending());
+        // But other streams can still queue one frame
+        assert!(matches!(push(&sched, 2, b"b"), Poll::Ready(Ok(()))));
+        assert!(push(&sched, 2, b"b").is_pending());
+        assert_eq!(sched.queued_bytes(), config::QUEUED_BYTES + 1);
+        assert!(pop(&sched).is_some());
+        assert_eq!(sched.queued_bytes(), 1);
+        assert!(matches!(push(&sched, 2, b"b"), Poll::Ready(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn test_discard_and_close() {
+        let (ours, theirs) = crate::ws::mock::get_mock_pair();
+        let ws = LockedWebSocket::new(ours);
+        let theirs = LockedWebSocket::new(theirs);
+        let sched = Scheduler::default();
+        assert!(matches!(push(&sched, 1, b"a"), Poll::Ready(Ok(()))));
+        assert!(matches!(push(&sched, 2, b"b"), Poll::Ready(Ok(()))));
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(sched.poll_sent(&mut cx, 1).is_pending());
+        sched.discard(1);
+        assert!(sched.poll_sent(&mut cx, 1).is_ready());
+        // Frames still queued are sent when closing
+        sched.close(&ws).await.unwrap();
+        let msg = theirs.next().await.unwrap().unwrap();
+        assert_eq!(msg, Message::Frame(Bytes::from_static(b"b")));
+        assert!(matches!(push(&sched, 1, b"a"), Poll::Ready(Err(_))));
+    }
+}
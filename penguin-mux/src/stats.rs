@@ -0,0 +1,112 @@
+//! Traffic counters of the streams.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Traffic of a stream, shared by its `MuxStream` and the multiplexor.
+#[derive(Debug, Default)]
+pub struct StreamCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+}
+
+impl StreamCounters {
+    /// Count a frame of `bytes` bytes written to the stream.
+    pub fn sent(&self, bytes: usize) {
+        // Atomic ordering: we are only counting
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a frame received for the stream.
+    pub fn received_frame(&self) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count bytes read from the stream.
+    pub fn received(&self, bytes: usize) {
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The counters of the stream on our port `id`.
+    pub fn snapshot(
+        &self,
+        id: u32,
+        dest_host: Bytes,
+        dest_port: u16,
+        frames_queued: usize,
+    ) -> StreamStats {
+        StreamStats {
+            id,
+            dest_host,
+            dest_port,
+            frames_queued,
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Traffic of a stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamStats {
+    /// See [`MuxStream::id`](crate::MuxStream::id)
+    pub id: u32,
+    /// Forwarding destination
+    pub dest_host: Bytes,
+    /// Forwarding destination port
+    pub dest_port: u16,
+    /// Bytes written to the stream
+    pub bytes_sent: u64,
+    /// Bytes read from the stream
+    pub bytes_received: u64,
+    /// `Psh` frames sent
+    pub frames_sent: u64,
+    /// `Psh` frames received, including those not read yet
+    pub frames_received: u64,
+    /// Received frames waiting to be read
+    pub frames_queued: usize,
+}
+
+impl std::fmt::Display for StreamStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stream {} to {}:{}: {} bytes in {} frames sent, {} bytes in {} frames received, {} frames queued",
+            self.id,
+            String::from_utf8_lossy(&self.dest_host),
+            self.dest_port,
+            self.bytes_sent,
+            self.frames_sent,
+            self.bytes_received,
+            self.frames_received,
+            self.frames_queued
+        )
+    }
+}
+
+/// Traffic of the open streams of a multiplexor.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MuxStats {
+    /// Established streams, ordered by ID
+    pub streams: Vec<StreamStats>,
+    /// Received datagrams waiting for [`Multiplexor::get_datagram`](crate::Multiplexor::get_datagram)
+    pub datagrams_queued: usize,
+    /// Received datagrams dropped because too many were waiting
+    pub datagrams_dropped: u64,
+    /// Reliable datagrams sent and not acknowledged yet
+    pub datagrams_unacked: usize,
+    /// Dropped streams waiting to be closed
+    pub dropped_ports_queued: usize,
+    /// `Ack`s waiting to be sent
+    pub acks_queued: usize,
+    /// Bytes written to the streams and waiting for the transport
+    pub bytes_unsent: usize,
+}
@@ -0,0 +1,509 @@
+//! `AsyncRead + AsyncWrite` object returned by `*_new_stream_channel`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::compress::Compression;
+use super::frame::{FrameVersion, RstReason, StreamFrame, TcpOptions};
+use super::locked_sink::LockedWebSocket;
+use super::queue::UnboundedSender;
+use super::rate::TokenBucket;
+use super::sched::Scheduler;
+use super::stats::StreamCounters;
+use crate::config;
+use crate::transport::Message;
+use bytes::{Bytes, BytesMut};
+use futures_util::task::AtomicWaker;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio::time::{Instant, Sleep};
+use tracing::{debug, trace, warn};
+
+/// All parameters of a stream channel
+pub struct MuxStream<S> {
+    /// Receive stream frames
+    pub(super) frame_rx: mpsc::Receiver<Bytes>,
+    /// Our port
+    pub(super) our_port: u32,
+    /// Port of the other end
+    pub(super) their_port: u32,
+    /// Format of the frames we send
+    pub(super) frame_version: FrameVersion,
+    /// See `MultiplexorInner`.
+    pub(super) peer_max_frame: Arc<AtomicU32>,
+    /// Compression negotiated for the `Psh` frames we send
+    pub(super) compression: Option<Compression>,
+    /// Number of payloads in a row that did not compress
+    pub(super) incompressible: AtomicU32,
+    /// Forwarding destination
+    pub dest_host: Bytes,
+    /// Forwarding destination port
+    pub dest_port: u16,
+    /// TCP options the client asked for the connection to the destination.
+    /// Always none on the client.
+    pub tcp_options: TcpOptions,
+    /// Trace context the client sent with the stream, e.g. a W3C
+    /// `traceparent`. Always empty on the client.
+    pub trace_context: Bytes,
+    /// Whether writes should succeed.
+    pub(super) can_write: Arc<AtomicBool>,
+    /// Number of frames we can still send before we need to wait for an `Ack`
+    pub(super) psh_send_remaining: Arc<AtomicU64>,
+    /// Number of `Psh` frames received after sending the previous `Ack` frame
+    /// `config::RWND - psh_recvd_since` is approximately the peer's `psh_send_remaining`
+    pub(super) psh_recvd_since: AtomicU64,
+    /// Channel to send `Ack` frames to the mux task (our port, their port, psh_recvd_since)
+    pub(super) ack_tx: UnboundedSender<(u32, u32, u64)>,
+    /// Waker to wake up the task that sends frames
+    pub(super) writer_waker: Arc<AtomicWaker>,
+    /// Remaining bytes to be read
+    pub(super) buf: Bytes,
+    /// See `MultiplexorInner`.
+    pub(super) ws: LockedWebSocket<S>,
+    /// See `MultiplexorInner`.
+    pub(super) sched: Arc<Scheduler>,
+    /// See `MultiplexorInner`.
+    pub(super) dropped_ports_tx: UnboundedSender<(u32, u32, RstReason)>,
+    /// Rate limits on reads
+    pub(super) read_limits: Vec<Arc<TokenBucket>>,
+    /// Rate limits on writes
+    pub(super) write_limits: Vec<Arc<TokenBucket>>,
+    /// Wait for the read limits before the next read
+    pub(super) read_delay: Option<Pin<Box<Sleep>>>,
+    /// Wait for the write limits before the next write
+    pub(super) write_delay: Option<Pin<Box<Sleep>>>,
+    /// When the stream last sent or received data
+    pub(super) activity: Arc<Activity>,
+    /// Bytes sent and received
+    pub(super) counters: Arc<StreamCounters>,
+    /// See `MuxStreamData`.
+    pub(super) peer_rst_reason: Arc<AtomicU8>,
+    /// Reason of the `Rst` sent if the stream is dropped without `Fin`
+    pub(super) rst_reason: RstReason,
+    /// `SynAck` not sent yet because of
+    /// [`Options::defer_syn_ack`](crate::Options::defer_syn_ack)
+    pub(super) syn_ack: Option<Message>,
+    /// See [`Options::cork`](crate::Options::cork)
+    pub(super) cork: Option<Duration>,
+    /// Written data not sent yet because of `cork`
+    pub(super) corked: BytesMut,
+    /// Wait for more writes before sending `corked`
+    pub(super) cork_delay: Option<Pin<Box<Sleep>>>,
+}
+
+/// When a stream (or the whole multiplexor) last sent or received data.
+#[derive(Debug)]
+pub(super) struct Activity {
+    created: Instant,
+    /// Milliseconds between `created` and the last activity
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that data was sent or received now.
+    pub fn touch(&self) {
+        let now = u64::try_from(self.created.elapsed().as_millis()).unwrap_or(u64::MAX);
+        // Atomic ordering: only the latest value matters
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    /// How long it has been idle.
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last)
+    }
+}
+
+impl<S> std::fmt::Debug for MuxStream<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxStream")
+            .field("our_port", &self.our_port)
+            .field("their_port", &self.their_port)
+            .field("dest_host", &self.dest_host)
+            .field("dest_port", &self.dest_port)
+            .field("tcp_options", &self.tcp_options)
+            .field("trace_context", &self.trace_context)
+            .field("can_write", &self.can_write)
+            .field("psh_send_remaining", &self.psh_send_remaining)
+            .field("psh_recvd_since", &self.psh_recvd_since)
+            .field("compression", &self.compression)
+            .field("buf.len", &self.buf.len())
+            .field("corked.len", &self.corked.len())
+            .field("read_limits", &self.read_limits)
+            .field("write_limits", &self.write_limits)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> MuxStream<S> {
+    /// Our port of this stream, unique among the open streams of the
+    /// multiplexor.
+    #[must_use]
+    pub const fn id(&self) -> u32 {
+        self.our_port
+    }
+
+    /// Answer the `Syn` of this stream with `SynAck`, if
+    /// [`Options::defer_syn_ack`](crate::Options::defer_syn_ack) held it
+    /// back. Reading, writing, or shutting down the stream also does this.
+    pub fn accept(&mut self) {
+        if let Some(syn_ack) = self.syn_ack.take() {
+            trace!("sending deferred `SynAck`");
+            // Goes before any frame the stream sends
+            self.sched.push_last(self.our_port, syn_ack);
+        }
+    }
+
+    /// Reset the stream, telling the peer why. Used by servers that could
+    /// not connect to the destination.
+    pub fn reset(mut self, reason: RstReason) {
+        self.rst_reason = reason;
+        // `drop` sends the `Rst`
+    }
+
+    /// Limit the rate of reads from this stream with `bucket`, which may be
+    /// shared with other streams. Multiple limits can be added.
+    pub fn limit_read(&mut self, bucket: Arc<TokenBucket>) {
+        self.read_limits.push(bucket);
+    }
+
+    /// Limit the rate of writes to this stream with `bucket`, which may be
+    /// shared with other streams. Multiple limits can be added.
+    pub fn limit_write(&mut self, bucket: Arc<TokenBucket>) {
+        self.write_limits.push(bucket);
+    }
+}
+
+/// Wait until none of `limits` is in debt.
+#[inline]
+fn poll_limits(
+    limits: &[Arc<TokenBucket>],
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    loop {
+        if let Some(sleep) = delay {
+            ready!(sleep.as_mut().poll(cx));
+            *delay = None;
+        }
+        let wait = limits
+            .iter()
+            .map(|bucket| bucket.delay())
+            .max()
+            .unwrap_or_default();
+        if wait.is_zero() {
+            return Poll::Ready(());
+        }
+        trace!("rate limited for {wait:?}");
+        *delay = Some(Box::pin(tokio::time::sleep(wait)));
+    }
+}
+
+impl<S> Drop for MuxStream<S> {
+    // Dropping the port should act like `close()` has been called.
+    // Since `drop` is not async, this is handled by the mux task.
+    /// Close the stream by instructing the mux task to send a `Rst` frame if
+    /// the stream is still open. The associated port will be freed for reuse.
+    fn drop(&mut self) {
+        // Notify the task that this port is no longer in use
+        self.dropped_ports_tx
+            .send((self.our_port, self.their_port, self.rst_reason))
+            // Maybe the task has already exited, who knows
+            .ok();
+    }
+}
+
+impl<S> AsyncRead for MuxStream<S> {
+    /// Read data from the stream.
+    /// There are two cases where this function gives EOF:
+    /// 1. One `Message` contains an empty payload.
+    /// 2. `Sink`'s sender is dropped.
+    ///
+    /// If the peer reset the stream with a [`RstReason`], that is given as an
+    /// error of the matching kind instead.
+    #[tracing::instrument(skip(cx, buf), level = "trace")]
+    #[inline]
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        this.accept();
+        ready!(poll_limits(&this.read_limits, &mut this.read_delay, cx));
+        let remaining = buf.remaining();
+        let filled = buf.filled().len();
+        if self.buf.is_empty() {
+            trace!("polling the stream");
+            let next = ready!(self.frame_rx.poll_recv(cx));
+            if next.is_none() || next.as_ref().unwrap().is_empty() {
+                // See `tokio::sync::mpsc`#clean-shutdown
+                self.frame_rx.close();
+                let reason = RstReason::from(self.peer_rst_reason.load(Ordering::Relaxed));
+                if reason != RstReason::Unspecified {
+                    return Poll::Ready(Err(io::Error::new(
+                        reason.into(),
+                        format!("stream reset by peer: {reason}"),
+                    )));
+                }
+                // The stream has been closed, just return 0 bytes read
+                return Poll::Ready(Ok(()));
+            }
+            self.buf = next.unwrap();
+            // Atomic ordering: as long as we atomically increment the counter,
+            // the counter is correct. If another reader reads the new value here,
+            // before we reset the counter, both of us will send an `Ack` frame.
+            // However, one of us will send an `Ack` frame with a value of 0,
+            // which is harmless.
+            let new = self.psh_recvd_since.fetch_add(1, Ordering::Relaxed) + 1;
+            if new >= config::RWND_THRESHOLD {
+                // Reset the counter
+                // Atomic ordering: as long as we use the atomically-fetched value
+                // to send an `Ack` frame, the net amount
+                // `Psh` frames received - `Ack`ed amount is correct.
+                let amount_to_ack = self.psh_recvd_since.swap(0, Ordering::Relaxed);
+                // Send an `Ack` frame
+                debug!("queueing `Ack` of {amount_to_ack} frames");
+                self.ack_tx
+                    .send((self.our_port, self.their_port, amount_to_ack))
+                    .ok();
+                // If the previous line fails, the task has exited.
+                // In this case, we don't care about the `Ack` frame and the
+                // user will discover the error when they try to write or read
+                // to EOF.
+            }
+        } else {
+            // There is some data left in `self.buf`.
+            trace!("using the remaining buffer");
+        }
+        if remaining < self.buf.len() {
+            // The buffer is too small. Fill it and advance `self.buf`
+            let to_write = self.buf.split_to(remaining);
+            buf.put_slice(&to_write);
+        } else {
+            // The buffer is large enough. Copy the frame into it
+            buf.put_slice(&self.buf);
+            self.buf.clear();
+        }
+        let read = buf.filled().len() - filled;
+        self.counters.received(read);
+        for bucket in &self.read_limits {
+            bucket.consume(read);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> MuxStream<S>
+where
+    S: crate::transport::Transport,
+{
+    /// Queue `data` for the transport in a `Psh` frame once the peer's
+    /// receive window allows it.
+    fn poll_queue_psh(&self, cx: &mut Context<'_>, data: &[u8]) -> Poll<io::Result<()>> {
+        // `ready`: if we return here, nothing is queued
+        ready!(self.sched.poll_push(cx, self.our_port, |cx| {
+            loop {
+                // Atomic ordering: we don't really have a critical section here,
+                // so `Relaxed` should be enough.
+                let original = self.psh_send_remaining.load(Ordering::Acquire);
+                trace!("congestion window: {}", original);
+                if original == 0 {
+                    // We have reached the congestion window limit. Wait for an `Ack`
+                    debug!("waiting for `Ack`");
+                    self.writer_waker.register(cx.waker());
+                    return Poll::Pending;
+                }
+                let new = original - 1;
+                // Atomic ordering: see the comment above
+                if self
+                    .psh_send_remaining
+                    .compare_exchange_weak(original, new, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    // We have successfully decremented the congestion window
+                    break;
+                }
+                trace!("congestion window race condition, retrying");
+            }
+            Poll::Ready(Message::Frame(self.encode_psh(data)))
+        }))?;
+        trace!("queued a frame");
+        self.activity.touch();
+        self.counters.sent(data.len());
+        Poll::Ready(Ok(()))
+    }
+
+    /// Encode a `Psh` frame carrying `data`, compressed if the stream
+    /// negotiated it and that makes it smaller.
+    fn encode_psh(&self, data: &[u8]) -> Bytes {
+        let compressed = self
+            .compression
+            .filter(|_| {
+                (config::COMPRESS_MIN_BYTES..=config::MAX_DECOMPRESSED_SIZE).contains(&data.len())
+                    && self.incompressible.load(Ordering::Relaxed) < config::COMPRESS_GIVE_UP
+            })
+            .and_then(|algo| {
+                let compressed = algo.compress(data);
+                if compressed.is_some() {
+                    self.incompressible.store(0, Ordering::Relaxed);
+                } else if self.incompressible.fetch_add(1, Ordering::Relaxed) + 1
+                    == config::COMPRESS_GIVE_UP
+                {
+                    debug!("stream {} stops compressing", self.our_port);
+                }
+                Some((algo, compressed?))
+            });
+        match compressed {
+            Some((algo, compressed)) => StreamFrame {
+                compression: algo.bit(),
+                ..StreamFrame::new_psh(self.our_port, self.their_port, compressed.into())
+            }
+            .encode(self.frame_version),
+            None => {
+                StreamFrame::encode_psh(self.frame_version, self.our_port, self.their_port, data)
+            }
+        }
+        .expect("port does not fit in the frame version (this is a bug)")
+    }
+
+    /// Most data a `Psh` frame can carry to the peer
+    fn max_payload(&self) -> usize {
+        let peer_max_frame = self.peer_max_frame.load(Ordering::Relaxed) as usize;
+        peer_max_frame.saturating_sub(StreamFrame::header_len(self.frame_version))
+    }
+
+    /// Send the data held back by corking in one frame.
+    fn poll_queue_corked(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.corked.is_empty() {
+            ready!(self.poll_queue_psh(cx, &self.corked))?;
+            self.corked.clear();
+        }
+        self.cork_delay = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for MuxStream<S>
+where
+    S: crate::transport::Transport,
+{
+    /// Write data to the stream. Unless [`Options::cork`](crate::Options::cork)
+    /// is set, each invocation of this method will send a separate frame in
+    /// a new [`Message`](crate::transport::Message), so it may be beneficial
+    /// to wrap it in a [`BufWriter`](tokio::io::BufWriter) where appropriate.
+    /// The data may stay buffered until
+    /// [`poll_flush`](AsyncWrite::poll_flush) is called.
+    #[tracing::instrument(skip(cx, buf), level = "trace")]
+    #[inline]
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.accept();
+        // Atomic ordering: if the operations around this line are reordered,
+        // the sent frame will be `Rst`ed by the remote peer, which is harmless.
+        // Both `close_port` and `shutdown` in `inner.rs` set this flag with
+        // `Relaxed` ordering because they are not releasing any access, but
+        // instead acting based on the WebSocket or the stream's states.
+        if !self.can_write.load(Ordering::Relaxed) {
+            // The stream has been closed. Return an error
+            debug!("stream has been closed, returning `BrokenPipe`");
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let this = &mut *self;
+        ready!(poll_limits(&this.write_limits, &mut this.write_delay, cx));
+        // Larger writes are sent in part, so that the frame fits the peer's
+        // limit
+        let max_payload = self.max_payload();
+        let buf = &buf[..buf.len().min(max_payload)];
+        // Frames are queued and interleaved with those of other streams by the
+        // mux task, which flushes the transport once it runs out of frames.
+        if self.cork.is_some() {
+            if self.corked.len() + buf.len() > config::CORK_BYTES.min(max_payload) {
+                ready!(self.poll_queue_corked(cx))?;
+            }
+            if buf.len() < config::CORK_BYTES {
+                // Sent by `poll_flush` after the cork delay, or by a later
+                // write that does not fit
+                self.corked.extend_from_slice(buf);
+                self.activity.touch();
+            } else {
+                ready!(self.poll_queue_psh(cx, buf))?;
+            }
+        } else {
+            ready!(self.poll_queue_psh(cx, buf))?;
+        }
+        for bucket in &self.write_limits {
+            bucket.consume(buf.len());
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Flush the stream. With [`Options::cork`](crate::Options::cork), data
+    /// written since the last frame is held back for up to the cork delay, so
+    /// that more writes can join it.
+    #[tracing::instrument(skip(cx), level = "trace")]
+    #[inline]
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(cork) = self.cork.filter(|_| !self.corked.is_empty()) {
+            let delay = self
+                .cork_delay
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(cork)));
+            ready!(delay.as_mut().poll(cx));
+            ready!(self.poll_queue_corked(cx))?;
+        }
+        ready!(self.sched.poll_sent(cx, self.our_port));
+        ready!(self.ws.poll_flush(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Close the write end of the stream (`shutdown(SHUT_WR)`).
+    /// This function will send a [`Fin`](crate::frame::StreamFlag::Fin) frame
+    /// to the remote peer.
+    #[tracing::instrument(skip(cx), level = "trace")]
+    #[inline]
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.accept();
+        // There is no need to send a `Fin` frame if the mux task has already removed the stream
+        // because either:
+        // 1. `MuxStream` was dropped before `poll_shutdown` is completed and the mux task should
+        //    have already sent a `Rst` frame.
+        // 2. The entire mux task has been dropped, so we will only get `BrokenPipe` error.
+        // Atomic ordering: see `inner.rs` -> `shutdown` and `close_port`.
+        // As a summary, duplicate `Fin`/`Rst` frames are harmless.
+        if self.can_write.load(Ordering::Relaxed) {
+            // Can write means that things above have not happened, so we should send a `Fin` frame.
+            // `ready`: nothing happens if return here
+            ready!(self.poll_queue_corked(cx))?;
+            ready!(self.sched.poll_push(cx, self.our_port, |_cx| {
+                Poll::Ready(
+                    StreamFrame::new_fin(self.our_port, self.their_port)
+                        .into_message(self.frame_version),
+                )
+            }))?;
+            // Atomic ordering: see `inner.rs` -> `shutdown` and `close_port`.
+            self.can_write.store(false, Ordering::Relaxed);
+        }
+        // `ready`: if poll resumes, `self.can_write` indicates where to continue
+        ready!(self.sched.poll_sent(cx, self.our_port));
+        // We don't want to `close()` the sink here!!!
+        // This line is allowed to fail, because the sink might have been closed altogether
+        ready!(self.ws.poll_flush(cx)).ok();
+        Poll::Ready(Ok(()))
+    }
+}
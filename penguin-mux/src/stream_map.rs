@@ -0,0 +1,226 @@
+//! The table of open streams, sharded by port so that frames for different
+//! streams seldom wait for the same lock.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::config;
+use crate::inner::MuxStreamSlot;
+use crate::IntKey;
+use parking_lot::Mutex;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::RwLock;
+use tokio::time::Instant;
+use tracing::trace;
+
+/// Streams whose port falls into one shard: our_port -> `MuxStreamSlot`
+pub type Shard<S> = HashMap<u32, MuxStreamSlot<S>>;
+
+/// Open stream channels, split into `config::STREAM_MAP_SHARDS` maps
+pub struct StreamMap<S> {
+    shards: Box<[RwLock<Shard<S>>]>,
+    ports: Mutex<Ports>,
+}
+
+/// Hands out ports for new streams. A closed stream's port is only reused
+/// after `config::PORT_QUARANTINE`, so that late frames of the old stream
+/// are not taken for frames of the new one.
+#[derive(Debug)]
+struct Ports {
+    /// Ports from here on have never been handed out
+    next_fresh: u64,
+    /// Largest port the frame version can carry
+    max: u32,
+    /// Released ports that can be reused
+    free: VecDeque<u32>,
+    /// Released ports with the time they can be reused, oldest first
+    quarantine: VecDeque<(Instant, u32)>,
+}
+
+impl Ports {
+    fn new(max: u32) -> Self {
+        Self {
+            next_fresh: u64::from(<u32 as IntKey>::MIN),
+            max,
+            free: VecDeque::new(),
+            quarantine: VecDeque::new(),
+        }
+    }
+
+    /// Take a port that was never used, or that was released long enough
+    /// ago. When there is none, the port released first is reused early.
+    fn allocate(&mut self) -> Option<u32> {
+        let now = Instant::now();
+        while let Some(&(until, port)) = self.quarantine.front() {
+            if until > now {
+                break;
+            }
+            self.quarantine.pop_front();
+            self.free.push_back(port);
+        }
+        if let Some(port) = u32::try_from(self.next_fresh)
+            .ok()
+            .filter(|port| *port <= self.max)
+        {
+            self.next_fresh += 1;
+            return Some(port);
+        }
+        self.free.pop_front().or_else(|| {
+            let (_, port) = self.quarantine.pop_front()?;
+            trace!("reusing port {port} before its quarantine ends");
+            Some(port)
+        })
+    }
+
+    /// Return a port after its stream is removed.
+    fn release(&mut self, port: u32) {
+        self.quarantine
+            .push_back((Instant::now() + config::PORT_QUARANTINE, port));
+    }
+}
+
+impl<S> StreamMap<S> {
+    /// Create an empty map handing out ports up to `max_port`.
+    pub fn new(max_port: u32) -> Self {
+        Self {
+            shards: (0..config::STREAM_MAP_SHARDS)
+                .map(|_| RwLock::new(HashMap::new()))
+                .collect(),
+            ports: Mutex::new(Ports::new(max_port)),
+        }
+    }
+
+    /// The shard holding `port`
+    #[inline]
+    pub fn shard(&self, port: u32) -> &RwLock<Shard<S>> {
+        &self.shards[port as usize % self.shards.len()]
+    }
+
+    /// Number of streams, including the ones not established yet
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+        for shard in &*self.shards {
+            len += shard.read().await.len();
+        }
+        len
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        for shard in &*self.shards {
+            if !shard.read().await.is_empty() {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Insert `slot` at a port not in use and return the port, or `None` if
+    /// all ports are in use.
+    pub async fn insert_available(&self, slot: MuxStreamSlot<S>) -> Option<u32> {
+        loop {
+            let port = self.ports.lock().allocate()?;
+            if let Entry::Vacant(entry) = self.shard(port).write().await.entry(port) {
+                entry.insert(slot);
+                break Some(port);
+            }
+            // Taken by a stream the peer chose the port for. The port comes
+            // back when that stream is removed.
+        }
+    }
+
+    /// Remove the stream at `port` and quarantine the port.
+    pub async fn remove(&self, port: u32) -> Option<MuxStreamSlot<S>> {
+        let slot = self.shard(port).write().await.remove(&port);
+        if slot.is_some() {
+            self.release(port);
+        }
+        slot
+    }
+
+    /// Quarantine `port` after its stream was removed from its shard.
+    pub fn release(&self, port: u32) {
+        self.ports.lock().release(port);
+    }
+
+    /// Collect the results of `f` on the streams, one shard at a time.
+    pub async fn filter_map<T>(
+        &self,
+        mut f: impl FnMut(u32, &MuxStreamSlot<S>) -> Option<T>,
+    ) -> Vec<T> {
+        let mut result = Vec::new();
+        for shard in &*self.shards {
+            let shard = shard.read().await;
+            result.extend(shard.iter().filter_map(|(port, slot)| f(*port, slot)));
+        }
+        result
+    }
+
+    /// Remove all streams.
+    pub async fn drain(&self) -> Vec<(u32, MuxStreamSlot<S>)> {
+        let mut result = Vec::new();
+        for shard in &*self.shards {
+            result.extend(shard.write().await.drain());
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ws::mock::MockTransport;
+    use bytes::Bytes;
+    use tokio::sync::oneshot;
+
+    fn requested() -> MuxStreamSlot<MockTransport> {
+        MuxStreamSlot::Requested {
+            sender: oneshot::channel().0,
+            dest_host: Bytes::new(),
+            dest_port: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shards() {
+        let map = StreamMap::new(u32::from(u16::MAX));
+        assert!(map.is_empty().await);
+        let mut ports = Vec::new();
+        for _ in 0..100 {
+            ports.push(map.insert_available(requested()).await.unwrap());
+        }
+        ports.sort_unstable();
+        ports.dedup();
+        assert_eq!(ports.len(), 100);
+        assert!(!ports.contains(&0));
+        assert_eq!(map.len().await, 100);
+        let port = ports[0];
+        assert!(map.shard(port).read().await.contains_key(&port));
+        assert_eq!(map.filter_map(|port, _| Some(port)).await.len(), 100);
+        assert!(map.remove(port).await.is_some());
+        assert!(map.remove(port).await.is_none());
+        assert_eq!(map.drain().await.len(), 99);
+        assert!(map.is_empty().await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_port_quarantine() {
+        let max = u32::from(u16::MAX);
+        let mut ports = Ports {
+            next_fresh: u64::from(max - 1),
+            ..Ports::new(max)
+        };
+        assert_eq!(ports.allocate(), Some(max - 1));
+        ports.release(max - 1);
+        // Fresh ports go first
+        assert_eq!(ports.allocate(), Some(max));
+        ports.release(max);
+        tokio::time::advance(config::PORT_QUARANTINE).await;
+        ports.release(1);
+        // Released ports in order once their quarantine is over
+        assert_eq!(ports.allocate(), Some(max - 1));
+        assert_eq!(ports.allocate(), Some(max));
+        // Out of ports: reuse the one released first
+        assert_eq!(ports.allocate(), Some(1));
+        assert_eq!(ports.allocate(), None);
+    }
+}
@@ -0,0 +1,1285 @@
+//! Module tests.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::*;
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, info};
+
+// `penguin-mux` is a separate crate, so it needs its own `test_setup_log`.
+#[ctor::ctor]
+fn test_setup_log() {
+    use tracing_subscriber::{filter, fmt, prelude::*};
+    let fmt_layer = fmt::Layer::default()
+        .compact()
+        .with_thread_ids(true)
+        .with_timer(fmt::time::time())
+        .with_writer(std::io::stderr);
+    tracing_subscriber::registry()
+        .with(filter::LevelFilter::INFO)
+        .with(fmt_layer)
+        .init();
+}
+
+#[tokio::test]
+async fn connect_succeeds() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let stream = server_mux.server_new_stream_channel().await.unwrap();
+        info!(
+            "sport = {}, dport = {}, dest = {:?}:{}",
+            stream.our_port, stream.their_port, stream.dest_host, stream.dest_port
+        );
+    });
+
+    let stream = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    info!("sport = {}, dport = {}", stream.our_port, stream.their_port);
+    debug!("Waiting for server task to finish");
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn syn_filter_rejects_stream() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let filter: SynFilter = Arc::new(|host: &[u8], port: u16| {
+        if host == b"allowed.example" && port == 443 {
+            Ok(())
+        } else {
+            Err(format!("port {port} is closed"))
+        }
+    });
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let options = Options {
+        syn_filter: Some(filter),
+        ..Options::default()
+    };
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+
+    let server_task = tokio::spawn(async move {
+        let stream = server_mux.server_new_stream_channel().await.unwrap();
+        assert_eq!(stream.dest_port, 443);
+    });
+
+    let err = client_mux
+        .client_new_stream_channel(b"allowed.example", 22)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::StreamRejected(RstReason::Unspecified, ref reason) if reason == "port 22 is closed")
+    );
+    client_mux
+        .client_new_stream_channel(b"allowed.example", 443)
+        .await
+        .unwrap();
+    server_task.await.unwrap();
+}
+
+#[cfg(feature = "fast-ws")]
+#[tokio::test]
+async fn stream_over_fast_ws() {
+    let (client, server) = crate::fast_ws::test::get_pair();
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut stream = server_mux.server_new_stream_channel().await.unwrap();
+        let mut buf = vec![0; 1 << 20];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let data: Vec<u8> = (0..1 << 20).map(|_| rand::random()).collect();
+    let mut stream = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    stream.write_all(&data).await.unwrap();
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(echoed, data);
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn max_streams_rejects_stream() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let options = Options {
+        max_streams: Some(2),
+        ..Options::default()
+    };
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+
+    let server_task = tokio::spawn(async move {
+        let _first = server_mux.server_new_stream_channel().await.unwrap();
+        let _second = server_mux.server_new_stream_channel().await.unwrap();
+        let _third = server_mux.server_new_stream_channel().await.unwrap();
+    });
+
+    let first = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    let _second = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    let err = client_mux
+        .client_new_stream_channel(&[], 0)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::StreamRejected(RstReason::Unspecified, ref reason) if reason.starts_with("too many streams"))
+    );
+    // Closing a stream makes room for another one once the server sees the `Rst`
+    drop(first);
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let _third = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn idle_stream_is_closed() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let options = Options {
+        stream_idle_timeout: Some(std::time::Duration::from_millis(200)),
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut stream = server_mux.server_new_stream_channel().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        // The client closes the stream after it has been idle
+        assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    });
+
+    let mut stream = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    let mut buf = [0u8; 1];
+    let start = tokio::time::Instant::now();
+    assert_eq!(stream.read(&mut buf).await.unwrap(), 0);
+    assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn idle_connection_is_closed() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let options = Options {
+        idle_timeout: Some(std::time::Duration::from_millis(200)),
+        ..Options::default()
+    };
+    let mut joinset = tokio::task::JoinSet::new();
+    let client_mux = Multiplexor::with_options(client, Role::Client, options, Some(&mut joinset));
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let _stream = server_mux.server_new_stream_channel().await.unwrap();
+        // The client closes the connection after the stream is gone
+        assert!(matches!(
+            server_mux.server_new_stream_channel().await,
+            Err(Error::Closed)
+        ));
+    });
+
+    let stream = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    // An open stream keeps the connection alive
+    tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+    assert!(joinset.try_join_next().is_none());
+    drop(stream);
+    let start = tokio::time::Instant::now();
+    let result = joinset.join_next().await.unwrap().unwrap();
+    assert!(matches!(result, Err(Error::Idle)));
+    assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn dropping_mux_closes_connection() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let mut joinset = tokio::task::JoinSet::new();
+    let client_mux = Multiplexor::new(client, Role::Client, None, Some(&mut joinset));
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+    drop(server_mux);
+    // The client's task notices the `Close` and exits
+    let result = tokio::time::timeout(std::time::Duration::from_secs(1), joinset.join_next())
+        .await
+        .unwrap();
+    assert!(matches!(result, Some(Ok(Ok(())))));
+    assert!(client_mux.client_new_stream_channel(&[], 0).await.is_err());
+}
+
+#[tokio::test]
+async fn missed_pongs_close_connection() {
+    let options = Options {
+        keepalive_interval: Some(std::time::Duration::from_millis(50)),
+        max_missed_pongs: Some(2),
+        ..Options::default()
+    };
+
+    // A live peer answers every `Ping`
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let mut joinset = tokio::task::JoinSet::new();
+    let client_mux =
+        Multiplexor::with_options(client, Role::Client, options.clone(), Some(&mut joinset));
+    let _server_mux = Multiplexor::new(server, Role::Server, None, None);
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert!(joinset.try_join_next().is_none());
+    // and the `Pong`s tell us the round-trip time
+    let rtt = client_mux.rtt().unwrap();
+    assert!(rtt.samples > 1 && rtt.max < std::time::Duration::from_millis(300));
+
+    // A dead peer never reads anything, so it never answers
+    let (client, _dead) = tokio::io::duplex(4096);
+    let client = crate::ws::WebSocket::new(
+        tokio_tungstenite::WebSocketStream::from_raw_socket(
+            client,
+            tokio_tungstenite::tungstenite::protocol::Role::Client,
+            None,
+        )
+        .await,
+    );
+    let mut joinset = tokio::task::JoinSet::new();
+    let _client_mux = Multiplexor::with_options(client, Role::Client, options, Some(&mut joinset));
+    let result = joinset.join_next().await.unwrap().unwrap();
+    assert!(matches!(result, Err(Error::PongTimeout(2))));
+}
+
+#[tokio::test]
+async fn mock_transport_passes_data_and_pongs() {
+    let options = Options {
+        keepalive_interval: Some(std::time::Duration::from_millis(50)),
+        max_missed_pongs: Some(2),
+        ..Options::default()
+    };
+    // No WebSocket underneath, so the multiplexor answers `Ping`s itself
+    let (client, server) = crate::ws::mock::get_mock_pair();
+    let mut joinset = tokio::task::JoinSet::new();
+    let client_mux = Multiplexor::with_options(client, Role::Client, options, Some(&mut joinset));
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut stream = server_mux.server_new_stream_channel().await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        stream.write_all(&buf).await.unwrap();
+        stream.flush().await.unwrap();
+        server_mux
+    });
+    let mut stream = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    stream.write_all(b"hello").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut buf = [0u8; 5];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"hello");
+    let _server_mux = server_task.await.unwrap();
+
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    assert!(joinset.try_join_next().is_none());
+    assert!(client_mux.rtt().unwrap().samples > 1);
+}
+
+#[tokio::test]
+async fn datagram_channel_passes_data() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        for _ in 0..64 {
+            let dgram = server_mux.get_datagram().await.unwrap();
+            debug!("Server got datagram");
+            server_mux.send_datagram(dgram).await.unwrap();
+        }
+    });
+
+    for _ in 0..64 {
+        let payload: Bytes = (0..32768).map(|_| rand::random::<u8>()).collect();
+        debug!("Client sending datagram");
+        client_mux
+            .send_datagram(DatagramFrame {
+                host: Bytes::from_static("example.com".as_bytes()),
+                port: 53,
+                sid: 1,
+                data: payload.clone(),
+            })
+            .await
+            .unwrap();
+        debug!("Client awaiting datagram");
+        let recvd = client_mux.get_datagram().await.unwrap();
+        assert_eq!(recvd.host, "example.com".as_bytes());
+        assert_eq!(recvd.port, 53);
+        assert_eq!(recvd.sid, 1);
+        assert_eq!(recvd.data, payload);
+    }
+    debug!("Waiting for server task to finish");
+    server_task.await.unwrap();
+}
+
+/// Send 16 more datagrams than the client queues, then read them all.
+/// Returns the ports of the ones that arrived and the number dropped.
+async fn overflow_datagram_queue(overflow: DatagramOverflow) -> (Vec<u16>, u64) {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        datagram_overflow: overflow,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        for port in 0..crate::config::DATAGRAM_BUFFER_SIZE as u16 + 16 {
+            server_mux
+                .send_datagram_to(b"example.com", port, Bytes::new())
+                .await
+                .unwrap();
+        }
+    });
+    if overflow == DatagramOverflow::Backpressure {
+        // The server cannot finish until the client reads
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!server_task.is_finished());
+    } else {
+        server_task.await.unwrap();
+    }
+    // The server closes the connection once it is done
+    let mut ports = Vec::new();
+    while let Ok(datagram) = client_mux.get_datagram().await {
+        ports.push(datagram.port);
+    }
+    (ports, client_mux.stats().await.datagrams_dropped)
+}
+
+#[tokio::test]
+async fn datagram_overflow_follows_policy() {
+    let queued = crate::config::DATAGRAM_BUFFER_SIZE as u16;
+    let (ports, dropped) = overflow_datagram_queue(DatagramOverflow::DropNewest).await;
+    assert_eq!(ports, (0..queued).collect::<Vec<_>>());
+    assert_eq!(dropped, 16);
+    let (ports, dropped) = overflow_datagram_queue(DatagramOverflow::DropOldest).await;
+    assert_eq!(ports, (16..queued + 16).collect::<Vec<_>>());
+    assert_eq!(dropped, 16);
+    let (ports, dropped) = overflow_datagram_queue(DatagramOverflow::Backpressure).await;
+    assert_eq!(ports, (0..queued + 16).collect::<Vec<_>>());
+    assert_eq!(dropped, 0);
+}
+
+#[tokio::test]
+async fn datagram_stream_ends_on_close() {
+    use futures_util::StreamExt;
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut received = Vec::new();
+        let mut datagrams = std::pin::pin!(server_mux.datagrams());
+        while let Some(datagram) = datagrams.next().await {
+            assert_eq!(datagram.host, &b"telemetry"[..]);
+            assert_eq!(datagram.sid, 0);
+            received.push(datagram.data);
+        }
+        received
+    });
+
+    for i in 0..8u8 {
+        client_mux
+            .send_datagram_to(b"telemetry", 9, Bytes::copy_from_slice(&[i]))
+            .await
+            .unwrap();
+    }
+    drop(client_mux);
+    let received = server_task.await.unwrap();
+    assert_eq!(received, (0..8u8).map(|i| vec![i]).collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn connected_stream_passes_data() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
+    let len = input_bytes.len();
+    let input_bytes_clone = input_bytes.clone();
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+        let mut i = 0;
+        while i < input_bytes_clone.len() {
+            conn.write_all(&input_bytes_clone[i..i + 1024])
+                .await
+                .unwrap();
+            i += 1024;
+        }
+        info!("Done send");
+        conn.shutdown().await.unwrap();
+    });
+
+    let mut output_bytes: Vec<u8> = vec![];
+
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    while output_bytes.len() < len {
+        let mut buf = [0u8; 2048];
+        let bytes = conn.read(&mut buf).await.unwrap();
+        if bytes == 0 {
+            break;
+        }
+        output_bytes.extend(&buf[..bytes]);
+        info!("Read {} bytes", output_bytes.len());
+    }
+
+    assert_eq!(input_bytes, output_bytes);
+    debug!("Waiting for server task to finish");
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn v2_frames_pass_data() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+        assert_eq!(&conn.dest_host[..], b"example.com");
+        conn.write_all(b"hello").await.unwrap();
+        conn.shutdown().await.unwrap();
+    });
+
+    let mut conn = client_mux
+        .client_new_stream_channel(b"example.com", 80)
+        .await
+        .unwrap();
+    let mut output = Vec::new();
+    conn.read_to_end(&mut output).await.unwrap();
+    assert_eq!(output, b"hello");
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn v2_rst_carries_reason() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let filter: SynFilter = Arc::new(|_: &[u8], port: u16| {
+        if port == 22 {
+            Err("port 22 is closed".to_string())
+        } else {
+            Ok(())
+        }
+    });
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(
+        server,
+        Role::Server,
+        Options {
+            syn_filter: Some(filter),
+            ..options
+        },
+        None,
+    );
+
+    let server_task = tokio::spawn(async move {
+        let stream = server_mux.server_new_stream_channel().await.unwrap();
+        stream.reset(RstReason::Refused);
+        // Keep the mux until the client has read the `Rst`
+        server_mux
+    });
+
+    let err = client_mux
+        .client_new_stream_channel(b"example.com", 22)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::StreamRejected(RstReason::Denied, ref reason) if reason == "port 22 is closed")
+    );
+    let mut conn = client_mux
+        .client_new_stream_channel(b"example.com", 80)
+        .await
+        .unwrap();
+    let err = conn.read_to_end(&mut Vec::new()).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused);
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn defer_syn_ack_rejects_with_reason() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(
+        server,
+        Role::Server,
+        Options {
+            defer_syn_ack: true,
+            ..options
+        },
+        None,
+    );
+
+    let server_task = tokio::spawn(async move {
+        let stream = server_mux.server_new_stream_channel().await.unwrap();
+        stream.reset(RstReason::TimedOut);
+        let mut stream = server_mux.server_new_stream_channel().await.unwrap();
+        stream.accept();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        // Writing accepts too
+        let mut stream = server_mux.server_new_stream_channel().await.unwrap();
+        stream.write_all(b"world").await.unwrap();
+        stream.shutdown().await.unwrap();
+        server_mux
+    });
+
+    // Rejected without ever being established
+    let err = client_mux
+        .client_new_stream_channel(b"example.com", 80)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::StreamRejected(RstReason::TimedOut, _)));
+    for expected in [b"hello", b"world"] {
+        let mut conn = client_mux
+            .client_new_stream_channel(b"example.com", 80)
+            .await
+            .unwrap();
+        let mut output = Vec::new();
+        conn.read_to_end(&mut output).await.unwrap();
+        assert_eq!(output, expected);
+    }
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn syn_timeout_resets_late_syn_ack() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(
+        client,
+        Role::Client,
+        Options {
+            syn_timeout: Some(std::time::Duration::from_millis(200)),
+            ..options.clone()
+        },
+        None,
+    );
+    let server_mux = Multiplexor::with_options(
+        server,
+        Role::Server,
+        Options {
+            defer_syn_ack: true,
+            ..options
+        },
+        None,
+    );
+    let (timed_out_tx, timed_out_rx) = tokio::sync::oneshot::channel();
+
+    let server_task = tokio::spawn(async move {
+        let mut late = server_mux.server_new_stream_channel().await.unwrap();
+        timed_out_rx.await.unwrap();
+        // The client no longer waits for it
+        late.accept();
+        let err = late.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        let mut stream = server_mux.server_new_stream_channel().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        server_mux
+    });
+
+    let err = client_mux
+        .client_new_stream_channel(b"example.com", 80)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::SynTimeout));
+    timed_out_tx.send(()).unwrap();
+    let mut conn = client_mux
+        .client_new_stream_channel(b"example.com", 80)
+        .await
+        .unwrap();
+    let mut output = Vec::new();
+    conn.read_to_end(&mut output).await.unwrap();
+    assert_eq!(output, b"hello");
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn hello_announces_capabilities() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(
+        server,
+        Role::Server,
+        Options {
+            capabilities: Capabilities {
+                compression: 0b10,
+                ..Capabilities::default()
+            },
+            ..options
+        },
+        None,
+    );
+    let server_task = tokio::spawn(async move {
+        server_mux.server_new_stream_channel().await.unwrap();
+    });
+    // The server's `Hello` goes out before its `SynAck`
+    client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    let caps = client_mux.peer_capabilities().unwrap();
+    assert_eq!(caps.compression, 0b10);
+    assert!(caps.datagrams);
+    server_task.await.unwrap();
+
+    // V1 peers do not send `Hello`
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+    let server_task = tokio::spawn(async move {
+        server_mux.server_new_stream_channel().await.unwrap();
+    });
+    client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    assert_eq!(client_mux.peer_capabilities(), None);
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn go_away_drains_connection() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+        server_mux.go_away().await.unwrap();
+        // Open streams keep working
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).await.unwrap();
+        conn.write_all(b"bye").await.unwrap();
+        conn.shutdown().await.unwrap();
+        received
+    });
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    client_mux.peer_going_away().await;
+    assert!(client_mux.is_peer_going_away());
+    let err = client_mux
+        .client_new_stream_channel(&[], 0)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::GoingAway));
+    conn.write_all(b"hello").await.unwrap();
+    conn.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    conn.read_to_end(&mut received).await.unwrap();
+    assert_eq!(received, b"bye");
+    assert_eq!(server_task.await.unwrap(), b"hello");
+
+    // V1 peers are not told, but their `Syn`s are rejected
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+    server_mux.go_away().await.unwrap();
+    let err = client_mux
+        .client_new_stream_channel(&[], 0)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::StreamRejected(RstReason::Unspecified, ref reason) if reason == "going away")
+    );
+    assert!(!client_mux.is_peer_going_away());
+}
+
+#[tokio::test]
+async fn writes_fit_peer_max_frame_size() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        capabilities: Capabilities {
+            max_frame_size: Some(2048),
+            ..Capabilities::default()
+        },
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+        let mut received = Vec::new();
+        conn.read_to_end(&mut received).await.unwrap();
+        received
+    });
+
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    let input: Vec<u8> = (0..10000).map(|_| rand::random::<u8>()).collect();
+    // A single write is split into frames the server accepts
+    let written = conn.write(&input).await.unwrap();
+    assert!(written <= 2048);
+    conn.write_all(&input[written..]).await.unwrap();
+    conn.shutdown().await.unwrap();
+    assert_eq!(server_task.await.unwrap(), input);
+}
+
+#[tokio::test]
+async fn large_datagrams_are_fragmented() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        capabilities: Capabilities {
+            max_frame_size: Some(2048),
+            ..Capabilities::default()
+        },
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+
+    let server_task = tokio::spawn(async move {
+        // Both `Hello`s have arrived once the stream is open
+        let _conn = server_mux.server_new_stream_channel().await.unwrap();
+        for _ in 0..4 {
+            let dgram = server_mux.get_datagram().await.unwrap();
+            server_mux.send_datagram(dgram).await.unwrap();
+        }
+    });
+    let _conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+
+    for _ in 0..4 {
+        let payload: Bytes = (0..65507).map(|_| rand::random::<u8>()).collect();
+        client_mux
+            .send_datagram_to(b"example.com", 53, payload.clone())
+            .await
+            .unwrap();
+        let recvd = client_mux.get_datagram().await.unwrap();
+        assert_eq!(recvd.host, &b"example.com"[..]);
+        assert_eq!(recvd.port, 53);
+        assert_eq!(recvd.data, payload);
+    }
+    server_task.await.unwrap();
+
+    // Peers that do not reassemble get no fragments
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        capabilities: Capabilities {
+            max_frame_size: Some(2048),
+            fragments: false,
+            ..Capabilities::default()
+        },
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+    let server_task = tokio::spawn(async move {
+        server_mux.server_new_stream_channel().await.unwrap();
+    });
+    client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    let err = client_mux
+        .send_datagram_to(b"example.com", 53, Bytes::from(vec![0; 4096]))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::DatagramTooLarge(_)));
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn reliable_datagrams_survive_overflow() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+    let server_task = tokio::spawn(async move {
+        server_mux.server_new_stream_channel().await.unwrap();
+        server_mux
+    });
+    // Both `Hello`s have arrived once the stream is open
+    let _conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    let server_mux = server_task.await.unwrap();
+
+    let sent = crate::config::DATAGRAM_BUFFER_SIZE as u16 + 64;
+    for port in 0..sent {
+        client_mux
+            .send_reliable_datagram(DatagramFrame {
+                host: Bytes::from_static(b"tftp"),
+                port,
+                sid: 7,
+                data: Bytes::new(),
+            })
+            .await
+            .unwrap();
+    }
+    // Let the server drop the ones it has no room for
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(server_mux.stats().await.datagrams_dropped > 0);
+    let mut ports = Vec::new();
+    while ports.len() < usize::from(sent) {
+        ports.push(server_mux.get_datagram().await.unwrap().port);
+    }
+    ports.sort_unstable();
+    assert_eq!(ports, (0..sent).collect::<Vec<_>>());
+    while client_mux.stats().await.datagrams_unacked != 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    // Replies are reliable too
+    server_mux
+        .send_datagram_to(b"tftp", 69, Bytes::from_static(b"ack"))
+        .await
+        .unwrap();
+    let reply = DatagramFrame {
+        host: Bytes::from_static(b"tftp"),
+        port: 69,
+        sid: 7,
+        data: Bytes::from_static(b"ack"),
+    };
+    server_mux.send_datagram(reply.clone()).await.unwrap();
+    assert_eq!(client_mux.get_datagram().await.unwrap().sid, 0);
+    assert_eq!(client_mux.get_datagram().await.unwrap(), reply);
+    while server_mux.stats().await.datagrams_unacked != 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn compressed_streams_pass_data() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        capabilities: Capabilities {
+            compression: Compression::supported(),
+            ..Capabilities::default()
+        },
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+            let mut received = Vec::new();
+            conn.read_to_end(&mut received).await.unwrap();
+            streams.push((conn.compression, received));
+        }
+        streams
+    });
+
+    // Nothing is offered before the server's `Hello` arrives
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    assert_eq!(conn.compression, None);
+    conn.shutdown().await.unwrap();
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    let expected = Compression::choose(Compression::supported());
+    assert!(expected.is_some());
+    assert_eq!(conn.compression, expected);
+    let text = b"penguin ".repeat(4096);
+    let random: Vec<u8> = (0..4096).map(|_| rand::random::<u8>()).collect();
+    conn.write_all(&text).await.unwrap();
+    conn.write_all(&random).await.unwrap();
+    conn.shutdown().await.unwrap();
+    let streams = server_task.await.unwrap();
+    assert_eq!(streams[0], (None, Vec::new()));
+    assert_eq!(streams[1].0, expected);
+    assert_eq!(streams[1].1, [text, random].concat());
+}
+
+#[tokio::test]
+async fn syn_carries_tcp_options() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+    let tcp_options = TcpOptions {
+        nodelay: Some(false),
+        keepalive_interval: Some(10),
+        keepalive_count: Some(3),
+        ..TcpOptions::NONE
+    };
+    let server_task = tokio::spawn(async move {
+        let first = server_mux.server_new_stream_channel().await.unwrap();
+        let second = server_mux.server_new_stream_channel().await.unwrap();
+        (first.tcp_options, second.tcp_options)
+    });
+    // Sent even before the server's `Hello` arrives
+    let conn = client_mux
+        .client_new_stream_channel_with_options(&[], 0, tcp_options)
+        .await
+        .unwrap();
+    assert!(conn.tcp_options.is_empty());
+    client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    assert_eq!(server_task.await.unwrap(), (tcp_options, TcpOptions::NONE));
+}
+
+#[tokio::test]
+async fn syn_carries_trace_context() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options.clone(), None);
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, None);
+    let traceparent = b"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+    let server_task = tokio::spawn(async move {
+        let first = server_mux.server_new_stream_channel().await.unwrap();
+        let second = server_mux.server_new_stream_channel().await.unwrap();
+        (first.trace_context.clone(), second.trace_context.clone())
+    });
+    let conn = client_mux
+        .client_new_stream_channel_with_context(&[], 0, TcpOptions::NONE, traceparent)
+        .await
+        .unwrap();
+    assert!(conn.trace_context.is_empty());
+    client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    let (first, second) = server_task.await.unwrap();
+    assert_eq!(first, &traceparent[..]);
+    assert!(second.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn obfs_traffic_pads_frames() {
+    use futures_util::{SinkExt, StreamExt};
+    let (client, mut server) = crate::ws::mock::get_mock_pair();
+    let options = Options {
+        frame_version: FrameVersion::V2,
+        obfs_traffic: true,
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options, None);
+    // Nothing is padded before the peer's `Hello` says it may be
+    let Some(Ok(Message::Frame(hello))) = server.next().await else {
+        panic!("not a frame");
+    };
+    assert!(matches!(
+        Frame::decode(hello, FrameVersion::V2),
+        Ok(Frame::Hello(_))
+    ));
+    let hello = Frame::Hello(Capabilities::default())
+        .encode(FrameVersion::V2)
+        .unwrap();
+    server.send(Message::Frame(hello)).await.unwrap();
+    while client_mux.peer_capabilities().is_none() {
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    }
+    let client_task = tokio::spawn(async move {
+        client_mux
+            .client_new_stream_channel(b"example.com", 80)
+            .await
+    });
+    let Some(Ok(Message::Frame(syn))) = server.next().await else {
+        panic!("not a frame");
+    };
+    assert_eq!(syn.len(), 256);
+    assert!(matches!(
+        Frame::decode(syn, FrameVersion::V2),
+        Ok(Frame::Stream(StreamFrame {
+            flag: StreamFlag::Syn,
+            ..
+        }))
+    ));
+    // Dummy frames follow
+    let Some(Ok(Message::Frame(dummy))) = server.next().await else {
+        panic!("not a frame");
+    };
+    assert!(dummy.len().is_power_of_two());
+    assert!(matches!(
+        Frame::decode(dummy, FrameVersion::V2),
+        Ok(Frame::Padding)
+    ));
+    client_task.abort();
+}
+
+#[tokio::test]
+async fn oversized_frame_closes_connection() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+    let options = Options {
+        capabilities: Capabilities {
+            max_frame_size: Some(2048),
+            ..Capabilities::default()
+        },
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let mut joinset = tokio::task::JoinSet::new();
+    let server_mux = Multiplexor::with_options(server, Role::Server, options, Some(&mut joinset));
+
+    let server_task = tokio::spawn(async move {
+        let _conn = server_mux.server_new_stream_channel().await.unwrap();
+        // V1 peers cannot be told the limit
+        let result = joinset.join_next().await.unwrap().unwrap();
+        assert!(matches!(result, Err(Error::FrameTooLarge(_))));
+    });
+
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    conn.write_all(&[0; 4096]).await.unwrap();
+    conn.flush().await.unwrap();
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn stream_stats_count_bytes() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+        let mut buf = [0u8; 100];
+        conn.read_exact(&mut buf).await.unwrap();
+        let stats = server_mux.stats().await;
+        assert_eq!(stats.streams.len(), 1);
+        assert_eq!(stats.streams[0].id, conn.id());
+        assert_eq!(stats.streams[0].dest_host, "example.com");
+        assert_eq!(stats.streams[0].dest_port, 443);
+        assert_eq!(stats.streams[0].bytes_received, 100);
+        assert_eq!(stats.streams[0].frames_received, 1);
+        assert_eq!(stats.streams[0].bytes_sent, 0);
+        (server_mux, conn)
+    });
+
+    let mut conn = client_mux
+        .client_new_stream_channel(b"example.com", 443)
+        .await
+        .unwrap();
+    assert_eq!(conn.dest_host, "example.com");
+    conn.write_all(&[1; 100]).await.unwrap();
+    conn.flush().await.unwrap();
+    let stats = client_mux.stats().await;
+    assert_eq!(stats.streams.len(), 1);
+    assert_eq!(stats.streams[0].id, conn.id());
+    assert_eq!(stats.streams[0].dest_port, 443);
+    assert_eq!(stats.streams[0].bytes_sent, 100);
+    assert_eq!(stats.streams[0].frames_sent, 1);
+    assert_eq!(stats.streams[0].bytes_received, 0);
+    let _server = server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn reset_stream_closes_both_ends() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+        // The client's `Rst` ends the stream
+        conn.read_to_end(&mut Vec::new()).await.ok();
+        assert!(server_mux.stats().await.streams.is_empty());
+        server_mux
+    });
+
+    let mut conn = client_mux
+        .client_new_stream_channel(b"example.com", 443)
+        .await
+        .unwrap();
+    assert!(!client_mux.reset_stream(conn.id() + 1).await);
+    assert!(client_mux.reset_stream(conn.id()).await);
+    let mut buf = Vec::new();
+    assert_eq!(conn.read_to_end(&mut buf).await.unwrap(), 0);
+    conn.write_all(b"late").await.unwrap_err();
+    assert!(client_mux.stats().await.streams.is_empty());
+    assert!(!client_mux.reset_stream(conn.id()).await);
+    let _server = server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn cork_coalesces_small_writes() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let options = Options {
+        cork: Some(std::time::Duration::from_millis(20)),
+        ..Options::default()
+    };
+    let client_mux = Multiplexor::with_options(client, Role::Client, options, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+        let mut buf = [0u8; 10];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"keystrokes");
+        let mut buf = vec![0u8; config::CORK_BYTES + 2];
+        conn.read_exact(&mut buf).await.unwrap();
+        (server_mux, conn)
+    });
+
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    for byte in b"keystrokes" {
+        conn.write_all(&[*byte]).await.unwrap();
+    }
+    let start = std::time::Instant::now();
+    conn.flush().await.unwrap();
+    assert!(start.elapsed() >= std::time::Duration::from_millis(20));
+    let stats = client_mux.stats().await;
+    assert_eq!(stats.streams[0].frames_sent, 1);
+    assert_eq!(stats.streams[0].bytes_sent, 10);
+    // Writes that do not fit are not held back
+    conn.write_all(&[1; 2]).await.unwrap();
+    conn.write_all(&vec![2; config::CORK_BYTES]).await.unwrap();
+    let stats = client_mux.stats().await;
+    assert_eq!(stats.streams[0].frames_sent, 3);
+    conn.flush().await.unwrap();
+    let _server = server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn stats_count_queued_frames_and_datagrams() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let conn = server_mux.server_new_stream_channel().await.unwrap();
+        // Neither the frames nor the datagram are read
+        loop {
+            let stats = server_mux.stats().await;
+            if stats.streams[0].frames_queued == 2 && stats.datagrams_queued == 1 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        (server_mux, conn)
+    });
+
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    conn.write_all(b"first").await.unwrap();
+    conn.write_all(b"second").await.unwrap();
+    conn.flush().await.unwrap();
+    client_mux
+        .send_datagram(DatagramFrame {
+            sid: 1,
+            host: Bytes::from_static(b"example.com"),
+            port: 53,
+            data: Bytes::from_static(b"query"),
+        })
+        .await
+        .unwrap();
+    let (server_mux, mut server_conn) =
+        tokio::time::timeout(std::time::Duration::from_secs(5), server_task)
+            .await
+            .unwrap()
+            .unwrap();
+    let mut buf = [0u8; 11];
+    server_conn.read_exact(&mut buf).await.unwrap();
+    server_mux.get_datagram().await.unwrap();
+    let stats = server_mux.stats().await;
+    assert_eq!(stats.streams[0].frames_queued, 0);
+    assert_eq!(stats.datagrams_queued, 0);
+}
+
+#[tokio::test]
+async fn test_early_eof_detected() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let input_bytes: Vec<u8> = (0..1024).map(|_| rand::random::<u8>()).collect();
+    let len = input_bytes.len();
+    let input_bytes_clone = input_bytes.clone();
+
+    let server_task = tokio::spawn(async move {
+        let mut conn = server_mux.server_new_stream_channel().await.unwrap();
+        conn.write_all(&input_bytes_clone).await.unwrap();
+        info!("Done send");
+    });
+
+    let mut output_bytes: Vec<u8> = vec![];
+
+    let mut conn = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    while output_bytes.len() < len + 2 {
+        let mut buf = [0u8; 2048];
+        let bytes = conn.read(&mut buf).await.unwrap();
+        if bytes == 0 {
+            break;
+        }
+        output_bytes.extend(&buf[..bytes]);
+        info!("Read {} bytes", output_bytes.len());
+    }
+
+    assert_eq!(input_bytes, output_bytes);
+    debug!("Waiting for server task to finish");
+    server_task.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_several_channels() {
+    let (client, server) = crate::ws::mock::get_pair().await;
+
+    let client_mux = Multiplexor::new(client, Role::Client, None, None);
+    let server_mux = Multiplexor::new(server, Role::Server, None, None);
+
+    let server_task = tokio::spawn(async move {
+        let mut conn1 = server_mux.server_new_stream_channel().await.unwrap();
+        info!("server conn1 = {:?}", conn1);
+        let mut conn2 = server_mux.server_new_stream_channel().await.unwrap();
+        info!("server conn2 = {:?}", conn2);
+        let mut conn3 = server_mux.server_new_stream_channel().await.unwrap();
+        info!("server conn3 = {:?}", conn3);
+        let mut buf = [0u8; 32];
+        let bytes = conn3.read(&mut buf).await.unwrap();
+        assert_eq!(buf[..bytes], b"!"[..]);
+        info!("server conn3 read = {:?}", bytes);
+        let bytes = conn2.read(&mut buf).await.unwrap();
+        assert_eq!(buf[..bytes], b"world"[..]);
+        info!("server conn2 read = {:?}", bytes);
+        let bytes = conn1.read(&mut buf).await.unwrap();
+        assert_eq!(buf[..bytes], b"hello"[..]);
+        info!("server conn1 read = {:?}", bytes);
+    });
+    let mut conn1 = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    info!("client conn1 = {:?}", conn1);
+    let mut conn2 = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    info!("client conn2 = {:?}", conn2);
+    let mut conn3 = client_mux.client_new_stream_channel(&[], 0).await.unwrap();
+    info!("client conn3 = {:?}", conn3);
+    conn1.write_all(b"hello").await.unwrap();
+    conn1.shutdown().await.unwrap();
+    info!("client conn1 wrote");
+    conn2.write_all(b"world").await.unwrap();
+    conn2.shutdown().await.unwrap();
+    info!("client conn2 wrote");
+    conn3.write_all(b"!").await.unwrap();
+    conn3.shutdown().await.unwrap();
+    info!("client conn3 wrote");
+
+    debug!("Waiting for server task to finish");
+    server_task.await.unwrap();
+}
@@ -0,0 +1,49 @@
+//! What the multiplexor runs over.
+//!
+//! The multiplexor only needs a reliable, ordered channel of messages, each
+//! carrying a whole [`Frame`](crate::Frame), and a way to check that the peer
+//! is still alive. WebSockets are adapted by [`ws::WebSocket`](crate::ws::WebSocket);
+//! other transports, such as raw TLS with a length prefix, QUIC streams, or
+//! in-memory pipes, only need to implement [`Transport`].
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use bytes::Bytes;
+use futures_util::{Sink, Stream};
+use std::io;
+
+/// A message over a [`Transport`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    /// An encoded [`Frame`](crate::Frame)
+    Frame(Bytes),
+    /// Keepalive request. The payload is echoed in the `Pong`.
+    Ping(Bytes),
+    /// Keepalive reply
+    Pong(Bytes),
+    /// The peer is closing the transport
+    Close,
+}
+
+/// A transport the multiplexor can run over.
+///
+/// Errors should have the kind [`io::ErrorKind::BrokenPipe`] when the
+/// transport is closed, which the multiplexor does not always treat as
+/// an error.
+///
+/// `poll_ready` should stay pending while the transport cannot keep up, so
+/// that writes to the streams wait instead of piling up in memory.
+pub trait Transport:
+    Stream<Item = io::Result<Message>> + Sink<Message, Error = io::Error> + Send + Unpin + 'static
+{
+    /// Whether the transport answers [`Message::Ping`] by itself. If not,
+    /// the multiplexor replies with a [`Message::Pong`].
+    fn ping_auto_pong(&self) -> bool {
+        false
+    }
+}
+
+/// Whether `error` means that the transport is closed.
+pub(crate) fn because_closed(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::BrokenPipe
+}
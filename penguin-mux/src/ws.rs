@@ -0,0 +1,182 @@
+//! Running the multiplexor over Tungstenite's WebSocket implementation.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::transport::{Message, Transport};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite;
+pub use tokio_tungstenite::tungstenite::protocol::Role;
+
+/// A `tokio-tungstenite` WebSocket as a [`Transport`]. Each frame is sent in
+/// a `Binary` message.
+#[derive(Debug)]
+pub struct WebSocket<RW>(tokio_tungstenite::WebSocketStream<RW>);
+
+impl<RW> WebSocket<RW> {
+    /// Wrap an established WebSocket.
+    #[inline]
+    pub const fn new(ws: tokio_tungstenite::WebSocketStream<RW>) -> Self {
+        Self(ws)
+    }
+
+    /// Get the WebSocket back.
+    #[inline]
+    pub fn into_inner(self) -> tokio_tungstenite::WebSocketStream<RW> {
+        self.0
+    }
+}
+
+/// Convert a WebSocket error into the kind [`Transport`] expects.
+fn into_io_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::AlreadyClosed | tungstenite::Error::ConnectionClosed => {
+            io::ErrorKind::BrokenPipe.into()
+        }
+        e => io::Error::other(e),
+    }
+}
+
+impl<RW> Stream for WebSocket<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = io::Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(msg) = ready!(self.0.poll_next_unpin(cx)) else {
+            return Poll::Ready(None);
+        };
+        let msg = match msg.map_err(into_io_error) {
+            Ok(tungstenite::Message::Binary(data)) => Ok(Message::Frame(data.into())),
+            Ok(tungstenite::Message::Ping(data)) => Ok(Message::Ping(data.into())),
+            Ok(tungstenite::Message::Pong(data)) => Ok(Message::Pong(data.into())),
+            Ok(tungstenite::Message::Close(_)) => Ok(Message::Close),
+            // "The client and server MUST NOT use other WebSocket data frame types"
+            Ok(tungstenite::Message::Text(text)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("received `Text` message: `{text}'"),
+            )),
+            Ok(tungstenite::Message::Frame(_)) => {
+                unreachable!("`Frame` message should not be received")
+            }
+            Err(e) => Err(e),
+        };
+        Poll::Ready(Some(msg))
+    }
+}
+
+impl<RW> Sink<Message> for WebSocket<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.poll_ready_unpin(cx).map_err(into_io_error)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> io::Result<()> {
+        let msg = match msg {
+            Message::Frame(data) => tungstenite::Message::Binary(data.into()),
+            Message::Ping(data) => tungstenite::Message::Ping(data.into()),
+            Message::Pong(data) => tungstenite::Message::Pong(data.into()),
+            Message::Close => tungstenite::Message::Close(None),
+        };
+        self.0.start_send_unpin(msg).map_err(into_io_error)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.poll_flush_unpin(cx).map_err(into_io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.poll_close_unpin(cx).map_err(into_io_error)
+    }
+}
+
+impl<RW> Transport for WebSocket<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// `tungstenite` queues a `Pong` for every `Ping`
+    fn ping_auto_pong(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use tokio::io::DuplexStream;
+    use tokio::sync::mpsc;
+
+    /// An in-memory transport without WebSocket framing.
+    #[derive(Debug)]
+    pub(crate) struct MockTransport {
+        tx: mpsc::UnboundedSender<Message>,
+        rx: mpsc::UnboundedReceiver<Message>,
+    }
+
+    impl Stream for MockTransport {
+        type Item = io::Result<Message>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_recv(cx).map(|msg| msg.map(Ok))
+        }
+    }
+
+    impl Sink<Message> for MockTransport {
+        type Error = io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, msg: Message) -> io::Result<()> {
+            self.tx
+                .send(msg)
+                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl Transport for MockTransport {}
+
+    /// A pair of connected in-memory transports.
+    pub(crate) fn get_mock_pair() -> (MockTransport, MockTransport) {
+        let (client_tx, server_rx) = mpsc::unbounded_channel();
+        let (server_tx, client_rx) = mpsc::unbounded_channel();
+        (
+            MockTransport {
+                tx: client_tx,
+                rx: client_rx,
+            },
+            MockTransport {
+                tx: server_tx,
+                rx: server_rx,
+            },
+        )
+    }
+
+    /// A pair of WebSockets over a `tokio` `DuplexStream`.
+    pub(crate) async fn get_pair() -> (WebSocket<DuplexStream>, WebSocket<DuplexStream>) {
+        let (client, server) = tokio::io::duplex(10);
+        let client =
+            tokio_tungstenite::WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        let server =
+            tokio_tungstenite::WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        (WebSocket::new(client), WebSocket::new(server))
+    }
+}
@@ -0,0 +1,215 @@
+//! HMAC challenge-response PSK authentication.
+//!
+//! Instead of sending the PSK in `X-Penguin-PSK`, the client proves that it
+//! knows the PSK right after the WebSocket upgrade, before the multiplexor
+//! starts:
+//! 1. The server sends a binary message containing a 16-byte random nonce
+//!    followed by the current Unix timestamp (8 bytes, big-endian).
+//! 2. The client replies with a binary message containing
+//!    `HMAC-SHA256(PSK, nonce || timestamp)`, followed by the part of its PSK
+//!    before the first `:` (the user name if a users file is used), if any.
+//!
+//! The server closes the connection if the response is wrong or late.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::config;
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use hmac::{Hmac, Mac};
+use penguin_mux::transport::{Message, Transport};
+use rand::RngCore;
+use sha2::Sha256;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const NONCE_LEN: usize = 16;
+const CHALLENGE_LEN: usize = NONCE_LEN + 8;
+const MAC_LEN: usize = 32;
+
+/// Errors that can occur during the challenge-response exchange.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("WebSocket error during PSK challenge: {0}")]
+    WebSocket(#[from] io::Error),
+    #[error("Timed out during PSK challenge")]
+    Timeout,
+    #[error("Invalid PSK challenge message")]
+    InvalidMessage,
+    #[error("Connection closed during PSK challenge")]
+    Closed,
+}
+
+/// A challenge sent by the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    nonce: [u8; NONCE_LEN],
+    timestamp: u64,
+}
+
+/// The client's response to a `Challenge`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChallengeResponse {
+    /// HMAC of the challenge
+    pub mac: Bytes,
+    /// User name hint, empty if the PSK does not contain `:`
+    pub hint: Bytes,
+}
+
+impl Challenge {
+    /// Create a new challenge with a random nonce and the current time.
+    pub fn new() -> Self {
+        let mut nonce = [0; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self { nonce, timestamp }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CHALLENGE_LEN);
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != CHALLENGE_LEN {
+            return None;
+        }
+        let (nonce, timestamp) = bytes.split_at(NONCE_LEN);
+        Some(Self {
+            nonce: nonce.try_into().ok()?,
+            timestamp: u64::from_be_bytes(timestamp.try_into().ok()?),
+        })
+    }
+
+    fn mac(&self, psk: &[u8]) -> HmacSha256 {
+        // `expect`: HMAC accepts keys of any length
+        let mut mac = HmacSha256::new_from_slice(psk).expect("Invalid HMAC key (this is a bug)");
+        mac.update(&self.nonce);
+        mac.update(&self.timestamp.to_be_bytes());
+        mac
+    }
+
+    /// Compute the response to this challenge using the given PSK.
+    pub fn respond(&self, psk: &[u8]) -> ChallengeResponse {
+        let hint = psk
+            .iter()
+            .position(|&b| b == b':')
+            .map_or(&[][..], |pos| &psk[..pos]);
+        ChallengeResponse {
+            mac: Bytes::from(self.mac(psk).finalize().into_bytes().to_vec()),
+            hint: Bytes::copy_from_slice(hint),
+        }
+    }
+
+    /// Check if the response was computed with the given PSK.
+    pub fn verify(&self, psk: &[u8], response: &ChallengeResponse) -> bool {
+        self.mac(psk).verify_slice(&response.mac).is_ok()
+    }
+}
+
+impl ChallengeResponse {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(MAC_LEN + self.hint.len());
+        bytes.extend_from_slice(&self.mac);
+        bytes.extend_from_slice(&self.hint);
+        bytes
+    }
+
+    fn from_bytes(bytes: Bytes) -> Option<Self> {
+        if bytes.len() < MAC_LEN {
+            return None;
+        }
+        let mut mac = bytes;
+        let hint = mac.split_off(MAC_LEN);
+        Some(Self { mac, hint })
+    }
+}
+
+/// Wait for the next binary message.
+async fn next_binary<T: Transport>(ws: &mut T) -> Result<Bytes, Error> {
+    let recv = async {
+        loop {
+            match ws.next().await.transpose()? {
+                Some(Message::Frame(data)) => return Ok(data),
+                Some(Message::Ping(_) | Message::Pong(_)) => {}
+                Some(Message::Close) | None => return Err(Error::Closed),
+            }
+        }
+    };
+    tokio::time::timeout(config::PSK_CHALLENGE_TIMEOUT, recv)
+        .await
+        .map_err(|_| Error::Timeout)?
+}
+
+/// Server side: send a challenge and wait for the response.
+/// The caller is responsible for checking the response.
+pub async fn server_challenge<T: Transport>(
+    ws: &mut T,
+) -> Result<(Challenge, ChallengeResponse), Error> {
+    let challenge = Challenge::new();
+    ws.send(Message::Frame(challenge.to_bytes().into())).await?;
+    let response =
+        ChallengeResponse::from_bytes(next_binary(ws).await?).ok_or(Error::InvalidMessage)?;
+    Ok((challenge, response))
+}
+
+/// Client side: wait for a challenge and respond to it with the given PSK.
+pub async fn client_respond<T: Transport>(ws: &mut T, psk: &[u8]) -> Result<(), Error> {
+    let challenge = Challenge::from_bytes(&next_binary(ws).await?).ok_or(Error::InvalidMessage)?;
+    let response = challenge.respond(psk);
+    ws.send(Message::Frame(response.to_bytes().into())).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use penguin_mux::ws::{Role, WebSocket};
+    use tokio_tungstenite::WebSocketStream;
+
+    #[test]
+    fn test_respond_and_verify() {
+        let challenge = Challenge::new();
+        let response = challenge.respond(b"alice:s3cret");
+        assert_eq!(response.hint, "alice");
+        assert!(challenge.verify(b"alice:s3cret", &response));
+        assert!(!challenge.verify(b"alice:wrong", &response));
+        // A different nonce should not accept the same response
+        assert!(!Challenge::new().verify(b"alice:s3cret", &response));
+        let response = challenge.respond(b"no user");
+        assert!(response.hint.is_empty());
+        let bytes = response.to_bytes();
+        assert_eq!(
+            ChallengeResponse::from_bytes(bytes.into()).unwrap(),
+            response
+        );
+        assert_eq!(
+            Challenge::from_bytes(&challenge.to_bytes()).unwrap(),
+            challenge
+        );
+        assert!(Challenge::from_bytes(b"short").is_none());
+        assert!(ChallengeResponse::from_bytes(Bytes::from_static(b"short")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_challenge_exchange() {
+        let (client, server) = tokio::io::duplex(1024);
+        let mut client =
+            WebSocket::new(WebSocketStream::from_raw_socket(client, Role::Client, None).await);
+        let mut server =
+            WebSocket::new(WebSocketStream::from_raw_socket(server, Role::Server, None).await);
+        let client_task =
+            tokio::spawn(async move { client_respond(&mut client, b"bob:hunter2").await });
+        let (challenge, response) = server_challenge(&mut server).await.unwrap();
+        client_task.await.unwrap().unwrap();
+        assert_eq!(response.hint, "bob");
+        assert!(challenge.verify(b"bob:hunter2", &response));
+    }
+}
@@ -0,0 +1,101 @@
+//! Keepalive interval tuned to the disconnects we observe.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use std::time::Duration;
+
+/// Shortest interval we go down to
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+/// A connection that survives this many keepalive intervals was not
+/// dropped for being idle
+const STABLE_INTERVALS: u32 = 10;
+/// Stop searching when the bounds are this close
+const PRECISION: Duration = Duration::from_secs(1);
+
+/// Binary search for the longest keepalive interval that keeps
+/// middleboxes from dropping the connection.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct AdaptiveKeepalive {
+    /// Longest interval known to keep the connection alive
+    good: Duration,
+    /// Shortest interval the connection was dropped with, if any
+    bad: Option<Duration>,
+    /// Interval for the next connection
+    current: Duration,
+}
+
+impl AdaptiveKeepalive {
+    /// Start the search from `initial`, which is also the upper bound.
+    #[must_use]
+    pub fn new(initial: Duration) -> Self {
+        let initial = initial.max(MIN_INTERVAL);
+        Self {
+            good: MIN_INTERVAL,
+            bad: None,
+            current: initial,
+        }
+    }
+
+    /// The interval to use for the next connection.
+    #[must_use]
+    pub const fn current(&self) -> Duration {
+        self.current
+    }
+
+    /// Adjust the interval after a connection using [`Self::current`] was
+    /// lost after `uptime`.
+    pub fn on_disconnect(&mut self, uptime: Duration) {
+        if uptime >= self.current * STABLE_INTERVALS {
+            // Probably not an idle timeout, so try a longer interval
+            self.good = self.current;
+        } else {
+            if self.good >= self.current {
+                // What used to work no longer does
+                self.good = (self.current / 2).max(MIN_INTERVAL);
+            }
+            self.bad = Some(self.current);
+        }
+        if let Some(bad) = self.bad {
+            if bad.saturating_sub(self.good) > PRECISION {
+                self.current = (self.good + bad) / 2;
+            } else {
+                self.current = self.good;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_adaptive_keepalive() {
+        let mut keepalive = AdaptiveKeepalive::new(Duration::from_secs(25));
+        assert_eq!(keepalive.current(), Duration::from_secs(25));
+        // Long-lived connections do not change anything
+        keepalive.on_disconnect(Duration::from_secs(3600));
+        assert_eq!(keepalive.current(), Duration::from_secs(25));
+        // Dropped after 20 seconds: search below 25 seconds
+        keepalive.on_disconnect(Duration::from_secs(20));
+        assert_eq!(keepalive.current(), Duration::from_millis(18750));
+        // 18.75 seconds works: search between that and 25 seconds
+        keepalive.on_disconnect(Duration::from_secs(3600));
+        assert_eq!(keepalive.current(), Duration::from_millis(21875));
+        keepalive.on_disconnect(Duration::from_secs(20));
+        assert_eq!(keepalive.current(), Duration::from_micros(20_312_500));
+        keepalive.on_disconnect(Duration::from_secs(3600));
+        assert_eq!(keepalive.current(), Duration::from_micros(21_093_750));
+        // Close enough
+        keepalive.on_disconnect(Duration::from_secs(3600));
+        assert_eq!(keepalive.current(), Duration::from_micros(21_093_750));
+        // The middlebox became more aggressive
+        keepalive.on_disconnect(Duration::from_secs(10));
+        assert_eq!(keepalive.current(), Duration::from_nanos(15_820_312_500));
+        // Never below the minimum
+        for _ in 0..100 {
+            keepalive.on_disconnect(Duration::ZERO);
+        }
+        assert_eq!(keepalive.current(), MIN_INTERVAL);
+    }
+}
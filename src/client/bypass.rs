@@ -0,0 +1,147 @@
+//! Split-tunnel rules of `--bypass-file`: destinations that "socks"
+//! remotes connect to from this machine instead of through the server.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Destinations to connect to directly
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Bypass {
+    /// Domains whose subdomains also match, lowercase and without dots
+    /// around them
+    domains: Vec<String>,
+    /// Networks that IP address destinations can be in
+    networks: Vec<IpNet>,
+}
+
+impl FromStr for Bypass {
+    /// Line number and reason
+    type Err = (usize, &'static str);
+
+    /// Parse the rules of a `--bypass-file`: a domain suffix (e.g.
+    /// `corp.example.com` or `.corp.example.com`), an IP address, or a CIDR
+    /// on each line, where empty lines and everything after `#` are ignored.
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut bypass = Self::default();
+        for (lineno, line) in content.lines().enumerate() {
+            // `unwrap`: `split` gives at least one item
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Ok(network) = line.parse::<IpNet>() {
+                bypass.networks.push(network.trunc());
+            } else if let Ok(ip) = line.parse::<IpAddr>() {
+                bypass.networks.push(ip.into());
+            } else {
+                let domain = line.trim_start_matches("*.").trim_matches('.');
+                if domain.is_empty()
+                    || !domain
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+                {
+                    return Err((lineno + 1, "not a domain, IP address, or CIDR"));
+                }
+                bypass.domains.push(domain.to_ascii_lowercase());
+            }
+        }
+        Ok(bypass)
+    }
+}
+
+impl Bypass {
+    /// Whether `host` matches a rule. Hostnames are not resolved to match
+    /// networks.
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.networks.iter().any(|network| network.contains(&ip));
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.domains.iter().any(|domain| {
+            host.strip_suffix(domain.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+        })
+    }
+
+    /// A JavaScript condition for a PAC file that is true when `host`
+    /// matches a rule, if there are rules. PAC files cannot check IPv6
+    /// networks, so those are left out.
+    pub fn pac_condition(&self) -> Option<String> {
+        let domains = self
+            .domains
+            .iter()
+            .map(|domain| format!("host == \"{domain}\" || dnsDomainIs(host, \".{domain}\")"));
+        let networks = self.networks.iter().filter_map(|network| match network {
+            // `isInNet` would resolve hostnames
+            IpNet::V4(network) => Some(format!(
+                "(/^[0-9.]+$/.test(host) && isInNet(host, \"{}\", \"{}\"))",
+                network.network(),
+                network.netmask()
+            )),
+            IpNet::V6(_) => None,
+        });
+        let conditions: Vec<String> = domains.chain(networks).collect();
+        if conditions.is_empty() {
+            None
+        } else {
+            Some(conditions.join(" || "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bypass_fromstr() {
+        let bypass: Bypass = "# Local\n\nCorp.Example.com\n*.lan  # printers\n.internal.\n\
+                              10.0.0.0/8\n192.168.1.7\nfd00::/8\n"
+            .parse()
+            .unwrap();
+        assert_eq!(bypass.domains, ["corp.example.com", "lan", "internal"]);
+        assert_eq!(
+            bypass.networks,
+            [
+                "10.0.0.0/8".parse::<IpNet>().unwrap(),
+                "192.168.1.7/32".parse().unwrap(),
+                "fd00::/8".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            "a.b\n\nhttp://x/\n".parse::<Bypass>(),
+            Err((3, "not a domain, IP address, or CIDR"))
+        );
+        assert_eq!("*.\n".parse::<Bypass>().unwrap_err().0, 1);
+    }
+
+    #[test]
+    fn test_bypass_matches() {
+        let bypass: Bypass = "corp.example.com\n10.0.0.0/8\nfd00::/8\n".parse().unwrap();
+        assert!(bypass.matches("corp.example.com"));
+        assert!(bypass.matches("DB.Corp.Example.com."));
+        assert!(!bypass.matches("notcorp.example.com"));
+        assert!(!bypass.matches("example.com"));
+        assert!(bypass.matches("10.1.2.3"));
+        assert!(!bypass.matches("11.1.2.3"));
+        assert!(bypass.matches("fd00::1"));
+        assert!(bypass.matches("[fd00::1]"));
+        assert!(!bypass.matches("2001:db8::1"));
+        assert!(!Bypass::default().matches("corp.example.com"));
+    }
+
+    #[test]
+    fn test_bypass_pac_condition() {
+        assert_eq!(Bypass::default().pac_condition(), None);
+        let bypass: Bypass = "lan\n10.0.0.0/8\nfd00::/8\n".parse().unwrap();
+        assert_eq!(
+            bypass.pac_condition().unwrap(),
+            "host == \"lan\" || dnsDomainIs(host, \".lan\") || \
+             (/^[0-9.]+$/.test(host) && isInNet(host, \"10.0.0.0\", \"255.0.0.0\"))"
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! The `--control-socket` of the client.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::handle_remote::handle_remote;
+use super::{ControlRequest, HandlerResources, Listening};
+use crate::config;
+use crate::control::{Command, RemoteStatus, Response, Status, HELP};
+use crate::parse_remote::{LocalSpec, Remote};
+use crate::Dupe;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, warn};
+
+/// Listen on the control socket at `path`, only for the owner. A socket
+/// left behind by a client that exited is replaced.
+pub(super) async fn bind(path: &Path) -> io::Result<UnixListener> {
+    match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a file that is not a socket is in the way",
+            ));
+        }
+        Ok(_) if UnixStream::connect(path).await.is_ok() => {
+            // Another client is using it
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another client is using it",
+            ));
+        }
+        _ => {}
+    }
+    let Some(file_name) = path.file_name() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a file path",
+        ));
+    };
+    // Create the socket in a directory only we can enter, so that nobody
+    // can connect before it is 0600, then move it into place
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let private = parent.join(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    tokio::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .await?;
+    let private_path = private.join(file_name);
+    let result = bind_private(&private_path, path).await;
+    if result.is_err() {
+        tokio::fs::remove_file(&private_path).await.ok();
+    }
+    tokio::fs::remove_dir(&private).await.ok();
+    result
+}
+
+/// Listen on `private_path`, which nobody else can reach, and move it to
+/// `path`.
+async fn bind_private(private_path: &Path, path: &Path) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(private_path)?;
+    tokio::fs::set_permissions(private_path, std::fs::Permissions::from_mode(0o600)).await?;
+    tokio::fs::rename(private_path, path).await?;
+    Ok(listener)
+}
+
+/// Answer the commands sent to `listener`.
+pub(super) async fn serve(
+    listener: UnixListener,
+    control_tx: mpsc::Sender<ControlRequest>,
+    handler_resources: HandlerResources,
+) -> Infallible {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let control_tx = control_tx.dupe();
+                let handler_resources = handler_resources.dupe();
+                tokio::spawn(async move {
+                    if let Err(err) = handle(stream, &control_tx, &handler_resources).await {
+                        debug!("Control connection failed: {err}");
+                    }
+                });
+            }
+            Err(err) => warn!("Cannot accept control connection: {err}"),
+        }
+    }
+}
+
+/// Answer each line of `stream` with a line of JSON.
+async fn handle(
+    stream: UnixStream,
+    control_tx: &mpsc::Sender<ControlRequest>,
+    handler_resources: &HandlerResources,
+) -> io::Result<()> {
+    let (rx, mut tx) = stream.into_split();
+    let mut lines = BufReader::new(rx).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let listening = &handler_resources.listening;
+        let response = match line.parse() {
+            Ok(Command::Status) => to_line(status(control_tx, listening).await),
+            Ok(Command::Streams) => to_line(
+                status(control_tx, listening)
+                    .await
+                    .map(|status| status.streams),
+            ),
+            Ok(Command::Kill(id)) => to_line(kill(control_tx, id).await),
+            Ok(Command::Add(remote)) => to_line(add(remote, handler_resources).await),
+            Ok(Command::Help) => to_line(Ok(HELP)),
+            Err(error) => to_line::<()>(Err(error)),
+        };
+        tx.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// A response line for `result`
+fn to_line<T: Serialize>(result: Result<T, String>) -> String {
+    let response = match result {
+        Ok(response) => Response::Ok(response),
+        Err(error) => Response::Error { error },
+    };
+    // `expect`: the responses have nothing that JSON cannot represent
+    let mut line = serde_json::to_string(&response)
+        .expect("Cannot serialize a control response (this is a bug)");
+    line.push('\n');
+    line
+}
+
+/// Send the request `make_request` makes to the main loop and wait for the
+/// answer.
+async fn ask<T>(
+    control_tx: &mpsc::Sender<ControlRequest>,
+    make_request: impl FnOnce(oneshot::Sender<T>) -> ControlRequest,
+) -> Result<T, String> {
+    let (tx, rx) = oneshot::channel();
+    control_tx
+        .send(make_request(tx))
+        .await
+        .map_err(|_| "the client is exiting")?;
+    let answer = tokio::time::timeout(config::CONTROL_TIMEOUT, rx)
+        .await
+        .map_err(|_| "the client is busy connecting to the server")?
+        .map_err(|_| "the client is exiting")?;
+    Ok(answer)
+}
+
+/// Ask the main loop for the status and add the listeners to it.
+async fn status(
+    control_tx: &mpsc::Sender<ControlRequest>,
+    listening: &Listening,
+) -> Result<Status, String> {
+    let mut status = ask(control_tx, ControlRequest::Status).await?;
+    status.remotes = listening
+        .addrs
+        .lock()
+        .iter()
+        .map(|(remote, addr)| RemoteStatus {
+            remote: remote.label(),
+            local_addr: *addr,
+        })
+        .collect();
+    Ok(status)
+}
+
+/// Reset the stream with this ID.
+async fn kill(control_tx: &mpsc::Sender<ControlRequest>, id: u32) -> Result<u32, String> {
+    if ask(control_tx, |tx| ControlRequest::Reset(id, tx)).await? {
+        info!("Reset stream {id} for the control socket");
+        Ok(id)
+    } else {
+        Err(format!("no stream {id}"))
+    }
+}
+
+/// Start the listeners of `remote` and wait until they are bound. Unlike
+/// the remotes on the command line, the client keeps running if they fail
+/// later.
+async fn add(
+    remote: Remote,
+    handler_resources: &HandlerResources,
+) -> Result<Vec<RemoteStatus>, String> {
+    if remote.local_addr == LocalSpec::Stdio {
+        return Err("stdio remotes cannot be added".to_string());
+    }
+    if let Some(name) = &remote.name {
+        let addrs = handler_resources.listening.addrs.lock();
+        if addrs.iter().any(|(r, _)| r.name.as_ref() == Some(name)) {
+            return Err(format!("a remote is already named {name:?}"));
+        }
+    }
+    // They live as long as the client, like the others
+    let remotes: &'static [Remote] = Box::leak(remote.expand().collect());
+    let mut added = Vec::with_capacity(remotes.len());
+    for remote in remotes {
+        let mut handler = tokio::spawn(handle_remote(remote, handler_resources.dupe()));
+        tokio::select! {
+            biased;
+            local_addr = handler_resources.listening.wait_bound(remote) => {
+                added.push(RemoteStatus { remote: remote.label(), local_addr });
+                tokio::spawn(async move {
+                    if let Ok(Err(err)) = handler.await {
+                        error!("Remote {} failed: {err}", remote.label());
+                    }
+                });
+            }
+            result = &mut handler => {
+                return Err(match result {
+                    Ok(Err(err)) => err.to_string(),
+                    _ => "the remote stopped".to_string(),
+                });
+            }
+        }
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::{DatagramCommand, StreamCommand};
+    use crate::control::{request, StreamStatus, STATUS_COMMAND};
+
+    /// Serve a control socket in `dir` whose main loop is never connected.
+    /// Also returns the receivers of the main loop to keep.
+    async fn serve_disconnected(dir: &Path) -> (std::path::PathBuf, HandlerResources, impl Sized) {
+        let path = dir.join("control.sock");
+        let listener = bind(&path).await.unwrap();
+        // Still in use
+        bind(&path).await.unwrap_err();
+        let (stream_command_tx, stream_command_rx) = mpsc::channel::<StreamCommand>(1);
+        let (datagram_tx, datagram_rx) = mpsc::channel::<DatagramCommand>(1);
+        let handler_resources = HandlerResources::for_test(stream_command_tx, datagram_tx);
+        let (control_tx, mut control_rx) = mpsc::channel(1);
+        tokio::spawn(serve(listener, control_tx, handler_resources.dupe()));
+        tokio::spawn(async move {
+            while let Some(request) = control_rx.recv().await {
+                request.answer_disconnected(3);
+            }
+        });
+        (path, handler_resources, (stream_command_rx, datagram_rx))
+    }
+
+    #[tokio::test]
+    async fn test_control_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, handler_resources, _receivers) = serve_disconnected(dir.path()).await;
+        let remote: &'static Remote = Box::leak(Box::new("db=5432".parse().unwrap()));
+        handler_resources
+            .listening
+            .addrs
+            .lock()
+            .push((remote, "127.0.0.1:5432".parse().unwrap()));
+        let status: Status = request(&path, STATUS_COMMAND).await.unwrap();
+        assert!(!status.connected);
+        assert_eq!(status.reconnects, 3);
+        assert_eq!(status.remotes[0].remote, "db");
+        let streams: Vec<StreamStatus> = request(&path, "streams").await.unwrap();
+        assert!(streams.is_empty());
+        let help: String = request(&path, "help").await.unwrap();
+        assert_eq!(help, HELP);
+        let err = request::<u32>(&path, "kill 1").await.unwrap_err();
+        assert!(matches!(err, crate::control::Error::Client(_)));
+        let err = request::<Status>(&path, "nonsense").await.unwrap_err();
+        assert!(matches!(err, crate::control::Error::Client(_)));
+    }
+
+    #[tokio::test]
+    async fn test_control_add() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _handler_resources, _receivers) = serve_disconnected(dir.path()).await;
+        let added: Vec<RemoteStatus> = request(&path, "add web=127.0.0.1:0:example.com:80")
+            .await
+            .unwrap();
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0].remote, "web");
+        assert_ne!(added[0].local_addr.port(), 0);
+        tokio::net::TcpStream::connect(added[0].local_addr)
+            .await
+            .unwrap();
+        let status: Status = request(&path, STATUS_COMMAND).await.unwrap();
+        assert_eq!(status.remotes, added);
+        // Names stay unique
+        let err = request::<Vec<RemoteStatus>>(&path, "add web=127.0.0.1:0:example.com:443")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, crate::control::Error::Client(_)));
+        // The port is taken
+        let taken = format!("add {}:example.com:80", added[0].local_addr);
+        let err = request::<Vec<RemoteStatus>>(&path, &taken)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, crate::control::Error::Client(_)));
+    }
+
+    #[tokio::test]
+    async fn test_bind_replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        drop(bind(&path).await.unwrap());
+        assert!(path.exists());
+        bind(&path).await.unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Nothing else is left in the directory
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        // Other files are not replaced
+        let file = dir.path().join("control.txt");
+        std::fs::write(&file, "keep").unwrap();
+        bind(&file).await.unwrap_err();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,113 @@
+//! Going to the background once connected, like `ssh -f`.
+//!
+//! Forking a multi-threaded runtime is not safe, so the foreground process
+//! starts a copy of itself instead and exits when the copy says it is
+//! connected.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use std::io::{self, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use tokio::signal::unix::{SignalKind, signal};
+
+/// Set in the environment of the background process
+const CHILD_ENV: &str = "PENGUIN_DAEMON_CHILD";
+
+/// Held by the background process until it is connected.
+#[derive(Debug)]
+pub(super) struct Daemon {
+    pidfile: Option<&'static Path>,
+}
+
+/// In the foreground process, start the background process, wait until it
+/// is connected, and return `None`. In the background process, detach from
+/// the terminal and return a [`Daemon`].
+pub(super) async fn daemonize(pidfile: Option<&'static Path>) -> Result<Option<Daemon>, Error> {
+    if std::env::var_os(CHILD_ENV).is_some() {
+        nix::unistd::setsid().map_err(|err| Error::Daemon(err.into()))?;
+        return Ok(Some(Daemon { pidfile }));
+    }
+    let mut child = Command::new(std::env::current_exe().map_err(Error::Daemon)?)
+        .args(std::env::args_os().skip(1))
+        .env(CHILD_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(Error::Daemon)?;
+    let mut stdout = child
+        .stdout
+        .take()
+        .expect("Child stdout is piped (this is a bug)");
+    let mut ready = [0; 1];
+    if stdout.read(&mut ready).await.map_err(Error::Daemon)? == 1 {
+        return Ok(None);
+    }
+    // The pipe was closed before the child connected
+    let status = child.wait().await.map_err(Error::Daemon)?;
+    Err(Error::DaemonExited(status))
+}
+
+impl Daemon {
+    /// Write the pidfile, let the foreground process exit, and stop writing
+    /// to its terminal. The returned [`Pidfile`] removes the pidfile when
+    /// dropped.
+    pub fn ready(self) -> io::Result<Option<Pidfile>> {
+        let pidfile = self.pidfile.map(Pidfile::create).transpose()?;
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+        // Nobody reads the pipe after this, and the terminal may be gone.
+        // Keeping either open would also hold up a reader waiting for EOF.
+        let devnull = std::fs::OpenOptions::new().write(true).open("/dev/null")?;
+        nix::unistd::dup2(devnull.as_raw_fd(), stdout.as_raw_fd())?;
+        nix::unistd::dup2(devnull.as_raw_fd(), io::stderr().as_raw_fd())?;
+        Ok(pidfile)
+    }
+}
+
+/// The pidfile of the background process, removed on drop or `SIGTERM`.
+#[derive(Debug)]
+pub(super) struct Pidfile(&'static Path);
+
+impl Pidfile {
+    /// Write our PID to `path`.
+    fn create(path: &'static Path) -> io::Result<Self> {
+        // Handling `SIGTERM` replaces the default action, so exit ourselves
+        let mut sigterm = signal(SignalKind::terminate())?;
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+        tokio::spawn(async move {
+            if sigterm.recv().await.is_some() {
+                std::fs::remove_file(path).ok();
+                std::process::exit(128 + SignalKind::terminate().as_raw_value());
+            }
+        });
+        Ok(Self(path))
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        std::fs::remove_file(self.0).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pidfile_removed_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let path: &'static Path = Box::leak(dir.path().join("penguin.pid").into_boxed_path());
+        let pidfile = Pidfile::create(path).unwrap();
+        let contents = std::fs::read_to_string(path).unwrap();
+        assert_eq!(contents, format!("{}\n", std::process::id()));
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
@@ -10,17 +10,21 @@
 
 pub(super) mod socks;
 mod tcp;
+#[cfg(target_os = "linux")]
+mod tproxy;
 mod udp;
 
 use self::socks::{handle_socks, handle_socks_stdio};
 use self::tcp::{handle_tcp, handle_tcp_stdio};
+#[cfg(target_os = "linux")]
+use self::tproxy::{handle_tproxy_tcp, handle_tproxy_udp};
 use self::udp::{handle_udp, handle_udp_stdio};
 use crate::client::HandlerResources;
 use crate::parse_remote::{LocalSpec, RemoteSpec};
 use crate::parse_remote::{Protocol, Remote};
 use thiserror::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tracing::{debug, error};
+use tracing::debug;
 
 /// Handler errors
 /// These are all fatal errors that will cause the client to exit.
@@ -50,32 +54,52 @@ pub enum FatalError {
 /// to persist after the connection.
 /// This should be spawned as tasks and they will remain as long as `client`
 /// is alive. Individual connection tasks are spawned as connections appear.
-#[tracing::instrument(skip_all, fields(remote = %remote), level = "debug")]
+#[tracing::instrument(skip_all, fields(remote = %remote.label()), level = "debug")]
 pub(super) async fn handle_remote(
     remote: &'static Remote,
     handler_resources: HandlerResources,
 ) -> Result<(), FatalError> {
     debug!("opening remote");
+    let handler_resources = HandlerResources {
+        remote: Some(remote),
+        ..handler_resources
+    };
     match (&remote.local_addr, &remote.remote_addr, remote.protocol) {
-        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Inet((rhost, rport)), Protocol::Tcp) => {
-            handle_tcp(lhost, *lport, rhost, *rport, &handler_resources).await
+        (
+            LocalSpec::Inet((lhost, lport)),
+            RemoteSpec::Inet((rhost, rport)),
+            Protocol::Tcp(options),
+        ) => handle_tcp(lhost, *lport, rhost, *rport, options, &handler_resources).await,
+        (
+            LocalSpec::Inet((lhost, lport)),
+            RemoteSpec::Inet((rhost, rport)),
+            Protocol::Udp(options),
+        ) => handle_udp(lhost, *lport, rhost, *rport, options, &handler_resources).await,
+        (LocalSpec::Stdio, RemoteSpec::Inet((rhost, rport)), Protocol::Tcp(options)) => {
+            handle_tcp_stdio(rhost, *rport, options, &handler_resources).await
         }
-        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Inet((rhost, rport)), Protocol::Udp) => {
-            handle_udp(lhost, *lport, rhost, *rport, &handler_resources).await
+        (LocalSpec::Stdio, RemoteSpec::Inet((rhost, rport)), Protocol::Udp(options)) => {
+            handle_udp_stdio(rhost, *rport, options, &handler_resources).await
         }
-        (LocalSpec::Stdio, RemoteSpec::Inet((rhost, rport)), Protocol::Tcp) => {
-            handle_tcp_stdio(rhost, *rport, &handler_resources).await
+        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Socks, Protocol::Tcp(options)) => {
+            handle_socks(lhost, *lport, options, &handler_resources).await
         }
-        (LocalSpec::Stdio, RemoteSpec::Inet((rhost, rport)), Protocol::Udp) => {
-            handle_udp_stdio(rhost, *rport, &handler_resources).await
+        (LocalSpec::Stdio, RemoteSpec::Socks, Protocol::Tcp(options)) => {
+            handle_socks_stdio(options, &handler_resources).await
         }
-        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Socks, _) => {
-            // The parser guarantees that the protocol is TCP
-            handle_socks(lhost, *lport, &handler_resources).await
+        (_, RemoteSpec::Socks, Protocol::Udp(_)) => {
+            unreachable!("the parser only accepts TCP socks remotes")
         }
-        (LocalSpec::Stdio, RemoteSpec::Socks, _) => {
-            // The parser guarantees that the protocol is TCP
-            handle_socks_stdio(&handler_resources).await
+        #[cfg(target_os = "linux")]
+        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Tproxy, Protocol::Tcp(options)) => {
+            handle_tproxy_tcp(lhost, *lport, options, &handler_resources).await
+        }
+        #[cfg(target_os = "linux")]
+        (LocalSpec::Inet((lhost, lport)), RemoteSpec::Tproxy, Protocol::Udp(options)) => {
+            handle_tproxy_udp(lhost, *lport, options, &handler_resources).await
+        }
+        (_, RemoteSpec::Tproxy, _) => {
+            unreachable!("the parser only accepts local tproxy remotes on Linux")
         }
     }
 }
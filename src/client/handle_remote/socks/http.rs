@@ -0,0 +1,370 @@
+//! HTTP proxy server on the listeners of "socks" remotes, for programs
+//! that speak HTTP proxy instead of SOCKS: CONNECT, and requests for
+//! `http://` URLs, which are forwarded with `Connection: close`. With
+//! `--pac`, it also serves the [PAC file](super::pac).
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::{connect, pac, Error, HandlerResources};
+use crate::arg::Credentials;
+use crate::config;
+use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
+use base64::Engine;
+use bytes::Bytes;
+use http::uri::{Authority, Uri};
+use penguin_mux::TcpOptions;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+/// Hop-by-hop headers not forwarded to the destination
+const HOP_BY_HOP: [&str; 4] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+/// Where a request goes
+#[derive(Debug, PartialEq, Eq)]
+struct Destination {
+    host: String,
+    port: u16,
+    /// Request head to send there, or `None` for CONNECT
+    head: Option<String>,
+}
+
+/// Handle an HTTP proxy request, whose first byte is still in `stream`.
+#[tracing::instrument(skip_all, level = "trace")]
+pub(super) async fn handle_http_connection<RW>(
+    mut stream: RW,
+    tcp_options: TcpOptions,
+    handler_resources: &HandlerResources,
+) -> Result<(), Error>
+where
+    RW: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let head = read_head(&mut stream).await?;
+    if handler_resources.pac {
+        if let Some(body) = pac_request(&head) {
+            // Browsers fetch it without credentials
+            let proxies = pac::proxies(&handler_resources.listening, host(&head).as_ref());
+            let pac = pac::generate(
+                &proxies,
+                handler_resources.local_auth.is_some(),
+                handler_resources.bypass.as_deref(),
+            );
+            let headers = format!("Content-Type: {}\r\n", pac::CONTENT_TYPE);
+            let body = if body { pac.as_str() } else { "" };
+            write_response(&mut stream, "200 OK", &headers, body).await?;
+            return Ok(());
+        }
+    }
+    if let Some(credentials) = handler_resources.local_auth {
+        if !authorized(&head, credentials) {
+            write_response(
+                &mut stream,
+                "407 Proxy Authentication Required",
+                "Proxy-Authenticate: Basic realm=\"penguin\"\r\n",
+                "",
+            )
+            .await?;
+            return Err(Error::BadCredentials);
+        }
+    }
+    let destination = match parse_request(&head) {
+        Ok(destination) => destination,
+        Err(reason) => {
+            write_response(&mut stream, "400 Bad Request", "", "").await?;
+            return Err(Error::HttpRequest(reason));
+        }
+    };
+    debug!(
+        host = destination.host,
+        port = destination.port,
+        "HTTP proxy request"
+    );
+    let channel = connect(
+        Bytes::from(destination.host),
+        destination.port,
+        tcp_options,
+        handler_resources,
+    )
+    .await?;
+    let mut channel = match channel {
+        Ok(channel) => channel,
+        Err(error) => {
+            write_response(&mut stream, "502 Bad Gateway", "", "").await?;
+            return Err(Error::ProcessSocksRequest("open stream", error));
+        }
+    };
+    match destination.head {
+        Some(head) => channel.write_all(head.as_bytes()).await?,
+        None => {
+            stream
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+            stream.flush().await?;
+        }
+    }
+    tokio::io::copy_bidirectional_with_sizes(
+        &mut stream,
+        &mut channel,
+        config::TCP_RELAY_BUFFER_SIZE,
+        config::TCP_RELAY_BUFFER_SIZE,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Read the lines of a request head, without the empty line ending it.
+async fn read_head<R>(stream: &mut R) -> Result<Vec<String>, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop {
+        let mut line = Vec::new();
+        let limit = (config::HTTP_PROXY_MAX_HEAD_SIZE - size) as u64;
+        size += (&mut *stream)
+            .take(limit)
+            .read_until(b'\n', &mut line)
+            .await
+            .map_err(|e| Error::ProcessSocksRequest("read HTTP request", e))?;
+        if !line.ends_with(b"\n") {
+            return Err(Error::HttpRequest("request head too long or incomplete"));
+        }
+        let line = String::from_utf8(line).map_err(|_| Error::HttpRequest("not UTF-8"))?;
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return Ok(lines);
+        }
+        lines.push(line.to_string());
+    }
+}
+
+/// Whether a `Proxy-Authorization` header of `head` has `credentials`.
+fn authorized(head: &[String], credentials: &Credentials) -> bool {
+    let expected = format!("{}:{}", credentials.user, credentials.password);
+    headers(head)
+        .filter(|(name, _)| name.eq_ignore_ascii_case("proxy-authorization"))
+        .filter_map(|(_, value)| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .filter_map(|(_, encoded)| B64_STANDARD_ENGINE.decode(encoded.trim()).ok())
+        .any(|decoded| bool::from(decoded.ct_eq(expected.as_bytes())))
+}
+
+/// The names and values of the headers of `head`.
+fn headers(head: &[String]) -> impl Iterator<Item = (&str, &str)> {
+    head.iter()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+}
+
+/// Whether `head` asks for the PAC file: `Some(true)` for a GET, and
+/// `Some(false)` for a HEAD.
+fn pac_request(head: &[String]) -> Option<bool> {
+    let mut parts = head.first()?.split_ascii_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    if path != pac::PATH {
+        return None;
+    }
+    match method {
+        "GET" => Some(true),
+        "HEAD" => Some(false),
+        _ => None,
+    }
+}
+
+/// The `Host` header of `head`, if valid.
+fn host(head: &[String]) -> Option<Authority> {
+    headers(head)
+        .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Find where the request of `head` goes.
+fn parse_request(head: &[String]) -> Result<Destination, &'static str> {
+    let request_line = head.first().ok_or("empty request")?;
+    let mut parts = request_line.split_ascii_whitespace();
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err("invalid request line");
+    };
+    if method == "CONNECT" {
+        let authority: Authority = target.parse().map_err(|_| "invalid CONNECT target")?;
+        let port = authority.port_u16().ok_or("CONNECT target without port")?;
+        return Ok(Destination {
+            host: strip_brackets(authority.host()).to_string(),
+            port,
+            head: None,
+        });
+    }
+    let uri: Uri = target.parse().map_err(|_| "invalid request target")?;
+    if uri.scheme_str() != Some("http") {
+        return Err("only http:// URLs can be proxied without CONNECT");
+    }
+    let authority = uri.authority().ok_or("URL without host")?;
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let mut forwarded = format!("{method} {path} {version}\r\n");
+    let mut has_host = false;
+    for (name, value) in headers(head) {
+        if HOP_BY_HOP.iter().any(|hop| name.eq_ignore_ascii_case(hop)) {
+            continue;
+        }
+        has_host |= name.eq_ignore_ascii_case("host");
+        forwarded.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !has_host {
+        forwarded.push_str(&format!("Host: {authority}\r\n"));
+    }
+    forwarded.push_str("Connection: close\r\n\r\n");
+    Ok(Destination {
+        host: strip_brackets(authority.host()).to_string(),
+        port: authority.port_u16().unwrap_or(80),
+        head: Some(forwarded),
+    })
+}
+
+/// `host` without the brackets around IPv6 addresses
+fn strip_brackets(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Write a response with `status`, `headers`, and `body`.
+async fn write_response<W>(
+    mut stream: W,
+    status: &str,
+    headers: &str,
+    body: &str,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn head(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(ToString::to_string).collect()
+    }
+
+    #[tokio::test]
+    async fn test_read_head() {
+        let mut reader: &[u8] = b"CONNECT a:1 HTTP/1.1\r\nHost: a:1\r\n\r\nrest";
+        assert_eq!(
+            read_head(&mut reader).await.unwrap(),
+            head(&["CONNECT a:1 HTTP/1.1", "Host: a:1"])
+        );
+        assert_eq!(reader, b"rest");
+        let mut reader: &[u8] = b"GET / HTTP/1.1\r\n";
+        read_head(&mut reader).await.unwrap_err();
+        let long = "X".repeat(config::HTTP_PROXY_MAX_HEAD_SIZE);
+        let request = format!("GET / HTTP/1.1\r\nX: {long}\r\n\r\n");
+        read_head(&mut request.as_bytes()).await.unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_connect() {
+        assert_eq!(
+            parse_request(&head(&["CONNECT example.com:443 HTTP/1.1"])),
+            Ok(Destination {
+                host: "example.com".to_string(),
+                port: 443,
+                head: None,
+            })
+        );
+        assert_eq!(
+            parse_request(&head(&["CONNECT [2001:db8::1]:22 HTTP/1.1"]))
+                .unwrap()
+                .host,
+            "2001:db8::1"
+        );
+        parse_request(&head(&["CONNECT example.com HTTP/1.1"])).unwrap_err();
+        parse_request(&head(&["CONNECT example.com:443"])).unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_absolute() {
+        let destination = parse_request(&head(&[
+            "GET http://example.com:8080/a?b=c HTTP/1.1",
+            "Host: example.com:8080",
+            "Proxy-Authorization: Basic YWRtaW46cGFzcw==",
+            "Proxy-Connection: keep-alive",
+            "Accept: */*",
+        ]))
+        .unwrap();
+        assert_eq!(destination.host, "example.com");
+        assert_eq!(destination.port, 8080);
+        assert_eq!(
+            destination.head.unwrap(),
+            "GET /a?b=c HTTP/1.1\r\n\
+             Host: example.com:8080\r\n\
+             Accept: */*\r\n\
+             Connection: close\r\n\r\n"
+        );
+        let destination = parse_request(&head(&["GET http://example.com HTTP/1.0"])).unwrap();
+        assert_eq!(destination.port, 80);
+        assert_eq!(
+            destination.head.unwrap(),
+            "GET / HTTP/1.0\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+        );
+        parse_request(&head(&["GET / HTTP/1.1", "Host: example.com"])).unwrap_err();
+        parse_request(&head(&["GET https://example.com/ HTTP/1.1"])).unwrap_err();
+    }
+
+    #[test]
+    fn test_pac_request() {
+        assert_eq!(pac_request(&head(&["GET /proxy.pac HTTP/1.1"])), Some(true));
+        assert_eq!(
+            pac_request(&head(&["HEAD /proxy.pac?v=2 HTTP/1.1"])),
+            Some(false)
+        );
+        assert_eq!(pac_request(&head(&["POST /proxy.pac HTTP/1.1"])), None);
+        assert_eq!(pac_request(&head(&["GET / HTTP/1.1"])), None);
+        assert_eq!(
+            pac_request(&head(&["GET http://example.com/proxy.pac HTTP/1.1"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_authorized() {
+        let credentials = "admin:pass".parse().unwrap();
+        let with = |value: &str| head(&["CONNECT a:1 HTTP/1.1", value]);
+        assert!(authorized(
+            &with("Proxy-Authorization: Basic YWRtaW46cGFzcw=="),
+            &credentials
+        ));
+        assert!(authorized(
+            &with("proxy-authorization:  basic YWRtaW46cGFzcw=="),
+            &credentials
+        ));
+        // admin:wrong
+        assert!(!authorized(
+            &with("Proxy-Authorization: Basic YWRtaW46d3Jvbmc="),
+            &credentials
+        ));
+        assert!(!authorized(
+            &with("Authorization: Basic YWRtaW46cGFzcw=="),
+            &credentials
+        ));
+        assert!(!authorized(&with("Accept: */*"), &credentials));
+    }
+}
@@ -1,22 +1,25 @@
-//! SOCKS server.
+//! SOCKS server, which also answers HTTP proxy requests.
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+mod http;
+mod pac;
 mod v4;
 mod v5;
 
-use super::tcp::{open_tcp_listener, request_tcp_channel};
+use super::tcp::{open_tcp_listener, request_tcp_channel, set_tcp_options};
+use super::udp::bind_udp_socket;
 use super::HandlerResources;
-use crate::client::StreamCommand;
+use crate::client::DatagramCommand;
 use crate::{config, Dupe};
 use bytes::{Buf, Bytes};
-use penguin_mux::DatagramFrame;
+use penguin_mux::{DatagramFrame, TcpOptions};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncBufRead, BufStream};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufStream};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
+use tokio::net::{TcpStream, UdpSocket};
 use tokio::task::JoinSet;
 use tracing::{debug, info, trace, warn};
 
@@ -37,8 +40,12 @@ pub enum Error {
     ProcessSocksRequest(&'static str, std::io::Error),
     #[error("Cannot parse SOCKS associate datagram")]
     ParseAssociate,
-    #[error("Client does not support NOAUTH")]
+    #[error("Client does not support the authentication method we need")]
     OtherAuth,
+    #[error("Client did not authenticate with the right credentials")]
+    BadCredentials,
+    #[error("Invalid HTTP proxy request: {0}")]
+    HttpRequest(&'static str),
     /// Fatal error that we should propagate to main.
     #[error(transparent)]
     Fatal(#[from] super::FatalError),
@@ -47,12 +54,19 @@ pub enum Error {
 pub(super) async fn handle_socks(
     lhost: &'static str,
     lport: u16,
+    tcp_options: TcpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), super::FatalError> {
     // Failing to open the listener is a fatal error and should be propagated.
-    let listener = open_tcp_listener(lhost, lport)
-        .await
-        .map_err(super::FatalError::ClientIo)?;
+    let listener = open_tcp_listener(
+        lhost,
+        lport,
+        handler_resources.buffers,
+        handler_resources.listen_interface,
+    )
+    .await
+    .map_err(super::FatalError::ClientIo)?;
+    handler_resources.bound(listener.local_addr().map_err(super::FatalError::ClientIo)?);
     let mut socks_jobs = JoinSet::new();
     loop {
         tokio::select! {
@@ -67,10 +81,11 @@ pub(super) async fn handle_socks(
             }
             result = listener.accept() => {
                 // A failed accept() is a fatal error and should be propagated.
-                let (stream, _) = result.map_err(super::FatalError::ClientIo)?;
+                let (stream, peer) = result.map_err(super::FatalError::ClientIo)?;
+                set_tcp_options(&stream, &tcp_options, peer);
                 let handler_resources = handler_resources.dupe();
                 socks_jobs.spawn(async move {
-                    handle_socks_connection(stream, lhost, &handler_resources).await
+                    handle_socks_connection(stream, Some(peer.ip()), lhost, tcp_options, &handler_resources).await
                 });
             }
         }
@@ -79,10 +94,17 @@ pub(super) async fn handle_socks(
 
 #[inline]
 pub(super) async fn handle_socks_stdio(
+    tcp_options: TcpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), super::FatalError> {
-    if let Err(e) =
-        handle_socks_connection(super::Stdio::new(), "localhost", handler_resources).await
+    if let Err(e) = handle_socks_connection(
+        super::Stdio::new(),
+        None,
+        "localhost",
+        tcp_options,
+        handler_resources,
+    )
+    .await
     {
         if let Error::Fatal(e) = e {
             return Err(e);
@@ -92,27 +114,43 @@ pub(super) async fn handle_socks_stdio(
     Ok(())
 }
 
-/// Handle a SOCKS5 connection.
+/// Handle a SOCKS4, SOCKS5, or HTTP proxy connection.
 /// Based on socksv5's example.
 /// We need to be able to request additional channels, so we need `command_tx`
+/// The server sets `tcp_options` on the connections it makes for us.
+/// `peer` is the address of the client, or `None` for stdio.
 #[tracing::instrument(skip_all, level = "trace")]
 #[inline]
 pub(super) async fn handle_socks_connection<RW>(
     stream: RW,
+    peer: Option<IpAddr>,
     local_addr: &str,
+    tcp_options: TcpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), Error>
 where
     RW: AsyncRead + AsyncWrite + Unpin,
 {
     let mut bufrw = BufStream::new(stream);
-    let version = bufrw
-        .read_u8()
-        .await
-        .map_err(|e| Error::ProcessSocksRequest("read version", e))?;
+    let version = match bufrw.fill_buf().await {
+        Ok([version, ..]) => *version,
+        Ok([]) => {
+            let eof = std::io::ErrorKind::UnexpectedEof.into();
+            return Err(Error::ProcessSocksRequest("read version", eof));
+        }
+        Err(e) => return Err(Error::ProcessSocksRequest("read version", e)),
+    };
     match version {
-        4 => handle_socks4_connection(bufrw, handler_resources).await,
-        5 => handle_socks5_connection(bufrw, local_addr, handler_resources).await,
+        4 => {
+            bufrw.consume(1);
+            handle_socks4_connection(bufrw, tcp_options, handler_resources).await
+        }
+        5 => {
+            bufrw.consume(1);
+            handle_socks5_connection(bufrw, peer, local_addr, tcp_options, handler_resources).await
+        }
+        // The method of an HTTP request
+        b'A'..=b'Z' => http::handle_http_connection(bufrw, tcp_options, handler_resources).await,
         version => Err(Error::SocksVersion(version)),
     }
 }
@@ -120,6 +158,7 @@ where
 #[inline]
 async fn handle_socks4_connection<RW>(
     mut stream: RW,
+    tcp_options: TcpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), Error>
 where
@@ -127,15 +166,14 @@ where
 {
     let (command, rhost, rport) = v4::read_request(&mut stream).await?;
     trace!("SOCKSv4 request rhost={rhost:?} rport={rport}");
+    if handler_resources.local_auth.is_some() {
+        // SOCKS4 has no passwords
+        v4::write_response(&mut stream, 0x5b).await?;
+        return Err(Error::OtherAuth);
+    }
     if command == 0x01 {
         // CONNECT
-        // This fails only if main has exited, which is a fatal error.
-        let stream_command_tx_permit = handler_resources
-            .stream_command_tx
-            .reserve()
-            .await
-            .map_err(|_| super::FatalError::RequestStream)?;
-        handle_connect(stream, rhost, rport, stream_command_tx_permit, false).await
+        handle_connect(stream, rhost, rport, tcp_options, handler_resources, false).await
     } else {
         v4::write_response(&mut stream, 0x5b).await?;
         Err(Error::InvalidCommand(command))
@@ -145,7 +183,9 @@ where
 #[inline]
 async fn handle_socks5_connection<RW>(
     mut stream: RW,
+    peer: Option<IpAddr>,
     local_addr: &str,
+    tcp_options: TcpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), Error>
 where
@@ -153,32 +193,44 @@ where
 {
     // Complete the handshake
     let methods = v5::read_auth_methods(&mut stream).await?;
-    if !methods.contains(&0x00) {
+    // USERNAME/PASSWORD if we need credentials, NO AUTHENTICATION REQUIRED
+    // otherwise
+    let method = if handler_resources.local_auth.is_some() {
+        0x02
+    } else {
+        0x00
+    };
+    if !methods.contains(&method) {
         // Send back NO ACCEPTABLE METHODS
         // Note that we are not compliant with RFC 1928 here, as we MUST
-        // support GSSAPI and SHOULD support USERNAME/PASSWORD
+        // support GSSAPI
         v5::write_auth_method(&mut stream, 0xff).await?;
         return Err(Error::OtherAuth);
     }
-    // Send back NO AUTHENTICATION REQUIRED
-    v5::write_auth_method(&mut stream, 0x00).await?;
+    v5::write_auth_method(&mut stream, method).await?;
+    if let Some(credentials) = handler_resources.local_auth {
+        let (user, password) = v5::read_credentials(&mut stream).await?;
+        let success = bool::from(
+            user.ct_eq(credentials.user.as_bytes())
+                & password.ct_eq(credentials.password.as_bytes()),
+        );
+        v5::write_auth_status(&mut stream, success).await?;
+        if !success {
+            return Err(Error::BadCredentials);
+        }
+    }
     // Read the request
     let (command, rhost, rport) = v5::read_request(&mut stream).await?;
     trace!("SOCKSv5 cmd={command} rhost={rhost:?} rport={rport}");
     match command {
         0x01 => {
             // CONNECT
-            // This fails only if main has exited, which is a fatal error.
-            let stream_command_tx_permit = handler_resources
-                .stream_command_tx
-                .reserve()
-                .await
-                .map_err(|_| super::FatalError::RequestStream)?;
-            handle_connect(stream, rhost, rport, stream_command_tx_permit, true).await
+            handle_connect(stream, rhost, rport, tcp_options, handler_resources, true).await
         }
         0x03 => {
             // UDP ASSOCIATE
-            handle_associate(stream, rhost, rport, local_addr, handler_resources).await
+            let client = RelayClient::new(peer, &rhost, rport);
+            handle_associate(stream, client, local_addr, handler_resources).await
         }
         // We don't support BIND because I can't ask the remote host to bind
         _ => {
@@ -201,7 +253,8 @@ async fn handle_connect<RW>(
     mut stream: RW,
     rhost: Bytes,
     rport: u16,
-    stream_command_tx_permit: mpsc::Permit<'_, StreamCommand>,
+    tcp_options: TcpOptions,
+    handler_resources: &HandlerResources,
     version_is_5: bool,
 ) -> Result<(), Error>
 where
@@ -209,9 +262,20 @@ where
 {
     debug!("SOCKS connect");
     // Establish a connection to the remote host
-    let mut channel = request_tcp_channel(stream_command_tx_permit, rhost, rport)
-        .await
-        .map_err(|_| super::FatalError::MainLoopExitWithoutSendingStream)?;
+    let channel = connect(rhost, rport, tcp_options, handler_resources).await?;
+    let mut channel = match channel {
+        Ok(channel) => channel,
+        Err(error) => {
+            // Tell the client why the server rejected it
+            if version_is_5 {
+                v5::write_response_unspecified(&mut stream, v5::error_reply(&error)).await?;
+            } else {
+                v4::write_response(&mut stream, 0x5b).await?;
+                stream.flush().await?;
+            }
+            return Err(Error::ProcessSocksRequest("open stream", error));
+        }
+    };
     // Send back a successful response
     if version_is_5 {
         v5::write_response_unspecified(&mut stream, 0x00).await?;
@@ -219,22 +283,66 @@ where
         v4::write_response(&mut stream, 0x5a).await?;
     };
     stream.flush().await?;
-    tokio::io::copy_bidirectional(&mut stream, &mut channel).await?;
+    tokio::io::copy_bidirectional_with_sizes(
+        &mut stream,
+        &mut channel,
+        config::TCP_RELAY_BUFFER_SIZE,
+        config::TCP_RELAY_BUFFER_SIZE,
+    )
+    .await?;
     Ok(())
 }
 
+/// A connection [`connect`] opened
+trait Outbound: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Outbound for T {}
+
+/// Connect to `rhost:rport` from here if the `--bypass-file` rules match
+/// it, or open a stream to it through the server otherwise.
+async fn connect(
+    rhost: Bytes,
+    rport: u16,
+    tcp_options: TcpOptions,
+    handler_resources: &HandlerResources,
+) -> Result<std::io::Result<Box<dyn Outbound>>, super::FatalError> {
+    let host = String::from_utf8_lossy(&rhost);
+    if let Some(bypass) = &handler_resources.bypass {
+        if bypass.matches(&host) {
+            debug!("connecting directly");
+            let stream = TcpStream::connect((&*host, rport)).await.map(|stream| {
+                if let Ok(peer) = stream.peer_addr() {
+                    set_tcp_options(&stream, &tcp_options, peer);
+                }
+                Box::new(stream) as Box<dyn Outbound>
+            });
+            return Ok(stream);
+        }
+    }
+    // This fails only if main has exited, which is a fatal error.
+    let stream_command_tx_permit = handler_resources
+        .stream_command_tx
+        .reserve()
+        .await
+        .map_err(|_| super::FatalError::RequestStream)?;
+    let channel = request_tcp_channel(
+        stream_command_tx_permit,
+        handler_resources.overrides.as_deref(),
+        handler_resources.socks_resolver.as_deref(),
+        rhost,
+        rport,
+        tcp_options,
+    )
+    .await
+    .map_err(|_| super::FatalError::MainLoopExitWithoutSendingStream)?;
+    Ok(channel.map(|channel| Box::new(channel) as Box<dyn Outbound>))
+}
+
 #[inline]
-#[tracing::instrument(
-    skip_all,
-    fields(
-        host = %String::from_utf8_lossy(&rhost),
-        port = rport,
-    ),
-)]
+#[tracing::instrument(skip_all, fields(client = ?client))]
 async fn handle_associate<RW>(
     mut stream: RW,
-    rhost: Bytes,
-    rport: u16,
+    client: RelayClient,
     local_addr: &str,
     handler_resources: &HandlerResources,
 ) -> Result<(), Error>
@@ -242,7 +350,7 @@ where
     RW: AsyncRead + AsyncWrite + Unpin,
 {
     debug!("SOCKS associate");
-    let socket = match UdpSocket::bind((local_addr, 0)).await {
+    let socket = match bind_udp_socket(local_addr, 0, handler_resources.listen_interface).await {
         Ok(s) => s,
         Err(e) => {
             v5::write_response_unspecified(&mut stream, 0x01).await?;
@@ -256,26 +364,63 @@ where
             return Err(Error::ProcessSocksRequest("get udp socket local addr", e));
         }
     };
-    let relay_task = tokio::spawn(udp_relay(rhost, rport, handler_resources.dupe(), socket));
+    let relay_task = tokio::spawn(udp_relay(client, handler_resources.dupe(), socket));
     // Send back a successful response
     v5::write_response(&mut stream, 0x00, sock_local_addr).await?;
     // My crude way to detect when the client closes the connection
-    stream.read(&mut [0; 1]).await.ok();
+    stream.read_u8().await.ok();
     relay_task.abort();
     Ok(())
 }
 
+/// Where the client of a UDP ASSOCIATE may send datagrams from
+#[derive(Clone, Copy, Debug)]
+struct RelayClient {
+    /// Address of the TCP connection, or `None` for stdio, whose client
+    /// can only be local
+    peer: Option<IpAddr>,
+    /// DST.ADDR of the request, if the client gave one
+    addr: Option<IpAddr>,
+    /// DST.PORT of the request, if the client gave one
+    port: Option<u16>,
+}
+
+impl RelayClient {
+    /// The client at `peer` that asked to send from `rhost`:`rport`, where
+    /// zeros mean that it does not know yet (RFC 1928 section 6).
+    fn new(peer: Option<IpAddr>, rhost: &[u8], rport: u16) -> Self {
+        let addr = std::str::from_utf8(rhost)
+            .ok()
+            .and_then(|host| host.parse::<IpAddr>().ok())
+            .filter(|addr| !addr.is_unspecified())
+            .map(|addr| addr.to_canonical());
+        Self {
+            peer: peer.map(|peer| peer.to_canonical()),
+            addr,
+            port: (rport != 0).then_some(rport),
+        }
+    }
+
+    /// Whether a datagram from `source` is from this client.
+    fn allows(&self, source: SocketAddr) -> bool {
+        let ip = source.ip().to_canonical();
+        self.peer.map_or(ip.is_loopback(), |peer| peer == ip)
+            && self.addr.is_none_or(|addr| addr == ip)
+            && self.port.is_none_or(|port| port == source.port())
+    }
+}
+
 /// UDP task spawned by the TCP connection
 #[allow(clippy::similar_names)]
 async fn udp_relay(
-    _rhost: Bytes,
-    _rport: u16,
+    client: RelayClient,
     handler_resources: HandlerResources,
     socket: UdpSocket,
 ) -> Result<(), Error> {
     let socket = Arc::new(socket);
     loop {
-        let Some((dst, dport, data, src, sport)) = handle_udp_relay_header(&socket).await? else {
+        let Some((dst, dport, data, src, sport)) = handle_udp_relay_header(&socket, client).await?
+        else {
             continue;
         };
         let client_id = handler_resources
@@ -290,20 +435,28 @@ async fn udp_relay(
         // This fails only if main has exited, which is a fatal error.
         handler_resources
             .datagram_tx
-            .send(datagram_frame)
+            .send(DatagramCommand {
+                frame: datagram_frame,
+                reliable: false,
+            })
             .await
             .map_err(|_| super::FatalError::SendDatagram)?;
     }
 }
 
-/// Parse a UDP relay request.
+/// Parse a UDP relay request from `client`, dropping those from anyone else.
 /// Returns (dst, dport, data, src, sport)
 #[inline]
 async fn handle_udp_relay_header(
     socket: &UdpSocket,
+    client: RelayClient,
 ) -> Result<Option<(Bytes, u16, Bytes, IpAddr, u16)>, Error> {
     let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
     let (len, addr) = socket.recv_from(&mut buf).await?;
+    if !client.allows(addr) {
+        warn!("Dropping datagram from {addr}, which is not the SOCKS client");
+        return Ok(None);
+    }
     buf.truncate(len);
     let mut buf = Bytes::from(buf);
     if buf.remaining() < 4 {
@@ -381,3 +534,180 @@ pub async fn send_udp_relay_response(
     content.extend(data);
     socket.send_to(&content, target).await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    /// Run `handle_socks_connection` requiring `admin:pass` if `auth`,
+    /// serving a PAC file if `pac`, and with the `bypass` rules, on one end
+    /// of a pipe, and return the other end.
+    fn serve(
+        auth: bool,
+        pac: bool,
+        bypass: Option<&str>,
+    ) -> (
+        tokio::io::DuplexStream,
+        tokio::task::JoinHandle<Result<(), Error>>,
+    ) {
+        let (stream_command_tx, _) = mpsc::channel(1);
+        let (datagram_tx, _) = mpsc::channel(1);
+        let handler_resources = HandlerResources {
+            local_auth: auth.then(|| &*Box::leak(Box::new("admin:pass".parse().unwrap()))),
+            pac,
+            bypass: bypass.map(|rules| Arc::new(rules.parse().unwrap())),
+            ..HandlerResources::for_test(stream_command_tx, datagram_tx)
+        };
+        let (client, server) = tokio::io::duplex(1 << 10);
+        let task = tokio::spawn(async move {
+            handle_socks_connection(
+                server,
+                Some(IpAddr::from([127, 0, 0, 1])),
+                "127.0.0.1",
+                TcpOptions::default(),
+                &handler_resources,
+            )
+            .await
+        });
+        (client, task)
+    }
+
+    #[tokio::test]
+    async fn test_socks5_auth() {
+        let (mut client, task) = serve(true, false, None);
+        // Only NO AUTHENTICATION REQUIRED
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0x05, 0xff]);
+        assert!(matches!(task.await.unwrap(), Err(Error::OtherAuth)));
+
+        let (mut client, task) = serve(true, false, None);
+        client.write_all(&[0x05, 0x02, 0x00, 0x02]).await.unwrap();
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0x05, 0x02]);
+        client.write_all(b"\x01\x05admin\x05wrong").await.unwrap();
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0x01, 0x01]);
+        assert!(matches!(task.await.unwrap(), Err(Error::BadCredentials)));
+
+        let (mut client, task) = serve(true, false, None);
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        client.read_exact(&mut response).await.unwrap();
+        client.write_all(b"\x01\x05admin\x04pass").await.unwrap();
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0x01, 0x00]);
+        drop(client);
+        // Authenticated, but the request never came
+        assert!(matches!(
+            task.await.unwrap(),
+            Err(Error::ProcessSocksRequest("read version", _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_socks4_refused_with_auth() {
+        let (mut client, task) = serve(true, false, None);
+        client
+            .write_all(&[0x04, 0x01, 0x00, 0x50, 0x7f, 0x00, 0x00, 0x01, 0x00])
+            .await
+            .unwrap();
+        let mut response = [0; 8];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[1], 0x5b);
+        assert!(matches!(task.await.unwrap(), Err(Error::OtherAuth)));
+    }
+
+    #[tokio::test]
+    async fn test_http_auth() {
+        let (mut client, task) = serve(true, false, None);
+        client
+            .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 407 "));
+        assert!(response.contains("Proxy-Authenticate: Basic"));
+        assert!(matches!(task.await.unwrap(), Err(Error::BadCredentials)));
+    }
+
+    #[tokio::test]
+    async fn test_socks5_bypass() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"direct").await.unwrap();
+        });
+        // The main loop is gone, so only a direct connection works
+        let (mut client, task) = serve(false, false, Some("127.0.0.0/8"));
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        client
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 0x7f, 0x00, 0x00, 0x01])
+            .await
+            .unwrap();
+        client.write_all(&port.to_be_bytes()).await.unwrap();
+        let mut response = [0; 12];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response[..2], [0x05, 0x00]);
+        assert_eq!(response[2..4], [0x05, 0x00]);
+        let mut data = [0; 6];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"direct");
+        drop(client);
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_pac() {
+        let (mut client, task) = serve(true, true, None);
+        client
+            .write_all(b"GET /proxy.pac HTTP/1.1\r\nHost: 192.168.1.2:1080\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/x-ns-proxy-autoconfig\r\n"));
+        assert!(response.ends_with("function FindProxyForURL(url, host) {\n    return \"\";\n}\n"));
+        task.await.unwrap().unwrap();
+
+        // Only with --pac
+        let (mut client, task) = serve(true, false, None);
+        client
+            .write_all(b"GET /proxy.pac HTTP/1.1\r\nHost: 192.168.1.2:1080\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 407 "));
+        task.await.unwrap().unwrap_err();
+    }
+
+    #[test]
+    fn test_relay_client() {
+        let peer = IpAddr::from([192, 168, 1, 2]);
+        let from = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+        // Without DST.ADDR and DST.PORT, any port of the TCP client
+        let client = RelayClient::new(Some(peer), b"0.0.0.0", 0);
+        assert!(client.allows(from("192.168.1.2:5000")));
+        assert!(client.allows(from("[::ffff:192.168.1.2]:5001")));
+        assert!(!client.allows(from("192.168.1.3:5000")));
+        // Otherwise only those
+        let client = RelayClient::new(Some(peer), b"192.168.1.2", 5000);
+        assert!(client.allows(from("192.168.1.2:5000")));
+        assert!(!client.allows(from("192.168.1.2:5001")));
+        let client = RelayClient::new(Some(peer), b"192.168.1.3", 0);
+        assert!(!client.allows(from("192.168.1.3:5000")));
+        assert!(!client.allows(from("192.168.1.2:5000")));
+        // A domain name is not an address to check
+        let client = RelayClient::new(Some(peer), b"example.com", 5000);
+        assert!(client.allows(from("192.168.1.2:5000")));
+        // Over stdio, only local clients
+        let client = RelayClient::new(None, b"0.0.0.0", 0);
+        assert!(client.allows(from("127.0.0.1:5000")));
+        assert!(!client.allows(from("192.168.1.2:5000")));
+    }
+}
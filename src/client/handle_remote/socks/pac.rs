@@ -0,0 +1,109 @@
+//! Proxy auto-config (PAC) file, which the HTTP proxy of "socks" remotes
+//! serves with `--pac` to send browsers through them.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::client::bypass::Bypass;
+use crate::client::Listening;
+use crate::parse_remote::RemoteSpec;
+use http::uri::Authority;
+
+/// Where the PAC file is served
+pub(super) const PATH: &str = "/proxy.pac";
+
+/// `Content-Type` of PAC files
+pub(super) const CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// The `host:port`s of the "socks" remotes listening, with the host of
+/// `authority` (the `Host` the PAC file was requested from) for those
+/// listening on all addresses.
+pub(super) fn proxies(listening: &Listening, authority: Option<&Authority>) -> Vec<String> {
+    listening
+        .addrs
+        .lock()
+        .iter()
+        .filter(|(remote, _)| matches!(remote.remote_addr, RemoteSpec::Socks))
+        .map(|(_, addr)| match authority {
+            Some(authority) if addr.ip().is_unspecified() => {
+                format!("{}:{}", authority.host(), addr.port())
+            }
+            _ => addr.to_string(),
+        })
+        .collect()
+}
+
+/// A PAC file that sends everything through `proxies`, except what
+/// matches the `bypass` rules. Browsers cannot authenticate to SOCKS5
+/// proxies, so they only get the HTTP proxies if they must authenticate
+/// (`auth`).
+pub(super) fn generate(proxies: &[String], auth: bool, bypass: Option<&Bypass>) -> String {
+    let proxies = proxies
+        .iter()
+        .map(|proxy| {
+            if auth {
+                format!("PROXY {proxy}")
+            } else {
+                format!("SOCKS5 {proxy}; PROXY {proxy}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("; ");
+    let direct = bypass
+        .and_then(Bypass::pac_condition)
+        .map(|condition| format!("    if ({condition}) {{\n        return \"DIRECT\";\n    }}\n"))
+        .unwrap_or_default();
+    format!("function FindProxyForURL(url, host) {{\n{direct}    return \"{proxies}\";\n}}\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse_remote::Remote;
+
+    #[test]
+    fn test_proxies() {
+        let listening = Listening::default();
+        for (remote, addr) in [
+            ("0.0.0.0:1080:socks", "0.0.0.0:1080"),
+            ("8080:example.com:80", "0.0.0.0:8080"),
+            ("127.0.0.1:1081:socks", "127.0.0.1:1081"),
+        ] {
+            let remote: &'static Remote = Box::leak(Box::new(remote.parse().unwrap()));
+            listening.addrs.lock().push((remote, addr.parse().unwrap()));
+        }
+        assert_eq!(
+            proxies(&listening, None),
+            ["0.0.0.0:1080", "127.0.0.1:1081"]
+        );
+        let authority = "192.168.1.2:1080".parse().unwrap();
+        assert_eq!(
+            proxies(&listening, Some(&authority)),
+            ["192.168.1.2:1080", "127.0.0.1:1081"]
+        );
+    }
+
+    #[test]
+    fn test_generate() {
+        let proxies = ["192.168.1.2:1080".to_string(), "[::1]:1081".to_string()];
+        assert_eq!(
+            generate(&proxies, false, None),
+            "function FindProxyForURL(url, host) {\n    \
+             return \"SOCKS5 192.168.1.2:1080; PROXY 192.168.1.2:1080; \
+             SOCKS5 [::1]:1081; PROXY [::1]:1081\";\n}\n"
+        );
+        assert_eq!(
+            generate(&proxies[..1], true, Some(&Bypass::default())),
+            "function FindProxyForURL(url, host) {\n    \
+             return \"PROXY 192.168.1.2:1080\";\n}\n"
+        );
+        let bypass = "lan\n".parse().unwrap();
+        assert_eq!(
+            generate(&proxies[..1], true, Some(&bypass)),
+            "function FindProxyForURL(url, host) {\n    \
+             if (host == \"lan\" || dnsDomainIs(host, \".lan\")) {\n        \
+             return \"DIRECT\";\n    \
+             }\n    \
+             return \"PROXY 192.168.1.2:1080\";\n}\n"
+        );
+    }
+}
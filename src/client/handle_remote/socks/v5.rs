@@ -1,4 +1,4 @@
-//! SOCKS5 server helpers (RFC 1928).
+//! SOCKS5 server helpers (RFC 1928 and RFC 1929).
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
@@ -30,6 +30,25 @@ where
     Ok(methods)
 }
 
+/// The reply code for a stream that failed with `error`.
+#[must_use]
+pub fn error_reply(error: &std::io::Error) -> u8 {
+    match error.kind() {
+        // Connection not allowed by ruleset
+        std::io::ErrorKind::PermissionDenied => 0x02,
+        // Network unreachable
+        std::io::ErrorKind::NetworkUnreachable => 0x03,
+        // Host unreachable
+        std::io::ErrorKind::HostUnreachable => 0x04,
+        // Connection refused
+        std::io::ErrorKind::ConnectionRefused => 0x05,
+        // TTL expired, the closest to a timeout
+        std::io::ErrorKind::TimedOut => 0x06,
+        // General SOCKS server failure
+        _ => 0x01,
+    }
+}
+
 /// Write a SOCKS5 authentication method selection to the given writer.
 ///
 /// # Errors
@@ -50,6 +69,60 @@ where
     Ok(())
 }
 
+/// Read a username/password authentication request (RFC 1929) from the
+/// given reader. Returns the user name and the password.
+///
+/// # Errors
+/// Underlying I/O error with a description of the context.
+#[inline]
+pub async fn read_credentials<R>(mut reader: R) -> Result<(Vec<u8>, Vec<u8>), Error>
+where
+    R: AsyncRead + Unpin,
+{
+    let version = reader
+        .read_u8()
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("read auth version", e))?;
+    if version != 0x01 {
+        return Err(Error::SocksVersion(version));
+    }
+    let mut fields = [vec![], vec![]];
+    for field in &mut fields {
+        let len = reader
+            .read_u8()
+            .await
+            .map_err(|e| Error::ProcessSocksRequest("read credential length", e))?;
+        field.resize(usize::from(len), 0);
+        reader
+            .read_exact(field)
+            .await
+            .map_err(|e| Error::ProcessSocksRequest("read credential", e))?;
+    }
+    let [user, password] = fields;
+    Ok((user, password))
+}
+
+/// Write the status of a username/password authentication (RFC 1929) to
+/// the given writer.
+///
+/// # Errors
+/// Underlying I/O error with a description of the context.
+#[inline]
+pub async fn write_auth_status<W>(mut writer: W, success: bool) -> Result<(), Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer
+        .write_all(&[0x01, u8::from(!success)])
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("write auth status", e))?;
+    writer
+        .flush()
+        .await
+        .map_err(|e| Error::ProcessSocksRequest("flush", e))?;
+    Ok(())
+}
+
 /// Read a SOCKS5 request from the given reader. Returns the command, address, and port.
 /// Writes an `Address type not supported` response to the given writer if the address type is not
 /// valid.
@@ -219,6 +292,24 @@ mod test {
         assert_eq!(writer.get_ref(), &[0x05, 0x00]);
     }
 
+    #[tokio::test]
+    async fn test_read_credentials() {
+        let mut reader = Cursor::new(b"\x01\x05admin\x00".to_vec());
+        let (user, password) = read_credentials(&mut reader).await.unwrap();
+        assert_eq!(user, b"admin");
+        assert_eq!(password, b"");
+        let mut reader = Cursor::new(b"\x05\x05admin\x00".to_vec());
+        read_credentials(&mut reader).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_write_auth_status() {
+        let mut writer = Cursor::new(vec![]);
+        write_auth_status(&mut writer, true).await.unwrap();
+        write_auth_status(&mut writer, false).await.unwrap();
+        assert_eq!(writer.get_ref(), &[0x01, 0x00, 0x01, 0x01]);
+    }
+
     #[tokio::test]
     async fn test_read_request_v4() {
         let mut reader = Cursor::new(vec![
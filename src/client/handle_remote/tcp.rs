@@ -4,47 +4,141 @@
 
 use super::super::MaybeRetryableError;
 use super::FatalError;
+use crate::client::overrides::Overrides;
 use crate::client::HandlerResources;
 use crate::client::{MuxStream, StreamCommand};
+use crate::config;
+use crate::resolver::Resolver;
+use crate::sockopt::{self, BufferSizes};
 use bytes::Bytes;
+use penguin_mux::TcpOptions;
+use std::net::SocketAddr;
 use tokio::{
-    net::TcpListener,
+    net::{lookup_host, TcpListener, TcpStream},
     sync::{mpsc, oneshot},
 };
-use tracing::{error, info, warn};
+use tracing::{debug, debug_span, error, info, warn, Instrument, Span};
 
-/// Request a channel from the mux
-/// Returns an error if the main loop timed out waiting for a response.
+/// Request a channel from the mux, rewriting the destination with the
+/// `overrides` and then resolving `dest_host` if there is a `resolver`. The
+/// server sets `tcp_options` on its connection.
+/// Returns an error if the main loop timed out waiting for a response, or
+/// the inner error if the server rejected the stream or the host could not
+/// be resolved.
 #[inline]
-#[tracing::instrument(skip(stream_command_tx_permit), level = "debug")]
+#[tracing::instrument(skip(stream_command_tx_permit, overrides, resolver), level = "debug")]
 pub(super) async fn request_tcp_channel(
     stream_command_tx_permit: mpsc::Permit<'_, StreamCommand>,
+    overrides: Option<&Overrides>,
+    resolver: Option<&Resolver>,
     dest_host: Bytes,
     dest_port: u16,
-) -> Result<MuxStream, oneshot::error::RecvError> {
+    tcp_options: TcpOptions,
+) -> Result<std::io::Result<MuxStream>, oneshot::error::RecvError> {
+    let (dest_host, dest_port) =
+        match overrides.and_then(|overrides| overrides.rewrite(&dest_host, dest_port)) {
+            Some((host, port)) => {
+                debug!("rewritten to {}:{port}", String::from_utf8_lossy(&host));
+                (host, port)
+            }
+            None => (dest_host, dest_port),
+        };
+    let dest_host = match resolver {
+        Some(resolver) => match resolver.resolve(&dest_host).await {
+            Ok(address) => address,
+            Err(error) => return Ok(Err(error.into())),
+        },
+        None => dest_host,
+    };
     let (tx, rx) = oneshot::channel();
+    // Each stream is a trace of its own, linked to the listener
+    let span = debug_span!(
+        parent: None,
+        "stream",
+        host = %String::from_utf8_lossy(&dest_host),
+        port = dest_port,
+    );
+    span.follows_from(Span::current());
     let stream_request = StreamCommand {
         tx,
         host: dest_host,
         port: dest_port,
+        tcp_options,
+        span: span.clone(),
     };
     stream_command_tx_permit.send(stream_request);
-    rx.await
+    rx.instrument(span).await
 }
 
-/// Open a TCP listener.
+/// Open a TCP listener with `buffers` for the connections it accepts, only
+/// on the network `interface` if given.
 #[inline]
 #[tracing::instrument(level = "trace")]
-pub(super) async fn open_tcp_listener(lhost: &str, lport: u16) -> std::io::Result<TcpListener> {
-    let listener = TcpListener::bind((lhost, lport)).await?;
-    // `expect`: at this point `listener` should be bound. Otherwise, it's a bug.
-    let local_addr = listener
-        .local_addr()
-        .expect("Failed to get local address of TCP listener (this is a bug)");
-    info!("Listening on {local_addr}");
+pub(super) async fn open_tcp_listener(
+    lhost: &str,
+    lport: u16,
+    buffers: BufferSizes,
+    interface: Option<&str>,
+) -> std::io::Result<TcpListener> {
+    let mut last_err = None;
+    let mut listener = None;
+    for addr in lookup_host((lhost, lport)).await? {
+        match sockopt::tcp_listener(addr, buffers, interface) {
+            Ok(bound) => {
+                listener = Some(bound);
+                break;
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    let listener = listener.ok_or_else(|| {
+        last_err.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            )
+        })
+    })?;
     Ok(listener)
 }
 
+/// Set the TCP options of a remote on a connection it accepted from `peer`.
+pub(super) fn set_tcp_options(tcp_stream: &TcpStream, tcp_options: &TcpOptions, peer: SocketAddr) {
+    if let Err(error) = sockopt::set_tcp_options(tcp_stream, tcp_options) {
+        warn!(%peer, "Failed to set TCP options: {error}");
+    }
+}
+
+/// Pipe `tcp_stream` from `peer` to and from `channel` in a new task.
+pub(super) fn forward_tcp_stream(
+    mut tcp_stream: TcpStream,
+    mut channel: MuxStream,
+    peer: SocketAddr,
+) {
+    let stream_id = channel.id();
+    debug!(%peer, stream_id, "TCP stream opened");
+    // Transient errors in the forwarder don't matter.
+    tokio::spawn(async move {
+        match tokio::io::copy_bidirectional_with_sizes(
+            &mut tcp_stream,
+            &mut channel,
+            config::TCP_RELAY_BUFFER_SIZE,
+            config::TCP_RELAY_BUFFER_SIZE,
+        )
+        .await
+        {
+            Ok((bytes_up, bytes_down)) => debug!(
+                %peer,
+                stream_id,
+                bytes_up,
+                bytes_down,
+                "TCP stream closed"
+            ),
+            Err(error) => warn!(%peer, stream_id, "TCP forwarder failed: {error}"),
+        }
+    });
+}
+
 /// Handle a TCP Inet->Inet remote.
 #[inline]
 #[tracing::instrument(skip(handler_resources), level = "debug")]
@@ -53,12 +147,19 @@ pub(super) async fn handle_tcp(
     lport: u16,
     rhost: &'static str,
     rport: u16,
+    tcp_options: TcpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), FatalError> {
     // Not being able to open a TCP listener is a fatal error.
-    let listener = open_tcp_listener(lhost, lport)
-        .await
-        .map_err(FatalError::ClientIo)?;
+    let listener = open_tcp_listener(
+        lhost,
+        lport,
+        handler_resources.buffers,
+        handler_resources.listen_interface,
+    )
+    .await
+    .map_err(FatalError::ClientIo)?;
+    handler_resources.bound(listener.local_addr().map_err(FatalError::ClientIo)?);
     let rhost = rhost.as_bytes();
     loop {
         // This fails only if main has exited, which is a fatal error.
@@ -70,19 +171,25 @@ pub(super) async fn handle_tcp(
         // Only `accept` when we have a permit to send a request.
         // This way, the backpressure is propagated to the TCP listener.
         // Not being able to accept a TCP connection is a fatal error.
-        let (mut tcp_stream, _) = listener.accept().await.map_err(FatalError::ClientIo)?;
+        let (tcp_stream, peer) = listener.accept().await.map_err(FatalError::ClientIo)?;
+        set_tcp_options(&tcp_stream, &tcp_options, peer);
         // A new channel is created for each incoming TCP connection.
         // It's already TCP, anyways.
-        let mut channel =
-            request_tcp_channel(stream_command_tx_permit, Bytes::from_static(rhost), rport)
-                .await
-                .map_err(|_| FatalError::MainLoopExitWithoutSendingStream)?;
-        // Transient errors in the forwarder don't matter.
-        tokio::spawn(async move {
-            if let Err(error) = tokio::io::copy_bidirectional(&mut channel, &mut tcp_stream).await {
-                warn!("TCP forwarder failed: {error}");
-            }
-        });
+        let channel = request_tcp_channel(
+            stream_command_tx_permit,
+            handler_resources.overrides.as_deref(),
+            handler_resources.resolver.as_deref(),
+            Bytes::from_static(rhost),
+            rport,
+            tcp_options,
+        )
+        .await
+        .map_err(|_| FatalError::MainLoopExitWithoutSendingStream)?;
+        match channel {
+            Ok(channel) => forward_tcp_stream(tcp_stream, channel, peer),
+            // Dropping `tcp_stream` closes it
+            Err(error) => warn!(%peer, "TCP stream rejected: {error}"),
+        }
     }
 }
 
@@ -91,6 +198,7 @@ pub(super) async fn handle_tcp(
 pub(super) async fn handle_tcp_stdio(
     rhost: &'static str,
     rport: u16,
+    tcp_options: TcpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), FatalError> {
     let mut stdio = super::Stdio::new();
@@ -103,10 +211,24 @@ pub(super) async fn handle_tcp_stdio(
             .reserve()
             .await
             .map_err(|_| FatalError::RequestStream)?;
-        let mut channel =
-            request_tcp_channel(stream_command_tx_permit, Bytes::from_static(rhost), rport)
-                .await
-                .map_err(|_| FatalError::MainLoopExitWithoutSendingStream)?;
+        let channel = request_tcp_channel(
+            stream_command_tx_permit,
+            handler_resources.overrides.as_deref(),
+            handler_resources.resolver.as_deref(),
+            Bytes::from_static(rhost),
+            rport,
+            tcp_options,
+        )
+        .await
+        .map_err(|_| FatalError::MainLoopExitWithoutSendingStream)?;
+        let mut channel = match channel {
+            Ok(channel) => channel,
+            Err(error) => {
+                // Retrying would only be rejected again
+                error!("TCP stdio stream rejected: {error}");
+                break Err(FatalError::ClientIo(error));
+            }
+        };
         match tokio::io::copy_bidirectional(&mut stdio, &mut channel).await {
             Ok(_) => {
                 info!("TCP stdio connection closed");
@@ -130,7 +252,9 @@ mod test {
 
     #[tokio::test]
     async fn test_open_tcp_listener() {
-        let listener = open_tcp_listener("127.0.0.1", 0).await.unwrap();
+        let listener = open_tcp_listener("127.0.0.1", 0, BufferSizes::default(), None)
+            .await
+            .unwrap();
         let local_addr = listener.local_addr().unwrap();
         assert_eq!(local_addr.ip(), std::net::Ipv4Addr::LOCALHOST);
         let accept_task = tokio::spawn(async move {
@@ -0,0 +1,396 @@
+//! Transparent proxying with TPROXY or REDIRECT (Linux only).
+//!
+//! With rules like
+//!   iptables -t mangle -A PREROUTING -p udp -j TPROXY --on-port 5300 --tproxy-mark 1
+//!   ip rule add fwmark 1 lookup 100
+//!   ip route add local 0.0.0.0/0 dev lo table 100
+//! datagrams to any address arrive at our socket, which learns their
+//! original destinations from `IP_RECVORIGDSTADDR`. Replies are sent from
+//! sockets bound to those destinations, so that clients see them come
+//! from where they expect.
+//!
+//! TCP connections keep their original destination as their local address
+//! with TPROXY, while REDIRECT leaves it in `SO_ORIGINAL_DST`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::tcp::{forward_tcp_stream, request_tcp_channel, set_tcp_options};
+use super::udp::full_cone_flag;
+use super::FatalError;
+use crate::client::{DatagramCommand, HandlerResources};
+use crate::parse_remote::UdpOptions;
+use crate::sockopt::BufferSizes;
+use crate::{config, Dupe};
+use bytes::Bytes;
+use nix::sys::socket::{
+    getsockopt, recvmsg, setsockopt, sockopt, ControlMessageOwned, MsgFlags, SockaddrIn,
+    SockaddrIn6, SockaddrStorage,
+};
+use parking_lot::Mutex;
+use penguin_mux::{DatagramFrame, TcpOptions};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, IoSliceMut};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::{Arc, Weak};
+use tokio::io::Interest;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{debug, warn};
+
+/// Sockets bound to original destinations, by destination
+type ReplySockets = Arc<Mutex<HashMap<SocketAddr, Weak<UdpSocket>>>>;
+
+/// Bind a transparent UDP socket, which may use an address that is not
+/// ours, to `addr`.
+fn bind_transparent(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_ip_transparent(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Listen on `addr` for connections to any address. Without the privilege
+/// to do so, only REDIRECT works.
+fn listen_transparent(addr: SocketAddr, buffers: BufferSizes) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let Err(e) = socket.set_ip_transparent(true) {
+        warn!("cannot accept TPROXY connections, only REDIRECT ones: {e}");
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    buffers.set_socket2(&socket)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// The destination `stream` was meant for.
+fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    let local_addr = stream.local_addr()?;
+    let original = if local_addr.is_ipv6() {
+        getsockopt(stream, sockopt::Ip6tOriginalDst)
+            .map(|addr| SocketAddrV6::from(SockaddrIn6::from(addr)).into())
+    } else {
+        getsockopt(stream, sockopt::OriginalDst)
+            .map(|addr| SocketAddrV4::from(SockaddrIn::from(addr)).into())
+    };
+    // Not NATed, so TPROXY or a direct connection
+    Ok(original.unwrap_or(local_addr))
+}
+
+/// Ask for the original destination of each datagram received on `socket`.
+fn set_recv_orig_dst(socket: &UdpSocket, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        setsockopt(socket, sockopt::Ipv6OrigDstAddr, &true)?;
+    } else {
+        setsockopt(socket, sockopt::Ipv4OrigDstAddr, &true)?;
+    }
+    Ok(())
+}
+
+/// Receive a datagram on `fd`, which must have `set_recv_orig_dst`, into
+/// `buf`. Returns its length, source, and original destination.
+fn recv_orig_dst(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut control = nix::cmsg_space!(libc::sockaddr_in6);
+    let msg = recvmsg::<SockaddrStorage>(fd, &mut iov, Some(&mut control), MsgFlags::empty())?;
+    let source = msg
+        .address
+        .as_ref()
+        .and_then(to_socket_addr)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an IP socket address"))?;
+    let destination = msg
+        .cmsgs()?
+        .find_map(|cmsg| match cmsg {
+            ControlMessageOwned::Ipv4OrigDstAddr(addr) => {
+                Some(SocketAddrV4::from(SockaddrIn::from(addr)).into())
+            }
+            ControlMessageOwned::Ipv6OrigDstAddr(addr) => {
+                Some(SocketAddrV6::from(SockaddrIn6::from(addr)).into())
+            }
+            _ => None,
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no original destination"))?;
+    Ok((msg.bytes, source, destination))
+}
+
+/// Convert a socket address filled in by the kernel.
+fn to_socket_addr(addr: &SockaddrStorage) -> Option<SocketAddr> {
+    if let Some(addr) = addr.as_sockaddr_in() {
+        Some(SocketAddrV4::from(*addr).into())
+    } else {
+        addr.as_sockaddr_in6()
+            .map(|addr| SocketAddrV6::from(*addr).into())
+    }
+}
+
+/// Wait for a datagram on `socket`, which must have `set_recv_orig_dst`.
+async fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || {
+            recv_orig_dst(socket.as_raw_fd(), buf)
+        }) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Send a datagram from `source` to `destination` to the main loop.
+/// Replies go out on `socket`.
+async fn forward(
+    handler_resources: &HandlerResources,
+    socket: &Arc<UdpSocket>,
+    source: SocketAddr,
+    destination: SocketAddr,
+    data: Bytes,
+    options: UdpOptions,
+) -> Result<(), FatalError> {
+    let client_id = handler_resources
+        .add_udp_client(source, socket.dupe(), false)
+        .await;
+    let frame = DatagramFrame {
+        host: Bytes::from(destination.ip().to_string()),
+        port: destination.port(),
+        sid: client_id | full_cone_flag(options),
+        data,
+    };
+    // This fails only if main has exited, which is a fatal error.
+    handler_resources
+        .datagram_tx
+        .send(DatagramCommand {
+            frame,
+            reliable: options.reliable,
+        })
+        .await
+        .map_err(|_| FatalError::SendDatagram)
+}
+
+/// The socket to reply from `destination` with. A new one comes with a task
+/// that reads from it, since TPROXY hands it the datagrams to `destination`
+/// instead of the listener.
+fn reply_socket(
+    reply_sockets: &ReplySockets,
+    destination: SocketAddr,
+    options: UdpOptions,
+    handler_resources: &HandlerResources,
+) -> io::Result<Arc<UdpSocket>> {
+    let mut sockets = reply_sockets.lock();
+    if let Some(socket) = sockets.get(&destination).and_then(Weak::upgrade) {
+        return Ok(socket);
+    }
+    sockets.retain(|_, socket| socket.strong_count() > 0);
+    let socket = Arc::new(bind_transparent(destination)?);
+    sockets.insert(destination, Arc::downgrade(&socket));
+    tokio::spawn(read_reply_socket(
+        socket.dupe(),
+        destination,
+        reply_sockets.dupe(),
+        options,
+        handler_resources.dupe(),
+    ));
+    Ok(socket)
+}
+
+/// Forward what clients send to `destination` on its reply socket, until
+/// no client uses it any more.
+async fn read_reply_socket(
+    socket: Arc<UdpSocket>,
+    destination: SocketAddr,
+    reply_sockets: ReplySockets,
+    options: UdpOptions,
+    handler_resources: HandlerResources,
+) {
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+    loop {
+        match tokio::time::timeout(config::UDP_PRUNE_TIMEOUT, socket.recv_from(&mut buf)).await {
+            Ok(Ok((len, source))) => {
+                let data = Bytes::copy_from_slice(&buf[..len]);
+                let result = forward(
+                    &handler_resources,
+                    &socket,
+                    source,
+                    destination,
+                    data,
+                    options,
+                )
+                .await;
+                if result.is_err() {
+                    break;
+                }
+            }
+            Ok(Err(e)) => {
+                warn!("cannot receive datagrams to {destination}: {e}");
+                break;
+            }
+            Err(_) => {
+                // Only we have it, so no client is left to reply to.
+                // Checked under the lock so that no new one can get it.
+                let mut sockets = reply_sockets.lock();
+                if Arc::strong_count(&socket) == 1 {
+                    sockets.remove(&destination);
+                    break;
+                }
+            }
+        }
+    }
+    debug!("closing the reply socket of {destination}");
+}
+
+/// Resolve the address to listen on.
+async fn resolve_local(lhost: &str, lport: u16) -> io::Result<SocketAddr> {
+    tokio::net::lookup_host((lhost, lport))
+        .await?
+        .next()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve the local address",
+            )
+        })
+}
+
+/// Handle a transparent TCP remote, which forwards connections redirected
+/// to `lhost`:`lport` to their original destinations.
+#[tracing::instrument(skip(handler_resources), level = "debug")]
+pub(super) async fn handle_tproxy_tcp(
+    lhost: &'static str,
+    lport: u16,
+    tcp_options: TcpOptions,
+    handler_resources: &HandlerResources,
+) -> Result<(), FatalError> {
+    // Not being able to listen is a fatal error.
+    let addr = resolve_local(lhost, lport)
+        .await
+        .map_err(FatalError::ClientIo)?;
+    let listener =
+        listen_transparent(addr, handler_resources.buffers).map_err(FatalError::ClientIo)?;
+    handler_resources.bound(listener.local_addr().map_err(FatalError::ClientIo)?);
+    loop {
+        // This fails only if main has exited, which is a fatal error.
+        let stream_command_tx_permit = handler_resources
+            .stream_command_tx
+            .reserve()
+            .await
+            .map_err(|_| FatalError::RequestStream)?;
+        // Not being able to accept a TCP connection is a fatal error.
+        let (tcp_stream, peer) = listener.accept().await.map_err(FatalError::ClientIo)?;
+        let destination = match original_dst(&tcp_stream) {
+            // Forwarding it to ourselves would loop
+            Ok(destination) if destination == addr => {
+                warn!(%peer, "dropped a connection that was not redirected");
+                continue;
+            }
+            Ok(destination) => destination,
+            Err(e) => {
+                warn!(%peer, "cannot get the original destination: {e}");
+                continue;
+            }
+        };
+        debug!(%peer, %destination, "accepted a redirected connection");
+        set_tcp_options(&tcp_stream, &tcp_options, peer);
+        let channel = request_tcp_channel(
+            stream_command_tx_permit,
+            handler_resources.overrides.as_deref(),
+            // Already an address
+            None,
+            Bytes::from(destination.ip().to_string()),
+            destination.port(),
+            tcp_options,
+        )
+        .await
+        .map_err(|_| FatalError::MainLoopExitWithoutSendingStream)?;
+        match channel {
+            Ok(channel) => forward_tcp_stream(tcp_stream, channel, peer),
+            // Dropping `tcp_stream` closes it
+            Err(error) => warn!(%peer, %destination, "TCP stream rejected: {error}"),
+        }
+    }
+}
+
+/// Handle a TPROXY UDP remote, which forwards datagrams redirected to
+/// `lhost`:`lport` to their original destinations.
+#[tracing::instrument(skip(handler_resources), level = "debug")]
+pub(super) async fn handle_tproxy_udp(
+    lhost: &'static str,
+    lport: u16,
+    options: UdpOptions,
+    handler_resources: &HandlerResources,
+) -> Result<(), FatalError> {
+    // Not being able to bind to the local port is a fatal error.
+    let addr = resolve_local(lhost, lport)
+        .await
+        .map_err(FatalError::ClientIo)?;
+    let socket = bind_transparent(addr).map_err(FatalError::ClientIo)?;
+    set_recv_orig_dst(&socket, addr.is_ipv6()).map_err(FatalError::ClientIo)?;
+    handler_resources.bound(socket.local_addr().map_err(FatalError::ClientIo)?);
+    let reply_sockets = ReplySockets::default();
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+    loop {
+        // Failing to receive is a fatal error.
+        let (len, source, destination) = recv(&socket, &mut buf)
+            .await
+            .map_err(FatalError::ClientIo)?;
+        debug!("received {len} bytes from {source} to {destination}");
+        let reply_socket =
+            match reply_socket(&reply_sockets, destination, options, handler_resources) {
+                Ok(reply_socket) => reply_socket,
+                Err(e) => {
+                    warn!("cannot reply from {destination}: {e}");
+                    continue;
+                }
+            };
+        let data = Bytes::copy_from_slice(&buf[..len]);
+        forward(
+            handler_resources,
+            &reply_socket,
+            source,
+            destination,
+            data,
+            options,
+        )
+        .await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn check_recv_orig_dst(local: &str) {
+        let socket = UdpSocket::bind(local).await.unwrap();
+        let local_addr = socket.local_addr().unwrap();
+        set_recv_orig_dst(&socket, local_addr.is_ipv6()).unwrap();
+        let sender = UdpSocket::bind(local).await.unwrap();
+        sender.send_to(b"hello", local_addr).await.unwrap();
+        let mut buf = [0; 16];
+        let (len, source, destination) = recv(&socket, &mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        assert_eq!(source, sender.local_addr().unwrap());
+        // Without TPROXY, the destination is our own address
+        assert_eq!(destination, local_addr);
+    }
+
+    #[tokio::test]
+    async fn test_recv_orig_dst_v4() {
+        check_recv_orig_dst("127.0.0.1:0").await;
+    }
+
+    #[tokio::test]
+    async fn test_recv_orig_dst_v6() {
+        check_recv_orig_dst("[::1]:0").await;
+    }
+
+    #[tokio::test]
+    async fn test_original_dst_not_redirected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _stream = TcpStream::connect(addr).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        assert_eq!(original_dst(&accepted).unwrap(), addr);
+    }
+}
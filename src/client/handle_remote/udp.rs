@@ -3,16 +3,17 @@
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
 use super::FatalError;
-use crate::client::HandlerResources;
+use crate::client::{DatagramCommand, HandlerResources};
+use crate::parse_remote::UdpOptions;
 use crate::{config, Dupe};
 use bytes::Bytes;
 use penguin_mux::DatagramFrame;
 use std::sync::Arc;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::net::UdpSocket;
-use tracing::{debug, info};
+use tracing::debug;
 
-/// Handle a UDP Inet->Inet remote.
+/// Handle a UDP Inet->Inet remote with the given `options`.
 #[inline]
 #[tracing::instrument(skip(handler_resources), level = "debug")]
 pub(super) async fn handle_udp(
@@ -20,10 +21,11 @@ pub(super) async fn handle_udp(
     lport: u16,
     rhost: &'static str,
     rport: u16,
+    options: UdpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), FatalError> {
     // Not being able to bind to the local port is a fatal error.
-    let socket = UdpSocket::bind((lhost, lport))
+    let socket = bind_udp_socket(lhost, lport, handler_resources.listen_interface)
         .await
         .map_err(FatalError::ClientIo)?;
     let socket = Arc::new(socket);
@@ -31,7 +33,7 @@ pub(super) async fn handle_udp(
     let local_addr = socket
         .local_addr()
         .expect("Failed to get local address of UDP socket (this is a bug)");
-    info!("Bound on {local_addr}");
+    handler_resources.bound(local_addr);
     loop {
         let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
         // `recv_from` can fail if the socket is closed, which is a fatal error.
@@ -47,24 +49,55 @@ pub(super) async fn handle_udp(
         let frame = DatagramFrame {
             host: Bytes::from_static(rhost.as_bytes()),
             port: rport,
-            sid: client_id,
+            sid: client_id | full_cone_flag(options),
             data: Bytes::from(buf),
         };
         // This fails only if main has exited, which is a fatal error.
         handler_resources
             .datagram_tx
-            .send(frame)
+            .send(DatagramCommand {
+                frame,
+                reliable: options.reliable,
+            })
             .await
             .map_err(|_| FatalError::SendDatagram)?;
     }
 }
 
+/// Bind a UDP socket to `lhost:lport`, only on the network `interface` if
+/// given.
+pub(super) async fn bind_udp_socket(
+    lhost: &str,
+    lport: u16,
+    interface: Option<&str>,
+) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((lhost, lport)).await?;
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    // The argument parser only accepts interfaces on Linux
+    #[cfg(not(target_os = "linux"))]
+    let _ = interface;
+    Ok(socket)
+}
+
+/// Bits to set in the client ID of datagrams with the given `options`
+pub(super) const fn full_cone_flag(options: UdpOptions) -> u32 {
+    if options.full_cone {
+        config::UDP_FULL_CONE_FLAG
+    } else {
+        0
+    }
+}
+
 /// Handle a UDP Stdio->Inet remote.
 #[inline]
 #[tracing::instrument(skip(handler_resources), level = "debug")]
 pub(super) async fn handle_udp_stdio(
     rhost: &'static str,
     rport: u16,
+    options: UdpOptions,
     handler_resources: &HandlerResources,
 ) -> Result<(), FatalError> {
     let mut stdin = BufReader::new(tokio::io::stdin());
@@ -78,13 +111,16 @@ pub(super) async fn handle_udp_stdio(
         let frame = DatagramFrame {
             host: Bytes::from_static(rhost.as_bytes()),
             port: rport,
-            sid: 0,
+            sid: full_cone_flag(options),
             data: line.into(),
         };
         // This fails only if main has exited, which is a fatal error.
         handler_resources
             .datagram_tx
-            .send(frame)
+            .send(DatagramCommand {
+                frame,
+                reliable: options.reliable,
+            })
             .await
             .map_err(|_| FatalError::SendDatagram)?;
     }
@@ -93,30 +129,28 @@ pub(super) async fn handle_udp_stdio(
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::client::ClientIdMaps;
-    use tokio::sync::RwLock;
 
     #[tokio::test]
     async fn test_handle_udp() {
         let (datagram_tx, mut datagram_rx) = tokio::sync::mpsc::channel(1);
         let (stream_command_tx, _) = tokio::sync::mpsc::channel(1);
-        let udp_client_map = Arc::new(RwLock::new(ClientIdMaps::new()));
-        let handler_resources = HandlerResources {
-            datagram_tx,
-            stream_command_tx,
-            udp_client_map: udp_client_map.dupe(),
-        };
-        static LHOST: &'static str = "127.0.0.1";
-        static RHOST: &'static str = "127.0.0.1";
-        let forwarding_task =
-            tokio::spawn(
-                async move { handle_udp(LHOST, 14196, RHOST, 255, &handler_resources).await },
-            );
+        let handler_resources = HandlerResources::for_test(stream_command_tx, datagram_tx);
+        let udp_client_map = handler_resources.udp_client_map.dupe();
+        static LHOST: &str = "127.0.0.1";
+        static RHOST: &str = "127.0.0.1";
+        let forwarding_task = tokio::spawn(async move {
+            let options = UdpOptions {
+                reliable: true,
+                full_cone: true,
+            };
+            handle_udp(LHOST, 14196, RHOST, 255, options, &handler_resources).await
+        });
         let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
         let local_addr = socket.local_addr().unwrap();
         socket.connect("127.0.0.1:14196").await.unwrap();
         socket.send(b"hello").await.unwrap();
-        let frame = datagram_rx.recv().await.unwrap();
+        let DatagramCommand { frame, reliable } = datagram_rx.recv().await.unwrap();
+        assert!(reliable);
         assert_eq!(frame.host, Bytes::from_static(RHOST.as_bytes()));
         assert_eq!(frame.port, 255);
         assert_eq!(frame.data, Bytes::from("hello"));
@@ -126,7 +160,7 @@ mod test {
             .client_addr_map
             .get(&(local_addr, ([127, 0, 0, 1], 14196).into()))
             .unwrap();
-        assert_eq!(frame.sid, client_id);
+        assert_eq!(frame.sid, client_id | config::UDP_FULL_CONE_FLAG);
         forwarding_task.abort();
     }
 }
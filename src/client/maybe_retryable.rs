@@ -9,6 +9,13 @@ pub(super) trait MaybeRetryableError: std::error::Error {
 
 impl MaybeRetryableError for std::io::Error {
     fn retryable(&self) -> bool {
+        // `ws::WebSocket` wraps the non-I/O `tungstenite` errors
+        if let Some(e) = self
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<tokio_tungstenite::tungstenite::Error>())
+        {
+            return e.retryable();
+        }
         self.kind() == std::io::ErrorKind::AddrNotAvailable
             || self.kind() == std::io::ErrorKind::BrokenPipe
             || self.kind() == std::io::ErrorKind::ConnectionReset
@@ -54,19 +61,43 @@ impl MaybeRetryableError for penguin_mux::Error {
         match self {
             Self::SendDatagram(e)
             | Self::SendStreamFrame(e)
+            | Self::SendHello(e)
             | Self::Next(e)
             | Self::PingPong(e) => e.retryable(),
-            Self::Closed => true,
+            Self::Closed | Self::PongTimeout(_) | Self::GoingAway => true,
             _ => false,
         }
     }
 }
 
+impl MaybeRetryableError for crate::challenge::Error {
+    fn retryable(&self) -> bool {
+        match self {
+            Self::WebSocket(e) => e.retryable(),
+            Self::Timeout | Self::Closed => true,
+            Self::InvalidMessage => false,
+        }
+    }
+}
+
+impl MaybeRetryableError for crate::noise::Error {
+    fn retryable(&self) -> bool {
+        match self {
+            Self::WebSocket(e) => e.retryable(),
+            // The server closes the connection if it does not accept our key
+            Self::Timeout | Self::Closed => true,
+            Self::Handshake(_) | Self::InvalidMessage | Self::UnknownClient(_) => false,
+        }
+    }
+}
+
 impl MaybeRetryableError for super::ws_connect::Error {
     fn retryable(&self) -> bool {
         match self {
             Self::Tungstenite(e) => e.retryable(),
-            Self::Tls(_) => false,
+            Self::Tls(_) | Self::UnsupportedProtocol(_) => false,
+            Self::Challenge(e) => e.retryable(),
+            Self::Noise(e) => e.retryable(),
         }
     }
 }
@@ -2,18 +2,39 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+mod adaptive_keepalive;
 mod backoff;
+mod bypass;
+#[cfg(unix)]
+mod control;
+#[cfg(unix)]
+mod daemon;
 mod handle_remote;
 mod maybe_retryable;
+mod overrides;
+mod tun;
 pub mod ws_connect;
 
+use self::adaptive_keepalive::AdaptiveKeepalive;
+use self::bypass::Bypass;
 use self::handle_remote::handle_remote;
 use self::maybe_retryable::MaybeRetryableError;
-use crate::arg::ClientArgs;
+use self::overrides::Overrides;
+use crate::arg::{ClientArgs, Credentials, ResolverUrl, SocksDns};
 use crate::config;
+use crate::control::{Status, StreamStatus};
+use crate::dump::DumpSignal;
+use crate::noise::NoiseTransport;
+use crate::parse_remote::{LocalSpec, Remote};
+use crate::resolver::Resolver;
+use crate::sockopt::BufferSizes;
+use crate::throughput::Throughput;
+use crate::tun::Tun;
 use crate::Dupe;
 use bytes::Bytes;
-use penguin_mux::{DatagramFrame, IntKey, Multiplexor, Role};
+use penguin_mux::{
+    Capabilities, Compression, DatagramFrame, IntKey, Multiplexor, Options, Role, TcpOptions,
+};
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
@@ -25,8 +46,8 @@ use tokio::net::{TcpStream, UdpSocket};
 use tokio::sync::{mpsc, oneshot, RwLock};
 use tokio::task::JoinSet;
 use tokio::time;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
-use tracing::{error, info, trace, warn};
+use tokio_tungstenite::MaybeTlsStream;
+use tracing::{debug, debug_span, error, info, trace, warn, Instrument, Span};
 
 /// Errors
 #[derive(Debug, Error)]
@@ -45,18 +66,123 @@ pub enum Error {
     StreamRequestTimeout,
     #[error("Remote disconnected normally")]
     RemoteDisconnected,
+    #[error("Cannot go to the background: {0}")]
+    Daemon(std::io::Error),
+    #[error("Background client exited before connecting: {0}")]
+    DaemonExited(std::process::ExitStatus),
+    #[error("Cannot register signal handler: {0}")]
+    Signal(std::io::Error),
+    #[error("--daemon cannot be used with stdio remotes")]
+    DaemonStdio,
+    #[error("Cannot create TUN device: {0}")]
+    Tun(std::io::Error),
+    #[error("More than one remote is named {0:?}")]
+    DuplicateRemoteName(String),
+    #[error("Cannot read remotes file: {0}")]
+    RemotesFile(std::io::Error),
+    #[error("Invalid remote on line {0} of the remotes file: {1}")]
+    RemotesFileEntry(usize, crate::parse_remote::Error),
+    #[error("Cannot read bypass file: {0}")]
+    BypassFile(std::io::Error),
+    #[error("Invalid rule on line {0} of the bypass file: {1}")]
+    BypassFileEntry(usize, &'static str),
+    #[error("Cannot read hosts file: {0}")]
+    HostsFile(std::io::Error),
+    #[error("Invalid entry on line {0} of the hosts file: {1}")]
+    HostsFileEntry(usize, &'static str),
+    #[error("Cannot listen on the control socket: {0}")]
+    ControlSocket(std::io::Error),
+    #[cfg(not(unix))]
+    #[error("--daemon is only supported on Unix")]
+    NoDaemon,
+    #[cfg(not(unix))]
+    #[error("--control-socket is only supported on Unix")]
+    NoControlSocket,
 }
 
-type MuxStream = penguin_mux::MuxStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+type WebSocket = NoiseTransport<crate::ws::WebSocket<MaybeTlsStream<TcpStream>>>;
+type MuxStream = penguin_mux::MuxStream<WebSocket>;
 
 // Send the information about how to send the stream to the listener
 /// Type that local listeners send to the main loop to request a connection
 #[derive(Debug)]
 struct StreamCommand {
-    /// Channel to send the stream back to the listener
-    tx: oneshot::Sender<MuxStream>,
+    /// Channel to send the stream back to the listener, or why the server
+    /// rejected it
+    tx: oneshot::Sender<std::io::Result<MuxStream>>,
     host: Bytes,
     port: u16,
+    /// Options for the server's connection to `host`
+    tcp_options: TcpOptions,
+    /// Span of the stream in the listener
+    span: Span,
+}
+
+/// Type that local listeners send to the main loop to forward a datagram
+#[derive(Debug)]
+struct DatagramCommand {
+    frame: DatagramFrame,
+    /// Whether to send it again until the server acknowledges it
+    reliable: bool,
+}
+
+impl DatagramCommand {
+    async fn send(self, mux: &Multiplexor<WebSocket>) -> penguin_mux::Result<()> {
+        if self.reliable {
+            mux.send_reliable_datagram(self.frame).await
+        } else {
+            mux.send_datagram(self.frame).await
+        }
+    }
+}
+
+/// Type that the control socket sends to the main loop for what only it
+/// knows
+#[derive(Debug)]
+enum ControlRequest {
+    /// The status of the connection to the server
+    Status(oneshot::Sender<Status>),
+    /// Reset the stream with this ID, answering whether there was one
+    Reset(u32, oneshot::Sender<bool>),
+}
+
+impl ControlRequest {
+    /// Answer while connected with `mux`.
+    async fn answer(self, mux: &Multiplexor<WebSocket>, reconnects: u64) {
+        match self {
+            Self::Status(tx) => {
+                let stats = mux.stats().await;
+                let status = Status {
+                    connected: true,
+                    reconnects,
+                    rtt_ms: mux.rtt().map(|rtt| rtt.mean.as_secs_f64() * 1000.0),
+                    streams: stats.streams.iter().map(StreamStatus::from).collect(),
+                    ..Status::default()
+                };
+                tx.send(status).ok();
+            }
+            Self::Reset(id, tx) => {
+                tx.send(mux.reset_stream(id).await).ok();
+            }
+        }
+    }
+
+    /// Answer while not connected to the server.
+    fn answer_disconnected(self, reconnects: u64) {
+        match self {
+            Self::Status(tx) => {
+                let status = Status {
+                    reconnects,
+                    ..Status::default()
+                };
+                tx.send(status).ok();
+            }
+            // No streams are open
+            Self::Reset(_, tx) => {
+                tx.send(false).ok();
+            }
+        }
+    }
 }
 
 /// Data for a function to be able to use the mux/connection
@@ -66,9 +192,30 @@ struct HandlerResources {
     /// Send a request for a TCP channel to the main loop
     stream_command_tx: mpsc::Sender<StreamCommand>,
     /// Send a UDP datagram to the main loop
-    datagram_tx: mpsc::Sender<DatagramFrame>,
+    datagram_tx: mpsc::Sender<DatagramCommand>,
     /// The map of client IDs to UDP sockets and the map of client addresses to client IDs
     udp_client_map: Arc<RwLock<ClientIdMaps>>,
+    /// Resolves remote hostnames before they are sent to the server
+    resolver: Option<Arc<Resolver>>,
+    /// Resolves the hostnames of SOCKS and HTTP proxy requests before they
+    /// are sent to the server
+    socks_resolver: Option<Arc<Resolver>>,
+    /// Buffer sizes of the connections listeners accept
+    buffers: BufferSizes,
+    /// The only network interface listeners accept on, if any
+    listen_interface: Option<&'static str>,
+    /// What SOCKS and HTTP proxy clients must authenticate with, if anything
+    local_auth: Option<&'static Credentials>,
+    /// Whether "socks" remotes serve a PAC file
+    pac: bool,
+    /// Destinations "socks" remotes connect to directly
+    bypass: Option<Arc<Bypass>>,
+    /// Rewrites the destinations of streams through the server
+    overrides: Option<Arc<Overrides>>,
+    /// The remote the handler serves, if any
+    remote: Option<&'static Remote>,
+    /// Where the listeners of all remotes are bound
+    listening: Arc<Listening>,
 }
 
 impl Dupe for HandlerResources {
@@ -77,11 +224,91 @@ impl Dupe for HandlerResources {
             stream_command_tx: self.stream_command_tx.dupe(),
             datagram_tx: self.datagram_tx.dupe(),
             udp_client_map: self.udp_client_map.dupe(),
+            resolver: self.resolver.clone(),
+            socks_resolver: self.socks_resolver.clone(),
+            buffers: self.buffers,
+            listen_interface: self.listen_interface,
+            local_auth: self.local_auth,
+            pac: self.pac,
+            bypass: self.bypass.clone(),
+            overrides: self.overrides.clone(),
+            remote: self.remote,
+            listening: self.listening.dupe(),
+        }
+    }
+}
+
+/// Addresses the listeners of the remotes are bound to, which tell the
+/// ports the OS picked for local ports of 0
+#[derive(Debug, Default)]
+struct Listening {
+    addrs: parking_lot::Mutex<Vec<(&'static Remote, SocketAddr)>>,
+    /// Notified when a listener is bound
+    bound: tokio::sync::Notify,
+}
+
+impl Listening {
+    /// Log where each listener is bound.
+    fn log(&self) {
+        for (remote, addr) in &*self.addrs.lock() {
+            info!(remote = %remote.label(), local_addr = %addr, "Listener");
+        }
+    }
+
+    /// Wait until the listener of `remote` is bound, and return where.
+    async fn wait_bound(&self, remote: &'static Remote) -> SocketAddr {
+        loop {
+            // Created before looking so that no notification is missed
+            let notified = self.bound.notified();
+            let found = self
+                .addrs
+                .lock()
+                .iter()
+                .find(|(r, _)| std::ptr::eq(*r, remote))
+                .map(|(_, addr)| *addr);
+            if let Some(addr) = found {
+                return addr;
+            }
+            notified.await;
         }
     }
 }
 
 impl HandlerResources {
+    /// Resources with nothing configured, sending to the main loop through
+    /// `stream_command_tx` and `datagram_tx`
+    #[cfg(test)]
+    fn for_test(
+        stream_command_tx: mpsc::Sender<StreamCommand>,
+        datagram_tx: mpsc::Sender<DatagramCommand>,
+    ) -> Self {
+        Self {
+            stream_command_tx,
+            datagram_tx,
+            udp_client_map: Arc::new(RwLock::new(ClientIdMaps::new())),
+            resolver: None,
+            socks_resolver: None,
+            buffers: BufferSizes::default(),
+            listen_interface: None,
+            local_auth: None,
+            pac: false,
+            bypass: None,
+            overrides: None,
+            remote: None,
+            listening: Arc::default(),
+        }
+    }
+
+    /// Log and record that the listener of the remote is bound to `addr`.
+    fn bound(&self, addr: SocketAddr) {
+        let Some(remote) = self.remote else {
+            return;
+        };
+        info!("Listening on {addr} for {}", remote.label());
+        self.listening.addrs.lock().push((remote, addr));
+        self.listening.bound.notify_waiters();
+    }
+
     /// Add a new UDP client to the maps, returns the new client ID
     #[must_use = "This function returns the new client ID, which should be used to mark the datagram"]
     pub async fn add_udp_client(
@@ -108,7 +335,13 @@ impl HandlerResources {
             *client_id
         } else {
             // The client doesn't exist, add it to the maps
-            let client_id = u32::next_available_key(client_id_map);
+            // The top bit is reserved for `UDP_FULL_CONE_FLAG`
+            let client_id = loop {
+                let client_id = u32::next_available_key(client_id_map);
+                if client_id & config::UDP_FULL_CONE_FLAG == 0 {
+                    break client_id;
+                }
+            };
             client_id_map.insert(
                 client_id,
                 ClientIdMapEntry::new(addr, our_addr, socket, socks5),
@@ -230,31 +463,195 @@ impl ClientIdMapEntry {
     }
 }
 
+/// Parse the remotes of a `--remotes-file`: one on each line, where empty
+/// lines and everything after `#` are ignored. Errors come with their line
+/// numbers.
+fn parse_remotes_file(content: &str) -> Result<Vec<Remote>, (usize, crate::parse_remote::Error)> {
+    let mut remotes = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        // `unwrap`: `split` gives at least one item
+        let line = line.split('#').next().unwrap().trim();
+        if !line.is_empty() {
+            let remote = line.parse().map_err(|err| (lineno + 1, err))?;
+            remotes.push(remote);
+        }
+    }
+    Ok(remotes)
+}
+
+/// The resolver of SOCKS and HTTP proxy requests for `--socks-dns`, given
+/// the `--resolver`
+fn socks_resolver(
+    socks_dns: Option<SocksDns>,
+    resolver: Option<Arc<Resolver>>,
+) -> Option<Arc<Resolver>> {
+    match socks_dns {
+        None => resolver,
+        Some(SocksDns::Local) => {
+            resolver.or_else(|| Some(Arc::new(Resolver::new(&ResolverUrl::System))))
+        }
+        Some(SocksDns::Remote) => None,
+    }
+}
+
 #[tracing::instrument(level = "trace")]
 pub async fn client_main(args: &'static ClientArgs) -> Result<(), Error> {
+    let file_remotes = if let Some(path) = &args.remotes_file {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(Error::RemotesFile)?;
+        let remotes = parse_remotes_file(&content)
+            .map_err(|(lineno, err)| Error::RemotesFileEntry(lineno, err))?;
+        info!("Loaded {} remotes from {path}", remotes.len());
+        remotes
+    } else {
+        Vec::new()
+    };
+    let bypass = if let Some(path) = &args.bypass_file {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(Error::BypassFile)?;
+        let bypass = content
+            .parse::<Bypass>()
+            .map_err(|(lineno, reason)| Error::BypassFileEntry(lineno, reason))?;
+        info!("Loaded bypass rules from {path}");
+        Some(Arc::new(bypass))
+    } else {
+        None
+    };
+    let overrides = if let Some(path) = &args.hosts_file {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .map_err(Error::HostsFile)?;
+        let overrides = content
+            .parse::<Overrides>()
+            .map_err(|(lineno, reason)| Error::HostsFileEntry(lineno, reason))?;
+        info!("Loaded destination overrides from {path}");
+        Some(Arc::new(overrides))
+    } else {
+        None
+    };
     // TODO: Temporary, remove when implemented
     // Blocked on `snapview/tungstenite-rs#177`
     if args.proxy.is_some() {
         warn!("Proxy not implemented yet");
     }
+    if args.daemon
+        && args
+            .remote
+            .iter()
+            .chain(&args.remote_option)
+            .chain(&file_remotes)
+            .any(|remote| remote.local_addr == LocalSpec::Stdio)
+    {
+        return Err(Error::DaemonStdio);
+    }
+    #[cfg(not(unix))]
+    if args.daemon {
+        return Err(Error::NoDaemon);
+    }
+    // Must happen before the listeners are bound
+    #[cfg(unix)]
+    let mut daemon = if args.daemon {
+        let Some(daemon) = daemon::daemonize(args.pidfile.as_deref()).await? else {
+            // The background process is connected
+            return Ok(());
+        };
+        Some(daemon)
+    } else {
+        None
+    };
+    let mut dump = DumpSignal::listen().map_err(Error::Signal)?;
     // Channel for listeners to request TCP channels the main loop
     let (stream_command_tx, mut stream_command_rx) =
         mpsc::channel::<StreamCommand>(config::STREAM_REQUEST_COMMAND_SIZE);
     // Channel for listeners to send UDP datagrams to the main loop
     let (datagram_tx, mut datagram_rx) =
-        mpsc::channel::<DatagramFrame>(config::INCOMING_DATAGRAM_BUFFER_SIZE);
+        mpsc::channel::<DatagramCommand>(config::INCOMING_DATAGRAM_BUFFER_SIZE);
     // Map of client IDs to `ClientIdMapEntry`
     let udp_client_map = Arc::new(RwLock::new(ClientIdMaps::new()));
+    // Channel for the control socket to ask the main loop
+    let (control_tx, mut control_rx) =
+        mpsc::channel::<ControlRequest>(config::CONTROL_REQUEST_SIZE);
+    let resolver = args
+        .resolver
+        .as_ref()
+        .map(|url| Arc::new(Resolver::new(url)));
     let handler_resources = HandlerResources {
         stream_command_tx,
         datagram_tx,
         udp_client_map: udp_client_map.dupe(),
+        resolver: resolver.clone(),
+        socks_resolver: socks_resolver(args.socks_dns, resolver),
+        buffers: args.buffer_sizes(),
+        listen_interface: args.listen_interface.as_deref(),
+        local_auth: args.local_auth.as_ref(),
+        pac: args.pac,
+        bypass,
+        overrides,
+        remote: None,
+        listening: Arc::default(),
     };
     let mut jobs = JoinSet::new();
+    let remotes = args
+        .remote
+        .iter()
+        .chain(&args.remote_option)
+        .chain(&file_remotes)
+        .chain(&args.transparent)
+        .chain(&args.local_forward)
+        .chain(&args.dynamic_forward);
+    let mut names = std::collections::HashSet::new();
+    for name in remotes.clone().filter_map(|remote| remote.name.as_ref()) {
+        if !names.insert(name) {
+            return Err(Error::DuplicateRemoteName(name.clone()));
+        }
+    }
+    // Port ranges get a listener for each port. They live as long as `args`.
+    let remotes: &'static [Remote] = Box::leak(remotes.flat_map(Remote::expand).collect());
     // Spawn listeners. See `handle_remote.rs` for the implementation considerations.
-    for remote in &args.remote {
+    for remote in remotes {
         jobs.spawn(handle_remote(remote, handler_resources.dupe()));
     }
+    // Also on `SIGUSR1`, where the listeners are bound
+    let listening = handler_resources.listening.dupe();
+    let mut listening_dump = dump.clone();
+    let dump_listening_future = async move {
+        loop {
+            listening_dump.requested().await;
+            listening.log();
+        }
+    };
+    #[cfg(unix)]
+    let control_future = match &args.control_socket {
+        Some(path) => {
+            let listener = control::bind(path).await.map_err(Error::ControlSocket)?;
+            futures_util::future::Either::Left(control::serve(
+                listener,
+                control_tx,
+                handler_resources.dupe(),
+            ))
+        }
+        None => futures_util::future::Either::Right(futures_util::future::pending()),
+    };
+    #[cfg(not(unix))]
+    let control_future = {
+        if args.control_socket.is_some() {
+            return Err(Error::NoControlSocket);
+        }
+        drop(control_tx);
+        futures_util::future::pending::<Infallible>()
+    };
+    let tun = match &args.tun {
+        Some(name) => {
+            let tun = Tun::create(name, args.tun_mtu, &args.tun_address).map_err(Error::Tun)?;
+            Some(Arc::new(tun))
+        }
+        None => None,
+    };
+    if let Some(tun) = &tun {
+        jobs.spawn(tun::handle_tun(tun.dupe(), handler_resources.dupe()));
+    }
     // Check if any listener has failed. If so, quit immediately.
     let check_listeners_future = async move {
         while let Some(result) = jobs.join_next().await {
@@ -276,32 +673,122 @@ pub async fn client_main(args: &'static ClientArgs) -> Result<(), Error> {
         );
         // Place to park one failed stream request so that it can be retried
         let mut failed_stream_request: Option<StreamCommand> = None;
-        // Keep alive interval
-        let keepalive = if args.keepalive == 0 {
-            None
-        } else {
-            Some(Duration::from_secs(args.keepalive))
+        // Datagram that woke us up after an idle disconnect
+        let mut pending_datagram: Option<DatagramCommand> = None;
+        let mut mux_options = Options {
+            // Keep alive interval
+            keepalive_interval: if args.keepalive == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(args.keepalive))
+            },
+            max_missed_pongs: (args.max_missed_pongs != 0).then_some(args.max_missed_pongs),
+            syn_timeout: Some(Duration::from_secs(args.channel_timeout)),
+            stream_idle_timeout: args.stream_idle_timeout.map(Duration::from_secs),
+            cork: args.cork.map(Duration::from_millis),
+            idle_timeout: args.idle_timeout.map(Duration::from_secs),
+            capabilities: Capabilities {
+                max_frame_size: Some(args.max_frame_size),
+                compression: if args.compress {
+                    Compression::supported()
+                } else {
+                    0
+                },
+                ip_packets: tun.is_some(),
+                ..Capabilities::default()
+            },
+            obfs_traffic: args.obfs_traffic,
+            datagram_overflow: args.datagram_overflow.into(),
+            ..Options::default()
         };
+        let mut adaptive_keepalive = mux_options
+            .keepalive_interval
+            .filter(|_| args.keepalive_adaptive)
+            .map(AdaptiveKeepalive::new);
         // Timeout for channel requests
         let channel_timeout = Duration::from_secs(args.channel_timeout);
+        // Shared across reconnections so that the total limit holds
+        let throughput = Throughput::new(args.limit_rate, args.limit_rate_total);
+        // Successful connections so far
+        let mut connections: u64 = 0;
+        // Removes the pidfile when we exit
+        #[cfg(unix)]
+        let mut _pidfile = None;
         // Retry loop
         loop {
+            // The server's spans descend from this one
+            let connection_span = debug_span!(parent: None, "connection");
             // TODO: Timeout for `ws_connect::handshake`.
-            match ws_connect::handshake(args).await {
-                Ok(ws_stream) => {
+            match ws_connect::handshake(args)
+                .instrument(connection_span.clone())
+                .await
+            {
+                Ok((ws_stream, frame_version)) => {
+                    #[cfg(unix)]
+                    if let Some(daemon) = daemon.take() {
+                        _pidfile = daemon.ready().map_err(Error::Daemon)?;
+                    }
+                    if let Some(adaptive_keepalive) = &adaptive_keepalive {
+                        mux_options.keepalive_interval = Some(adaptive_keepalive.current());
+                    }
+                    mux_options.frame_version = frame_version;
+                    let connected_at = time::Instant::now();
+                    let reconnects = connections;
+                    connections += 1;
                     let error = on_connected(
                         ws_stream,
                         &mut stream_command_rx,
                         &mut failed_stream_request,
+                        &mut pending_datagram,
                         &mut datagram_rx,
                         udp_client_map.dupe(),
-                        keepalive,
+                        &mux_options,
                         channel_timeout,
+                        &throughput,
+                        &mut dump,
+                        &mut control_rx,
+                        reconnects,
+                        tun.as_deref(),
                     )
+                    .instrument(connection_span)
                     .await
                     .expect_err("on_connected should never return `Ok` (this is a bug)");
+                    if matches!(error, Error::Mux(penguin_mux::Error::GoingAway)) {
+                        // The old connection stays until its streams finish
+                        backoff.reset();
+                        continue;
+                    }
+                    if matches!(error, Error::Mux(penguin_mux::Error::Idle)) {
+                        info!("Disconnected from server after being idle");
+                        // Reconnect when there is something to send
+                        loop {
+                            tokio::select! {
+                                Some(command) = stream_command_rx.recv() => {
+                                    failed_stream_request.replace(command);
+                                    break;
+                                }
+                                Some(datagram) = datagram_rx.recv() => {
+                                    pending_datagram.replace(datagram);
+                                    break;
+                                }
+                                Some(request) = control_rx.recv() => {
+                                    request.answer_disconnected(reconnects);
+                                }
+                                else => unreachable!("`handler_resources` holds the senders (this is a bug)"),
+                            }
+                        }
+                        backoff.reset();
+                        continue;
+                    }
                     if error.retryable() {
                         warn!("Disconnected from server: {error}");
+                        if let Some(adaptive_keepalive) = &mut adaptive_keepalive {
+                            adaptive_keepalive.on_disconnect(connected_at.elapsed());
+                            info!(
+                                "Keepalive interval is now {:?}",
+                                adaptive_keepalive.current()
+                            );
+                        }
                         // Since we once connected, reset the retry count
                         backoff.reset();
                         // Now retry
@@ -322,12 +809,27 @@ pub async fn client_main(args: &'static ClientArgs) -> Result<(), Error> {
                 return Err(Error::MaxRetryCountReached);
             };
             warn!("Reconnecting in {current_retry_interval:?}");
-            time::sleep(current_retry_interval).await;
+            let sleep = time::sleep(current_retry_interval);
+            tokio::pin!(sleep);
+            loop {
+                tokio::select! {
+                    () = &mut sleep => break,
+                    () = dump.requested() => {
+                        info!(reconnects = connections.saturating_sub(1), "Not connected to server");
+                    }
+                    Some(request) = control_rx.recv() => {
+                        request.answer_disconnected(connections.saturating_sub(1));
+                    }
+                }
+            }
         }
     };
     tokio::select! {
         biased;
         result = check_listeners_future => result,
+        // This future never resolves either
+        () = dump_listening_future => unreachable!("dump_listening_future should never return"),
+        _ = control_future => unreachable!("control_future should never return"),
         // This future never resolves
         _ = prune_client_id_map_task(handler_resources) => unreachable!("prune_client_id_map_task should never return"),
         result = main_future => result,
@@ -342,89 +844,245 @@ pub async fn client_main(args: &'static ClientArgs) -> Result<(), Error> {
 /// This function returns when the connection is lost, and the caller should
 /// retry based on the error.
 #[tracing::instrument(skip_all, level = "debug")]
+#[allow(clippy::too_many_arguments)]
 async fn on_connected(
-    ws_stream: tokio_tungstenite::WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ws_stream: WebSocket,
     stream_command_rx: &mut mpsc::Receiver<StreamCommand>,
     failed_stream_request: &mut Option<StreamCommand>,
-    datagram_rx: &mut mpsc::Receiver<DatagramFrame>,
+    pending_datagram: &mut Option<DatagramCommand>,
+    datagram_rx: &mut mpsc::Receiver<DatagramCommand>,
     udp_client_map: Arc<RwLock<ClientIdMaps>>,
-    keepalive: Option<Duration>,
+    mux_options: &Options,
     channel_timeout: Duration,
+    throughput: &Throughput,
+    dump: &mut DumpSignal,
+    control_rx: &mut mpsc::Receiver<ControlRequest>,
+    reconnects: u64,
+    tun: Option<&Tun>,
 ) -> Result<Infallible, Error> {
     let mut mux_task_joinset = JoinSet::new();
-    let mut mux = Multiplexor::new(
+    let mut mux = Multiplexor::with_options(
         ws_stream,
         Role::Client,
-        keepalive,
+        mux_options.clone(),
         Some(&mut mux_task_joinset),
     );
     info!("Connected to server");
-    // If we have a failed stream request, try it first
-    if let Some(sender) = failed_stream_request.take() {
-        get_send_stream_chan(&mut mux, sender, failed_stream_request, channel_timeout).await?;
-    }
-    // Main loop
-    loop {
-        tokio::select! {
-            Some(mux_task_joinset_result) = mux_task_joinset.join_next() => {
-                mux_task_joinset_result.expect("JoinSet panicked (this is a bug)")?;
-            }
-            Some(sender) = stream_command_rx.recv() => {
-                get_send_stream_chan(&mut mux, sender, failed_stream_request, channel_timeout).await?;
+    let result: Result<Infallible, Error> = async {
+        // If we have a failed stream request, try it first
+        if let Some(sender) = failed_stream_request.take() {
+            get_send_stream_chan(
+                &mut mux,
+                sender,
+                failed_stream_request,
+                channel_timeout,
+                throughput,
+            )
+            .await?;
+        }
+        if let Some(datagram) = pending_datagram.take() {
+            if let Err(e) = datagram.send(&mux).await {
+                error!("{e}");
             }
-            Some(datagram) = datagram_rx.recv() => {
-                if let Err(e) = mux.send_datagram(datagram).await {
-                    error!("{e}");
+        }
+        // Whether we told the user that the server drops our IP packets
+        let mut warned_ip_packets = false;
+        // Whether we told the user that the server ignores TCP options
+        let mut warned_tcp_options = false;
+        // Main loop
+        loop {
+            tokio::select! {
+                Some(mux_task_joinset_result) = mux_task_joinset.join_next() => {
+                    if let Some(rtt) = mux.rtt() {
+                        info!("Round-trip time to server: {rtt}");
+                    }
+                    mux_task_joinset_result.expect("JoinSet panicked (this is a bug)")?;
+                    // The server closed the connection
+                    return Err(Error::RemoteDisconnected);
                 }
-            }
-            Ok(dgram_frame) = mux.get_datagram() => {
-                let client_id = dgram_frame.sid;
-                let data = dgram_frame.data;
-                match ClientIdMaps::send_datagram(&udp_client_map, client_id, data).await {
-                    Some(Ok(())) => {
-                        trace!("sent datagram to client {client_id}");
+                Some(sender) = stream_command_rx.recv() => {
+                    let has_tcp_options = !sender.tcp_options.is_empty();
+                    get_send_stream_chan(&mut mux, sender, failed_stream_request, channel_timeout, throughput).await?;
+                    // The server's `Hello` arrives before its answer
+                    if has_tcp_options
+                        && !warned_tcp_options
+                        && !mux.peer_capabilities().is_some_and(|caps| caps.tcp_options)
+                    {
+                        warn!("The server does not support TCP options; they only apply to local connections");
+                        warned_tcp_options = true;
+                    }
+                }
+                Some(datagram) = datagram_rx.recv() => {
+                    if crate::tun::is_packet(&datagram.frame)
+                        && !mux.peer_capabilities().is_some_and(|caps| caps.ip_packets)
+                    {
+                        if !warned_ip_packets {
+                            warn!("Dropping IP packets: the server was not started with --tun");
+                            warned_ip_packets = true;
+                        }
+                    } else if let Err(e) = datagram.send(&mux).await {
+                        error!("{e}");
                     }
-                    Some(Err(e)) => {
-                        warn!("Failed to send datagram to client {client_id}: {e}");
+                }
+                () = mux.peer_going_away() => {
+                    return Err(penguin_mux::Error::GoingAway.into());
+                }
+                () = dump.requested() => {
+                    let stats = mux.stats().await;
+                    info!(
+                        streams = stats.streams.len(),
+                        reconnects,
+                        stream_request_queue = stream_command_rx.len(),
+                        datagram_queue = datagram_rx.len(),
+                        received_datagram_queue = stats.datagrams_queued,
+                        dropped_datagrams = stats.datagrams_dropped,
+                        dropped_port_queue = stats.dropped_ports_queued,
+                        ack_queue = stats.acks_queued,
+                        unsent_bytes = stats.bytes_unsent,
+                        "Client statistics"
+                    );
+                    // Sending is to the server
+                    for stream in &stats.streams {
+                        info!(
+                            stream_id = stream.id,
+                            destination = format_args!(
+                                "{}:{}",
+                                String::from_utf8_lossy(&stream.dest_host),
+                                stream.dest_port
+                            ),
+                            bytes_up = stream.bytes_sent,
+                            bytes_down = stream.bytes_received,
+                            frames_up = stream.frames_sent,
+                            frames_down = stream.frames_received,
+                            frame_queue = stream.frames_queued,
+                            "Stream statistics"
+                        );
                     }
-                    None => {
-                        // Just drop the datagram
-                        info!("Received datagram for unknown client ID: {client_id}");
+                    if let Some(rtt) = mux.rtt() {
+                        info!("Round-trip time to server: {rtt}");
                     }
                 }
+                Some(request) = control_rx.recv() => {
+                    request.answer(&mux, reconnects).await;
+                }
+                Ok(dgram_frame) = mux.get_datagram() => {
+                    if crate::tun::is_packet(&dgram_frame) {
+                        if let Some(tun) = tun {
+                            if let Err(e) = tun.send(&dgram_frame.data).await {
+                                warn!("Failed to send IP packet to {}: {e}", tun.name());
+                            }
+                        }
+                        continue;
+                    }
+                    // Servers echo the full-cone flag we set
+                    let client_id = dgram_frame.sid & !config::UDP_FULL_CONE_FLAG;
+                    let data = dgram_frame.data;
+                    match ClientIdMaps::send_datagram(&udp_client_map, client_id, data).await {
+                        Some(Ok(())) => {
+                            trace!("sent datagram to client {client_id}");
+                        }
+                        Some(Err(e)) => {
+                            warn!("Failed to send datagram to client {client_id}: {e}");
+                        }
+                        None => {
+                            // Just drop the datagram
+                            info!("Received datagram for unknown client ID: {client_id}");
+                        }
+                    }
+                }
+                else => {
+                    // The multiplexor has closed for some reason
+                    return Err(Error::RemoteDisconnected);
+                }
             }
-            else => {
-                // The multiplexor has closed for some reason
-                return Err(Error::RemoteDisconnected);
-            }
         }
     }
+    .await;
+    if mux.is_peer_going_away() {
+        info!("Server is going away, moving to a new connection");
+        tokio::spawn(drain(mux, mux_task_joinset).in_current_span());
+        return Err(penguin_mux::Error::GoingAway.into());
+    }
+    result
+}
+
+/// Keep a connection the server is going away from until the server closes
+/// it, so that the streams still open on it can finish. Dropping `mux`
+/// earlier would reset them.
+async fn drain(
+    mux: Multiplexor<WebSocket>,
+    mut mux_task_joinset: JoinSet<penguin_mux::Result<()>>,
+) {
+    while let Some(result) = mux_task_joinset.join_next().await {
+        match result.expect("JoinSet panicked (this is a bug)") {
+            Ok(()) => debug!("Old connection closed"),
+            Err(err) => debug!("Old connection closed: {err}"),
+        }
+    }
+    drop(mux);
 }
 
 /// Get a new channel from the multiplexor and send it to the handler.
 /// If we fail, put the request back in the failed_stream_request slot.
 #[tracing::instrument(skip_all, level = "trace")]
 async fn get_send_stream_chan(
-    mux: &mut Multiplexor<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    mux: &mut Multiplexor<WebSocket>,
     stream_command: StreamCommand,
     failed_stream_request: &mut Option<StreamCommand>,
     channel_timeout: Duration,
+    throughput: &Throughput,
 ) -> Result<(), Error> {
     trace!("requesting a new TCP channel");
+    let span = stream_command.span.clone();
+    // Link the stream to the connection carrying it
+    span.follows_from(Span::current());
+    // and let the server's forwarder join the stream's trace
+    let trace_context = crate::otel::stream_context(&span);
+    // The mux gives up on the server's answer after `channel_timeout`. If
+    // even sending the `Syn` takes longer, the connection is likely dead.
     match tokio::time::timeout(
-        channel_timeout,
-        mux.client_new_stream_channel(&stream_command.host, stream_command.port),
+        channel_timeout * 2,
+        mux.client_new_stream_channel_with_context(
+            &stream_command.host,
+            stream_command.port,
+            stream_command.tcp_options,
+            &trace_context,
+        ),
     )
+    .instrument(span)
     .await
     {
-        Ok(Ok(stream)) => {
+        Ok(Ok(mut stream)) => {
             trace!("got a new channel");
+            throughput.apply(&mut stream);
             // `Err(_)` means "the corresponding receiver has already been deallocated"
             // which means we don't care about the channel anymore.
-            stream_command.tx.send(stream).ok();
+            stream_command.tx.send(Ok(stream)).ok();
             trace!("sent stream to handler (or handler died)");
             Ok(())
         }
+        Ok(Err(penguin_mux::Error::StreamRejected(reason, message))) => {
+            // Only this stream failed
+            warn!(
+                "Server rejected stream to {}:{} ({reason}): {message}",
+                String::from_utf8_lossy(&stream_command.host),
+                stream_command.port
+            );
+            let error = std::io::Error::new(reason.into(), message);
+            stream_command.tx.send(Err(error)).ok();
+            Ok(())
+        }
+        Ok(Err(penguin_mux::Error::SynTimeout)) => {
+            // The server may just be slow to connect to this destination
+            warn!(
+                "Server did not answer stream request to {}:{} in time",
+                String::from_utf8_lossy(&stream_command.host),
+                stream_command.port
+            );
+            let error = std::io::ErrorKind::TimedOut.into();
+            stream_command.tx.send(Err(error)).ok();
+            Ok(())
+        }
         Ok(Err(e)) => {
             failed_stream_request.replace(stream_command);
             Err(e.into())
@@ -455,11 +1113,7 @@ mod test {
     async fn test_client_map_add_client() {
         let (stub_stream_tx, _stub_stream_rx) = mpsc::channel(1);
         let (stub_datagram_tx, _stub_datagram_rx) = mpsc::channel(1);
-        let handler_resources = HandlerResources {
-            stream_command_tx: stub_stream_tx,
-            datagram_tx: stub_datagram_tx,
-            udp_client_map: Arc::new(RwLock::new(ClientIdMaps::new())),
-        };
+        let handler_resources = HandlerResources::for_test(stub_stream_tx, stub_datagram_tx);
         let stub_socket = Arc::new(UdpSocket::bind(("127.0.0.1", 0)).await.unwrap());
         let client_id = handler_resources
             .add_udp_client(
@@ -502,11 +1156,7 @@ mod test {
     async fn test_client_map_remove_client() {
         let (stub_stream_tx, _stub_stream_rx) = mpsc::channel(1);
         let (stub_datagram_tx, _stub_datagram_rx) = mpsc::channel(1);
-        let handler_resources = HandlerResources {
-            stream_command_tx: stub_stream_tx,
-            datagram_tx: stub_datagram_tx,
-            udp_client_map: Arc::new(RwLock::new(ClientIdMaps::new())),
-        };
+        let handler_resources = HandlerResources::for_test(stub_stream_tx, stub_datagram_tx);
         let stub_socket = Arc::new(UdpSocket::bind(("127.0.0.1", 0)).await.unwrap());
         let _ = handler_resources
             .add_udp_client(
@@ -524,4 +1174,60 @@ mod test {
             .client_id_map
             .is_empty());
     }
+
+    #[tokio::test]
+    async fn test_listening_reports_picked_port() {
+        let (stub_stream_tx, _stub_stream_rx) = mpsc::channel(1);
+        let (stub_datagram_tx, _stub_datagram_rx) = mpsc::channel(1);
+        let listening = Arc::<Listening>::default();
+        let handler_resources = HandlerResources {
+            listening: listening.dupe(),
+            ..HandlerResources::for_test(stub_stream_tx, stub_datagram_tx)
+        };
+        let remote: &'static Remote =
+            Box::leak(Box::new("127.0.0.1:0:example.com:80".parse().unwrap()));
+        let task = tokio::spawn(handle_remote(remote, handler_resources));
+        let addr = loop {
+            if let Some((reported, addr)) = listening.addrs.lock().first() {
+                assert_eq!(*reported, remote);
+                break *addr;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert_eq!(addr.ip(), IpAddr::from([127, 0, 0, 1]));
+        assert_ne!(addr.port(), 0);
+        TcpStream::connect(addr).await.unwrap();
+        task.abort();
+    }
+
+    #[test]
+    fn test_socks_resolver() {
+        let resolver = Arc::new(Resolver::new(&"1.1.1.1".parse().unwrap()));
+        assert!(socks_resolver(None, None).is_none());
+        let same = socks_resolver(None, Some(resolver.dupe())).unwrap();
+        assert!(Arc::ptr_eq(&same, &resolver));
+        let same = socks_resolver(Some(SocksDns::Local), Some(resolver.dupe())).unwrap();
+        assert!(Arc::ptr_eq(&same, &resolver));
+        assert!(socks_resolver(Some(SocksDns::Local), None).is_some());
+        assert!(socks_resolver(Some(SocksDns::Remote), Some(resolver)).is_none());
+    }
+
+    #[test]
+    fn test_parse_remotes_file() {
+        let remotes = parse_remotes_file(
+            "# Forwards\n\ndb=5432:db.internal:5432\n  socks  # for the browser\n",
+        )
+        .unwrap();
+        assert_eq!(
+            remotes,
+            [
+                "db=5432:db.internal:5432".parse().unwrap(),
+                "socks".parse::<Remote>().unwrap(),
+            ]
+        );
+        assert!(matches!(
+            parse_remotes_file("socks\n\nsocks/udp\n"),
+            Err((3, _))
+        ));
+    }
 }
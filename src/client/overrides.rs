@@ -0,0 +1,145 @@
+//! Destination overrides of `--hosts-file`, which rewrite where streams
+//! through the server go, e.g. to give a server-side address a name.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Rewritten destinations
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Overrides {
+    /// Lowercase name and port, or no port for every port, to the host and
+    /// port, or no port to keep the requested one, to go to instead
+    entries: HashMap<(String, Option<u16>), (Bytes, Option<u16>)>,
+}
+
+impl FromStr for Overrides {
+    /// Line number and reason
+    type Err = (usize, &'static str);
+
+    /// Parse the entries of a `--hosts-file`: `NAME[:PORT] -> HOST[:PORT]`
+    /// on each line, where empty lines and everything after `#` are
+    /// ignored.
+    fn from_str(content: &str) -> Result<Self, Self::Err> {
+        let mut overrides = Self::default();
+        for (lineno, line) in content.lines().enumerate() {
+            // `unwrap`: `split` gives at least one item
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (name, target) = line
+                .split_once("->")
+                .ok_or((lineno + 1, "expected NAME[:PORT] -> HOST[:PORT]"))?;
+            let (name, port) = split_host_port(name.trim()).map_err(|err| (lineno + 1, err))?;
+            let (host, target_port) =
+                split_host_port(target.trim()).map_err(|err| (lineno + 1, err))?;
+            let key = (name.to_ascii_lowercase(), port);
+            let value = (Bytes::from(host.to_string()), target_port);
+            if overrides.entries.insert(key, value).is_some() {
+                return Err((lineno + 1, "duplicate name"));
+            }
+        }
+        Ok(overrides)
+    }
+}
+
+impl Overrides {
+    /// Where to go instead of `host:port`, if anywhere. An entry for the
+    /// port wins over one for every port.
+    pub fn rewrite(&self, host: &[u8], port: u16) -> Option<(Bytes, u16)> {
+        let host = String::from_utf8_lossy(host).to_ascii_lowercase();
+        let mut key = (host.trim_end_matches('.').to_string(), Some(port));
+        let (target, target_port) = match self.entries.get(&key) {
+            Some(entry) => entry,
+            None => {
+                key.1 = None;
+                self.entries.get(&key)?
+            }
+        };
+        Some((target.clone(), target_port.unwrap_or(port)))
+    }
+}
+
+/// Split `HOST[:PORT]`, where an IPv6 address with a port is in brackets.
+fn split_host_port(s: &str) -> Result<(&str, Option<u16>), &'static str> {
+    let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or("unclosed bracket")?;
+        match rest {
+            "" => (host, None),
+            _ => (host, Some(rest.strip_prefix(':').ok_or("invalid port")?)),
+        }
+    } else {
+        match s.split_once(':') {
+            // A bare IPv6 address
+            Some((_, port)) if port.contains(':') => (s, None),
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        }
+    };
+    if host.is_empty() || host.contains(char::is_whitespace) {
+        return Err("invalid host");
+    }
+    let port = port
+        .map(|port| port.parse::<u16>().ok().filter(|port| *port != 0))
+        .map(|port| port.ok_or("invalid port"))
+        .transpose()?;
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("db"), Ok(("db", None)));
+        assert_eq!(split_host_port("db:5432"), Ok(("db", Some(5432))));
+        assert_eq!(split_host_port("fd00::1"), Ok(("fd00::1", None)));
+        assert_eq!(split_host_port("[fd00::1]"), Ok(("fd00::1", None)));
+        assert_eq!(split_host_port("[fd00::1]:22"), Ok(("fd00::1", Some(22))));
+        split_host_port("db:0").unwrap_err();
+        split_host_port("db:http").unwrap_err();
+        split_host_port("[fd00::1").unwrap_err();
+        split_host_port("[fd00::1]22").unwrap_err();
+        split_host_port("").unwrap_err();
+    }
+
+    #[test]
+    fn test_overrides() {
+        let overrides: Overrides = "# Server-side names\n\n\
+                                    internal.db -> 10.1.2.3:5432\n\
+                                    Wiki -> wiki.corp.internal  # all ports\n\
+                                    wiki:80 -> [fd00::80]:8080\n"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            overrides.rewrite(b"internal.db", 5432),
+            Some((Bytes::from("10.1.2.3"), 5432))
+        );
+        assert_eq!(
+            overrides.rewrite(b"Internal.DB.", 1),
+            Some((Bytes::from("10.1.2.3"), 5432))
+        );
+        assert_eq!(
+            overrides.rewrite(b"wiki", 443),
+            Some((Bytes::from("wiki.corp.internal"), 443))
+        );
+        assert_eq!(
+            overrides.rewrite(b"wiki", 80),
+            Some((Bytes::from("fd00::80"), 8080))
+        );
+        assert_eq!(overrides.rewrite(b"db", 5432), None);
+        assert_eq!(
+            "a -> b\n\na:1 -> c\nb c\n".parse::<Overrides>(),
+            Err((4, "expected NAME[:PORT] -> HOST[:PORT]"))
+        );
+        assert_eq!(
+            "a -> b\nA -> c\n".parse::<Overrides>(),
+            Err((2, "duplicate name"))
+        );
+        assert_eq!("a -> b:x\n".parse::<Overrides>().unwrap_err().0, 1);
+    }
+}
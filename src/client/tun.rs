@@ -0,0 +1,35 @@
+//! Forward the IP packets of a TUN device through the tunnel.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::handle_remote::FatalError;
+use super::{DatagramCommand, HandlerResources};
+use crate::config;
+use crate::tun::{packet_frame, Tun};
+use bytes::Bytes;
+use std::sync::Arc;
+use tracing::info;
+
+/// Send the packets routed to `tun` to the main loop. The main loop writes
+/// the packets from the server to `tun` itself.
+#[tracing::instrument(skip_all, fields(tun = tun.name()), level = "debug")]
+pub(super) async fn handle_tun(
+    tun: Arc<Tun>,
+    handler_resources: HandlerResources,
+) -> Result<(), FatalError> {
+    info!("Forwarding IP packets from {}", tun.name());
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+    loop {
+        // Not being able to read from the device is a fatal error.
+        let len = tun.recv(&mut buf).await.map_err(FatalError::ClientIo)?;
+        let datagram = DatagramCommand {
+            frame: packet_frame(Bytes::copy_from_slice(&buf[..len])),
+            reliable: false,
+        };
+        handler_resources
+            .datagram_tx
+            .send(datagram)
+            .await
+            .map_err(|_| FatalError::SendDatagram)?;
+    }
+}
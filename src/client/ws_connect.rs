@@ -2,17 +2,22 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+use super::WebSocket;
 use crate::arg::ClientArgs;
-use crate::proto_version::PROTOCOL_VERSION;
+use crate::challenge::client_respond;
+use crate::noise::{client_handshake, NoiseTransport};
+use crate::proto_version::{self, OFFERED_PROTOCOLS};
+use crate::sockopt;
 use crate::tls::make_tls_connector;
+use crate::totp::TotpSecret;
 use crate::Dupe;
 use http::header::HeaderValue;
+use penguin_mux::FrameVersion;
+use std::io;
 use thiserror::Error;
-use tokio::net::TcpStream;
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
 use tokio_tungstenite::tungstenite::{client::IntoClientRequest, handshake::client::Request};
-use tokio_tungstenite::{
-    connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream,
-};
+use tokio_tungstenite::Connector;
 use tracing::{debug, warn};
 
 /// Error type for `WebSocket` connection.
@@ -24,13 +29,21 @@ pub enum Error {
     /// TLS error
     #[error(transparent)]
     Tls(#[from] crate::tls::Error),
+    /// PSK challenge error
+    #[error(transparent)]
+    Challenge(#[from] crate::challenge::Error),
+    /// Noise handshake error
+    #[error(transparent)]
+    Noise(#[from] crate::noise::Error),
+    /// The server picked a protocol version we did not offer
+    #[error("Server chose unsupported protocol {0:?}")]
+    UnsupportedProtocol(HeaderValue),
 }
 
-/// Perform a `WebSocket` handshake.
+/// Perform a `WebSocket` handshake, and the Noise one if configured. Returns
+/// the frame format of the protocol version the server chose.
 #[tracing::instrument(skip_all, fields(server = %args.server.0), level = "debug")]
-pub async fn handshake(
-    args: &ClientArgs,
-) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, Error> {
+pub async fn handshake(args: &ClientArgs) -> Result<(WebSocket, FrameVersion), Error> {
     // We already sanitized https URLs to wss
     let is_tls = args
         .server
@@ -42,14 +55,21 @@ pub async fn handshake(
     // Use a request to allow additional headers
     let mut req: Request = args.server.0.dupe().into_client_request()?;
     let req_headers = req.headers_mut();
-    // Add protocol version
+    // Offer the protocol versions we speak
     req_headers.insert(
         "sec-websocket-protocol",
-        HeaderValue::from_static(PROTOCOL_VERSION),
+        HeaderValue::from_static(OFFERED_PROTOCOLS),
     );
-    // Add PSK
-    if let Some(ref ws_psk) = args.ws_psk {
-        req_headers.insert("x-penguin-psk", ws_psk.dupe());
+    let ws_psk = args
+        .ws_psk_totp
+        .as_ref()
+        .map(TotpSecret::current_psk)
+        .or_else(|| args.ws_psk.as_ref().map(Dupe::dupe));
+    // Add PSK unless we are going to prove it with the challenge
+    if let Some(ref ws_psk) = ws_psk {
+        if !args.ws_psk_challenge {
+            req_headers.insert("x-penguin-psk", ws_psk.dupe());
+        }
     }
     // Add potentially custom hostname
     if let Some(ref hostname) = args.hostname {
@@ -59,6 +79,8 @@ pub async fn handshake(
     for header in &args.header {
         req_headers.insert(&header.name, header.value.dupe());
     }
+    // Let the server's spans join our trace
+    crate::otel::inject(req_headers);
 
     let connector = if is_tls {
         make_tls_connector(
@@ -66,6 +88,7 @@ pub async fn handshake(
             args.tls_key.as_deref(),
             args.tls_ca.as_deref(),
             args.tls_skip_verify,
+            &args.tls_pin,
         )
         .await?
     } else {
@@ -73,9 +96,76 @@ pub async fn handshake(
         warn!("Using insecure WebSocket connection");
         Connector::Plain
     };
-    let (ws_stream, _response) =
-        connect_async_tls_with_config(req, None, false, Some(connector)).await?;
-    // We don't need to check the response now...
-    debug!("WebSocket handshake succeeded");
-    Ok(ws_stream)
+    let stream = connect_tcp(args, is_tls)
+        .await
+        .map_err(tokio_tungstenite::tungstenite::Error::Io)?;
+    let (mut ws_stream, response) =
+        crate::ws::connect(req, stream, args.max_frame_size, connector).await?;
+    let protocol = response.headers().get("sec-websocket-protocol");
+    let frame_version = match protocol {
+        Some(protocol) => proto_version::frame_version(protocol.as_bytes())
+            .ok_or_else(|| Error::UnsupportedProtocol(protocol.dupe()))?,
+        // Servers before `penguin-v7` always answer, but be lenient
+        None => FrameVersion::V1,
+    };
+    debug!("WebSocket handshake succeeded with {frame_version:?} frames");
+    if args.ws_psk_challenge {
+        // `expect`: `clap` ensures that `--ws-psk` or `--ws-psk-totp` is specified
+        let ws_psk = ws_psk.expect("`ws_psk` is `None` (this is a bug)");
+        client_respond(&mut ws_stream, ws_psk.as_bytes()).await?;
+        debug!("Answered PSK challenge");
+    }
+    let cipher = match &args.noise_server_key {
+        Some(server_key) => {
+            let protocol = protocol.map_or(&[][..], HeaderValue::as_bytes);
+            let cipher = client_handshake(
+                &mut ws_stream,
+                args.noise_key.as_ref(),
+                server_key,
+                protocol,
+            )
+            .await?;
+            debug!("Noise handshake succeeded");
+            Some(cipher)
+        }
+        None => None,
+    };
+    let ws_stream = NoiseTransport::new(ws_stream, cipher);
+    Ok((ws_stream, frame_version))
+}
+
+/// Open the TCP connection to the server, with our socket options set
+/// before connecting.
+async fn connect_tcp(args: &ClientArgs, is_tls: bool) -> io::Result<TcpStream> {
+    let host = args
+        .server
+        .host()
+        .expect("URL host should be present (this is a bug)");
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = args
+        .server
+        .port_u16()
+        .unwrap_or(if is_tls { 443 } else { 80 });
+    let mut last_err = None;
+    for address in lookup_host((host, port)).await? {
+        let socket = if address.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(dscp) = args.dscp {
+            sockopt::set_dscp(&socket, dscp)?;
+        }
+        args.buffer_sizes().set(&socket)?;
+        match socket.connect(address).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{host} did not resolve to any address"),
+        )
+    }))
 }
@@ -6,11 +6,70 @@ use tokio::time;
 
 /// Both: how long to wait for responses to UDP outgoing datagrams
 pub const UDP_PRUNE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Server side: default number of UDP sessions a tunnel may have open
+pub const UDP_MAX_SESSIONS: usize = 1 << 10;
+/// Bit of the client ID of a datagram that asks the server to forward it
+/// with full-cone NAT
+pub const UDP_FULL_CONE_FLAG: u32 = 1 << 31;
+/// Server side: number of datagrams to buffer for each UDP session
+pub const UDP_SESSION_QUEUE_SIZE: usize = 1 << 4;
 /// Client side: Number of stream requests to buffer in the channels for the main
 /// loop to read from.
 pub const STREAM_REQUEST_COMMAND_SIZE: usize = 1 << 6;
+/// Client side: Number of `--control-socket` requests to buffer for the main
+/// loop to read from.
+pub const CONTROL_REQUEST_SIZE: usize = 1 << 4;
+/// Client side: how long the `--control-socket` waits for the main loop,
+/// which does not answer while connecting to the server
+pub const CONTROL_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+/// Client side: longest request head the HTTP proxy of "socks" remotes reads
+pub const HTTP_PROXY_MAX_HEAD_SIZE: usize = 1 << 14;
+/// Client side: longest `WebSocket` handshake response the `fast-ws`
+/// backend reads
+#[cfg(feature = "fast-ws")]
+pub const WS_MAX_RESPONSE_HEAD_SIZE: usize = 1 << 14;
 /// Both: Number of datagrams to buffer in the channels for the main loop
 /// to read from.
 pub const INCOMING_DATAGRAM_BUFFER_SIZE: usize = 1 << 6;
+/// Both: default MTU of TUN devices, leaving room for the tunnel's headers
+/// on a 1500-byte link
+pub const TUN_MTU: u16 = 1400;
+/// Server side: most source addresses each tunnel may send IP packets from
+pub const TUN_ADDRESSES_PER_TUNNEL: usize = 1 << 4;
+/// Server side: most source addresses routed back to tunnels, across all
+/// of them
+pub const TUN_MAX_ROUTES: usize = 1 << 12;
+/// Both: size of the buffer for each direction when piping a TCP stream to
+/// and from a channel. Each read becomes one frame, so larger buffers mean
+/// fewer frames and less CPU per byte at high rates.
+pub const TCP_RELAY_BUFFER_SIZE: usize = 1 << 16;
 /// Both: Maximum size of a UDP packet.
 pub const MAX_UDP_PACKET_SIZE: usize = 1 << 16;
+/// Server side: how often to health-check backends with alternatives
+pub const BACKEND_HEALTH_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(10);
+/// Server side: how long to wait for a backend health check response
+pub const BACKEND_HEALTH_CHECK_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+/// Both: how long to wait for the PSK challenge or its response
+pub const PSK_CHALLENGE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Both: how long to wait for each message of the Noise handshake
+pub const NOISE_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+/// Client side: how long to wait for an answer from the `--resolver`
+pub const DNS_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+/// Client side: how many hostnames the `--resolver` remembers
+pub const DNS_CACHE_SIZE: usize = 1 << 10;
+/// Client side: longest the `--resolver` remembers a hostname, whatever
+/// its TTL
+pub const DNS_MAX_TTL: time::Duration = time::Duration::from_secs(3600);
+/// Server side: number of access or audit log lines to queue for writing
+/// before dropping new ones
+pub const LOG_QUEUE_SIZE: usize = 1 << 12;
+/// Server side: default longest lifetime of a signed PSK token, in seconds
+pub const TOKEN_MAX_TTL: u64 = 24 * 60 * 60;
+/// Server side: how long to wait for the destination of a stream to accept
+/// the connection. Shorter than the client's default `--channel-timeout`, so
+/// that the client hears why instead of giving up.
+pub const TCP_CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(8);
+/// Server side: how long to wait for a connection attempt before also
+/// trying the next address of the destination (RFC 8305's "Connection
+/// Attempt Delay")
+pub const HAPPY_EYEBALLS_DELAY: time::Duration = time::Duration::from_millis(250);
@@ -0,0 +1,356 @@
+//! The control socket of a running client, and `penguin status` and
+//! `penguin control`, which talk to it.
+//!
+//! Each request is a line with a [`Command`], and each response is a line
+//! of JSON: the result, or `{"error": "..."}`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::{ControlArgs, StatusArgs};
+use crate::parse_remote::Remote;
+use penguin_mux::StreamStats;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Command that asks for a [`Status`]
+pub const STATUS_COMMAND: &str = "status";
+
+/// Answer to the `help` command
+pub const HELP: &str = "\
+status: the connection to the server, the listeners, and the open streams
+streams: the open streams
+kill ID: reset the stream with this ID
+add REMOTE: listen for another remote, e.g. \"add web=8080:example.com:80\"
+help: this list";
+
+/// A command of the control socket
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Answered with a [`Status`]
+    Status,
+    /// Answered with the [`StreamStatus`]es
+    Streams,
+    /// Reset a stream. Answered with its ID.
+    Kill(u32),
+    /// Listen for another remote. Answered with the [`RemoteStatus`]es of
+    /// its listeners.
+    Add(Remote),
+    /// Answered with [`HELP`]
+    Help,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (command, argument) = s
+            .split_once(char::is_whitespace)
+            .map_or((s, ""), |(command, argument)| (command, argument.trim()));
+        match (command, argument) {
+            (STATUS_COMMAND, "") => Ok(Self::Status),
+            ("streams", "") => Ok(Self::Streams),
+            ("help", "") => Ok(Self::Help),
+            ("kill", id) => id
+                .parse()
+                .map(Self::Kill)
+                .map_err(|_| format!("invalid stream ID {id:?}")),
+            ("add", remote) => remote
+                .parse()
+                .map(Self::Add)
+                .map_err(|err| format!("invalid remote {remote:?}: {err}")),
+            _ => Err(format!("unknown command {s:?}, try \"help\"")),
+        }
+    }
+}
+
+/// `penguin status` and `penguin control` errors
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot connect to the control socket: {0}")]
+    Connect(std::io::Error),
+    #[error("Cannot talk to the client: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid response from the client: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("The client closed the control socket without answering")]
+    NoResponse,
+    #[error("The client answered: {0}")]
+    Client(String),
+    #[cfg(not(unix))]
+    #[error("Control sockets are only supported on Unix")]
+    Unsupported,
+}
+
+/// Status of a client
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    /// Whether the client is connected to the server
+    pub connected: bool,
+    /// How many times the client connected to the server again
+    pub reconnects: u64,
+    /// Mean round-trip time to the server in milliseconds, if measured
+    pub rtt_ms: Option<f64>,
+    /// Where the listeners of the remotes are bound
+    pub remotes: Vec<RemoteStatus>,
+    /// Streams open on the current connection
+    pub streams: Vec<StreamStatus>,
+}
+
+/// A bound listener of a remote
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteStatus {
+    /// Name of the remote, or the remote itself if it has none
+    pub remote: String,
+    pub local_addr: SocketAddr,
+}
+
+/// A stream open on the connection to the server
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StreamStatus {
+    pub id: u32,
+    /// `host:port` the server forwards the stream to
+    pub destination: String,
+    /// Bytes sent to the server
+    pub bytes_up: u64,
+    /// Bytes received from the server
+    pub bytes_down: u64,
+}
+
+impl From<&StreamStats> for StreamStatus {
+    fn from(stats: &StreamStats) -> Self {
+        Self {
+            id: stats.id,
+            destination: format!(
+                "{}:{}",
+                String::from_utf8_lossy(&stats.dest_host),
+                stats.dest_port
+            ),
+            bytes_up: stats.bytes_sent,
+            bytes_down: stats.bytes_received,
+        }
+    }
+}
+
+/// A response line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Response<T> {
+    Error { error: String },
+    Ok(T),
+}
+
+impl std::fmt::Display for Status {
+    /// Tables for people to read.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let connected = if self.connected { "yes" } else { "no" };
+        write!(f, "Connected: {connected}, {} reconnects", self.reconnects)?;
+        if let Some(rtt_ms) = self.rtt_ms {
+            write!(f, ", RTT {rtt_ms:.1} ms")?;
+        }
+        writeln!(f)?;
+        if !self.remotes.is_empty() {
+            writeln!(f)?;
+            let rows = self
+                .remotes
+                .iter()
+                .map(|remote| vec![remote.remote.clone(), remote.local_addr.to_string()]);
+            write_table(f, &["REMOTE", "LISTENING"], rows)?;
+        }
+        if !self.streams.is_empty() {
+            writeln!(f)?;
+            let rows = self.streams.iter().map(|stream| {
+                vec![
+                    stream.id.to_string(),
+                    stream.destination.clone(),
+                    stream.bytes_up.to_string(),
+                    stream.bytes_down.to_string(),
+                ]
+            });
+            write_table(f, &["STREAM", "DESTINATION", "UP", "DOWN"], rows)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write rows under `header` with the columns aligned.
+fn write_table(
+    f: &mut std::fmt::Formatter<'_>,
+    header: &[&str],
+    rows: impl Iterator<Item = Vec<String>>,
+) -> std::fmt::Result {
+    let rows: Vec<Vec<String>> = std::iter::once(header.iter().map(ToString::to_string).collect())
+        .chain(rows)
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    for row in &rows {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(&widths) {
+            write!(line, "{cell:width$}  ")?;
+        }
+        writeln!(f, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// Print the status of the client listening on `args.control_socket`.
+pub async fn status_main(args: &StatusArgs) -> Result<(), Error> {
+    let status: Status = request(&args.control_socket, STATUS_COMMAND).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print!("{status}");
+    }
+    Ok(())
+}
+
+/// Send the command of `args`, or each line of the standard input, to the
+/// client and print the responses.
+pub async fn control_main(args: &ControlArgs) -> Result<(), Error> {
+    if !args.command.is_empty() {
+        let response: serde_json::Value =
+            request(&args.control_socket, &args.command.join(" ")).await?;
+        println!("{}", serde_json::to_string_pretty(&response)?);
+        return Ok(());
+    }
+    converse(&args.control_socket).await
+}
+
+/// Send each line of the standard input to the control socket at `path`
+/// and print the responses, errors included.
+#[cfg(unix)]
+async fn converse(path: &Path) -> Result<(), Error> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let stream = tokio::net::UnixStream::connect(path)
+        .await
+        .map_err(Error::Connect)?;
+    let (rx, mut tx) = stream.into_split();
+    let mut responses = BufReader::new(rx).lines();
+    let mut commands = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(command) = commands.next_line().await? {
+        if command.trim().is_empty() {
+            continue;
+        }
+        tx.write_all(format!("{command}\n").as_bytes()).await?;
+        let response = responses.next_line().await?.ok_or(Error::NoResponse)?;
+        match serde_json::from_str(&response)? {
+            Response::Ok(response) => {
+                let response: serde_json::Value = response;
+                match response.as_str() {
+                    Some(text) => println!("{text}"),
+                    None => println!("{}", serde_json::to_string_pretty(&response)?),
+                }
+            }
+            Response::Error { error } => eprintln!("Error: {error}"),
+        }
+    }
+    Ok(())
+}
+
+/// There are no Unix sockets on this platform.
+#[cfg(not(unix))]
+#[allow(clippy::unused_async)]
+async fn converse(_path: &Path) -> Result<(), Error> {
+    Err(Error::Unsupported)
+}
+
+/// Send `command` to the control socket at `path` and parse the response.
+#[cfg(unix)]
+pub(crate) async fn request<T: for<'de> Deserialize<'de>>(
+    path: &Path,
+    command: &str,
+) -> Result<T, Error> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let mut stream = tokio::net::UnixStream::connect(path)
+        .await
+        .map_err(Error::Connect)?;
+    stream.write_all(format!("{command}\n").as_bytes()).await?;
+    let mut line = String::new();
+    if BufReader::new(stream).read_line(&mut line).await? == 0 {
+        return Err(Error::NoResponse);
+    }
+    match serde_json::from_str(&line)? {
+        Response::Ok(response) => Ok(response),
+        Response::Error { error } => Err(Error::Client(error)),
+    }
+}
+
+/// There are no Unix sockets on this platform.
+#[cfg(not(unix))]
+#[allow(clippy::unused_async)]
+pub(crate) async fn request<T>(_path: &Path, _command: &str) -> Result<T, Error> {
+    Err(Error::Unsupported)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_status_table() {
+        let status = Status {
+            connected: true,
+            reconnects: 2,
+            rtt_ms: Some(12.34),
+            remotes: vec![RemoteStatus {
+                remote: "db".to_string(),
+                local_addr: "127.0.0.1:5432".parse().unwrap(),
+            }],
+            streams: vec![StreamStatus {
+                id: 7,
+                destination: "example.com:80".to_string(),
+                bytes_up: 1234,
+                bytes_down: 56,
+            }],
+        };
+        assert_eq!(
+            status.to_string(),
+            "Connected: yes, 2 reconnects, RTT 12.3 ms\n\
+             \n\
+             REMOTE  LISTENING\n\
+             db      127.0.0.1:5432\n\
+             \n\
+             STREAM  DESTINATION     UP    DOWN\n\
+             7       example.com:80  1234  56\n"
+        );
+    }
+
+    #[test]
+    fn test_command_fromstr() {
+        assert_eq!("status".parse(), Ok(Command::Status));
+        assert_eq!(" streams ".parse(), Ok(Command::Streams));
+        assert_eq!("kill  7".parse(), Ok(Command::Kill(7)));
+        assert_eq!(
+            "add web=8080:example.com:80".parse(),
+            Ok(Command::Add("web=8080:example.com:80".parse().unwrap()))
+        );
+        "kill".parse::<Command>().unwrap_err();
+        "kill seven".parse::<Command>().unwrap_err();
+        "add 99999".parse::<Command>().unwrap_err();
+        "status now".parse::<Command>().unwrap_err();
+        "reboot".parse::<Command>().unwrap_err();
+    }
+
+    #[test]
+    fn test_response() {
+        let status = Status::default();
+        let line = serde_json::to_string(&Response::Ok(&status)).unwrap();
+        let Response::Ok(parsed) = serde_json::from_str::<Response<Status>>(&line).unwrap() else {
+            panic!("not a status");
+        };
+        assert_eq!(parsed, status);
+        let line = r#"{"error":"busy"}"#;
+        assert!(matches!(
+            serde_json::from_str::<Response<Status>>(line).unwrap(),
+            Response::Error { error } if error == "busy"
+        ));
+    }
+}
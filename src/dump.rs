@@ -0,0 +1,79 @@
+//! Logging runtime statistics on `SIGUSR1`, for debugging without
+//! attaching a debugger.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use std::io;
+use tokio::sync::watch;
+
+/// Notice of a request to log statistics. Each clone only sees the requests
+/// made after it was created.
+#[derive(Debug)]
+pub struct DumpSignal(watch::Receiver<()>);
+
+impl Clone for DumpSignal {
+    fn clone(&self) -> Self {
+        let mut rx = self.0.clone();
+        rx.borrow_and_update();
+        Self(rx)
+    }
+}
+
+impl Default for DumpSignal {
+    /// A signal that never fires.
+    fn default() -> Self {
+        Self(watch::channel(()).1)
+    }
+}
+
+impl DumpSignal {
+    /// Start listening for `SIGUSR1`.
+    /// Only the returned signal and its clones see it.
+    #[cfg(unix)]
+    pub fn listen() -> io::Result<Self> {
+        let mut sigusr1 =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+        let (tx, rx) = watch::channel(());
+        tokio::spawn(async move {
+            while sigusr1.recv().await == Some(()) {
+                tx.send_replace(());
+            }
+        });
+        Ok(Self(rx))
+    }
+
+    /// There is no `SIGUSR1` on this platform.
+    #[cfg(not(unix))]
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn listen() -> io::Result<Self> {
+        Ok(Self::default())
+    }
+
+    /// Wait until statistics are requested.
+    pub async fn requested(&mut self) {
+        if self.0.changed().await.is_err() {
+            // No one can request statistics anymore
+            futures_util::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_clone_misses_earlier_requests() {
+        let (tx, rx) = watch::channel(());
+        let mut signal = DumpSignal(rx);
+        tx.send_replace(());
+        let mut clone = signal.clone();
+        signal.requested().await;
+        tokio::time::timeout(Duration::from_millis(100), clone.requested())
+            .await
+            .unwrap_err();
+        tx.send_replace(());
+        clone.requested().await;
+    }
+}
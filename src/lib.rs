@@ -0,0 +1,200 @@
+//! A fast TCP/UDP tunnel, transported over HTTP WebSockets.
+//!
+//! This is the `penguin` binary as a library. Besides [`cli_main`], it
+//! exports [`server::ws_route`] to serve the tunnel endpoint from another
+//! `hyper` application.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+#![cfg(feature = "penguin-binary")]
+#![warn(missing_docs, missing_debug_implementations)]
+// Only the Windows service glue and the TUN `ioctl`s need `unsafe`
+#![cfg_attr(not(any(windows, feature = "tun")), forbid(unsafe_code))]
+#![cfg_attr(any(windows, feature = "tun"), deny(unsafe_code))]
+#![allow(clippy::module_name_repetitions)]
+
+mod arg;
+mod challenge;
+mod client;
+mod config;
+mod control;
+mod dump;
+mod keygen;
+mod noise;
+mod otel;
+mod parse_remote;
+mod proto_version;
+mod resolver;
+pub mod server;
+#[cfg(windows)]
+mod service;
+mod sockopt;
+#[cfg(test)]
+mod test;
+mod throughput;
+mod tls;
+mod totp;
+mod tun;
+mod ws;
+
+use thiserror::Error;
+use tracing::trace;
+#[cfg(not(feature = "tokio-console"))]
+use tracing_subscriber::{filter, fmt, prelude::*, reload};
+
+pub use penguin_mux::dupe::Dupe;
+
+/// Errors
+#[derive(Error)]
+pub enum Error {
+    /// The client failed
+    #[error(transparent)]
+    Client(#[from] Box<client::Error>),
+    /// The server failed
+    #[error(transparent)]
+    Server(#[from] server::Error),
+    /// Generating keys failed
+    #[error(transparent)]
+    Keygen(#[from] keygen::Error),
+    /// Talking to a client failed
+    #[error(transparent)]
+    Status(#[from] control::Error),
+    /// OpenTelemetry could not be set up
+    #[cfg(feature = "otel")]
+    #[error("Cannot set up OpenTelemetry: {0}")]
+    Otel(#[from] opentelemetry_otlp::ExporterBuildError),
+    /// Running as a Windows service failed
+    #[cfg(windows)]
+    #[error("Windows service error: {0}")]
+    Service(#[from] windows_service::Error),
+}
+
+impl std::fmt::Debug for Error {
+    // Simply delegate to `Display` so when `main` exits, there
+    // is a nice error message.
+    #[inline]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(not(feature = "tokio-console"))]
+const QUIET_QUIET_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::ERROR;
+#[cfg(not(feature = "tokio-console"))]
+const QUIET_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::WARN;
+#[cfg(not(feature = "tokio-console"))]
+const DEFAULT_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::INFO;
+#[cfg(not(feature = "tokio-console"))]
+const VERBOSE_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::DEBUG;
+#[cfg(not(feature = "tokio-console"))]
+const VERBOSE_VERBOSE_LOG_LEVEL: filter::LevelFilter = filter::LevelFilter::TRACE;
+
+#[cfg(feature = "deadlock-detection")]
+fn spawn_deadlock_detection() {
+    use std::thread;
+    use tracing::error;
+
+    // Create a background thread which checks for deadlocks every 10s
+    thread::spawn(move || loop {
+        thread::sleep(std::time::Duration::from_secs(10));
+        let deadlocks = parking_lot::deadlock::check_deadlock();
+        if deadlocks.is_empty() {
+            continue;
+        }
+
+        error!("{} deadlocks detected", deadlocks.len());
+        for (i, threads) in deadlocks.iter().enumerate() {
+            error!("Deadlock #{}", i);
+            for t in threads {
+                error!("Thread Id {:#?}", t.thread_id());
+                error!("{:#?}", t.backtrace());
+            }
+        }
+    });
+}
+
+/// Entry point of the `penguin` binary: parse the command line, set up
+/// logging, and run the client or the server.
+pub async fn cli_main() -> Result<(), Error> {
+    arg::PenguinCli::parse_global();
+    let cli_args = arg::PenguinCli::get_global();
+    #[cfg(feature = "otel")]
+    let (otel_layer, _otel_guard) = otel::layer()?;
+    #[cfg(not(feature = "tokio-console"))]
+    let reload_handle = {
+        let fmt_layer = match cli_args.log_format {
+            arg::LogFormat::Compact => fmt::Layer::default()
+                .compact()
+                .with_timer(fmt::time::time())
+                .with_writer(std::io::stderr)
+                .boxed(),
+            arg::LogFormat::Json => fmt::Layer::default()
+                .json()
+                .flatten_event(true)
+                .with_current_span(false)
+                .with_span_list(false)
+                .with_timer(fmt::time::time())
+                .with_writer(std::io::stderr)
+                .boxed(),
+        };
+        // Only filters the log output, so that the exported spans do not
+        // depend on `-v` or `-q`
+        let (level_filter, reload_handle) = reload::Layer::new(DEFAULT_LOG_LEVEL);
+        let registry = tracing_subscriber::registry().with(fmt_layer.with_filter(level_filter));
+        #[cfg(feature = "otel")]
+        let registry = registry.with(otel_layer);
+        registry.init();
+        reload_handle
+    };
+    #[cfg(feature = "tokio-console")]
+    console_subscriber::init();
+    trace!("cli_args = {cli_args:#?}");
+    #[cfg(not(feature = "tokio-console"))]
+    {
+        match cli_args.verbose {
+            0 => {}
+            1 => reload_handle
+                .reload(VERBOSE_LOG_LEVEL)
+                .expect("Resetting log level failed (this is a bug)"),
+            _ => reload_handle
+                .reload(VERBOSE_VERBOSE_LOG_LEVEL)
+                .expect("Resetting log level failed (this is a bug)"),
+        };
+        match cli_args.quiet {
+            0 => {}
+            1 => reload_handle
+                .reload(QUIET_LOG_LEVEL)
+                .expect("Resetting log level failed (this is a bug)"),
+            _ => reload_handle
+                .reload(QUIET_QUIET_LOG_LEVEL)
+                .expect("Resetting log level failed (this is a bug)"),
+        };
+    }
+    #[cfg(feature = "deadlock-detection")]
+    spawn_deadlock_detection();
+    #[cfg(windows)]
+    if cli_args.service {
+        return service::run().await;
+    }
+    run(&cli_args.subcommand).await
+}
+
+/// Run the client or the server
+async fn run(command: &'static arg::Commands) -> Result<(), Error> {
+    match command {
+        arg::Commands::Client(args) => client::client_main(args).await.map_err(Box::new)?,
+        arg::Commands::Server(args) => server::server_main(args).await?,
+        arg::Commands::Keygen(args) => keygen::keygen_main(args).await?,
+        arg::Commands::Status(args) => control::status_main(args).await?,
+        arg::Commands::Control(args) => control::control_main(args).await?,
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "otel", feature = "tokio-console"))]
+compile_error!("Only one of otel and tokio-console can be enabled at a time");
+#[cfg(all(feature = "rustls-native-roots", feature = "rustls-webpki-roots"))]
+compile_error!("Only one of rustls-native-roots and rustls-webpki-roots can be enabled at a time");
+#[cfg(all(feature = "rustls-native-roots", feature = "nativetls"))]
+compile_error!("Only one of rustls-native-roots and nativetls can be enabled at a time");
+#[cfg(all(feature = "rustls-webpki-roots", feature = "nativetls"))]
+compile_error!("Only one of rustls-webpki-roots and nativetls can be enabled at a time");
@@ -0,0 +1,453 @@
+//! Noise end-to-end encryption inside the tunnel.
+//!
+//! With `--noise-key` on the server and `--noise-server-key` on the client,
+//! the frames of the multiplexor are encrypted between the client and the
+//! server themselves, so that they stay confidential when TLS is terminated
+//! by a CDN or reverse proxy in front of the server:
+//! 1. Right after the WebSocket upgrade (and the PSK challenge, if any), the
+//!    two sides perform a `Noise_IK_25519_ChaChaPoly_BLAKE2s` handshake in two
+//!    binary messages. The chosen `sec-websocket-protocol` is the prologue.
+//! 2. Each later binary message is a frame encrypted in chunks of at most
+//!    65519 bytes of plaintext, each followed by its 16-byte tag.
+//!
+//! The server closes the connection if the handshake fails, or if the client
+//! key is not one of `--noise-client-key` (when given).
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::config;
+use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
+use base64::Engine;
+use bytes::{Bytes, BytesMut};
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use penguin_mux::transport::{Message, Transport};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{Builder, HandshakeState, TransportState};
+use std::io;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ready, Context, Poll};
+use thiserror::Error;
+
+/// Noise protocol name
+const PATTERN: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+/// Length of Curve25519 keys
+pub const KEY_LEN: usize = 32;
+/// Length of the tag after each encrypted chunk
+const TAG_LEN: usize = 16;
+/// Largest Noise message
+const MAX_MESSAGE_LEN: usize = 65535;
+/// Largest plaintext in one Noise message
+const MAX_CHUNK_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+/// Errors that can occur during the Noise handshake.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("WebSocket error during Noise handshake: {0}")]
+    WebSocket(#[from] io::Error),
+    #[error("Noise handshake failed: {0}")]
+    Handshake(#[from] snow::Error),
+    #[error("Timed out during Noise handshake")]
+    Timeout,
+    #[error("Invalid Noise handshake message")]
+    InvalidMessage,
+    #[error("Connection closed during Noise handshake")]
+    Closed,
+    #[error("Noise client key {0} is not allowed")]
+    UnknownClient(String),
+}
+
+/// Errors that can occur when parsing a Noise key.
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("invalid base64 Noise key: {0}")]
+    Base64(#[from] base64::DecodeError),
+    #[error("Noise key must be {KEY_LEN} bytes, not {0}")]
+    Length(usize),
+}
+
+/// A Curve25519 private or public key, written in base64.
+#[derive(Clone, PartialEq, Eq)]
+pub struct NoiseKey([u8; KEY_LEN]);
+
+impl std::fmt::Debug for NoiseKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Might be a private key
+        f.write_str("NoiseKey(..)")
+    }
+}
+
+impl std::fmt::Display for NoiseKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&B64_STANDARD_ENGINE.encode(self.0))
+    }
+}
+
+impl FromStr for NoiseKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = B64_STANDARD_ENGINE.decode(s.trim())?;
+        let len = key.len();
+        Ok(Self(key.try_into().map_err(|_| KeyError::Length(len))?))
+    }
+}
+
+impl NoiseKey {
+    /// Generate a new private key.
+    pub fn generate() -> Self {
+        // `expect`: Curve25519 is built in and the system RNG does not fail
+        let keypair = builder()
+            .generate_keypair()
+            .expect("Cannot generate a Noise key (this is a bug)");
+        Self(
+            keypair
+                .private
+                .try_into()
+                .expect("Curve25519 key of the wrong length (this is a bug)"),
+        )
+    }
+
+    /// The public key of this private key.
+    pub fn public(&self) -> Self {
+        // `expect`: Curve25519 is built in
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("Curve25519 is not available (this is a bug)");
+        dh.set(&self.0);
+        Self(
+            dh.pubkey()
+                .try_into()
+                .expect("Curve25519 key of the wrong length (this is a bug)"),
+        )
+    }
+}
+
+fn builder() -> Builder<'static> {
+    // `expect`: the pattern is valid
+    Builder::new(
+        PATTERN
+            .parse()
+            .expect("Invalid Noise pattern (this is a bug)"),
+    )
+}
+
+/// Wait for the next binary message.
+async fn next_binary<T: Transport>(ws: &mut T) -> Result<Bytes, Error> {
+    let recv = async {
+        loop {
+            match ws.next().await.transpose()? {
+                Some(Message::Frame(data)) => return Ok(data),
+                Some(Message::Ping(_) | Message::Pong(_)) => {}
+                Some(Message::Close) | None => return Err(Error::Closed),
+            }
+        }
+    };
+    tokio::time::timeout(config::NOISE_HANDSHAKE_TIMEOUT, recv)
+        .await
+        .map_err(|_| Error::Timeout)?
+}
+
+/// Send the next handshake message of `handshake`.
+async fn send_handshake<T: Transport>(
+    ws: &mut T,
+    handshake: &mut HandshakeState,
+) -> Result<(), Error> {
+    let mut buf = vec![0; MAX_MESSAGE_LEN];
+    let len = handshake.write_message(&[], &mut buf)?;
+    buf.truncate(len);
+    ws.send(Message::Frame(buf.into())).await?;
+    Ok(())
+}
+
+/// Receive the next handshake message of `handshake`.
+async fn recv_handshake<T: Transport>(
+    ws: &mut T,
+    handshake: &mut HandshakeState,
+) -> Result<(), Error> {
+    let msg = next_binary(ws).await?;
+    if msg.len() > MAX_MESSAGE_LEN {
+        return Err(Error::InvalidMessage);
+    }
+    let mut payload = vec![0; msg.len()];
+    handshake.read_message(&msg, &mut payload)?;
+    Ok(())
+}
+
+/// Client side: perform the handshake with the server whose public key is
+/// `server_key`. Without `key`, a new one is used for this connection.
+pub async fn client_handshake<T: Transport>(
+    ws: &mut T,
+    key: Option<&NoiseKey>,
+    server_key: &NoiseKey,
+    protocol: &[u8],
+) -> Result<TransportState, Error> {
+    let key = key.cloned().unwrap_or_else(NoiseKey::generate);
+    let mut handshake = builder()
+        .local_private_key(&key.0)
+        .remote_public_key(&server_key.0)
+        .prologue(protocol)
+        .build_initiator()?;
+    send_handshake(ws, &mut handshake).await?;
+    recv_handshake(ws, &mut handshake).await?;
+    Ok(handshake.into_transport_mode()?)
+}
+
+/// Server side: perform the handshake with `key`, accepting the clients
+/// whose public key is in `client_keys`, or all if it is empty.
+pub async fn server_handshake<T: Transport>(
+    ws: &mut T,
+    key: &NoiseKey,
+    client_keys: &[NoiseKey],
+    protocol: &[u8],
+) -> Result<TransportState, Error> {
+    let mut handshake = builder()
+        .local_private_key(&key.0)
+        .prologue(protocol)
+        .build_responder()?;
+    recv_handshake(ws, &mut handshake).await?;
+    // `expect`: the first IK message carries the initiator's static key
+    let client_key = NoiseKey(
+        handshake
+            .get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .expect("No Noise client key after the first message (this is a bug)"),
+    );
+    if !client_keys.is_empty() && !client_keys.contains(&client_key) {
+        return Err(Error::UnknownClient(client_key.to_string()));
+    }
+    send_handshake(ws, &mut handshake).await?;
+    Ok(handshake.into_transport_mode()?)
+}
+
+/// Bytes added by encrypting a frame of `len` bytes.
+pub const fn overhead(len: usize) -> usize {
+    if len == 0 {
+        TAG_LEN
+    } else {
+        len.div_ceil(MAX_CHUNK_LEN) * TAG_LEN
+    }
+}
+
+/// A [`Transport`] that encrypts frames with the state of a finished
+/// handshake, or passes them through if there was none. Keepalives are not
+/// encrypted.
+#[derive(Debug)]
+pub struct NoiseTransport<T> {
+    inner: T,
+    cipher: Option<Box<TransportState>>,
+}
+
+impl<T> NoiseTransport<T> {
+    /// Wrap `inner`, encrypting frames with `cipher` if any.
+    pub fn new(inner: T, cipher: Option<TransportState>) -> Self {
+        Self {
+            inner,
+            cipher: cipher.map(Box::new),
+        }
+    }
+}
+
+fn into_io_error(error: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn encrypt(cipher: &mut TransportState, frame: &[u8]) -> io::Result<Bytes> {
+    let mut out = BytesMut::zeroed(frame.len() + overhead(frame.len()));
+    let mut written = 0;
+    let mut chunks = frame.chunks(MAX_CHUNK_LEN).peekable();
+    if chunks.peek().is_none() {
+        written += cipher.write_message(&[], &mut out).map_err(into_io_error)?;
+    }
+    for chunk in chunks {
+        written += cipher
+            .write_message(chunk, &mut out[written..])
+            .map_err(into_io_error)?;
+    }
+    debug_assert_eq!(written, out.len());
+    Ok(out.freeze())
+}
+
+fn decrypt(cipher: &mut TransportState, data: &[u8]) -> io::Result<Bytes> {
+    let mut out = BytesMut::zeroed(data.len());
+    let mut read = 0;
+    for chunk in data.chunks(MAX_MESSAGE_LEN) {
+        read += cipher
+            .read_message(chunk, &mut out[read..])
+            .map_err(into_io_error)?;
+    }
+    out.truncate(read);
+    Ok(out.freeze())
+}
+
+impl<T: Transport> Stream for NoiseTransport<T> {
+    type Item = io::Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let msg = ready!(self.inner.poll_next_unpin(cx));
+        let Some(cipher) = &mut self.cipher else {
+            return Poll::Ready(msg);
+        };
+        Poll::Ready(msg.map(|msg| match msg? {
+            Message::Frame(data) => decrypt(cipher, &data).map(Message::Frame),
+            msg => Ok(msg),
+        }))
+    }
+}
+
+impl<T: Transport> Sink<Message> for NoiseTransport<T> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, msg: Message) -> io::Result<()> {
+        let msg = match (&mut self.cipher, msg) {
+            (Some(cipher), Message::Frame(data)) => Message::Frame(encrypt(cipher, &data)?),
+            (_, msg) => msg,
+        };
+        self.inner.start_send_unpin(msg)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl<T: Transport> Transport for NoiseTransport<T> {
+    fn ping_auto_pong(&self) -> bool {
+        self.inner.ping_auto_pong()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use penguin_mux::ws::{Role, WebSocket};
+    use tokio_tungstenite::WebSocketStream;
+
+    async fn ws_pair() -> (
+        WebSocket<tokio::io::DuplexStream>,
+        WebSocket<tokio::io::DuplexStream>,
+    ) {
+        let (client, server) = tokio::io::duplex(1 << 20);
+        (
+            WebSocket::new(WebSocketStream::from_raw_socket(client, Role::Client, None).await),
+            WebSocket::new(WebSocketStream::from_raw_socket(server, Role::Server, None).await),
+        )
+    }
+
+    #[test]
+    fn test_key() {
+        let key = NoiseKey::generate();
+        let public = key.public();
+        assert_ne!(key, public);
+        assert_eq!(public, key.public());
+        assert_eq!(key.to_string().parse::<NoiseKey>().unwrap(), key);
+        assert_eq!(format!("{key:?}"), "NoiseKey(..)");
+        assert!(matches!(
+            "c2hvcnQ=".parse::<NoiseKey>(),
+            Err(KeyError::Length(5))
+        ));
+        assert!(matches!(
+            "not base64!".parse::<NoiseKey>(),
+            Err(KeyError::Base64(_))
+        ));
+    }
+
+    #[test]
+    fn test_overhead() {
+        assert_eq!(overhead(0), TAG_LEN);
+        assert_eq!(overhead(1), TAG_LEN);
+        assert_eq!(overhead(MAX_CHUNK_LEN), TAG_LEN);
+        assert_eq!(overhead(MAX_CHUNK_LEN + 1), 2 * TAG_LEN);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_and_frames() {
+        let server_key = NoiseKey::generate();
+        let server_public = server_key.public();
+        let client_key = NoiseKey::generate();
+        let client_public = client_key.public();
+        let (mut client, mut server) = ws_pair().await;
+        let client_task = tokio::spawn(async move {
+            let cipher = client_handshake(&mut client, Some(&client_key), &server_public, b"v7")
+                .await
+                .unwrap();
+            (client, cipher)
+        });
+        let cipher = server_handshake(&mut server, &server_key, &[client_public], b"v7")
+            .await
+            .unwrap();
+        let (client, client_cipher) = client_task.await.unwrap();
+        let mut client = NoiseTransport::new(client, Some(client_cipher));
+        let mut server = NoiseTransport::new(server, Some(cipher));
+        for len in [0, 1, MAX_CHUNK_LEN, 200_000] {
+            let frame = Bytes::from(vec![0x42; len]);
+            client.send(Message::Frame(frame.clone())).await.unwrap();
+            assert_eq!(
+                server.next().await.unwrap().unwrap(),
+                Message::Frame(frame.clone())
+            );
+            server.send(Message::Frame(frame.clone())).await.unwrap();
+            assert_eq!(client.next().await.unwrap().unwrap(), Message::Frame(frame));
+        }
+        client
+            .send(Message::Ping(Bytes::from_static(b"hi")))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.next().await.unwrap().unwrap(),
+            Message::Ping(Bytes::from_static(b"hi"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handshake_failures() {
+        let server_key = NoiseKey::generate();
+        // Client not in the list
+        let (mut client, mut server) = ws_pair().await;
+        let server_public = server_key.public();
+        let client_task = tokio::spawn(async move {
+            client_handshake(&mut client, None, &server_public, b"v7").await
+        });
+        let allowed = [NoiseKey::generate().public()];
+        assert!(matches!(
+            server_handshake(&mut server, &server_key, &allowed, b"v7").await,
+            Err(Error::UnknownClient(_))
+        ));
+        drop(server);
+        assert!(client_task.await.unwrap().is_err());
+        // Wrong server key
+        let (mut client, mut server) = ws_pair().await;
+        let wrong_public = NoiseKey::generate().public();
+        let client_task =
+            tokio::spawn(
+                async move { client_handshake(&mut client, None, &wrong_public, b"v7").await },
+            );
+        assert!(matches!(
+            server_handshake(&mut server, &server_key, &[], b"v7").await,
+            Err(Error::Handshake(_))
+        ));
+        drop(server);
+        assert!(client_task.await.unwrap().is_err());
+        // Different prologue
+        let (mut client, mut server) = ws_pair().await;
+        let server_public = server_key.public();
+        let client_task = tokio::spawn(async move {
+            client_handshake(&mut client, None, &server_public, b"v6").await
+        });
+        assert!(matches!(
+            server_handshake(&mut server, &server_key, &[], b"v7").await,
+            Err(Error::Handshake(_))
+        ));
+        drop(server);
+        assert!(client_task.await.unwrap().is_err());
+    }
+}
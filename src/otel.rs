@@ -0,0 +1,206 @@
+//! Exporting spans to an OpenTelemetry collector.
+//!
+//! The exporter is configured with the standard `OTEL_*` environment
+//! variables, e.g. `OTEL_EXPORTER_OTLP_ENDPOINT`. The client sends the
+//! context of its connection span in the `traceparent` header of the
+//! `WebSocket` upgrade request, so the server's tunnel and forwarder spans
+//! join the client's trace. Each stream the client requests is a trace of
+//! its own, linked to the connection that carried it. Its `Syn` frame
+//! carries the stream's context too, so the server's forwarder joins the
+//! stream's trace, linked to the server's tunnel span.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use bytes::Bytes;
+use http::HeaderMap;
+#[cfg(feature = "otel")]
+use http::{HeaderName, HeaderValue};
+#[cfg(feature = "otel")]
+use opentelemetry::propagation::{Extractor, Injector};
+#[cfg(feature = "otel")]
+use opentelemetry::{global, trace::TracerProvider as _};
+#[cfg(feature = "otel")]
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter};
+#[cfg(feature = "otel")]
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::Span;
+#[cfg(feature = "otel")]
+use tracing::Subscriber;
+#[cfg(feature = "otel")]
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+#[cfg(feature = "otel")]
+use tracing_subscriber::{filter::LevelFilter, registry::LookupSpan, Layer};
+
+/// Spans at this level and above are exported, regardless of `-v` or `-q`
+#[cfg(feature = "otel")]
+const OTEL_LEVEL: LevelFilter = LevelFilter::DEBUG;
+
+/// Header of the W3C trace context, which is also what streams carry
+#[cfg(feature = "otel")]
+const TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+/// Flushes the remaining spans when dropped.
+#[cfg(feature = "otel")]
+#[derive(Debug)]
+pub struct Guard(SdkTracerProvider);
+
+#[cfg(feature = "otel")]
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.shutdown() {
+            tracing::warn!("Failed to export the remaining spans: {err}");
+        }
+    }
+}
+
+/// Set up the OTLP exporter and return the layer that feeds it.
+#[cfg(feature = "otel")]
+pub fn layer<S>() -> Result<(impl Layer<S>, Guard), ExporterBuildError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = SpanExporter::builder().with_tonic().build()?;
+    let mut resource = Resource::builder();
+    if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name("penguin");
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer("penguin"))
+        .with_filter(OTEL_LEVEL);
+    Ok((layer, Guard(provider)))
+}
+
+#[cfg(feature = "otel")]
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+#[cfg(feature = "otel")]
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+#[cfg(feature = "otel")]
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+#[cfg(feature = "otel")]
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+/// Add the context of the current span to the headers of a request.
+#[cfg(feature = "otel")]
+pub fn inject(headers: &mut HeaderMap) {
+    let context = Span::current().context();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers));
+    });
+}
+
+/// Make `span` a child of the span context in the headers of a request.
+#[cfg(feature = "otel")]
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(context).ok();
+}
+
+/// The context of `span` to send with a stream, as a W3C `traceparent`.
+#[cfg(feature = "otel")]
+pub fn stream_context(span: &Span) -> Bytes {
+    let context = span.context();
+    let mut headers = HeaderMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(&mut headers));
+    });
+    headers
+        .get(TRACEPARENT)
+        .map_or_else(Bytes::new, |value| Bytes::copy_from_slice(value.as_bytes()))
+}
+
+/// Make `span` a child of the context sent with a stream, if any.
+#[cfg(feature = "otel")]
+pub fn set_stream_parent(span: &Span, context: &[u8]) {
+    if context.is_empty() {
+        return;
+    }
+    let Ok(value) = HeaderValue::from_bytes(context) else {
+        return;
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(TRACEPARENT, value);
+    set_parent(span, &headers);
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn inject(_headers: &mut HeaderMap) {}
+
+#[cfg(not(feature = "otel"))]
+pub fn stream_context(_span: &Span) -> Bytes {
+    Bytes::new()
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn set_stream_parent(_span: &Span, _context: &[u8]) {}
+
+#[cfg(not(feature = "otel"))]
+pub fn set_parent(_span: &Span, _headers: &HeaderMap) {}
+
+#[cfg(all(test, feature = "otel"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_propagation() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let mut headers = HeaderMap::new();
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        HeaderInjector(&mut headers).set("traceparent", traceparent.to_string());
+        let context = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(&headers))
+        });
+        let mut injected = HeaderMap::new();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut HeaderInjector(&mut injected));
+        });
+        assert_eq!(injected.get("traceparent").unwrap(), traceparent);
+    }
+
+    #[test]
+    fn test_stream_context() {
+        use tracing_subscriber::layer::SubscriberExt;
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let provider = SdkTracerProvider::builder().build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::debug_span!("stream");
+            let traceparent = b"00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+            set_stream_parent(&span, traceparent);
+            // A child in the same trace
+            let context = stream_context(&span);
+            assert!(context.starts_with(b"00-0af7651916cd43dd8448eb211c80319c-"));
+            assert_ne!(context, &traceparent[..]);
+            // Invalid contexts are ignored
+            let span = tracing::debug_span!("stream");
+            set_stream_parent(&span, b"\n");
+            assert!(!stream_context(&span).starts_with(b"00-0af7651916cd43dd8448eb211c80319c-"));
+        });
+    }
+}
@@ -2,7 +2,8 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use std::{fmt::Display, str::FromStr};
+use penguin_mux::TcpOptions;
+use std::{fmt::Display, net::IpAddr, str::FromStr};
 use thiserror::Error;
 
 #[cfg(not(feature = "default-is-ipv6"))]
@@ -31,6 +32,12 @@ pub struct Remote {
     pub local_addr: LocalSpec,
     pub remote_addr: RemoteSpec,
     pub protocol: Protocol,
+    /// Number of consecutive ports, from the local and remote ports, that
+    /// this remote forwards. More than 1 for a port range like
+    /// `8000-8010:target:8000-8010`.
+    pub ports: u16,
+    /// Name given with `name=spec`, e.g. `db=5432:db.internal:5432`
+    pub name: Option<String>,
 }
 
 /// The local side can be either IP+port or "stdio".
@@ -40,18 +47,36 @@ pub enum LocalSpec {
     Stdio,
 }
 
-/// The remote side can be either IP+port or "socks".
+/// The remote side can be either IP+port, "socks", or "tproxy", which
+/// forwards what TPROXY or REDIRECT sends to the local side to its
+/// original destination.
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum RemoteSpec {
     Inet((String, u16)),
     Socks,
+    Tproxy,
 }
 
-/// Protocol can be either "tcp" or "udp".
+/// Protocol can be either "tcp" or "udp", which may be followed by
+/// "+"-separated options. For TCP: "nodelay" or "nagle",
+/// "keepalive=IDLE[,INTERVAL[,COUNT]]", and "usertimeout=SECONDS", which
+/// apply to both the accepted and the server's connections. For UDP:
+/// "reliable" and "fullcone".
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
 pub enum Protocol {
-    Tcp,
-    Udp,
+    Tcp(TcpOptions),
+    Udp(UdpOptions),
+}
+
+/// Options of a UDP remote
+#[derive(Debug, Default, Copy, Clone, Hash, Eq, PartialEq)]
+pub struct UdpOptions {
+    /// Send datagrams again until the server acknowledges them
+    pub reliable: bool,
+    /// Ask the server for full-cone NAT: one socket for all destinations,
+    /// which anyone can send datagrams to, instead of one socket for each
+    /// destination that only accepts its responses
+    pub full_cone: bool,
 }
 
 /// Errors that can occur when parsing a remote.
@@ -67,14 +92,57 @@ pub enum Error {
     Port(#[from] std::num::ParseIntError),
     #[error("socks remote must be TCP")]
     UdpSocks,
+    #[error("mcast remote must be UDP")]
+    TcpMulticast,
+    #[error("tproxy remotes are only supported on Linux")]
+    TproxyUnsupported,
+    #[error("Invalid port range")]
+    PortRange,
 }
 
 impl Display for Protocol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(match self {
-            Self::Tcp => "tcp",
-            Self::Udp => "udp",
-        })
+        match self {
+            Self::Tcp(options) => {
+                f.write_str("tcp")?;
+                match options.nodelay {
+                    Some(true) => f.write_str("+nodelay")?,
+                    Some(false) => f.write_str("+nagle")?,
+                    None => {}
+                }
+                if options.keepalive() {
+                    let keepalive = [
+                        options.keepalive_idle,
+                        options.keepalive_interval,
+                        options.keepalive_count,
+                    ];
+                    // Without the trailing defaults
+                    let len = keepalive
+                        .iter()
+                        .rposition(Option::is_some)
+                        .map_or(0, |i| i + 1);
+                    let values = keepalive[..len]
+                        .iter()
+                        .map(|value| value.map(|value| value.to_string()).unwrap_or_default())
+                        .collect::<Vec<_>>();
+                    write!(f, "+keepalive={}", values.join(","))?;
+                }
+                if let Some(timeout) = options.user_timeout {
+                    write!(f, "+usertimeout={timeout}")?;
+                }
+                Ok(())
+            }
+            Self::Udp(options) => {
+                f.write_str("udp")?;
+                if options.reliable {
+                    f.write_str("+reliable")?;
+                }
+                if options.full_cone {
+                    f.write_str("+fullcone")?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
@@ -82,9 +150,66 @@ impl FromStr for Protocol {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "tcp" => Ok(Self::Tcp),
-            "udp" => Ok(Self::Udp),
+        let s = s.to_lowercase();
+        let mut parts = s.split('+');
+        match parts.next() {
+            Some("tcp") => {
+                let mut options = TcpOptions::NONE;
+                for option in parts {
+                    match option.split_once('=') {
+                        None if option == "nodelay" || option == "nagle" => {
+                            if options.nodelay.replace(option == "nodelay").is_some() {
+                                return Err(Error::Protocol);
+                            }
+                        }
+                        Some(("keepalive", values)) if !options.keepalive() => {
+                            let mut values = values.split(',');
+                            let keepalive = [
+                                &mut options.keepalive_idle,
+                                &mut options.keepalive_interval,
+                                &mut options.keepalive_count,
+                            ];
+                            for option in keepalive {
+                                match values.next() {
+                                    // Keep the default
+                                    Some("") | None => {}
+                                    Some(value) => {
+                                        *option = Some(value.parse().map_err(|_| Error::Protocol)?);
+                                    }
+                                }
+                            }
+                            if values.next().is_some() {
+                                return Err(Error::Protocol);
+                            }
+                            // Needs a value to turn keepalive on
+                            if !options.keepalive() {
+                                return Err(Error::Protocol);
+                            }
+                        }
+                        Some(("usertimeout", value)) if options.user_timeout.is_none() => {
+                            options.user_timeout =
+                                Some(value.parse().map_err(|_| Error::Protocol)?);
+                        }
+                        _ => return Err(Error::Protocol),
+                    }
+                }
+                Ok(Self::Tcp(options))
+            }
+            Some("udp") => {
+                let mut options = UdpOptions::default();
+                for option in parts {
+                    let flag = match option {
+                        "reliable" => &mut options.reliable,
+                        "fullcone" => &mut options.full_cone,
+                        _ => return Err(Error::Protocol),
+                    };
+                    if std::mem::replace(flag, true) {
+                        // Repeated option
+                        return Err(Error::Protocol);
+                    }
+                }
+                Ok(Self::Udp(options))
+            }
             _ => Err(Error::Protocol),
         }
     }
@@ -115,79 +240,261 @@ fn tokenize_remote(s: &str) -> Result<Vec<&str>, Error> {
     }
 }
 
+/// Write `port`, or the range of `ports` ports from it.
+fn fmt_ports(f: &mut std::fmt::Formatter<'_>, port: u16, ports: u16) -> std::fmt::Result {
+    if ports > 1 {
+        write!(f, "{port}-{}", port + (ports - 1))
+    } else {
+        write!(f, "{port}")
+    }
+}
+
 impl Display for Remote {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{name}=")?;
+        }
         match &self.local_addr {
             LocalSpec::Inet((host, port)) => {
                 if host.contains(':') {
-                    write!(f, "[{host}]:{port}")?;
+                    write!(f, "[{host}]:")?;
                 } else {
-                    write!(f, "{host}:{port}")?;
+                    write!(f, "{host}:")?;
                 }
+                fmt_ports(f, *port, self.ports)?;
             }
             LocalSpec::Stdio => f.write_str("stdio")?,
         }
         match &self.remote_addr {
             RemoteSpec::Inet((host, port)) => {
                 if host.contains(':') {
-                    write!(f, ":[{host}]:{port}")?;
+                    write!(f, ":[{host}]:")?;
                 } else {
-                    write!(f, ":{host}:{port}")?;
+                    write!(f, ":{host}:")?;
                 }
+                fmt_ports(f, *port, self.ports)?;
             }
             RemoteSpec::Socks => f.write_str(":socks")?,
+            RemoteSpec::Tproxy => f.write_str(":tproxy")?,
         }
         write!(f, "/{}", self.protocol)?;
         Ok(())
     }
 }
 
+/// The host of an OpenSSH bind address, where empty and `*` mean all
+/// interfaces.
+fn ssh_bind_address(bind: &str) -> String {
+    match bind {
+        "" | "*" => default_host!(unspec),
+        _ => remove_brackets(bind).to_string(),
+    }
+}
+
+/// Split a port range like `8000-8010` into its first and last ports.
+fn split_port_range(token: &str) -> Option<(&str, &str)> {
+    let (first, last) = token.split_once('-')?;
+    let is_port = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    (is_port(first) && is_port(last)).then_some((first, last))
+}
+
+impl Remote {
+    /// The remotes of each port of a port range, or just this remote.
+    pub fn expand(&self) -> impl Iterator<Item = Self> + '_ {
+        (0..self.ports).map(|offset| {
+            let shift = |(host, port): &(String, u16)| (host.clone(), port + offset);
+            Self {
+                local_addr: match &self.local_addr {
+                    LocalSpec::Inet(addr) => LocalSpec::Inet(shift(addr)),
+                    LocalSpec::Stdio => LocalSpec::Stdio,
+                },
+                remote_addr: match &self.remote_addr {
+                    RemoteSpec::Inet(addr) => RemoteSpec::Inet(shift(addr)),
+                    other => other.clone(),
+                },
+                protocol: self.protocol,
+                ports: 1,
+                name: self.name.clone(),
+            }
+        })
+    }
+
+    /// What logs and statistics call this remote: its name, or else its
+    /// specification.
+    pub fn label(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.to_string())
+    }
+
+    /// Parse an OpenSSH `-L [bind_address:]port:host:hostport` forward.
+    pub fn from_ssh_local(s: &str) -> Result<Self, Error> {
+        let (bind, port, host, host_port) = match tokenize_remote(s)?[..] {
+            [port, host, host_port] => (default_host!(local), port, host, host_port),
+            [bind, port, host, host_port] => (ssh_bind_address(bind), port, host, host_port),
+            _ => return Err(Error::Format),
+        };
+        Ok(Self {
+            local_addr: LocalSpec::Inet((bind, port.parse()?)),
+            remote_addr: RemoteSpec::Inet((remove_brackets(host).to_string(), host_port.parse()?)),
+            protocol: Protocol::Tcp(TcpOptions::NONE),
+            ports: 1,
+            name: None,
+        })
+    }
+
+    /// Parse an OpenSSH `-D [bind_address:]port` forward.
+    pub fn from_ssh_dynamic(s: &str) -> Result<Self, Error> {
+        let (bind, port) = match tokenize_remote(s)?[..] {
+            [port] => (default_host!(local), port),
+            [bind, port] => (ssh_bind_address(bind), port),
+            _ => return Err(Error::Format),
+        };
+        Ok(Self {
+            local_addr: LocalSpec::Inet((bind, port.parse()?)),
+            remote_addr: RemoteSpec::Socks,
+            protocol: Protocol::Tcp(TcpOptions::NONE),
+            ports: 1,
+            name: None,
+        })
+    }
+
+    /// Parse a remote whose remote side is `mcast://group:port`. `local` is
+    /// what comes before it, including the separating `:`.
+    fn from_multicast(local: &str, group: &str, protocol: Protocol) -> Result<Self, Error> {
+        if !matches!(protocol, Protocol::Udp(_)) {
+            return Err(Error::TcpMulticast);
+        }
+        let [host, port] = tokenize_remote(group)?[..] else {
+            return Err(Error::Format);
+        };
+        let host = remove_brackets(host);
+        if !host.parse::<IpAddr>().is_ok_and(|ip| ip.is_multicast()) {
+            return Err(Error::Host);
+        }
+        let port = port.parse()?;
+        let local_addr = if local.is_empty() {
+            LocalSpec::Inet((default_host!(unspec), port))
+        } else {
+            let local = local.strip_suffix(':').ok_or(Error::Format)?;
+            match tokenize_remote(local)?[..] {
+                ["stdio"] => LocalSpec::Stdio,
+                [local_port] => LocalSpec::Inet((default_host!(unspec), local_port.parse()?)),
+                [local_host, local_port] => {
+                    LocalSpec::Inet((remove_brackets(local_host).to_string(), local_port.parse()?))
+                }
+                _ => return Err(Error::Format),
+            }
+        };
+        Ok(Self {
+            local_addr,
+            remote_addr: RemoteSpec::Inet((host.to_string(), port)),
+            protocol,
+            ports: 1,
+            name: None,
+        })
+    }
+}
+
 impl FromStr for Remote {
     type Err = Error;
 
     /// Parse a remote specification.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // The `=` of TCP options comes after characters a name cannot have
+        if let Some((name, spec)) = s.split_once('=') {
+            let is_name_char = |c: char| c.is_ascii_alphanumeric() || "-_.".contains(c);
+            if !name.is_empty() && name.chars().all(is_name_char) {
+                return Ok(Self {
+                    name: Some(name.to_string()),
+                    ..Self::from_unnamed(spec)?
+                });
+            }
+        }
+        Self::from_unnamed(s)
+    }
+}
+
+impl Remote {
+    /// Parse a remote specification without a name.
+    fn from_unnamed(s: &str) -> Result<Self, Error> {
         let (rest, proto) = match s.rsplit_once('/') {
-            Some((rest, proto)) => (rest, proto.parse()?),
-            None => (s, Protocol::Tcp),
+            // Not the slashes of `mcast://`
+            Some((rest, proto)) if !proto.is_empty() && !rest.ends_with("mcast:/") => {
+                (rest, proto.parse()?)
+            }
+            _ => (s, Protocol::Tcp(TcpOptions::NONE)),
         };
-        let tokens = tokenize_remote(rest)?;
-        let result = match tokens[..] {
+        if let Some((local, group)) = rest.split_once("mcast://") {
+            return Self::from_multicast(local, group, proto);
+        }
+        let mut tokens = tokenize_remote(rest)?;
+        // Parse port ranges as their first ports and check them afterwards
+        let mut ranges = Vec::new();
+        for token in &mut tokens {
+            if let Some((first, last)) = split_port_range(token) {
+                let (first_port, last_port) = (first.parse::<u16>()?, last.parse::<u16>()?);
+                // The OS picks one port for 0
+                if first_port == 0 || first_port > last_port {
+                    return Err(Error::PortRange);
+                }
+                ranges.push(last_port - first_port + 1);
+                *token = first;
+            }
+        }
+        let mut result = match tokens[..] {
             // One element: either "socks" or a port number.
             ["socks"] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(local), 1080)),
                 remote_addr: RemoteSpec::Socks,
                 protocol: proto,
+                ports: 1,
+                name: None,
             }),
             [port] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(unspec), port.parse()?)),
                 remote_addr: RemoteSpec::Inet((default_host!(local), port.parse()?)),
                 protocol: proto,
+                ports: 1,
+                name: None,
             }),
             // Two elements: either "socks" and local port number, or remote host and port number.
             ["stdio", "socks"] => Ok(Self {
                 local_addr: LocalSpec::Stdio,
                 remote_addr: RemoteSpec::Socks,
                 protocol: proto,
+                ports: 1,
+                name: None,
             }),
             [port, "socks"] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(local), port.parse()?)),
                 remote_addr: RemoteSpec::Socks,
                 protocol: proto,
+                ports: 1,
+                name: None,
+            }),
+            [port, "tproxy"] => Ok(Self {
+                local_addr: LocalSpec::Inet((default_host!(unspec), port.parse()?)),
+                remote_addr: RemoteSpec::Tproxy,
+                protocol: proto,
+                ports: 1,
+                name: None,
             }),
             ["stdio", port] => Ok(Self {
                 local_addr: LocalSpec::Stdio,
                 remote_addr: RemoteSpec::Inet((default_host!(local), port.parse()?)),
                 protocol: proto,
+                ports: 1,
+                name: None,
             }),
             [host, port] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(unspec), port.parse()?)),
                 remote_addr: RemoteSpec::Inet((remove_brackets(host).to_string(), port.parse()?)),
                 protocol: proto,
+                ports: 1,
+                name: None,
             }),
             // Three elements:
             // - "stdio", remote host, and port number,
-            // - local host, local port number, and "socks", or
+            // - local host, local port number, and "socks" or "tproxy", or
             // - local port number, remote host, and port number.
             ["stdio", remote_host, remote_port] => Ok(Self {
                 local_addr: LocalSpec::Stdio,
@@ -196,6 +503,8 @@ impl FromStr for Remote {
                     remote_port.parse()?,
                 )),
                 protocol: proto,
+                ports: 1,
+                name: None,
             }),
             [local_host, local_port, "socks"] => Ok(Self {
                 local_addr: LocalSpec::Inet((
@@ -204,6 +513,18 @@ impl FromStr for Remote {
                 )),
                 remote_addr: RemoteSpec::Socks,
                 protocol: proto,
+                ports: 1,
+                name: None,
+            }),
+            [local_host, local_port, "tproxy"] => Ok(Self {
+                local_addr: LocalSpec::Inet((
+                    remove_brackets(local_host).to_string(),
+                    local_port.parse()?,
+                )),
+                remote_addr: RemoteSpec::Tproxy,
+                protocol: proto,
+                ports: 1,
+                name: None,
             }),
             [local_port, remote_host, remote_port] => Ok(Self {
                 local_addr: LocalSpec::Inet((default_host!(unspec), local_port.parse()?)),
@@ -212,6 +533,8 @@ impl FromStr for Remote {
                     remote_port.parse()?,
                 )),
                 protocol: proto,
+                ports: 1,
+                name: None,
             }),
             [local_host, local_port, remote_host, remote_port] => Ok(Self {
                 local_addr: LocalSpec::Inet((
@@ -223,20 +546,42 @@ impl FromStr for Remote {
                     remote_port.parse()?,
                 )),
                 protocol: proto,
+                ports: 1,
+                name: None,
             }),
             _ => Err(Error::Format),
         };
+        if let Some(&ports) = ranges.first() {
+            // Both ports of `local-port:remote-host:remote-port` must be
+            // ranges of the same length; other remotes have no ports to map
+            let port_tokens = if tokens.len() <= 2 { 1 } else { 2 };
+            match &mut result {
+                Ok(
+                    remote @ Self {
+                        local_addr: LocalSpec::Inet(_),
+                        remote_addr: RemoteSpec::Inet(_),
+                        ..
+                    },
+                ) if ranges.len() == port_tokens && ranges.iter().all(|&n| n == ports) => {
+                    remote.ports = ports;
+                }
+                Ok(_) => return Err(Error::PortRange),
+                Err(_) => {}
+            }
+        }
         // I love Rust's pattern matching
         // (this sentence is written by GitHub Copilot)
-        if let Ok(Self {
-            remote_addr: RemoteSpec::Socks,
-            protocol: Protocol::Udp,
-            ..
-        }) = &result
-        {
-            Err(Error::UdpSocks)
-        } else {
-            result
+        match &result {
+            Ok(Self {
+                remote_addr: RemoteSpec::Socks,
+                protocol: Protocol::Udp(_),
+                ..
+            }) => Err(Error::UdpSocks),
+            Ok(Self {
+                remote_addr: RemoteSpec::Tproxy,
+                ..
+            }) if cfg!(not(target_os = "linux")) => Err(Error::TproxyUnsupported),
+            _ => result,
         }
     }
 }
@@ -290,7 +635,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 3000)),
                     remote_addr: RemoteSpec::Inet((default_host!(local), 3000)),
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -298,7 +645,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 4000)),
                     remote_addr: RemoteSpec::Inet((default_host!(local), 4000)),
-                    protocol: Protocol::Udp,
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -306,7 +655,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 80)),
                     remote_addr: RemoteSpec::Inet((String::from("google.com"), 80)),
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -314,7 +665,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 80)),
                     remote_addr: RemoteSpec::Inet((String::from("示例網站.com"), 80)),
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -322,7 +675,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 8080)),
                     remote_addr: RemoteSpec::Inet((String::from("example.com"), 80)),
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -330,7 +685,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((default_host!(local), 1080)),
                     remote_addr: RemoteSpec::Socks,
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -338,7 +695,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((String::from("127.0.0.1"), 1081)),
                     remote_addr: RemoteSpec::Socks,
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -346,7 +705,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((default_host!(local), 9050)),
                     remote_addr: RemoteSpec::Socks,
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -354,7 +715,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((default_host!(unspec), 53)),
                     remote_addr: RemoteSpec::Inet((String::from("1.1.1.1"), 53)),
-                    protocol: Protocol::Udp,
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -362,7 +725,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((String::from("localhost"), 5353)),
                     remote_addr: RemoteSpec::Inet((String::from("1.1.1.1"), 53)),
-                    protocol: Protocol::Udp,
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -370,7 +735,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Inet((String::from("::1"), 8080)),
                     remote_addr: RemoteSpec::Inet((String::from("google.com"), 80)),
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -381,7 +748,9 @@ mod test {
                         String::from("2001:4860:4860:0:0:0:0:8888"),
                         53,
                     )),
-                    protocol: Protocol::Udp,
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -389,7 +758,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Stdio,
                     remote_addr: RemoteSpec::Inet((String::from("google.com"), 80)),
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -397,7 +768,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Stdio,
                     remote_addr: RemoteSpec::Socks,
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -405,7 +778,9 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Stdio,
                     remote_addr: RemoteSpec::Inet((default_host!(local), 443)),
-                    protocol: Protocol::Tcp,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
                 },
             ),
             (
@@ -413,7 +788,131 @@ mod test {
                 Remote {
                     local_addr: LocalSpec::Stdio,
                     remote_addr: RemoteSpec::Inet((default_host!(local), 5353)),
-                    protocol: Protocol::Udp,
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "6969:tftp.example.com:69/udp+reliable",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 6969)),
+                    remote_addr: RemoteSpec::Inet((String::from("tftp.example.com"), 69)),
+                    protocol: Protocol::Udp(UdpOptions {
+                        reliable: true,
+                        full_cone: false,
+                    }),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "3478:stun.example.com:3478/udp+fullcone",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 3478)),
+                    remote_addr: RemoteSpec::Inet((String::from("stun.example.com"), 3478)),
+                    protocol: Protocol::Udp(UdpOptions {
+                        reliable: false,
+                        full_cone: true,
+                    }),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "5060:sip.example.com:5060/UDP+fullcone+reliable",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 5060)),
+                    remote_addr: RemoteSpec::Inet((String::from("sip.example.com"), 5060)),
+                    protocol: Protocol::Udp(UdpOptions {
+                        reliable: true,
+                        full_cone: true,
+                    }),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "2222:ssh.example.com:22/tcp+nodelay+keepalive=60,,3+usertimeout=30",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 2222)),
+                    remote_addr: RemoteSpec::Inet((String::from("ssh.example.com"), 22)),
+                    protocol: Protocol::Tcp(TcpOptions {
+                        nodelay: Some(true),
+                        keepalive_idle: Some(60),
+                        keepalive_count: Some(3),
+                        user_timeout: Some(30),
+                        ..TcpOptions::NONE
+                    }),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "socks/tcp+nagle+keepalive=,10",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(local), 1080)),
+                    remote_addr: RemoteSpec::Socks,
+                    protocol: Protocol::Tcp(TcpOptions {
+                        nodelay: Some(false),
+                        keepalive_interval: Some(10),
+                        ..TcpOptions::NONE
+                    }),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "8000-8010:example.com:8000-8010",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 8000)),
+                    remote_addr: RemoteSpec::Inet((String::from("example.com"), 8000)),
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 11,
+                    name: None,
+                },
+            ),
+            (
+                "[::1]:6000-6002:example.com:7000-7002/udp",
+                Remote {
+                    local_addr: LocalSpec::Inet((String::from("::1"), 6000)),
+                    remote_addr: RemoteSpec::Inet((String::from("example.com"), 7000)),
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 3,
+                    name: None,
+                },
+            ),
+            (
+                "db=5432:db.internal:5432",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 5432)),
+                    remote_addr: RemoteSpec::Inet((String::from("db.internal"), 5432)),
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: Some(String::from("db")),
+                },
+            ),
+            (
+                "ssh-2=2222:host:22/tcp+keepalive=60",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 2222)),
+                    remote_addr: RemoteSpec::Inet((String::from("host"), 22)),
+                    protocol: Protocol::Tcp(TcpOptions {
+                        keepalive_idle: Some(60),
+                        ..TcpOptions::NONE
+                    }),
+                    ports: 1,
+                    name: Some(String::from("ssh-2")),
+                },
+            ),
+            (
+                "5000-5001",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 5000)),
+                    remote_addr: RemoteSpec::Inet((default_host!(local), 5000)),
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 2,
+                    name: None,
                 },
             ),
         ];
@@ -427,5 +926,183 @@ mod test {
         }
         "just_a_hostname".parse::<Remote>().unwrap_err();
         "socks/udp".parse::<Remote>().unwrap_err();
+        "socks/udp+reliable".parse::<Remote>().unwrap_err();
+        "53/udp+reliable+reliable".parse::<Remote>().unwrap_err();
+        "53/udp+fast".parse::<Remote>().unwrap_err();
+        "80/tcp+fullcone".parse::<Remote>().unwrap_err();
+        "80/tcp+nodelay+nagle".parse::<Remote>().unwrap_err();
+        "80/tcp+keepalive=".parse::<Remote>().unwrap_err();
+        "80/tcp+keepalive=1,2,3,4".parse::<Remote>().unwrap_err();
+        "80/tcp+usertimeout=-1".parse::<Remote>().unwrap_err();
+        "mcast://239.0.0.1:5353".parse::<Remote>().unwrap_err();
+        "mcast://10.0.0.1:5353/udp".parse::<Remote>().unwrap_err();
+        "1:2:3:mcast://239.0.0.1:5353/udp"
+            .parse::<Remote>()
+            .unwrap_err();
+        "stdio:tproxy/udp".parse::<Remote>().unwrap_err();
+        "8000-8010:example.com:80".parse::<Remote>().unwrap_err();
+        "8000-8010:example.com:9000-9001"
+            .parse::<Remote>()
+            .unwrap_err();
+        "8010-8000:example.com:8010-8000"
+            .parse::<Remote>()
+            .unwrap_err();
+        "65535-65536".parse::<Remote>().unwrap_err();
+        "1080-1081:socks".parse::<Remote>().unwrap_err();
+        "stdio:8000-8001".parse::<Remote>().unwrap_err();
+        "db=db=5432".parse::<Remote>().unwrap_err();
+        "=5432".parse::<Remote>().unwrap_err();
+        "d b=5432".parse::<Remote>().unwrap_err();
+        "0-10:example.com:80-90".parse::<Remote>().unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_ssh_forwards() {
+        let tests = [
+            ("8080:example.com:80", default_host!(local), "example.com"),
+            (
+                "*:8080:example.com:80",
+                default_host!(unspec),
+                "example.com",
+            ),
+            (":8080:[::1]:80", default_host!(unspec), "::1"),
+            ("[::1]:8080:db:80", String::from("::1"), "db"),
+        ];
+        for (s, bind, host) in tests {
+            assert_eq!(
+                Remote::from_ssh_local(s).unwrap(),
+                Remote {
+                    local_addr: LocalSpec::Inet((bind, 8080)),
+                    remote_addr: RemoteSpec::Inet((host.to_string(), 80)),
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
+                }
+            );
+        }
+        assert_eq!(
+            Remote::from_ssh_dynamic("1080").unwrap(),
+            "socks".parse::<Remote>().unwrap()
+        );
+        assert_eq!(
+            Remote::from_ssh_dynamic("192.168.1.2:9050").unwrap(),
+            "192.168.1.2:9050:socks".parse::<Remote>().unwrap()
+        );
+        Remote::from_ssh_local("8080:example.com").unwrap_err();
+        Remote::from_ssh_local("8080:example.com:http").unwrap_err();
+        Remote::from_ssh_dynamic("a:b:c").unwrap_err();
+    }
+
+    #[test]
+    fn test_expand_remote() {
+        let remote = "127.0.0.1:8000-8002:example.com:9000-9002/udp"
+            .parse::<Remote>()
+            .unwrap();
+        let expanded = remote.expand().map(|r| r.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            expanded,
+            [
+                "127.0.0.1:8000:example.com:9000/udp",
+                "127.0.0.1:8001:example.com:9001/udp",
+                "127.0.0.1:8002:example.com:9002/udp",
+            ]
+        );
+        let remote = "socks".parse::<Remote>().unwrap();
+        assert_eq!(remote.label(), remote.to_string());
+        assert_eq!(remote.expand().collect::<Vec<_>>(), [remote]);
+        let remote = "web=8000-8001".parse::<Remote>().unwrap();
+        assert!(remote.expand().all(|r| r.label() == "web"));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_parse_tproxy_remote() {
+        let tests = [
+            (
+                "5300:tproxy",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 5300)),
+                    remote_addr: RemoteSpec::Tproxy,
+                    protocol: Protocol::Tcp(TcpOptions::NONE),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "5300:tproxy/udp",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 5300)),
+                    remote_addr: RemoteSpec::Tproxy,
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "127.0.0.1:5300:tproxy/udp+fullcone",
+                Remote {
+                    local_addr: LocalSpec::Inet((String::from("127.0.0.1"), 5300)),
+                    remote_addr: RemoteSpec::Tproxy,
+                    protocol: Protocol::Udp(UdpOptions {
+                        reliable: false,
+                        full_cone: true,
+                    }),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+        ];
+        for (s, expected) in tests {
+            let actual = s.parse::<Remote>().unwrap();
+            assert_eq!(actual, expected);
+            let reparsed = actual.to_string().parse::<Remote>().unwrap();
+            assert_eq!(reparsed, expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_multicast_remote() {
+        let tests = [
+            (
+                "mcast://239.255.255.250:1900/udp",
+                Remote {
+                    local_addr: LocalSpec::Inet((default_host!(unspec), 1900)),
+                    remote_addr: RemoteSpec::Inet((String::from("239.255.255.250"), 1900)),
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "127.0.0.1:5454:mcast://[ff02::fb]:5353/udp",
+                Remote {
+                    local_addr: LocalSpec::Inet((String::from("127.0.0.1"), 5454)),
+                    remote_addr: RemoteSpec::Inet((String::from("ff02::fb"), 5353)),
+                    protocol: Protocol::Udp(UdpOptions::default()),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+            (
+                "stdio:mcast://224.0.0.251:5353/udp+reliable",
+                Remote {
+                    local_addr: LocalSpec::Stdio,
+                    remote_addr: RemoteSpec::Inet((String::from("224.0.0.251"), 5353)),
+                    protocol: Protocol::Udp(UdpOptions {
+                        reliable: true,
+                        full_cone: false,
+                    }),
+                    ports: 1,
+                    name: None,
+                },
+            ),
+        ];
+        for (s, expected) in tests {
+            let actual = s.parse::<Remote>().unwrap();
+            assert_eq!(actual, expected);
+            // The canonical format names the group as a plain host
+            let reparsed = actual.to_string().parse::<Remote>().unwrap();
+            assert_eq!(reparsed, expected);
+        }
     }
 }
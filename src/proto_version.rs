@@ -1 +1,80 @@
-pub const PROTOCOL_VERSION: &str = "penguin-v6";
+//! Protocol versions, negotiated in the `sec-websocket-protocol` header.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::noise;
+use penguin_mux::FrameVersion;
+#[cfg(not(feature = "fast-ws"))]
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+/// Newest protocol version, with 32-bit stream ports
+pub const PROTOCOL_VERSION: &str = "penguin-v7";
+/// Previous protocol version, still spoken with older peers
+pub const PROTOCOL_VERSION_V6: &str = "penguin-v6";
+/// What the client offers, newest first
+pub const OFFERED_PROTOCOLS: &str = "penguin-v7, penguin-v6";
+
+/// Frame format of the protocol `version`, or `None` if we do not speak it.
+pub fn frame_version(version: &[u8]) -> Option<FrameVersion> {
+    if version.eq_ignore_ascii_case(PROTOCOL_VERSION.as_bytes()) {
+        Some(FrameVersion::V2)
+    } else if version.eq_ignore_ascii_case(PROTOCOL_VERSION_V6.as_bytes()) {
+        Some(FrameVersion::V1)
+    } else {
+        None
+    }
+}
+
+/// Pick the newest version we speak among the comma-separated `offered`
+/// ones.
+pub fn choose(offered: &[u8]) -> Option<(&'static str, FrameVersion)> {
+    offered
+        .split(|&b| b == b',')
+        .filter_map(|version| frame_version(version.trim_ascii()))
+        .max()
+        .map(|frame_version| match frame_version {
+            FrameVersion::V1 => (PROTOCOL_VERSION_V6, frame_version),
+            FrameVersion::V2 => (PROTOCOL_VERSION, frame_version),
+        })
+}
+
+/// Largest WebSocket message that carries a frame of `max_frame_size`,
+/// since each message carries one frame, plus room for Noise tags.
+pub const fn max_message_size(max_frame_size: u32) -> usize {
+    let max_frame_size = max_frame_size as usize;
+    max_frame_size + noise::overhead(max_frame_size)
+}
+
+/// WebSocket settings that reject messages larger than `max_frame_size`.
+#[cfg(not(feature = "fast-ws"))]
+pub fn ws_config(max_frame_size: u32) -> WebSocketConfig {
+    let max_frame_size = max_message_size(max_frame_size);
+    WebSocketConfig {
+        max_message_size: Some(max_frame_size),
+        max_frame_size: Some(max_frame_size),
+        ..WebSocketConfig::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_choose() {
+        assert_eq!(
+            choose(OFFERED_PROTOCOLS.as_bytes()),
+            Some((PROTOCOL_VERSION, FrameVersion::V2))
+        );
+        assert_eq!(
+            choose(b"penguin-v6"),
+            Some((PROTOCOL_VERSION_V6, FrameVersion::V1))
+        );
+        assert_eq!(
+            choose(b"chat, Penguin-V7"),
+            Some((PROTOCOL_VERSION, FrameVersion::V2))
+        );
+        assert_eq!(choose(b"penguin-v5, chat"), None);
+        assert_eq!(choose(b""), None);
+    }
+}
@@ -0,0 +1,497 @@
+//! Resolve the hostnames of remotes with a resolver other than the system's
+//! default one: a plain DNS server, DNS-over-HTTPS (RFC 8484), or
+//! DNS-over-TLS (RFC 7858). The client uses it so that the server's resolver
+//! does not see or answer them, and the server for split-horizon DNS.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::ResolverUrl;
+use crate::config;
+use crate::tls::{make_client_https, ClientTlsStream};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{header, Method, Request, StatusCode, Uri};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+#[cfg(feature = "__rustls")]
+use hyper_rustls::HttpsConnector;
+#[cfg(feature = "nativetls")]
+use hyper_tls::HttpsConnector;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+/// Record type of IPv4 addresses
+const TYPE_A: u16 = 1;
+/// Record type of IPv6 addresses
+const TYPE_AAAA: u16 = 28;
+/// Class of Internet records
+const CLASS_IN: u16 = 1;
+/// Response code of a name that does not exist
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Resolution errors
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid host name")]
+    InvalidName,
+    #[error("malformed DNS response")]
+    Malformed,
+    #[error("DNS server answered with response code {0}")]
+    Rcode(u8),
+    #[error("no address for the host")]
+    NoAddress,
+    #[error("DNS query timed out")]
+    Timeout,
+    #[error("DNS query failed: {0}")]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Tls(#[from] crate::tls::Error),
+    #[error("DNS-over-HTTPS query failed: {0}")]
+    Http(#[from] hyper::Error),
+    #[error("DNS-over-HTTPS server answered {0}")]
+    HttpStatus(StatusCode),
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        let kind = match err {
+            Error::NoAddress | Error::Rcode(RCODE_NXDOMAIN) => io::ErrorKind::NotFound,
+            Error::InvalidName => io::ErrorKind::InvalidInput,
+            Error::Timeout => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::Other,
+        };
+        Self::new(kind, err)
+    }
+}
+
+/// How queries reach the DNS server
+#[derive(Debug)]
+enum Transport {
+    /// The system's resolver, which does its own caching
+    System,
+    /// Plain DNS over UDP
+    Udp(SocketAddr),
+    /// POST to this URL
+    Https {
+        url: Uri,
+        client: Box<Client<HttpsConnector<HttpConnector>>>,
+    },
+    /// Over a TLS connection, which is kept for the next queries
+    Tls {
+        host: String,
+        port: u16,
+        connection: tokio::sync::Mutex<Option<Box<ClientTlsStream>>>,
+    },
+}
+
+/// A caching DNS client
+#[derive(Debug)]
+pub struct Resolver {
+    transport: Transport,
+    /// Host name -> address, and when it expires
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl Resolver {
+    /// Create a resolver that asks the server at `url`.
+    pub fn new(url: &ResolverUrl) -> Self {
+        let transport = match url {
+            ResolverUrl::System => Transport::System,
+            ResolverUrl::Udp(addr) => Transport::Udp(*addr),
+            ResolverUrl::Https(url) => Transport::Https {
+                url: url.clone(),
+                client: Box::new(Client::builder().build(make_client_https())),
+            },
+            ResolverUrl::Tls { host, port } => Transport::Tls {
+                host: host.clone(),
+                port: *port,
+                connection: tokio::sync::Mutex::new(None),
+            },
+        };
+        Self {
+            transport,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve `host` to an IP address, or return it as is if it is
+    /// already one.
+    pub async fn resolve(&self, host: &[u8]) -> Result<Bytes, Error> {
+        let host = std::str::from_utf8(host).map_err(|_| Error::InvalidName)?;
+        if host.parse::<IpAddr>().is_ok() {
+            return Ok(Bytes::copy_from_slice(host.as_bytes()));
+        }
+        let address = self.resolve_ip(host).await?;
+        Ok(Bytes::from(address.to_string()))
+    }
+
+    /// Resolve `host` to an IP address, preferring IPv4.
+    pub async fn resolve_ip(&self, host: &str) -> Result<IpAddr, Error> {
+        let addresses = self.resolve_all(host).await?;
+        addresses
+            .iter()
+            .find(|address| address.is_ipv4())
+            .or_else(|| addresses.first())
+            .copied()
+            .ok_or(Error::NoAddress)
+    }
+
+    /// Resolve `host` to all of its IP addresses.
+    pub async fn resolve_all(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        if let Ok(address) = host.parse() {
+            return Ok(vec![address]);
+        }
+        let addresses = if let Transport::System = self.transport {
+            lookup_host((host, 0))
+                .await?
+                .map(|addr| addr.ip())
+                .collect()
+        } else {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            self.lookup(&host).await?
+        };
+        if addresses.is_empty() {
+            return Err(Error::NoAddress);
+        }
+        Ok(addresses)
+    }
+
+    /// Find the IPv4 and IPv6 addresses of `host`.
+    async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, Error> {
+        let now = Instant::now();
+        if let Some((addresses, expires)) = self.cache.lock().get(host) {
+            if *expires > now {
+                trace!("{host} is {addresses:?} (cached)");
+                return Ok(addresses.clone());
+            }
+        }
+        let answers = match tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA)) {
+            (Err(err), Err(_)) => return Err(err),
+            (a, aaaa) => [a.unwrap_or_default(), aaaa.unwrap_or_default()].concat(),
+        };
+        let addresses = answers
+            .iter()
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        debug!("{host} is {addresses:?}");
+        let Some(ttl) = answers.iter().map(|(_, ttl)| *ttl).min() else {
+            return Ok(addresses);
+        };
+        let mut cache = self.cache.lock();
+        if cache.len() >= config::DNS_CACHE_SIZE {
+            cache.retain(|_, (_, expires)| *expires > now);
+        }
+        if cache.len() < config::DNS_CACHE_SIZE {
+            let ttl = Duration::from_secs(ttl.into()).min(config::DNS_MAX_TTL);
+            cache.insert(host.to_string(), (addresses.clone(), now + ttl));
+        }
+        Ok(addresses)
+    }
+
+    /// Ask the server for the `qtype` records of `host`.
+    async fn query(&self, host: &str, qtype: u16) -> Result<Vec<(IpAddr, u32)>, Error> {
+        // RFC 8484 recommends ID 0 so that HTTP caches work
+        let id = match self.transport {
+            Transport::Https { .. } => 0,
+            _ => rand::random(),
+        };
+        let query = encode_query(id, host, qtype)?;
+        let response = tokio::time::timeout(config::DNS_TIMEOUT, self.exchange(query))
+            .await
+            .map_err(|_| Error::Timeout)??;
+        parse_response(response, id)
+    }
+
+    /// Send a DNS message and receive the answer.
+    async fn exchange(&self, query: Bytes) -> Result<Bytes, Error> {
+        match &self.transport {
+            Transport::System => {
+                unreachable!("the system resolver takes no queries (this is a bug)")
+            }
+            Transport::Udp(server) => {
+                let unspecified = match server {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                };
+                let socket = UdpSocket::bind((unspecified, 0)).await?;
+                // Only answers from the server are received
+                socket.connect(server).await?;
+                socket.send(&query).await?;
+                let mut response = vec![0; config::MAX_UDP_PACKET_SIZE];
+                let len = socket.recv(&mut response).await?;
+                response.truncate(len);
+                Ok(response.into())
+            }
+            Transport::Https { url, client } => {
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(url)
+                    .header(header::CONTENT_TYPE, "application/dns-message")
+                    .header(header::ACCEPT, "application/dns-message")
+                    .body(Body::from(query))
+                    .expect("DNS-over-HTTPS request should be valid (this is a bug)");
+                let response = client.request(request).await?;
+                if response.status() != StatusCode::OK {
+                    return Err(Error::HttpStatus(response.status()));
+                }
+                Ok(hyper::body::to_bytes(response.into_body()).await?)
+            }
+            Transport::Tls {
+                host,
+                port,
+                connection,
+            } => {
+                let mut connection = connection.lock().await;
+                // The server may have closed the one we kept
+                if let Some(stream) = connection.as_mut() {
+                    if let Ok(response) = exchange_tls(stream, &query).await {
+                        return Ok(response);
+                    }
+                }
+                let tcp = TcpStream::connect((host.as_str(), *port)).await?;
+                let stream = connection.insert(Box::new(crate::tls::connect(host, tcp).await?));
+                exchange_tls(stream, &query).await.inspect_err(|_| {
+                    connection.take();
+                })
+            }
+        }
+    }
+}
+
+/// Send a DNS message over a DNS-over-TLS connection and receive the
+/// answer. Each message has a 2-byte length prefix.
+async fn exchange_tls(stream: &mut ClientTlsStream, query: &[u8]) -> Result<Bytes, Error> {
+    let len = u16::try_from(query.len()).map_err(|_| Error::InvalidName)?;
+    let mut message = Vec::with_capacity(2 + query.len());
+    message.extend_from_slice(&len.to_be_bytes());
+    message.extend_from_slice(query);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+    let len = stream.read_u16().await?;
+    let mut response = vec![0; len.into()];
+    stream.read_exact(&mut response).await?;
+    Ok(response.into())
+}
+
+/// Encode a recursive query for the `qtype` records of `host`.
+fn encode_query(id: u16, host: &str, qtype: u16) -> Result<Bytes, Error> {
+    // Header, name, type, and class
+    let mut query = BytesMut::with_capacity(12 + host.len() + 2 + 4);
+    query.put_u16(id);
+    // Recursion desired
+    query.put_u16(0x0100);
+    // One question, no other records
+    query.put_u16(1);
+    query.put_u16(0);
+    query.put_u16(0);
+    query.put_u16(0);
+    if host.is_empty() || host.len() > 253 {
+        return Err(Error::InvalidName);
+    }
+    for label in host.split('.') {
+        let len = u8::try_from(label.len()).map_err(|_| Error::InvalidName)?;
+        if len == 0 || len > 63 {
+            return Err(Error::InvalidName);
+        }
+        query.put_u8(len);
+        query.put_slice(label.as_bytes());
+    }
+    query.put_u8(0);
+    query.put_u16(qtype);
+    query.put_u16(CLASS_IN);
+    Ok(query.freeze())
+}
+
+/// Skip a possibly compressed name.
+fn skip_name(message: &mut Bytes) -> Result<(), Error> {
+    loop {
+        if !message.has_remaining() {
+            return Err(Error::Malformed);
+        }
+        let len = message.get_u8();
+        match len {
+            0 => return Ok(()),
+            // A pointer ends the name
+            0xc0.. => {
+                if !message.has_remaining() {
+                    return Err(Error::Malformed);
+                }
+                message.advance(1);
+                return Ok(());
+            }
+            _ => {
+                if message.remaining() < usize::from(len) {
+                    return Err(Error::Malformed);
+                }
+                message.advance(usize::from(len));
+            }
+        }
+    }
+}
+
+/// Parse the addresses and their TTLs from the answer to query `id`.
+fn parse_response(mut message: Bytes, id: u16) -> Result<Vec<(IpAddr, u32)>, Error> {
+    if message.remaining() < 12 {
+        return Err(Error::Malformed);
+    }
+    let response_id = message.get_u16();
+    let flags = message.get_u16();
+    // Must be a response
+    if response_id != id || flags & 0x8000 == 0 {
+        return Err(Error::Malformed);
+    }
+    #[allow(clippy::cast_possible_truncation)]
+    let rcode = (flags & 0x000f) as u8;
+    if rcode != 0 {
+        return Err(Error::Rcode(rcode));
+    }
+    let questions = message.get_u16();
+    let answers = message.get_u16();
+    // Authority and additional records are not needed
+    message.advance(4);
+    for _ in 0..questions {
+        skip_name(&mut message)?;
+        if message.remaining() < 4 {
+            return Err(Error::Malformed);
+        }
+        message.advance(4);
+    }
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        skip_name(&mut message)?;
+        if message.remaining() < 10 {
+            return Err(Error::Malformed);
+        }
+        let rtype = message.get_u16();
+        let class = message.get_u16();
+        let ttl = message.get_u32();
+        let len = usize::from(message.get_u16());
+        if message.remaining() < len {
+            return Err(Error::Malformed);
+        }
+        let data = message.split_to(len);
+        // Also skips the `CNAME`s on the way to the addresses
+        match (rtype, class, len) {
+            (TYPE_A, CLASS_IN, 4) => {
+                let octets: [u8; 4] = data[..].try_into().expect("length checked");
+                addresses.push((Ipv4Addr::from(octets).into(), ttl));
+            }
+            (TYPE_AAAA, CLASS_IN, 16) => {
+                let octets: [u8; 16] = data[..].try_into().expect("length checked");
+                addresses.push((Ipv6Addr::from(octets).into(), ttl));
+            }
+            _ => {}
+        }
+    }
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_query() {
+        let query = encode_query(0x1234, "example.com", TYPE_A).unwrap();
+        assert_eq!(
+            query[..],
+            [
+                0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 7, b'e', b'x', b'a', b'm', b'p',
+                b'l', b'e', 3, b'c', b'o', b'm', 0, 0, 1, 0, 1
+            ]
+        );
+        encode_query(0, "", TYPE_A).unwrap_err();
+        encode_query(0, "a..b", TYPE_A).unwrap_err();
+        encode_query(0, &"a".repeat(64), TYPE_A).unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_response() {
+        let query = encode_query(7, "www.example.com", TYPE_A).unwrap();
+        let mut response = BytesMut::from(&query[..]);
+        // A response with two answers
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 2;
+        // www.example.com CNAME example.com, pointing into the question
+        response.put_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 16]);
+        // example.com A 93.184.216.34
+        response.put_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 93, 184, 216, 34]);
+        let response = response.freeze();
+        assert_eq!(
+            parse_response(response.clone(), 7).unwrap(),
+            [(IpAddr::from([93, 184, 216, 34]), 256)]
+        );
+        // Answers to other queries are rejected
+        assert!(matches!(
+            parse_response(response.clone(), 8),
+            Err(Error::Malformed)
+        ));
+        // So are truncated ones
+        assert!(matches!(
+            parse_response(response.slice(..response.len() - 1), 7),
+            Err(Error::Malformed)
+        ));
+        let mut nxdomain = BytesMut::from(&query[..]);
+        nxdomain[2] = 0x81;
+        nxdomain[3] = 0x83;
+        assert!(matches!(
+            parse_response(nxdomain.freeze(), 7),
+            Err(Error::Rcode(RCODE_NXDOMAIN))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ip_literal() {
+        let resolver = Resolver::new(&ResolverUrl::Tls {
+            host: "localhost".to_string(),
+            port: 853,
+        });
+        assert_eq!(resolver.resolve(b"192.0.2.1").await.unwrap(), "192.0.2.1");
+        assert_eq!(resolver.resolve(b"::1").await.unwrap(), "::1");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_udp() {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let resolver = Resolver::new(&ResolverUrl::Udp(server.local_addr().unwrap()));
+        let answer = async {
+            // One query for A records and one for AAAA records
+            for _ in 0..2 {
+                let mut buf = vec![0; 512];
+                let (len, client) = server.recv_from(&mut buf).await.unwrap();
+                let mut response = BytesMut::from(&buf[..len]);
+                response[2] |= 0x80;
+                response[7] = 1;
+                if response[len - 3] == 1 {
+                    response.put_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 7]);
+                } else {
+                    response.put_slice(&[0xc0, 12, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16]);
+                    response
+                        .put_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7]);
+                }
+                server.send_to(&response, client).await.unwrap();
+            }
+        };
+        let (addresses, ()) = tokio::join!(resolver.resolve_all("Host.Example."), answer);
+        assert_eq!(
+            addresses.unwrap(),
+            [
+                IpAddr::from([192, 0, 2, 7]),
+                "2001:db8::7".parse::<IpAddr>().unwrap()
+            ]
+        );
+        // Cached, since the server does not answer again
+        assert_eq!(
+            resolver.resolve(b"host.example").await.unwrap(),
+            "192.0.2.7"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+//! Access log for plain HTTP requests, like the one of a real web server.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::AccessLogFormat;
+use crate::config;
+use http::{header, Request, Response, StatusCode};
+use hyper::body::HttpBody;
+use hyper::Body;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+/// Open a log file for appending, or stdout if `path` is `-`.
+fn open_log_file(path: &str) -> std::io::Result<Box<dyn Write + Send>> {
+    if path == "-" {
+        return Ok(Box::new(std::io::stdout()));
+    }
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    Ok(Box::new(file))
+}
+
+/// A log file written on its own thread, so that a slow disk does not
+/// block the runtime.
+pub(super) struct LogWriter {
+    tx: Option<SyncSender<String>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LogWriter {
+    /// Append to the file at `path`, or write to stdout if `path` is `-`.
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        let mut out = open_log_file(path)?;
+        let (tx, rx) = mpsc::sync_channel::<String>(config::LOG_QUEUE_SIZE);
+        let path = path.to_string();
+        let thread = std::thread::Builder::new()
+            .name("log-writer".to_string())
+            .spawn(move || {
+                for line in rx {
+                    if let Err(err) = writeln!(out, "{line}") {
+                        error!("Failed to write to {path}: {err}");
+                    }
+                }
+            })?;
+        Ok(Self {
+            tx: Some(tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Queue a line for writing. The line is dropped if the queue is full.
+    pub fn write(&self, line: String) {
+        // `expect`: only taken in `drop`
+        let tx = self
+            .tx
+            .as_ref()
+            .expect("Log writer is closed (this is a bug)");
+        if let Err(TrySendError::Full(_)) = tx.try_send(line) {
+            warn!("Log writer cannot keep up, dropping a line");
+        }
+    }
+}
+
+impl Drop for LogWriter {
+    /// Wait for the queued lines to be written.
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Where access log lines go.
+pub(super) struct AccessLog {
+    format: AccessLogFormat,
+    out: LogWriter,
+}
+
+impl std::fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl AccessLog {
+    /// Append to the file at `path`, or write to stdout if `path` is `-`.
+    pub fn open(path: &str, format: AccessLogFormat) -> std::io::Result<Self> {
+        Ok(Self {
+            format,
+            out: LogWriter::open(path)?,
+        })
+    }
+
+    /// Write an entry for a finished request.
+    pub fn log(&self, entry: &Entry, resp: &Response<Body>) {
+        let line = match self.format {
+            AccessLogFormat::Combined => entry.combined(resp),
+            AccessLogFormat::Json => entry.json(resp),
+        };
+        self.out.write(line);
+    }
+}
+
+/// The parts of a request that go into the access log.
+#[derive(Debug)]
+pub(super) struct Entry {
+    client: Option<IpAddr>,
+    time: SystemTime,
+    method: String,
+    target: String,
+    version: &'static str,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    pub fn new(req: &Request<Body>, client: Option<IpAddr>) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        Self {
+            client,
+            time: SystemTime::now(),
+            method: req.method().to_string(),
+            // Only the path, since the query may carry secrets
+            target: req.uri().path().to_string(),
+            version: version_str(req.version()),
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+        }
+    }
+
+    /// Whether the request became a tunnel instead of a plain HTTP request.
+    pub fn is_tunnel(resp: &Response<Body>) -> bool {
+        resp.status() == StatusCode::SWITCHING_PROTOCOLS
+    }
+
+    /// Format in the Combined Log Format.
+    fn combined(&self, resp: &Response<Body>) -> String {
+        let quoted = |value: &Option<String>| {
+            value
+                .as_deref()
+                .map_or_else(|| "-".to_string(), |v| v.replace('"', "\\\""))
+        };
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+            self.client
+                .map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+            clf_time(self.time),
+            self.method,
+            self.target.replace('"', "%22"),
+            self.version,
+            resp.status().as_u16(),
+            body_len(resp).map_or_else(|| "-".to_string(), |len| len.to_string()),
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+        )
+    }
+
+    /// Format as a JSON object.
+    fn json(&self, resp: &Response<Body>) -> String {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |d| d.as_secs_f64());
+        serde_json::json!({
+            "time": time,
+            "client": self.client,
+            "method": self.method,
+            "target": self.target,
+            "version": self.version,
+            "status": resp.status().as_u16(),
+            "bytes": body_len(resp),
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+        })
+        .to_string()
+    }
+}
+
+fn version_str(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "HTTP/0.9",
+        http::Version::HTTP_10 => "HTTP/1.0",
+        http::Version::HTTP_2 => "HTTP/2.0",
+        http::Version::HTTP_3 => "HTTP/3.0",
+        _ => "HTTP/1.1",
+    }
+}
+
+/// Length of the response body, if known up front.
+fn body_len(resp: &Response<Body>) -> Option<u64> {
+    resp.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse().ok())
+        .or_else(|| resp.body().size_hint().exact())
+}
+
+/// Format a time like `10/Oct/2000:13:55:36 +0000`.
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        // `month` is in 1..=12
+        MONTHS[usize::try_from(month - 1).unwrap_or_default()],
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_clf_time() {
+        let time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        assert_eq!(clf_time(time), "10/Oct/2000:13:55:36 +0000");
+        assert_eq!(clf_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(clf_time(leap_day), "29/Feb/2024:12:34:56 +0000");
+    }
+
+    #[test]
+    fn test_entry() {
+        let req = Request::get("/index.html?a=b")
+            .header(header::USER_AGENT, "curl/8.0 \"x\"")
+            .body(Body::empty())
+            .unwrap();
+        let mut entry = Entry::new(&req, Some("192.0.2.1".parse().unwrap()));
+        entry.time = UNIX_EPOCH + Duration::from_secs(971_186_136);
+        let resp = Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found"))
+            .unwrap();
+        assert_eq!(
+            entry.combined(&resp),
+            "192.0.2.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /index.html HTTP/1.1\" 404 9 \"-\" \"curl/8.0 \\\"x\\\"\""
+        );
+        let json: serde_json::Value = serde_json::from_str(&entry.json(&resp)).unwrap();
+        assert_eq!(json["client"], "192.0.2.1");
+        assert_eq!(json["status"], 404);
+        assert_eq!(json["bytes"], 9);
+        assert_eq!(json["referer"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_log_writer() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("access.log");
+        let writer = LogWriter::open(path.to_str().unwrap()).unwrap();
+        writer.write("first".to_string());
+        writer.write("second".to_string());
+        // Dropping waits for the lines to be written
+        drop(writer);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "first\nsecond\n");
+    }
+}
@@ -0,0 +1,169 @@
+//! Audit log of tunneled connections.
+//!
+//! Every TCP stream is logged when it ends, with the user, the client
+//! address, the destination, the start and end times, and the bytes
+//! transferred. With `--audit-log`, the records are also appended to a file
+//! as JSON lines.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::access_log::LogWriter;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// The `--audit-log` file.
+pub(super) struct AuditLog {
+    out: LogWriter,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Append to the file at `path`, or write to stdout if `path` is `-`.
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            out: LogWriter::open(path)?,
+        })
+    }
+
+    fn write(&self, record: &serde_json::Value) {
+        self.out.write(record.to_string());
+    }
+}
+
+/// Who is on the other end of a `WebSocket` connection.
+#[derive(Clone, Debug, Default)]
+pub(super) struct Auditor {
+    /// Authenticated user, if any
+    pub user: Option<String>,
+    /// Real client address, if known
+    pub client: Option<IpAddr>,
+    /// Where to write the records, in addition to the log
+    pub log: Option<Arc<AuditLog>>,
+}
+
+impl Auditor {
+    /// Start a record for a stream to `host` port `port`.
+    pub fn start(&self, host: &[u8], port: u16) -> StreamRecord {
+        StreamRecord {
+            auditor: self.clone(),
+            host: String::from_utf8_lossy(host).into_owned(),
+            port,
+            start: SystemTime::now(),
+        }
+    }
+}
+
+/// A tunneled stream being audited.
+#[derive(Debug)]
+pub(super) struct StreamRecord {
+    auditor: Auditor,
+    host: String,
+    port: u16,
+    start: SystemTime,
+}
+
+/// How a stream ended.
+#[derive(Debug)]
+pub(super) enum Outcome<'a> {
+    /// Closed after transferring bytes up (client to destination) and down
+    Closed { up: u64, down: u64 },
+    /// Not allowed by the user's destination policy
+    Denied,
+    /// Failed to connect or transfer
+    Failed(&'a dyn std::error::Error),
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+impl StreamRecord {
+    /// Log the record.
+    pub fn finish(self, outcome: &Outcome) {
+        let end = SystemTime::now();
+        let (up, down) = match outcome {
+            Outcome::Closed { up, down } => (*up, *down),
+            _ => (0, 0),
+        };
+        let result = match outcome {
+            Outcome::Closed { .. } => "closed".to_string(),
+            Outcome::Denied => "denied".to_string(),
+            Outcome::Failed(err) => format!("failed: {err}"),
+        };
+        let user = self.auditor.user.as_deref().unwrap_or("-");
+        let client = self
+            .auditor
+            .client
+            .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+        let duration = end.duration_since(self.start).unwrap_or_default();
+        info!(
+            user = %user,
+            peer = %client,
+            destination = format_args!("{}:{}", self.host, self.port),
+            duration = format_args!("{:.3}s", duration.as_secs_f64()),
+            bytes_up = up,
+            bytes_down = down,
+            result = %result,
+            "Stream finished"
+        );
+        if let Some(log) = &self.auditor.log {
+            log.write(&serde_json::json!({
+                "user": self.auditor.user,
+                "client": self.auditor.client,
+                "host": self.host,
+                "port": self.port,
+                "start": unix_time(self.start),
+                "end": unix_time(end),
+                "bytes_up": up,
+                "bytes_down": down,
+                "result": result,
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("audit.jsonl");
+        let log = AuditLog::open(path.to_str().unwrap()).unwrap();
+        let auditor = Auditor {
+            user: Some("alice".to_string()),
+            client: Some("192.0.2.1".parse().unwrap()),
+            log: Some(Arc::new(log)),
+        };
+        auditor
+            .start(b"example.com", 443)
+            .finish(&Outcome::Closed { up: 12, down: 34 });
+        auditor.start(b"example.org", 22).finish(&Outcome::Denied);
+        // Wait for the records to be written
+        drop(auditor);
+        let content = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["user"], "alice");
+        assert_eq!(records[0]["client"], "192.0.2.1");
+        assert_eq!(records[0]["host"], "example.com");
+        assert_eq!(records[0]["port"], 443);
+        assert_eq!(records[0]["bytes_up"], 12);
+        assert_eq!(records[0]["bytes_down"], 34);
+        assert_eq!(records[0]["result"], "closed");
+        assert!(records[0]["end"].as_f64() >= records[0]["start"].as_f64());
+        assert_eq!(records[1]["result"], "denied");
+    }
+}
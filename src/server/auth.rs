@@ -0,0 +1,442 @@
+//! Multi-user authentication database.
+//!
+//! The users file contains one user per line in the form
+//!
+//! ```text
+//! <user>:<secret> [up=<rate>] [down=<rate>] [tun=<cidr> ...] [<allowed-host>:<allowed-port>[/tcp|/udp] ...]
+//! ```
+//!
+//! where the client presents `<user>:<secret>` as its PSK. If no allowed
+//! destinations are listed, the user may connect anywhere. Hosts can be `*`
+//! or start with `*.` to match subdomains, and ports can be `*` or a range
+//! such as `1-1024`. A `/tcp` or `/udp` suffix restricts the destination
+//! to streams or datagrams only. `up` and `down` limit the bandwidth of all of the
+//! user's streams, datagrams, and IP packets in bytes per second, e.g.
+//! `up=1M`. Datagrams and packets over the limit are dropped. `tun` restricts the
+//! source addresses of the user's IP packets to a prefix, e.g.
+//! `tun=10.0.0.2/32`, and can be given multiple times. Empty lines and
+//! lines starting with `#` are ignored.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::token::PskToken;
+use crate::arg::ByteRate;
+use crate::challenge::{Challenge, ChallengeResponse};
+use crate::parse_remote::remove_brackets;
+use http::HeaderValue;
+use ipnet::IpNet;
+use penguin_mux::TokenBucket;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+/// Errors that can occur when loading the users file.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read users file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid users file entry on line {0}: {1}")]
+    Entry(usize, &'static str),
+    #[error("Duplicate user on line {0}: {1}")]
+    Duplicate(usize, String),
+}
+
+/// A pattern that matches destination hosts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum HostPattern {
+    /// Matches everything
+    Any,
+    /// Matches the domain itself and all of its subdomains
+    Suffix(String),
+    /// Matches exactly this host (case-insensitive)
+    Exact(String),
+}
+
+impl HostPattern {
+    fn matches(&self, host: &str) -> bool {
+        let host = remove_brackets(host);
+        match self {
+            Self::Any => true,
+            Self::Suffix(suffix) => {
+                host.eq_ignore_ascii_case(suffix)
+                    || (host.len() > suffix.len()
+                        && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+                        && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix))
+            }
+            Self::Exact(exact) => host.eq_ignore_ascii_case(exact),
+        }
+    }
+}
+
+/// Transport protocol of a forwarded connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proto {
+    /// Streams (`Syn`)
+    Tcp,
+    /// Datagrams
+    Udp,
+}
+
+impl std::fmt::Display for Proto {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp => f.write_str("tcp"),
+            Self::Udp => f.write_str("udp"),
+        }
+    }
+}
+
+/// A destination a user is allowed to connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Destination {
+    host: HostPattern,
+    ports: (u16, u16),
+    /// `None` means both protocols
+    proto: Option<Proto>,
+}
+
+impl Destination {
+    /// Check if the given host, port, and protocol match this destination.
+    pub fn matches(&self, host: &str, port: u16, proto: Proto) -> bool {
+        self.proto.is_none_or(|p| p == proto)
+            && (self.ports.0..=self.ports.1).contains(&port)
+            && self.host.matches(host)
+    }
+}
+
+impl FromStr for Destination {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, proto) = if let Some(s) = s.strip_suffix("/tcp") {
+            (s, Some(Proto::Tcp))
+        } else if let Some(s) = s.strip_suffix("/udp") {
+            (s, Some(Proto::Udp))
+        } else {
+            (s, None)
+        };
+        let (host, port) = s.rsplit_once(':').ok_or("missing port in destination")?;
+        let host = remove_brackets(host);
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some(suffix) = host.strip_prefix("*.") {
+            HostPattern::Suffix(suffix.to_string())
+        } else if host.is_empty() {
+            return Err("empty host in destination");
+        } else {
+            HostPattern::Exact(host.to_string())
+        };
+        let ports = if port == "*" {
+            (0, u16::MAX)
+        } else if let Some((start, end)) = port.split_once('-') {
+            let start = start.parse().map_err(|_| "invalid port in destination")?;
+            let end = end.parse().map_err(|_| "invalid port in destination")?;
+            if start > end {
+                return Err("invalid port range in destination");
+            }
+            (start, end)
+        } else {
+            let port = port.parse().map_err(|_| "invalid port in destination")?;
+            (port, port)
+        };
+        Ok(Self { host, ports, proto })
+    }
+}
+
+/// A user in the users file.
+pub struct User {
+    /// Name of the user
+    pub name: String,
+    /// The secret the user should present
+    secret: String,
+    /// Allowed destinations. Empty means unrestricted.
+    allowed: Vec<Destination>,
+    /// Limit on the bytes sent by the user's clients, shared by all streams
+    pub up_limit: Option<Arc<TokenBucket>>,
+    /// Limit on the bytes sent to the user's clients, shared by all streams
+    pub down_limit: Option<Arc<TokenBucket>>,
+    /// Prefixes the user's IP packets may come from. Empty means any
+    /// address of the TUN device's networks.
+    pub tun_prefixes: Vec<IpNet>,
+}
+
+impl std::fmt::Debug for User {
+    // Do not print the secret in logs
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("name", &self.name)
+            .field("allowed", &self.allowed)
+            .field("up_limit", &self.up_limit)
+            .field("down_limit", &self.down_limit)
+            .field("tun_prefixes", &self.tun_prefixes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl User {
+    /// Create a user that may connect anywhere and has no secret in the
+    /// users file (e.g. one authenticated by a bearer token).
+    pub fn unrestricted(name: String) -> Self {
+        Self {
+            name,
+            secret: String::new(),
+            allowed: Vec::new(),
+            up_limit: None,
+            down_limit: None,
+            tun_prefixes: Vec::new(),
+        }
+    }
+
+    /// Check if the user may connect anywhere, and so send IP packets.
+    pub fn is_unrestricted(&self) -> bool {
+        self.allowed.is_empty()
+    }
+
+    /// Check if the user may connect to the given host and port.
+    pub fn may_connect(&self, host: &str, port: u16, proto: Proto) -> bool {
+        self.allowed.is_empty() || self.allowed.iter().any(|d| d.matches(host, port, proto))
+    }
+}
+
+/// Users loaded from the users file.
+#[derive(Debug, Default)]
+pub struct UserDb {
+    users: HashMap<String, Arc<User>>,
+}
+
+impl UserDb {
+    /// Load the users file from the given path.
+    pub async fn load(path: &str) -> Result<Self, Error> {
+        let content = tokio::fs::read_to_string(path).await?;
+        content.parse()
+    }
+
+    /// Find the user with the given name.
+    pub fn get(&self, name: &str) -> Option<Arc<User>> {
+        self.users.get(name).map(Arc::clone)
+    }
+
+    /// Find the user matching the `user:secret` credential, if any.
+    pub fn authenticate(&self, credential: &HeaderValue) -> Option<Arc<User>> {
+        let (name, secret) = credential.to_str().ok()?.split_once(':')?;
+        self.users
+            .get(name)
+            // Constant-time so that the secret cannot be guessed by timing
+            .filter(|user| bool::from(user.secret.as_bytes().ct_eq(secret.as_bytes())))
+            .map(Arc::clone)
+    }
+
+    /// Find the user named by the hint in the challenge response and check
+    /// that the response was computed with its `user:secret` credential.
+    pub fn authenticate_challenge(
+        &self,
+        challenge: &Challenge,
+        response: &ChallengeResponse,
+    ) -> Option<Arc<User>> {
+        let name = std::str::from_utf8(&response.hint).ok()?;
+        let user = self.users.get(name)?;
+        let credential = format!("{}:{}", user.name, user.secret);
+        challenge
+            .verify(credential.as_bytes(), response)
+            .then(|| Arc::clone(user))
+    }
+
+    /// Find the user named in the token and check that the token was made
+    /// with its `user:secret` credential.
+    pub fn authenticate_token(&self, token: &PskToken) -> Option<Arc<User>> {
+        let user = self.users.get(token.user?)?;
+        let credential = format!("{}:{}", user.name, user.secret);
+        token
+            .verify(credential.as_bytes())
+            .then(|| Arc::clone(user))
+    }
+
+    /// Number of users in the database.
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+}
+
+impl FromStr for UserDb {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut users = HashMap::new();
+        for (lineno, line) in s.lines().enumerate() {
+            let lineno = lineno + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split_whitespace();
+            // `expect`: `line` is not empty after trimming
+            let credential = fields.next().expect("Empty line (this is a bug)");
+            let (name, secret) = credential
+                .split_once(':')
+                .ok_or(Error::Entry(lineno, "missing `:` between user and secret"))?;
+            if name.is_empty() {
+                return Err(Error::Entry(lineno, "empty user name"));
+            }
+            if secret.is_empty() {
+                return Err(Error::Entry(lineno, "empty secret"));
+            }
+            let mut allowed = Vec::new();
+            let mut up_limit = None;
+            let mut down_limit = None;
+            let mut tun_prefixes = Vec::new();
+            for field in fields {
+                let (limit, rate) = if let Some(prefix) = field.strip_prefix("tun=") {
+                    let prefix = prefix
+                        .parse::<IpNet>()
+                        .map_err(|_| Error::Entry(lineno, "invalid tun prefix"))?;
+                    tun_prefixes.push(prefix.trunc());
+                    continue;
+                } else if let Some(rate) = field.strip_prefix("up=") {
+                    (&mut up_limit, rate)
+                } else if let Some(rate) = field.strip_prefix("down=") {
+                    (&mut down_limit, rate)
+                } else {
+                    let dest = Destination::from_str(field).map_err(|e| Error::Entry(lineno, e))?;
+                    allowed.push(dest);
+                    continue;
+                };
+                let ByteRate(rate) = rate.parse().map_err(|e| Error::Entry(lineno, e))?;
+                *limit = Some(Arc::new(TokenBucket::new(rate)));
+            }
+            let user = User {
+                name: name.to_string(),
+                secret: secret.to_string(),
+                allowed,
+                up_limit,
+                down_limit,
+                tun_prefixes,
+            };
+            if users.insert(name.to_string(), Arc::new(user)).is_some() {
+                return Err(Error::Duplicate(lineno, name.to_string()));
+            }
+        }
+        Ok(Self { users })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_destination_matches() {
+        let dest = Destination::from_str("example.com:443").unwrap();
+        assert!(dest.matches("example.com", 443, Proto::Tcp));
+        assert!(dest.matches("EXAMPLE.com", 443, Proto::Tcp));
+        assert!(!dest.matches("example.com", 80, Proto::Tcp));
+        assert!(!dest.matches("www.example.com", 443, Proto::Tcp));
+        let dest = Destination::from_str("*.example.com:1-1024").unwrap();
+        assert!(dest.matches("example.com", 22, Proto::Tcp));
+        assert!(dest.matches("a.b.example.com", 1024, Proto::Tcp));
+        assert!(!dest.matches("badexample.com", 22, Proto::Tcp));
+        assert!(!dest.matches("example.com", 1025, Proto::Tcp));
+        let dest = Destination::from_str("[::1]:*").unwrap();
+        assert!(dest.matches("::1", 65535, Proto::Tcp));
+        assert!(dest.matches("[::1]", 1, Proto::Tcp));
+        let dest = Destination::from_str("*:53").unwrap();
+        assert!(dest.matches("1.1.1.1", 53, Proto::Tcp));
+        let dest = Destination::from_str("*:53/udp").unwrap();
+        assert!(dest.matches("1.1.1.1", 53, Proto::Udp));
+        assert!(!dest.matches("1.1.1.1", 53, Proto::Tcp));
+        let dest = Destination::from_str("[::1]:22/tcp").unwrap();
+        assert!(dest.matches("::1", 22, Proto::Tcp));
+        assert!(!dest.matches("::1", 22, Proto::Udp));
+        Destination::from_str("example.com:53/sctp").unwrap_err();
+        Destination::from_str("example.com").unwrap_err();
+        Destination::from_str(":80").unwrap_err();
+        Destination::from_str("example.com:1024-1").unwrap_err();
+        Destination::from_str("example.com:http").unwrap_err();
+    }
+
+    #[test]
+    fn test_userdb_parse_and_authenticate() {
+        let db = UserDb::from_str(
+            "# comment\n\
+             alice:s3cret\n\
+             \n\
+             bob:hunter2:with:colons example.com:443 *.internal:*\n",
+        )
+        .unwrap();
+        assert_eq!(db.len(), 2);
+        let alice = db
+            .authenticate(&HeaderValue::from_static("alice:s3cret"))
+            .unwrap();
+        assert_eq!(alice.name, "alice");
+        assert!(alice.may_connect("anything", 1, Proto::Tcp));
+        let bob = db
+            .authenticate(&HeaderValue::from_static("bob:hunter2:with:colons"))
+            .unwrap();
+        assert!(bob.may_connect("example.com", 443, Proto::Tcp));
+        assert!(bob.may_connect("db.internal", 5432, Proto::Tcp));
+        assert!(!bob.may_connect("example.com", 80, Proto::Tcp));
+        assert!(db
+            .authenticate(&HeaderValue::from_static("alice:wrong"))
+            .is_none());
+        assert!(db
+            .authenticate(&HeaderValue::from_static("carol:s3cret"))
+            .is_none());
+        assert!(db
+            .authenticate(&HeaderValue::from_static("alice"))
+            .is_none());
+    }
+
+    #[test]
+    fn test_userdb_parse_limits() {
+        let db = UserDb::from_str("alice:s3cret up=1M *:443 down=10M").unwrap();
+        let alice = db.get("alice").unwrap();
+        assert!(alice.up_limit.is_some());
+        assert!(alice.down_limit.is_some());
+        assert!(alice.may_connect("example.com", 443, Proto::Tcp));
+        assert!(!alice.may_connect("example.com", 80, Proto::Tcp));
+        assert!(matches!(
+            UserDb::from_str("alice:a up=fast"),
+            Err(Error::Entry(1, _))
+        ));
+    }
+
+    #[test]
+    fn test_userdb_parse_tun_prefixes() {
+        let db = UserDb::from_str("alice:a tun=10.0.0.2/32 tun=fd00::1:0/112").unwrap();
+        let alice = db.get("alice").unwrap();
+        assert_eq!(
+            alice.tun_prefixes,
+            vec![
+                "10.0.0.2/32".parse::<IpNet>().unwrap(),
+                "fd00::1:0/112".parse().unwrap()
+            ]
+        );
+        assert!(alice.is_unrestricted());
+        assert!(matches!(
+            UserDb::from_str("alice:a tun=10.0.0.2"),
+            Err(Error::Entry(1, _))
+        ));
+    }
+
+    #[test]
+    fn test_userdb_parse_errors() {
+        assert!(matches!(UserDb::from_str("alice"), Err(Error::Entry(1, _))));
+        assert!(matches!(
+            UserDb::from_str("\n:secret"),
+            Err(Error::Entry(2, _))
+        ));
+        assert!(matches!(
+            UserDb::from_str("alice:a\nbob:"),
+            Err(Error::Entry(2, "empty secret"))
+        ));
+        assert!(matches!(
+            UserDb::from_str("alice:a\nalice:b"),
+            Err(Error::Duplicate(2, _))
+        ));
+        assert!(matches!(
+            UserDb::from_str("alice:a example.com"),
+            Err(Error::Entry(1, _))
+        ));
+    }
+}
@@ -0,0 +1,244 @@
+//! Backends for normal HTTP requests.
+//!
+//! Backends configured for the same route form a group. If a group has more
+//! than one backend, they are health-checked periodically and requests go to
+//! the first healthy one.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::{BackendRoute, BackendUrl};
+use crate::config;
+use crate::tls::make_client_https;
+use http::{Request, Response, Uri};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+#[cfg(feature = "__rustls")]
+use hyper_rustls::HttpsConnector;
+#[cfg(feature = "nativetls")]
+use hyper_tls::HttpsConnector;
+#[cfg(unix)]
+use hyperlocal::UnixConnector;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Hyper clients for the different kinds of backends.
+#[derive(Clone, Debug)]
+pub(super) struct BackendClients {
+    /// HTTP/1.1 or HTTP/2 over TCP, with or without TLS
+    http: Client<HttpsConnector<HttpConnector>, Body>,
+    /// HTTP/2 with prior knowledge over TCP
+    h2c: Client<HttpConnector, Body>,
+    /// HTTP/1.1 over Unix domain sockets
+    #[cfg(unix)]
+    unix: Client<UnixConnector, Body>,
+}
+
+impl BackendClients {
+    pub fn new() -> Self {
+        Self {
+            http: Client::builder().build(make_client_https()),
+            h2c: Client::builder().http2_only(true).build_http(),
+            #[cfg(unix)]
+            unix: Client::builder().build(UnixConnector),
+        }
+    }
+
+    /// Send the request to `backend` with the right client.
+    pub async fn request(
+        &self,
+        backend: &BackendUrl,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        #[cfg(unix)]
+        if backend.unix_socket.is_some() {
+            return self.unix.request(req).await;
+        }
+        if backend.h2c {
+            self.h2c.request(req).await
+        } else {
+            self.http.request(req).await
+        }
+    }
+
+    /// Check if `backend` responds without a server error.
+    async fn check(&self, backend: &BackendUrl) -> bool {
+        // `expect`: `BackendUrl` is validated by clap.
+        let uri = Uri::builder()
+            .scheme(backend.scheme.clone())
+            .authority(backend.authority.clone())
+            .path_and_query(backend.path.clone())
+            .build()
+            .expect("Failed to build URI for backend (this is a bug)");
+        let req = Request::get(uri)
+            .body(Body::empty())
+            .expect("Failed to build health check request (this is a bug)");
+        match tokio::time::timeout(
+            config::BACKEND_HEALTH_CHECK_TIMEOUT,
+            self.request(backend, req),
+        )
+        .await
+        {
+            Ok(Ok(resp)) => !resp.status().is_server_error(),
+            _ => false,
+        }
+    }
+}
+
+/// A backend and whether it passed the last health check.
+#[derive(Debug)]
+struct Backend {
+    url: &'static BackendUrl,
+    healthy: AtomicBool,
+}
+
+impl Backend {
+    fn new(url: &'static BackendUrl) -> Self {
+        Self {
+            url,
+            healthy: AtomicBool::new(true),
+        }
+    }
+}
+
+/// All configured backends, grouped by route.
+#[derive(Debug, Default)]
+pub(super) struct BackendPool {
+    /// Backends for hosts without a route
+    default: Vec<Backend>,
+    /// Backends for specific host names
+    routes: Vec<(&'static str, Vec<Backend>)>,
+}
+
+impl BackendPool {
+    pub fn new(default: &'static [BackendUrl], routes: &'static [BackendRoute]) -> Self {
+        let mut grouped: Vec<(&'static str, Vec<Backend>)> = Vec::new();
+        for route in routes {
+            let backend = Backend::new(&route.backend);
+            if let Some((_, group)) = grouped.iter_mut().find(|(host, _)| route.matches(host)) {
+                group.push(backend);
+            } else {
+                grouped.push((&route.host, vec![backend]));
+            }
+        }
+        Self {
+            default: default.iter().map(Backend::new).collect(),
+            routes: grouped,
+        }
+    }
+
+    /// All groups of backends.
+    fn groups(&self) -> impl Iterator<Item = &[Backend]> {
+        std::iter::once(&self.default[..]).chain(self.routes.iter().map(|(_, group)| &group[..]))
+    }
+
+    /// Choose the first healthy backend for the given host name.
+    pub fn select(&self, host: Option<&str>) -> Option<&'static BackendUrl> {
+        let group = host
+            .and_then(|host| {
+                self.routes
+                    .iter()
+                    .find(|(route, _)| route.eq_ignore_ascii_case(host))
+            })
+            .map_or(&self.default, |(_, group)| group);
+        group
+            .iter()
+            .find(|backend| backend.healthy.load(Ordering::Relaxed))
+            .map(|backend| backend.url)
+    }
+
+    /// Take a backend out of rotation after a failed request until it
+    /// passes a health check again. Backends without alternatives are
+    /// never taken out.
+    pub fn report_failure(&self, url: &BackendUrl) {
+        for group in self.groups().filter(|group| group.len() > 1) {
+            for backend in group {
+                if std::ptr::eq(backend.url, url) && backend.healthy.swap(false, Ordering::Relaxed)
+                {
+                    warn!("Backend {} is down", backend.url);
+                }
+            }
+        }
+    }
+
+    /// Whether any route has more than one backend.
+    pub fn needs_health_check(&self) -> bool {
+        self.groups().any(|group| group.len() > 1)
+    }
+
+    /// Periodically health-check the backends in groups with alternatives.
+    pub async fn health_check(self: Arc<Self>, clients: Arc<BackendClients>) {
+        let mut interval = tokio::time::interval(config::BACKEND_HEALTH_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for group in self.groups().filter(|group| group.len() > 1) {
+                for backend in group {
+                    let healthy = clients.check(backend.url).await;
+                    let was_healthy = backend.healthy.swap(healthy, Ordering::Relaxed);
+                    if healthy && !was_healthy {
+                        info!("Backend {} is up", backend.url);
+                    } else if !healthy && was_healthy {
+                        warn!("Backend {} is down", backend.url);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use once_cell::sync::Lazy;
+
+    static DEFAULT: Lazy<Vec<BackendUrl>> = Lazy::new(|| {
+        vec![
+            "http://127.0.0.1:24373".parse().unwrap(),
+            "http://default2".parse().unwrap(),
+        ]
+    });
+    static ROUTES: Lazy<Vec<BackendRoute>> = Lazy::new(|| {
+        vec![
+            "blog.example.com=http://blog".parse().unwrap(),
+            "BLOG.example.com=http://blog2".parse().unwrap(),
+            "shop.example.com=http://shop".parse().unwrap(),
+        ]
+    });
+
+    #[test]
+    fn test_select_and_failover() {
+        let pool = BackendPool::new(&DEFAULT, &ROUTES);
+        assert!(pool.needs_health_check());
+        let selected = |host| pool.select(host).unwrap().to_string();
+        assert_eq!(selected(Some("Blog.Example.com")), "http://blog/");
+        assert_eq!(selected(Some("shop.example.com")), "http://shop/");
+        assert_eq!(selected(Some("example.com")), "http://127.0.0.1:24373/");
+        assert_eq!(selected(None), "http://127.0.0.1:24373/");
+        pool.report_failure(&ROUTES[0].backend);
+        assert_eq!(selected(Some("blog.example.com")), "http://blog2/");
+        // The only backend for a route stays
+        pool.report_failure(&ROUTES[2].backend);
+        assert_eq!(selected(Some("shop.example.com")), "http://shop/");
+        pool.report_failure(&ROUTES[1].backend);
+        assert!(pool.select(Some("blog.example.com")).is_none());
+        assert!(BackendPool::default().select(None).is_none());
+        assert!(!BackendPool::new(&DEFAULT[..1], &ROUTES[2..]).needs_health_check());
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        use hyper::service::{make_service_fn, service_fn};
+        use std::convert::Infallible;
+        let clients = BackendClients::new();
+        assert!(!clients.check(&DEFAULT[0]).await);
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|_| async {
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 24373).into()).serve(make_svc);
+        let server_task = tokio::spawn(server);
+        assert!(clients.check(&DEFAULT[0]).await);
+        server_task.abort();
+    }
+}
@@ -0,0 +1,124 @@
+//! Temporary bans for clients that repeatedly fail to authenticate.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Prune stale entries when the list grows beyond this many addresses.
+const PRUNE_THRESHOLD: usize = 1 << 12;
+
+/// Failed attempts from one address.
+#[derive(Debug)]
+struct Entry {
+    /// Failures since `since`
+    failures: u32,
+    /// Time of the first failure counted
+    since: Instant,
+    /// End of the ban, if banned
+    banned_until: Option<Instant>,
+}
+
+/// Addresses with failed authentication attempts.
+#[derive(Debug)]
+pub(super) struct BanList {
+    /// Number of failures within `duration` that leads to a ban
+    threshold: u32,
+    /// How long a ban lasts, and the window in which failures are counted
+    duration: Duration,
+    entries: Mutex<HashMap<IpAddr, Entry>>,
+}
+
+impl BanList {
+    pub fn new(threshold: u32, duration: Duration) -> Self {
+        Self {
+            threshold,
+            duration,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `ip` is currently banned.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .get(&ip)
+            .and_then(|entry| entry.banned_until)
+            .is_some_and(|until| until > now)
+    }
+
+    /// Count a failed attempt from `ip` and ban it if it reaches the threshold.
+    pub fn record_failure(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock();
+        if entries.len() >= PRUNE_THRESHOLD {
+            entries.retain(|_, entry| !self.is_stale(entry, now));
+        }
+        let entry = entries.entry(ip).or_insert(Entry {
+            failures: 0,
+            since: now,
+            banned_until: None,
+        });
+        if self.is_stale(entry, now) {
+            *entry = Entry {
+                failures: 0,
+                since: now,
+                banned_until: None,
+            };
+        }
+        entry.failures += 1;
+        if entry.failures >= self.threshold && entry.banned_until.is_none() {
+            warn!(
+                "Banning {ip} for {}s after {} failed attempts",
+                self.duration.as_secs(),
+                entry.failures
+            );
+            entry.banned_until = Some(now + self.duration);
+        }
+    }
+
+    /// Forget the failures of `ip` after it authenticates.
+    pub fn record_success(&self, ip: IpAddr) {
+        self.entries.lock().remove(&ip);
+    }
+
+    /// Whether an entry no longer counts: its ban is over, or its failures
+    /// are older than the window.
+    fn is_stale(&self, entry: &Entry, now: Instant) -> bool {
+        match entry.banned_until {
+            Some(until) => until <= now,
+            None => entry.since + self.duration <= now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ban_list() {
+        let bans = BanList::new(3, Duration::from_millis(100));
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        bans.record_failure(ip);
+        bans.record_failure(ip);
+        assert!(!bans.is_banned(ip));
+        bans.record_success(ip);
+        bans.record_failure(ip);
+        bans.record_failure(ip);
+        assert!(!bans.is_banned(ip));
+        bans.record_failure(ip);
+        assert!(bans.is_banned(ip));
+        assert!(!bans.is_banned(other));
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(!bans.is_banned(ip));
+        // Counting starts over after the ban
+        bans.record_failure(ip);
+        assert!(!bans.is_banned(ip));
+    }
+}
@@ -0,0 +1,148 @@
+//! Canned responses that mimic common web servers.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::Camouflage;
+use http::StatusCode;
+use std::borrow::Cow;
+
+const NGINX_404: &str = "<html>\r
+<head><title>404 Not Found</title></head>\r
+<body>\r
+<center><h1>404 Not Found</h1></center>\r
+<hr><center>nginx</center>\r
+</body>\r
+</html>\r
+";
+
+const APACHE_404: &str = "<!DOCTYPE HTML PUBLIC \"-//IETF//DTD HTML 2.0//EN\">
+<html><head>
+<title>404 Not Found</title>
+</head><body>
+<h1>Not Found</h1>
+<p>The requested URL was not found on this server.</p>
+</body></html>
+";
+
+const IIS_404: &str = "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">
+<html xmlns=\"http://www.w3.org/1999/xhtml\">
+<head>
+<meta http-equiv=\"Content-Type\" content=\"text/html; charset=iso-8859-1\"/>
+<title>404 - File or directory not found.</title>
+</head>
+<body>
+<div id=\"header\"><h1>Server Error</h1></div>
+<div id=\"content\">
+ <div class=\"content-container\"><fieldset>
+  <h2>404 - File or directory not found.</h2>
+  <h3>The resource you are looking for might have been removed, had its name changed, or is temporarily unavailable.</h3>
+ </fieldset></div>
+</div>
+</body>
+</html>
+";
+
+impl Camouflage {
+    /// Value of the `Server` header.
+    pub fn server_header(self) -> &'static str {
+        match self {
+            Self::Nginx => "nginx",
+            Self::Apache => "Apache",
+            Self::Iis => "Microsoft-IIS/10.0",
+        }
+    }
+
+    /// Body of the 404 page.
+    pub fn not_found_page(self) -> &'static str {
+        match self {
+            Self::Nginx => NGINX_404,
+            Self::Apache => APACHE_404,
+            Self::Iis => IIS_404,
+        }
+    }
+
+    /// Body of the error page for `status`.
+    pub fn error_page(self, status: StatusCode) -> Cow<'static, str> {
+        if status == StatusCode::NOT_FOUND {
+            return Cow::Borrowed(self.not_found_page());
+        }
+        let code = status.as_u16();
+        let reason = status.canonical_reason().unwrap_or("Error");
+        Cow::Owned(match self {
+            Self::Nginx => {
+                // nginx has its own name for 405
+                let reason = if status == StatusCode::METHOD_NOT_ALLOWED {
+                    "Not Allowed"
+                } else {
+                    reason
+                };
+                format!(
+                    "<html>\r\n<head><title>{code} {reason}</title></head>\r\n<body>\r\n\
+                     <center><h1>{code} {reason}</h1></center>\r\n\
+                     <hr><center>nginx</center>\r\n</body>\r\n</html>\r\n"
+                )
+            }
+            Self::Apache => {
+                let message = match status {
+                    StatusCode::BAD_REQUEST => {
+                        "Your browser sent a request that this server could not understand."
+                    }
+                    StatusCode::METHOD_NOT_ALLOWED => {
+                        "The requested method is not allowed for this URL."
+                    }
+                    StatusCode::TOO_MANY_REQUESTS => {
+                        "The user has sent too many requests in a given amount of time."
+                    }
+                    _ => "The server could not complete your request.",
+                };
+                format!(
+                    "<!DOCTYPE HTML PUBLIC \"-//IETF//DTD HTML 2.0//EN\">\n<html><head>\n\
+                     <title>{code} {reason}</title>\n</head><body>\n<h1>{reason}</h1>\n\
+                     <p>{message}</p>\n</body></html>\n"
+                )
+            }
+            Self::Iis => {
+                let (title, message) = match status {
+                    StatusCode::METHOD_NOT_ALLOWED => (
+                        "HTTP verb used to access this page is not allowed.",
+                        "The page you are looking for cannot be displayed because an invalid method (HTTP verb) was used to attempt access.",
+                    ),
+                    _ => (reason, "There is a problem with the resource you are looking for, and it cannot be displayed."),
+                };
+                format!(
+                    "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">\n\
+                     <html xmlns=\"http://www.w3.org/1999/xhtml\">\n<head>\n\
+                     <meta http-equiv=\"Content-Type\" content=\"text/html; charset=iso-8859-1\"/>\n\
+                     <title>{code} - {title}</title>\n</head>\n<body>\n\
+                     <div id=\"header\"><h1>Server Error</h1></div>\n<div id=\"content\">\n \
+                     <div class=\"content-container\"><fieldset>\n  <h2>{code} - {title}</h2>\n  \
+                     <h3>{message}</h3>\n </fieldset></div>\n</div>\n</body>\n</html>\n"
+                )
+            }
+        })
+    }
+
+    /// `Content-Type` of the error pages.
+    pub fn error_content_type(self) -> &'static str {
+        match self {
+            Self::Nginx | Self::Iis => "text/html",
+            Self::Apache => "text/html; charset=iso-8859-1",
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_page() {
+        let page = Camouflage::Nginx.error_page(StatusCode::TOO_MANY_REQUESTS);
+        assert!(page.contains("<center><h1>429 Too Many Requests</h1></center>"));
+        assert!(page.ends_with("<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n"));
+        let page = Camouflage::Apache.error_page(StatusCode::METHOD_NOT_ALLOWED);
+        assert!(page.contains("<title>405 Method Not Allowed</title>"));
+        let page = Camouflage::Iis.error_page(StatusCode::NOT_FOUND);
+        assert_eq!(page, IIS_404);
+    }
+}
@@ -0,0 +1,108 @@
+//! Limits on the number of simultaneous WebSocket tunnels.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// Open tunnels in total and per client address
+#[derive(Debug, Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts open tunnels and refuses new ones beyond the limits.
+#[derive(Debug)]
+pub(super) struct ConnLimiter {
+    /// Maximum tunnels per client address
+    per_ip: Option<usize>,
+    /// Maximum tunnels in total
+    total: Option<usize>,
+    counts: Mutex<Counts>,
+}
+
+impl ConnLimiter {
+    pub fn new(per_ip: Option<usize>, total: Option<usize>) -> Self {
+        Self {
+            per_ip,
+            total,
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+
+    /// Count a new tunnel from `ip` (`None` if unknown, which is only
+    /// subject to the total limit). Returns `None` if a limit is reached.
+    /// The tunnel is counted until the returned guard is dropped.
+    pub fn acquire(self: &Arc<Self>, ip: Option<IpAddr>) -> Option<ConnGuard> {
+        let mut counts = self.counts.lock();
+        if self.total.is_some_and(|total| counts.total >= total) {
+            return None;
+        }
+        if let Some(ip) = ip {
+            let count = counts.per_ip.get(&ip).copied().unwrap_or_default();
+            if self.per_ip.is_some_and(|per_ip| count >= per_ip) {
+                return None;
+            }
+            counts.per_ip.insert(ip, count + 1);
+        }
+        counts.total += 1;
+        Some(ConnGuard {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+/// An open tunnel counted by a [`ConnLimiter`].
+#[derive(Debug)]
+pub(super) struct ConnGuard {
+    limiter: Arc<ConnLimiter>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let mut counts = self.limiter.counts.lock();
+        counts.total -= 1;
+        if let Some(ip) = self.ip {
+            // `expect`: `acquire` counted this address
+            let count = counts
+                .per_ip
+                .get_mut(&ip)
+                .expect("Tunnel not counted (this is a bug)");
+            *count -= 1;
+            if *count == 0 {
+                counts.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conn_limiter() {
+        let limiter = Arc::new(ConnLimiter::new(Some(2), Some(3)));
+        let a: IpAddr = "192.0.2.1".parse().unwrap();
+        let b: IpAddr = "192.0.2.2".parse().unwrap();
+        let a1 = limiter.acquire(Some(a)).unwrap();
+        let a2 = limiter.acquire(Some(a)).unwrap();
+        assert!(limiter.acquire(Some(a)).is_none());
+        let b1 = limiter.acquire(Some(b)).unwrap();
+        // The total limit applies to everyone
+        assert!(limiter.acquire(Some(b)).is_none());
+        assert!(limiter.acquire(None).is_none());
+        drop(a1);
+        assert!(limiter.acquire(Some(a)).is_some());
+        let unknown = limiter.acquire(None).unwrap();
+        drop((a2, b1, unknown));
+        let counts = limiter.counts.lock();
+        assert_eq!(counts.total, 0);
+        assert!(counts.per_ip.is_empty());
+    }
+}
@@ -0,0 +1,112 @@
+//! CORS for the `/ws`, `/health`, and `/version` endpoints.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode};
+use hyper::Body;
+
+/// CORS configuration from `--cors-origin` and `--cors-allow-headers`.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct Cors<'a> {
+    /// Allowed origins. `*` allows any origin. Empty disables CORS.
+    pub origins: &'a [HeaderValue],
+    /// Value of `Access-Control-Allow-Headers`. Reflects the requested
+    /// headers if not set.
+    pub allow_headers: Option<&'a HeaderValue>,
+}
+
+impl Cors<'_> {
+    /// Whether CORS is configured.
+    pub fn is_enabled(&self) -> bool {
+        !self.origins.is_empty()
+    }
+
+    /// The request's `Origin` if it is allowed.
+    pub fn allowed_origin(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+        let origin = headers.get(header::ORIGIN)?;
+        self.origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+            .then(|| origin.clone())
+    }
+
+    /// Whether a WebSocket upgrade may proceed: browsers send their origin
+    /// but do not enforce CORS on WebSockets, so we have to.
+    pub fn allows_upgrade(&self, headers: &HeaderMap) -> bool {
+        !self.is_enabled()
+            || !headers.contains_key(header::ORIGIN)
+            || self.allowed_origin(headers).is_some()
+    }
+
+    /// Respond to a preflight request.
+    pub fn preflight(&self, req: &Request<Body>) -> Response<Body> {
+        let mut resp = Response::builder().status(StatusCode::NO_CONTENT);
+        if let Some(origin) = self.allowed_origin(req.headers()) {
+            resp = resp
+                .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                .header(header::ACCESS_CONTROL_ALLOW_METHODS, "GET, OPTIONS")
+                .header(header::ACCESS_CONTROL_MAX_AGE, "86400");
+            let allow_headers = self
+                .allow_headers
+                .or_else(|| req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS));
+            if let Some(allow_headers) = allow_headers {
+                resp = resp.header(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+            }
+        }
+        resp.header(header::VARY, "Origin")
+            .body(Body::empty())
+            .expect("Failed to build preflight response (this is a bug)")
+    }
+
+    /// Add `Access-Control-Allow-Origin` to a response if the origin is allowed.
+    pub fn add_headers(&self, origin: Option<HeaderValue>, headers: &mut HeaderMap) {
+        if let Some(origin) = origin {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cors() {
+        let origins = [HeaderValue::from_static("https://app.example.com")];
+        let cors = Cors {
+            origins: &origins,
+            allow_headers: None,
+        };
+        let req = Request::options("/health")
+            .header(header::ORIGIN, "https://app.example.com")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "x-custom")
+            .body(Body::empty())
+            .unwrap();
+        let resp = cors.preflight(&req);
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            resp.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "x-custom"
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ORIGIN, "https://evil.example".parse().unwrap());
+        assert!(cors.allowed_origin(&headers).is_none());
+        assert!(!cors.allows_upgrade(&headers));
+        assert!(cors.allows_upgrade(&HeaderMap::new()));
+        assert!(Cors::default().allows_upgrade(&headers));
+        let any = [HeaderValue::from_static("*")];
+        let cors = Cors {
+            origins: &any,
+            allow_headers: None,
+        };
+        assert_eq!(
+            cors.allowed_origin(&headers).unwrap(),
+            "https://evil.example"
+        );
+    }
+}
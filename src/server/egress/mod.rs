@@ -0,0 +1,242 @@
+//! How the server reaches the destinations of the clients: which of them it
+//! may connect to, which resolver it asks, which source address and
+//! interface its connections use, and which proxy dials them.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+mod proxy;
+
+use super::happy_eyeballs;
+use crate::arg::{EgressRule, ProxyUrl, ServerArgs};
+use crate::resolver::Resolver;
+use crate::sockopt::{self, BufferSizes};
+use crate::Dupe;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::{lookup_host, TcpSocket, TcpStream, UdpSocket};
+
+/// Settings of the outbound connections of the forwarder
+#[derive(Clone, Debug, Default)]
+pub struct Egress {
+    /// Resolver of the destinations of streams, if not the system's
+    pub resolver: Option<Arc<Resolver>>,
+    /// Source addresses. Connections use the first one of their family.
+    pub bind: &'static [IpAddr],
+    /// Interface (or VRF) that connections are bound to
+    pub interface: Option<&'static str>,
+    /// Upstream proxy for TCP connections
+    pub proxy: Option<&'static ProxyUrl>,
+    /// The destinations that connections may go to. Empty allows all.
+    pub allow: &'static [EgressRule],
+    /// DSCP value that connections mark their packets with
+    pub dscp: Option<u8>,
+    /// Buffer sizes of TCP connections
+    pub buffers: BufferSizes,
+}
+
+impl Dupe for Egress {
+    fn dupe(&self) -> Self {
+        Self {
+            resolver: self.resolver.clone(),
+            bind: self.bind,
+            interface: self.interface,
+            proxy: self.proxy,
+            allow: self.allow,
+            dscp: self.dscp,
+            buffers: self.buffers,
+        }
+    }
+}
+
+impl Egress {
+    /// The egress configured by `args`
+    pub fn new(args: &'static ServerArgs) -> Self {
+        Self {
+            resolver: args
+                .resolver
+                .as_ref()
+                .map(|url| Arc::new(Resolver::new(url))),
+            bind: &args.egress_bind,
+            interface: args.egress_interface.as_deref(),
+            proxy: args.egress_proxy.as_ref(),
+            allow: &args.egress_allow,
+            dscp: args.egress_dscp,
+            buffers: args.buffer_sizes(),
+        }
+    }
+
+    /// Whether connections may go to `destination`.
+    pub fn allows(&self, destination: SocketAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(destination))
+    }
+
+    /// The ones of `addresses` that connections may go to, or an error if
+    /// there are none.
+    pub fn allowed(&self, mut addresses: Vec<SocketAddr>) -> io::Result<Vec<SocketAddr>> {
+        if self.allow.is_empty() {
+            return Ok(addresses);
+        }
+        addresses.retain(|address| self.allows(*address));
+        if addresses.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "destination not allowed by --egress-allow",
+            ));
+        }
+        Ok(addresses)
+    }
+
+    /// The source address for destinations in the family of `like`.
+    fn source(&self, like: IpAddr) -> IpAddr {
+        let configured = self
+            .bind
+            .iter()
+            .find(|source| source.is_ipv4() == like.is_ipv4());
+        match (configured, like) {
+            (Some(source), _) => *source,
+            (None, IpAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            (None, IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    }
+
+    /// Open a TCP connection to `host` and `port`, through the proxy if
+    /// there is one.
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let Some(proxy) = self.proxy else {
+            let addresses = self.allowed(self.resolve(host, port).await?)?;
+            return happy_eyeballs::connect(addresses, self).await;
+        };
+        // The proxy resolves `host`, unless we have to check it
+        let destination = if self.allow.is_empty() {
+            host.to_string()
+        } else {
+            let addresses = self.allowed(self.resolve(host, port).await?)?;
+            addresses[0].ip().to_string()
+        };
+        let addresses = self.resolve(&proxy.host, proxy.port).await?;
+        let mut stream = happy_eyeballs::connect(addresses, self).await?;
+        proxy::handshake(&mut stream, proxy, &destination, port).await?;
+        Ok(stream)
+    }
+
+    /// Resolve `host` to its addresses.
+    async fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        match &self.resolver {
+            Some(resolver) => Ok(resolver
+                .resolve_all(host)
+                .await?
+                .into_iter()
+                .map(|address| SocketAddr::new(address, port))
+                .collect()),
+            None => Ok(lookup_host((host, port)).await?.collect()),
+        }
+    }
+
+    /// Open a TCP connection to `destination`.
+    pub async fn connect_tcp(&self, destination: SocketAddr) -> io::Result<TcpStream> {
+        let socket = if destination.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        let source = self.source(destination.ip());
+        if !source.is_unspecified() {
+            socket.bind(SocketAddr::new(source, 0))?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(dscp) = self.dscp {
+            sockopt::set_dscp(&socket, dscp)?;
+        }
+        self.buffers.set(&socket)?;
+        socket.connect(destination).await
+    }
+
+    /// Bind a UDP socket for destinations in the family of `like`.
+    pub async fn bind_udp(&self, like: IpAddr) -> io::Result<UdpSocket> {
+        let socket = UdpSocket::bind((self.source(like), 0)).await?;
+        self.configure_udp(&socket)?;
+        Ok(socket)
+    }
+
+    /// Bind `socket` to the interface, if there is one, and set its DSCP.
+    pub fn configure_udp(&self, socket: &UdpSocket) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = self.interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        if let Some(dscp) = self.dscp {
+            sockopt::set_dscp(socket, dscp)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use once_cell::sync::Lazy;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_source() {
+        static BIND: [IpAddr; 2] = [
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)),
+        ];
+        let egress = Egress {
+            bind: &BIND,
+            ..Egress::default()
+        };
+        assert_eq!(egress.source([192, 0, 2, 1].into()), BIND[1]);
+        assert_eq!(egress.source(Ipv6Addr::LOCALHOST.into()), BIND[0]);
+        let egress = Egress::default();
+        assert!(egress.source([192, 0, 2, 1].into()).is_unspecified());
+    }
+
+    #[tokio::test]
+    async fn test_allow() {
+        static ALLOW: Lazy<Vec<EgressRule>> =
+            Lazy::new(|| vec!["127.0.0.0/8:1-1023".parse().unwrap()]);
+        let egress = Egress {
+            allow: &ALLOW,
+            ..Egress::default()
+        };
+        let allowed = SocketAddr::from(([127, 0, 0, 1], 80));
+        let denied = SocketAddr::from(([127, 0, 0, 1], 8080));
+        assert_eq!(egress.allowed(vec![denied, allowed]).unwrap(), [allowed]);
+        let err = egress.allowed(vec![denied]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        // Checked before dialing
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let err = egress.connect("127.0.0.1", port).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(Egress::default().allows(denied));
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp_from_source() {
+        static BIND: [IpAddr; 1] = [IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2))];
+        let egress = Egress {
+            bind: &BIND,
+            ..Egress::default()
+        };
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let stream = egress
+            .connect_tcp(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+        assert_eq!(peer.ip(), BIND[0]);
+        let socket = egress
+            .bind_udp(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .await
+            .unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), BIND[0]);
+    }
+}
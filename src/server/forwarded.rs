@@ -0,0 +1,242 @@
+//! `Forwarded` and `X-Forwarded-*` headers.
+//!
+//! Requests proxied to the backend carry the client's address in both the
+//! standard `Forwarded` header (RFC 7239) and the de-facto `X-Forwarded-*`
+//! headers. Existing headers are only kept and trusted if the request comes
+//! from one of the `--trusted-proxy` addresses.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+use std::net::{IpAddr, SocketAddr};
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+
+/// Parse the address in a `Forwarded` `for=` parameter or an
+/// `X-Forwarded-For` entry, ignoring the port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // `[v6]` without a port
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+/// Addresses in the forwarding headers, from the original client to the
+/// last proxy. `None` for obfuscated or unknown addresses.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<_> = headers
+        .get_all(header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value))
+            })?
+        })
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+    headers
+        .get_all(&X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Find the real client address. If `peer` is a trusted proxy, this is the
+/// last address in the forwarding headers that is not a trusted proxy.
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+    walk_chain(Some(peer), headers, trusted).unwrap_or(peer)
+}
+
+/// Like [`client_ip`], but for a peer without an address (a proxy on a
+/// Unix socket), which is always trusted. `None` if the headers do not
+/// tell.
+pub fn proxied_client_ip(headers: &HeaderMap, trusted: &[IpAddr]) -> Option<IpAddr> {
+    walk_chain(None, headers, trusted)
+}
+
+/// Walk the forwarding headers back from a trusted `peer` to the first
+/// address that is not a trusted proxy.
+fn walk_chain(peer: Option<IpAddr>, headers: &HeaderMap, trusted: &[IpAddr]) -> Option<IpAddr> {
+    let mut client = peer;
+    for node in forwarded_chain(headers).into_iter().rev() {
+        match node {
+            Some(ip) if trusted.contains(&ip) => client = Some(ip),
+            Some(ip) => return Some(ip),
+            None => return client,
+        }
+    }
+    client
+}
+
+/// Format an address as a `Forwarded` node.
+fn format_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{ip}]\""),
+    }
+}
+
+/// Format a value as an RFC 7230 `quoted-string`.
+fn quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Append the forwarding headers for a request from `peer` before
+/// proxying it. Existing headers are removed unless `peer` is trusted.
+pub fn add_forwarded_headers(
+    headers: &mut HeaderMap,
+    peer: IpAddr,
+    proto: &'static str,
+    trusted: &[IpAddr],
+) {
+    if !trusted.contains(&peer) {
+        headers.remove(header::FORWARDED);
+        headers.remove(&X_FORWARDED_FOR);
+        headers.remove(&X_FORWARDED_HOST);
+        headers.remove(&X_FORWARDED_PROTO);
+    }
+    let host = headers
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .map(ToString::to_string);
+    let mut forwarded = format!("for={};proto={proto}", format_node(peer));
+    if let Some(host) = &host {
+        forwarded.push_str(&format!(";host={}", quote(host)));
+    }
+    // `expect`: all parts are valid header values
+    headers.append(
+        header::FORWARDED,
+        forwarded
+            .parse()
+            .expect("Invalid `Forwarded` header (this is a bug)"),
+    );
+    let xff = match headers.get(&X_FORWARDED_FOR).map(HeaderValue::to_str) {
+        Some(Ok(existing)) => format!("{existing}, {peer}"),
+        _ => peer.to_string(),
+    };
+    headers.insert(
+        &X_FORWARDED_FOR,
+        xff.parse()
+            .expect("Invalid `X-Forwarded-For` header (this is a bug)"),
+    );
+    if !headers.contains_key(&X_FORWARDED_PROTO) {
+        headers.insert(&X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    }
+    if let Some(host) = host {
+        if !headers.contains_key(&X_FORWARDED_HOST) {
+            headers.insert(
+                &X_FORWARDED_HOST,
+                host.parse()
+                    .expect("Invalid `X-Forwarded-Host` header (this is a bug)"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PROXY: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn test_client_ip() {
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(&X_FORWARDED_FOR, "203.0.113.7".parse().unwrap());
+        // Not trusted
+        assert_eq!(client_ip(peer, &headers, &[PROXY]), peer);
+        // Trusted
+        assert_eq!(
+            client_ip(PROXY, &headers, &[PROXY]),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        // The client cannot spoof its address by adding entries
+        headers.insert(&X_FORWARDED_FOR, "1.1.1.1, 203.0.113.7".parse().unwrap());
+        assert_eq!(
+            client_ip(PROXY, &headers, &[PROXY]),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        // `Forwarded` takes precedence
+        headers.insert(
+            header::FORWARDED,
+            "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.1"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            client_ip(PROXY, &headers, &[PROXY]),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+        headers.insert(header::FORWARDED, "for=_hidden".parse().unwrap());
+        assert_eq!(client_ip(PROXY, &headers, &[PROXY]), PROXY);
+        assert_eq!(proxied_client_ip(&headers, &[PROXY]), None);
+        headers.insert(header::FORWARDED, "for=203.0.113.7".parse().unwrap());
+        assert_eq!(
+            proxied_client_ip(&headers, &[]),
+            Some("203.0.113.7".parse::<IpAddr>().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_add_forwarded_headers() {
+        let peer: IpAddr = "2001:db8::2".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "example.com".parse().unwrap());
+        headers.insert(&X_FORWARDED_FOR, "1.1.1.1".parse().unwrap());
+        add_forwarded_headers(&mut headers, peer, "https", &[PROXY]);
+        assert_eq!(
+            headers[header::FORWARDED],
+            "for=\"[2001:db8::2]\";proto=https;host=\"example.com\""
+        );
+        assert_eq!(headers[&X_FORWARDED_FOR], "2001:db8::2");
+        assert_eq!(headers[&X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[&X_FORWARDED_HOST], "example.com");
+        // Trusted proxy: keep and append
+        let mut headers = HeaderMap::new();
+        headers.insert(header::FORWARDED, "for=1.1.1.1".parse().unwrap());
+        headers.insert(&X_FORWARDED_FOR, "1.1.1.1".parse().unwrap());
+        headers.insert(&X_FORWARDED_PROTO, "https".parse().unwrap());
+        add_forwarded_headers(&mut headers, PROXY, "http", &[PROXY]);
+        let forwarded: Vec<_> = headers.get_all(header::FORWARDED).iter().collect();
+        assert_eq!(forwarded, ["for=1.1.1.1", "for=10.0.0.1;proto=http"]);
+        assert_eq!(headers[&X_FORWARDED_FOR], "1.1.1.1, 10.0.0.1");
+        assert_eq!(headers[&X_FORWARDED_PROTO], "https");
+        // The host cannot break out of its quoted-string
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "a\";for=1.1.1.1;x=\"\\".parse().unwrap());
+        add_forwarded_headers(&mut headers, PROXY, "http", &[]);
+        assert_eq!(
+            headers[header::FORWARDED],
+            r#"for=10.0.0.1;proto=http;host="a\";for=1.1.1.1;x=\"\\""#
+        );
+    }
+}
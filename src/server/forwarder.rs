@@ -3,17 +3,18 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use crate::{config, Dupe};
+use super::egress::Egress;
+use crate::{config, sockopt, Dupe};
 use bytes::Bytes;
-use penguin_mux::DatagramFrame;
-use std::net::SocketAddr;
+use penguin_mux::{DatagramFrame, RstReason};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
 use thiserror::Error;
-use tokio::net::TcpStream;
 use tokio::{
-    net::{lookup_host, UdpSocket},
-    sync::mpsc::Sender,
+    net::{lookup_host, TcpStream, UdpSocket},
+    sync::mpsc::{Receiver, Sender},
 };
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 /// Error type for the forwarder.
 #[derive(Error, Debug)]
@@ -25,18 +26,19 @@ pub(super) enum Error {
 }
 
 /// Bind a UDP socket with the same address family as the given target,
-/// connect to the target, and send the given data.
+/// connect to the target unless `full_cone`, and send the given data.
 /// Finally, return the bound socket and the target address.
 #[inline]
-async fn bind_and_send(target: (&str, u16), data: &[u8]) -> Result<(UdpSocket, SocketAddr), Error> {
-    let targets = lookup_host(target).await?;
+async fn bind_and_send(
+    target: (&str, u16),
+    data: &[u8],
+    full_cone: bool,
+    egress: &Egress,
+) -> Result<(UdpSocket, SocketAddr), Error> {
+    let targets = egress.allowed(lookup_host(target).await?.collect())?;
     let mut last_err = None;
     for target in targets {
-        let socket = match if target.is_ipv4() {
-            UdpSocket::bind(("0.0.0.0", 0)).await
-        } else {
-            UdpSocket::bind(("::", 0)).await
-        } {
+        let socket = match egress.bind_udp(target.ip()).await {
             Ok(socket) => socket,
             Err(e) => {
                 last_err = Some(e);
@@ -48,6 +50,10 @@ async fn bind_and_send(target: (&str, u16), data: &[u8]) -> Result<(UdpSocket, S
             .local_addr()
             .expect("Failed to get local address of UDP socket (this is a bug)");
         debug!("bound to {local_addr}");
+        if full_cone {
+            socket.send_to(data, target).await?;
+            return Ok((socket, target));
+        }
         if let Err(e) = socket.connect(target).await {
             last_err = Some(e);
             continue;
@@ -65,12 +71,99 @@ async fn bind_and_send(target: (&str, u16), data: &[u8]) -> Result<(UdpSocket, S
         .into())
 }
 
-/// Send a UDP datagram to the given host and port and wait for a response
-/// in the following `UDP_PRUNE_TIMEOUT` seconds.
-#[tracing::instrument(skip(datagram_tx), level = "debug")]
+/// The multicast group or broadcast address `host` is, if any
+fn group_address(host: &str) -> Option<IpAddr> {
+    let ip = host.parse::<IpAddr>().ok()?;
+    let is_broadcast = matches!(ip, IpAddr::V4(ip) if ip.is_broadcast());
+    (ip.is_multicast() || is_broadcast).then_some(ip)
+}
+
+/// Bind a UDP socket that can send to `group`, join it if it is a
+/// multicast group, and send the given data. The socket gets the group's
+/// port, so that it also receives what others send to the group, unless
+/// it is taken.
+async fn bind_group_and_send(
+    group: IpAddr,
+    port: u16,
+    data: &[u8],
+    egress: &Egress,
+) -> Result<(UdpSocket, SocketAddr), Error> {
+    let target = SocketAddr::new(group, port);
+    egress.allowed(vec![target])?;
+    let unspecified = match group {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = if group.is_multicast() {
+        let group_port = SocketAddr::new(unspecified, port);
+        #[cfg(unix)]
+        let socket = super::reuseport::bind_udp(&group_port);
+        #[cfg(not(unix))]
+        let socket = UdpSocket::bind(group_port).await;
+        match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                debug!("cannot bind to the group's port: {e}");
+                UdpSocket::bind((unspecified, 0)).await?
+            }
+        }
+    } else {
+        UdpSocket::bind((unspecified, 0)).await?
+    };
+    egress.configure_udp(&socket)?;
+    match group {
+        IpAddr::V4(group) if group.is_multicast() => {
+            socket.join_multicast_v4(group, Ipv4Addr::UNSPECIFIED)?;
+            // Not our own datagrams
+            socket.set_multicast_loop_v4(false)?;
+        }
+        IpAddr::V6(group) => {
+            socket.join_multicast_v6(&group, 0)?;
+            socket.set_multicast_loop_v6(false)?;
+        }
+        IpAddr::V4(_) => socket.set_broadcast(true)?,
+    }
+    socket.send_to(data, target).await?;
+    Ok((socket, target))
+}
+
+/// Resolve `host` and `port` to an address in the family of `like`.
+async fn resolve_like(host: &[u8], port: u16, like: SocketAddr) -> Result<SocketAddr, Error> {
+    lookup_host((std::str::from_utf8(host)?, port))
+        .await?
+        .find(|addr| addr.is_ipv4() == like.is_ipv4())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "could not resolve to an address of the session's family",
+            )
+            .into()
+        })
+}
+
+/// Send a UDP datagram to the given host and port, then keep sending the
+/// next ones from `data_rx` on the same socket and the responses to
+/// `datagram_tx`, until there are none in either direction for
+/// `idle_timeout` or `data_rx` is closed.
+///
+/// Without `full_cone`, the socket is connected to the first destination,
+/// so only its responses come back and later datagrams go there too.
+/// With `full_cone`, later datagrams go to their own destinations, and
+/// datagrams from anyone reach the client, marked with their source.
+///
+/// With `multicast`, a session that starts with a datagram to a multicast
+/// group or broadcast address is always full-cone, since the answers come
+/// from the members of the group.
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(data_rx, datagram_tx, egress), level = "debug")]
 pub(super) async fn udp_forward_to(
     datagram_frame: DatagramFrame,
+    mut data_rx: Receiver<DatagramFrame>,
     datagram_tx: Sender<DatagramFrame>,
+    idle_timeout: Duration,
+    full_cone: bool,
+    multicast: bool,
+    egress: Egress,
 ) -> Result<(), Error> {
     trace!("got datagram frame: {datagram_frame:?}");
     let rhost = datagram_frame.host;
@@ -78,33 +171,62 @@ pub(super) async fn udp_forward_to(
     let rport = datagram_frame.port;
     let data = datagram_frame.data;
     let client_id = datagram_frame.sid;
-    let (socket, target) = bind_and_send((rhost_str, rport), &data).await?;
+    let group = group_address(rhost_str).filter(|_| multicast);
+    let full_cone = full_cone || group.is_some();
+    let (socket, target) = match group {
+        Some(group) => bind_group_and_send(group, rport, &data, &egress).await?,
+        None => bind_and_send((rhost_str, rport), &data, full_cone, &egress).await?,
+    };
     trace!("sent UDP packet to {target}");
+    let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
     loop {
-        let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
-        match tokio::time::timeout(config::UDP_PRUNE_TIMEOUT, socket.recv(&mut buf)).await {
-            Ok(Ok(len)) => {
-                trace!("got UDP response from {target}");
-                buf.truncate(len);
+        tokio::select! {
+            result = socket.recv_from(&mut buf) => {
+                let (len, source) = result?;
+                trace!("got UDP response from {source}");
+                let (host, port) = if full_cone {
+                    (Bytes::from(source.ip().to_string()), source.port())
+                } else {
+                    (rhost.dupe(), rport)
+                };
                 let datagram_frame = DatagramFrame {
                     sid: client_id,
-                    host: rhost.dupe(),
-                    port: rport,
-                    data: Bytes::from(buf),
+                    host,
+                    port,
+                    data: Bytes::copy_from_slice(&buf[..len]),
                 };
                 if datagram_tx.send(datagram_frame).await.is_err() {
                     // The main loop has exited, so we should exit too.
                     break;
                 }
             }
-            Ok(Err(e)) => {
-                return Err(e.into());
+            datagram_frame = data_rx.recv() => {
+                let Some(datagram_frame) = datagram_frame else {
+                    trace!("UDP session closed");
+                    break;
+                };
+                if !full_cone {
+                    socket.send(&datagram_frame.data).await?;
+                    trace!("sent UDP packet to {target}");
+                    continue;
+                }
+                match resolve_like(&datagram_frame.host, datagram_frame.port, target).await {
+                    Ok(dest) if !egress.allows(dest) => {
+                        warn!("dropped datagram to {dest}: not allowed by --egress-allow");
+                    }
+                    Ok(dest) => {
+                        socket.send_to(&datagram_frame.data, dest).await?;
+                        trace!("sent UDP packet to {dest}");
+                    }
+                    // Other destinations of the session may still work
+                    Err(e) => warn!("dropped datagram: {e}"),
+                }
             }
-            Err(_) => {
-                trace!("UDP prune timeout");
+            () = tokio::time::sleep(idle_timeout) => {
+                trace!("UDP session idle");
                 break;
             }
-        };
+        }
     }
     debug!("UDP forwarding finished");
     Ok(())
@@ -115,21 +237,65 @@ pub(super) async fn udp_forward_to(
 /// This forwarder is trivial: it just pipes the TCP stream to and from the
 /// channel.
 ///
+/// Returns the number of bytes sent to and received from the destination.
+///
 /// # Errors
 /// It carries the errors from the underlying TCP or channel IO functions.
-#[tracing::instrument(skip(channel), level = "debug")]
+#[tracing::instrument(skip(channel, egress), level = "debug")]
 pub(super) async fn tcp_forwarder_on_channel(
     mut channel: super::websocket::MuxStream,
-) -> Result<(), Error> {
+    egress: &Egress,
+) -> Result<(u64, u64), Error> {
     let rhost = std::str::from_utf8(&channel.dest_host)?;
     let rport = channel.dest_port;
+    let stream_id = channel.id();
     trace!("attempting TCP connect to {rhost} port={rport}");
-    let mut rstream = TcpStream::connect((rhost, rport)).await?;
+    let connected = tokio::time::timeout(config::TCP_CONNECT_TIMEOUT, egress.connect(rhost, rport))
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+    let rstream = match connected {
+        Ok(rstream) => rstream,
+        Err(err) => {
+            // The stream is not accepted yet, so the client hears why
+            channel.reset(RstReason::from(&err));
+            return Err(err.into());
+        }
+    };
+    channel.accept();
+    if let Err(err) = sockopt::set_tcp_options(&rstream, &channel.tcp_options) {
+        warn!(stream_id, "Failed to set TCP options: {err}");
+    }
     // Here `rstream` should be connected. Pass the error (unlikely) otherwise
-    debug!("TCP forwarding to {}", rstream.peer_addr()?);
-    tokio::io::copy_bidirectional(&mut channel, &mut rstream).await?;
-    trace!("TCP forwarding finished");
-    Ok(())
+    let destination = rstream.peer_addr()?;
+    debug!(stream_id, %destination, "TCP forwarding started");
+    let (bytes_up, bytes_down) = pipe(channel, rstream).await?;
+    debug!(
+        stream_id,
+        %destination,
+        bytes_up,
+        bytes_down,
+        "TCP forwarding finished"
+    );
+    Ok((bytes_up, bytes_down))
+}
+
+/// Pipe `rstream` to and from `channel`, with io_uring if it is enabled
+/// and available.
+async fn pipe(
+    mut channel: super::websocket::MuxStream,
+    mut rstream: TcpStream,
+) -> std::io::Result<(u64, u64)> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    if super::uring::available() {
+        return super::uring::forward(channel, rstream).await;
+    }
+    tokio::io::copy_bidirectional_with_sizes(
+        &mut channel,
+        &mut rstream,
+        config::TCP_RELAY_BUFFER_SIZE,
+        config::TCP_RELAY_BUFFER_SIZE,
+    )
+    .await
 }
 
 #[cfg(test)]
@@ -137,13 +303,34 @@ mod test {
     use super::*;
     use tokio::net::UdpSocket;
 
+    #[test]
+    fn test_group_address() {
+        assert_eq!(
+            group_address("239.255.255.250"),
+            Some(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)))
+        );
+        assert_eq!(group_address("ff02::fb"), Some("ff02::fb".parse().unwrap()));
+        assert_eq!(
+            group_address("255.255.255.255"),
+            Some(IpAddr::V4(Ipv4Addr::BROADCAST))
+        );
+        assert_eq!(group_address("127.0.0.1"), None);
+        assert_eq!(group_address("::1"), None);
+        assert_eq!(group_address("mdns.example.com"), None);
+    }
+
     #[tokio::test]
     async fn test_bind_and_send_v4() {
         let target_sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
         let target_addr = target_sock.local_addr().unwrap();
-        let (socket, target) = bind_and_send(("127.0.0.1", target_addr.port()), b"hello")
-            .await
-            .unwrap();
+        let (socket, target) = bind_and_send(
+            ("127.0.0.1", target_addr.port()),
+            b"hello",
+            false,
+            &Egress::default(),
+        )
+        .await
+        .unwrap();
         assert_eq!(target, target_addr);
         let mut buf = vec![0; 5];
         let (len, addr) = target_sock.recv_from(&mut buf).await.unwrap();
@@ -159,9 +346,14 @@ mod test {
     async fn test_bind_and_send_v6() {
         let target_sock = UdpSocket::bind(("::1", 0)).await.unwrap();
         let target_addr = target_sock.local_addr().unwrap();
-        let (socket, target) = bind_and_send(("::1", target_addr.port()), b"hello")
-            .await
-            .unwrap();
+        let (socket, target) = bind_and_send(
+            ("::1", target_addr.port()),
+            b"hello",
+            false,
+            &Egress::default(),
+        )
+        .await
+        .unwrap();
         assert_eq!(target, target_addr);
         let mut buf = vec![0; 5];
         let (len, addr) = target_sock.recv_from(&mut buf).await.unwrap();
@@ -184,7 +376,16 @@ mod test {
             port: target_addr.port(),
             data: Bytes::from_static(b"hello"),
         };
-        let forwarder = tokio::spawn(udp_forward_to(datagram_frame, tx));
+        let (_data_tx, data_rx) = tokio::sync::mpsc::channel(1);
+        let forwarder = tokio::spawn(udp_forward_to(
+            datagram_frame,
+            data_rx,
+            tx,
+            config::UDP_PRUNE_TIMEOUT,
+            false,
+            false,
+            Egress::default(),
+        ));
         let mut buf = vec![0; 5];
         let (len, addr) = target_sock.recv_from(&mut buf).await.unwrap();
         assert_eq!(len, 5);
@@ -212,7 +413,16 @@ mod test {
             port: target_addr.port(),
             data: Bytes::from_static(b"hello"),
         };
-        let forwarder = tokio::spawn(udp_forward_to(datagram_frame, tx));
+        let (_data_tx, data_rx) = tokio::sync::mpsc::channel(1);
+        let forwarder = tokio::spawn(udp_forward_to(
+            datagram_frame,
+            data_rx,
+            tx,
+            config::UDP_PRUNE_TIMEOUT,
+            false,
+            false,
+            Egress::default(),
+        ));
         let mut buf = vec![0; 5];
         let (len, addr) = target_sock.recv_from(&mut buf).await.unwrap();
         assert_eq!(len, 5);
@@ -228,4 +438,53 @@ mod test {
         let datagram_frame = rx.recv().await.unwrap();
         assert_eq!(datagram_frame.data.as_ref(), b"test 3");
     }
+
+    #[tokio::test]
+    async fn test_udp_forward_to_full_cone() {
+        let target_sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let target_addr = target_sock.local_addr().unwrap();
+        let stranger_sock = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let stranger_addr = stranger_sock.local_addr().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+        let (data_tx, data_rx) = tokio::sync::mpsc::channel(1);
+        let datagram_frame = DatagramFrame {
+            sid: 1,
+            host: Bytes::from_static(b"127.0.0.1"),
+            port: target_addr.port(),
+            data: Bytes::from_static(b"hello"),
+        };
+        let forwarder = tokio::spawn(udp_forward_to(
+            datagram_frame,
+            data_rx,
+            tx,
+            config::UDP_PRUNE_TIMEOUT,
+            true,
+            false,
+            Egress::default(),
+        ));
+        let mut buf = vec![0; 5];
+        let (_, mapped_addr) = target_sock.recv_from(&mut buf).await.unwrap();
+        // Anyone can reach the client through the mapped address
+        stranger_sock.send_to(b"hi", mapped_addr).await.unwrap();
+        let datagram_frame = rx.recv().await.unwrap();
+        assert_eq!(datagram_frame.sid, 1);
+        assert_eq!(datagram_frame.host.as_ref(), b"127.0.0.1");
+        assert_eq!(datagram_frame.port, stranger_addr.port());
+        assert_eq!(datagram_frame.data.as_ref(), b"hi");
+        // And the client can answer from the same address
+        data_tx
+            .send(DatagramFrame {
+                sid: 1,
+                host: Bytes::from_static(b"127.0.0.1"),
+                port: stranger_addr.port(),
+                data: Bytes::from_static(b"there"),
+            })
+            .await
+            .unwrap();
+        let (len, addr) = stranger_sock.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"there");
+        assert_eq!(addr, mapped_addr);
+        drop(data_tx);
+        forwarder.await.unwrap().unwrap();
+    }
 }
@@ -0,0 +1,119 @@
+//! Connect to a destination with several addresses the Happy Eyeballs way
+//! (RFC 8305): the attempts to the addresses are staggered instead of
+//! serial, alternating between IPv6 and IPv4, and the first connection wins.
+//! A broken IPv6 path then costs a short delay rather than a timeout.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::egress::Egress;
+use crate::{config, Dupe};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tracing::{debug, trace};
+
+/// Order `addresses` by alternating between IPv6 and IPv4, starting with
+/// IPv6, and otherwise keeping the order of each family.
+fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to one of `addresses`, starting an attempt to the next one
+/// whenever an attempt fails or has not succeeded within
+/// [`config::HAPPY_EYEBALLS_DELAY`].
+pub(super) async fn connect(addresses: Vec<SocketAddr>, egress: &Egress) -> io::Result<TcpStream> {
+    let mut pending = interleave(addresses).into_iter();
+    // Dropping it aborts the attempts that lost
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+    loop {
+        if let Some(address) = pending.next() {
+            trace!("attempting TCP connect to {address}");
+            let egress = egress.dupe();
+            attempts.spawn(async move {
+                egress
+                    .connect_tcp(address)
+                    .await
+                    .inspect_err(|err| debug!("TCP connect to {address} failed: {err}"))
+            });
+        }
+        tokio::select! {
+            Some(result) = attempts.join_next() => {
+                match result.expect("connection attempts should not panic (this is a bug)") {
+                    Ok(stream) => return Ok(stream),
+                    Err(err) => last_err = Some(err),
+                }
+            }
+            () = tokio::time::sleep(config::HAPPY_EYEBALLS_DELAY), if pending.len() > 0 => {}
+            else => {
+                return Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "could not resolve to any address",
+                    )
+                }));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_interleave() {
+        let a4: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let b4: SocketAddr = "192.0.2.2:80".parse().unwrap();
+        let c4: SocketAddr = "192.0.2.3:80".parse().unwrap();
+        let a6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+        let b6: SocketAddr = "[2001:db8::2]:80".parse().unwrap();
+        assert_eq!(interleave(vec![a4, b4, c4, a6, b6]), [a6, a4, b6, b4, c4]);
+        assert_eq!(interleave(vec![a4, b4]), [a4, b4]);
+        assert!(interleave(vec![]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_skips_failures() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // Nothing listens on a port we just closed
+        let closed = TcpListener::bind(("127.0.0.1", 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let egress = Egress::default();
+        let stream = connect(vec![closed, open], &egress).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+        connect(vec![closed], &egress).await.unwrap_err();
+        connect(vec![], &egress).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_connect_does_not_wait_for_slow_attempts() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // Unroutable, so the attempt hangs or fails
+        let blackhole: SocketAddr = "[100::1]:9".parse().unwrap();
+        let stream = tokio::time::timeout(
+            config::HAPPY_EYEBALLS_DELAY * 4,
+            connect(vec![open, blackhole], &Egress::default()),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), open);
+    }
+}
@@ -0,0 +1,296 @@
+//! JWT bearer token authentication.
+//!
+//! Clients present `Authorization: Bearer <token>` instead of `X-Penguin-PSK`.
+//! Tokens are either signed with a shared HMAC secret or with one of the keys
+//! in a JWKS file from an identity provider. The `sub` claim names the
+//! user, who must be in the users file if there is one.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::auth::{User, UserDb};
+use http::{header, HeaderMap};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::sync::Arc;
+use thiserror::Error;
+use tracing::debug;
+
+/// Errors that can occur when setting up JWT validation.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot read JWKS file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid JWKS file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid key in JWKS file: {0}")]
+    Key(#[from] jsonwebtoken::errors::Error),
+    #[error("No usable keys in JWKS file")]
+    NoKeys,
+}
+
+/// Claims we care about.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: Option<String>,
+}
+
+/// A key used to verify tokens.
+struct VerifyingKey {
+    /// Key ID, matched against the `kid` in the token header
+    kid: Option<String>,
+    key: DecodingKey,
+    /// `Validation` restricted to the algorithms that make sense for `key`
+    validation: Validation,
+}
+
+impl std::fmt::Debug for VerifyingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VerifyingKey")
+            .field("kid", &self.kid)
+            .field("algorithms", &self.validation.algorithms)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Validates JWT bearer tokens.
+#[derive(Debug)]
+pub struct JwtValidator {
+    keys: Vec<VerifyingKey>,
+}
+
+impl JwtValidator {
+    /// Create a validator using an HMAC secret.
+    pub fn from_secret(secret: &[u8], issuer: Option<&str>, audience: Option<&str>) -> Self {
+        let validation = make_validation(
+            &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+            issuer,
+            audience,
+        );
+        Self {
+            keys: vec![VerifyingKey {
+                kid: None,
+                key: DecodingKey::from_secret(secret),
+                validation,
+            }],
+        }
+    }
+
+    /// Create a validator using the keys in a JWKS document.
+    pub fn from_jwks(
+        jwks: &str,
+        issuer: Option<&str>,
+        audience: Option<&str>,
+    ) -> Result<Self, Error> {
+        let jwks: JwkSet = serde_json::from_str(jwks)?;
+        let keys = jwks
+            .keys
+            .iter()
+            .map(|jwk| {
+                let algorithms: &[Algorithm] = match &jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => &[
+                        Algorithm::RS256,
+                        Algorithm::RS384,
+                        Algorithm::RS512,
+                        Algorithm::PS256,
+                        Algorithm::PS384,
+                        Algorithm::PS512,
+                    ],
+                    AlgorithmParameters::EllipticCurve(_) => &[Algorithm::ES256, Algorithm::ES384],
+                    AlgorithmParameters::OctetKeyPair(_) => &[Algorithm::EdDSA],
+                    AlgorithmParameters::OctetKey(_) => {
+                        &[Algorithm::HS256, Algorithm::HS384, Algorithm::HS512]
+                    }
+                };
+                Ok(VerifyingKey {
+                    kid: jwk.common.key_id.clone(),
+                    key: DecodingKey::from_jwk(jwk)?,
+                    validation: make_validation(algorithms, issuer, audience),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        if keys.is_empty() {
+            return Err(Error::NoKeys);
+        }
+        Ok(Self { keys })
+    }
+
+    /// Load a JWKS file from the given path.
+    pub async fn load_jwks(
+        path: &str,
+        issuer: Option<&str>,
+        audience: Option<&str>,
+    ) -> Result<Self, Error> {
+        let jwks = tokio::fs::read_to_string(path).await?;
+        Self::from_jwks(&jwks, issuer, audience)
+    }
+
+    /// Validate the token and return the user it represents: the entry of
+    /// its `sub` in `users` if given, or else an unrestricted user.
+    pub fn validate(&self, token: &str, users: Option<&UserDb>) -> Option<Arc<User>> {
+        let kid = jsonwebtoken::decode_header(token).ok()?.kid;
+        for key in &self.keys {
+            if kid.is_some() && key.kid.is_some() && kid != key.kid {
+                continue;
+            }
+            match jsonwebtoken::decode::<Claims>(token, &key.key, &key.validation) {
+                Ok(data) => {
+                    let Some(name) = data.claims.sub.filter(|name| !name.is_empty()) else {
+                        debug!("JWT has no subject");
+                        return None;
+                    };
+                    return match users {
+                        Some(users) => {
+                            let user = users.get(&name);
+                            if user.is_none() {
+                                debug!("JWT subject {name:?} is not in the users file");
+                            }
+                            user
+                        }
+                        None => Some(Arc::new(User::unrestricted(name))),
+                    };
+                }
+                Err(err) => debug!("JWT validation failed: {err}"),
+            }
+        }
+        None
+    }
+
+    /// Validate the bearer token in the `Authorization` header, if any.
+    pub fn validate_headers(
+        &self,
+        headers: &HeaderMap,
+        users: Option<&UserDb>,
+    ) -> Option<Arc<User>> {
+        let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, token) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return None;
+        }
+        self.validate(token.trim(), users)
+    }
+}
+
+/// Make a `Validation` for the given algorithms, issuer, and audience.
+fn make_validation(
+    algorithms: &[Algorithm],
+    issuer: Option<&str>,
+    audience: Option<&str>,
+) -> Validation {
+    let mut validation = Validation::new(algorithms[0]);
+    validation.algorithms = algorithms.to_vec();
+    if let Some(issuer) = issuer {
+        validation.set_issuer(&[issuer]);
+    }
+    if let Some(audience) = audience {
+        validation.set_audience(&[audience]);
+    } else {
+        validation.validate_aud = false;
+    }
+    validation
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::auth::Proto;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn make_token(secret: &[u8], kid: Option<&str>, claims: &serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = kid.map(ToString::to_string);
+        encode(&header, claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn test_validate_hmac() {
+        let validator = JwtValidator::from_secret(b"secret", Some("idp"), None);
+        let claims = serde_json::json!({ "sub": "alice", "iss": "idp", "exp": now() + 60 });
+        let user = validator
+            .validate(&make_token(b"secret", None, &claims), None)
+            .unwrap();
+        assert_eq!(user.name, "alice");
+        assert!(user.may_connect("example.com", 80, Proto::Tcp));
+        // Wrong secret
+        assert!(validator
+            .validate(&make_token(b"wrong", None, &claims), None)
+            .is_none());
+        // Wrong issuer
+        let claims = serde_json::json!({ "sub": "alice", "iss": "other", "exp": now() + 60 });
+        assert!(validator
+            .validate(&make_token(b"secret", None, &claims), None)
+            .is_none());
+        // Expired
+        let claims = serde_json::json!({ "sub": "alice", "iss": "idp", "exp": now() - 3600 });
+        assert!(validator
+            .validate(&make_token(b"secret", None, &claims), None)
+            .is_none());
+        assert!(validator.validate("not a token", None).is_none());
+    }
+
+    #[test]
+    fn test_validate_users() {
+        let users: UserDb = "alice:s3cret *:443".parse().unwrap();
+        let validator = JwtValidator::from_secret(b"secret", None, None);
+        let claims = serde_json::json!({ "sub": "alice", "exp": now() + 60 });
+        let token = make_token(b"secret", None, &claims);
+        let user = validator.validate(&token, Some(&users)).unwrap();
+        // Still restricted by the users file
+        assert!(!user.is_unrestricted());
+        assert!(user.may_connect("example.com", 443, Proto::Tcp));
+        assert!(!user.may_connect("example.com", 80, Proto::Tcp));
+        // Unknown or missing subjects
+        let claims = serde_json::json!({ "sub": "mallory", "exp": now() + 60 });
+        let token = make_token(b"secret", None, &claims);
+        assert!(validator.validate(&token, Some(&users)).is_none());
+        let claims = serde_json::json!({ "exp": now() + 60 });
+        let token = make_token(b"secret", None, &claims);
+        assert!(validator.validate(&token, Some(&users)).is_none());
+        assert!(validator.validate(&token, None).is_none());
+    }
+
+    #[test]
+    fn test_validate_jwks_and_headers() {
+        // "c2VjcmV0" is base64url for "secret"
+        let jwks = r#"{"keys": [
+            {"kty": "oct", "kid": "k1", "k": "d3Jvbmc"},
+            {"kty": "oct", "kid": "k2", "k": "c2VjcmV0"}
+        ]}"#;
+        let validator = JwtValidator::from_jwks(jwks, None, Some("penguin")).unwrap();
+        let claims = serde_json::json!({ "sub": "bob", "aud": "penguin", "exp": now() + 60 });
+        let token = make_token(b"secret", Some("k2"), &claims);
+        assert_eq!(validator.validate(&token, None).unwrap().name, "bob");
+        let token = make_token(b"secret", Some("k1"), &claims);
+        assert!(validator.validate(&token, None).is_none());
+        // Wrong audience
+        let claims = serde_json::json!({ "sub": "bob", "aud": "other", "exp": now() + 60 });
+        let token = make_token(b"secret", Some("k2"), &claims);
+        assert!(validator.validate(&token, None).is_none());
+        // From headers
+        let claims = serde_json::json!({ "sub": "bob", "aud": "penguin", "exp": now() + 60 });
+        let token = make_token(b"secret", None, &claims);
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        assert_eq!(
+            validator.validate_headers(&headers, None).unwrap().name,
+            "bob"
+        );
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Basic {token}").parse().unwrap(),
+        );
+        assert!(validator.validate_headers(&headers, None).is_none());
+        JwtValidator::from_jwks(r#"{"keys": []}"#, None, None).unwrap_err();
+    }
+}
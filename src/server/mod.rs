@@ -2,59 +2,401 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+mod access_log;
+mod audit;
+mod auth;
+mod backend;
+mod ban;
+mod camouflage;
+mod conn_limit;
+mod cors;
+mod egress;
+mod forwarded;
 mod forwarder;
+mod happy_eyeballs;
+mod jwt;
+mod rate_limit;
+#[cfg(unix)]
+mod reuseport;
 mod service;
+mod shutdown;
+mod systemd;
+mod token;
+mod tun;
+mod udp_session;
+#[cfg(unix)]
+mod unix;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
 mod websocket;
+mod www;
 
+use self::access_log::AccessLog;
+use self::audit::AuditLog;
+use self::auth::UserDb;
+use self::jwt::JwtValidator;
+pub use self::service::WsRoute;
 use self::service::{MakeStateService, State};
-use crate::arg::ServerArgs;
-use crate::tls::{make_tls_identity, reload_tls_identity, TlsAcceptor};
+use self::shutdown::{Shutdown, ShutdownWatch};
+use self::tun::TunRouter;
+use crate::arg::ListenAddr;
+pub use crate::arg::ServerArgs;
+use crate::dump::DumpSignal;
+use crate::sockopt;
+use crate::tls::{
+    make_self_signed_tls_identity, make_tls_identity, reload_tls_identity, TlsAcceptor, TlsIdentity,
+};
+use crate::tun::Tun;
 use crate::Dupe;
 use hyper::server::conn::AddrIncoming;
 use hyper::upgrade::Upgraded;
 use hyper::Server;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
-use tokio_tungstenite::WebSocketStream;
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 
-type WebSocket = WebSocketStream<Upgraded>;
+type WebSocket = crate::ws::WebSocket<Upgraded>;
 
 /// Server Errors
 #[derive(Debug, Error)]
 pub enum Error {
+    /// The listening host is not an IP address
     #[error("Invalid listening host: {0}")]
     InvalidHost(#[from] std::net::AddrParseError),
+    /// The TLS identity could not be loaded
     #[error(transparent)]
     Tls(#[from] crate::tls::Error),
+    /// The TCP socket could not be bound
+    #[error("Cannot listen: {0}")]
+    Listen(std::io::Error),
+    /// The socket passed by systemd is unusable
+    #[error("Cannot use the socket passed by systemd: {0}")]
+    SocketActivation(std::io::Error),
+    /// The Unix socket could not be bound
+    #[cfg(unix)]
+    #[error("Cannot listen on Unix socket: {0}")]
+    UnixSocket(std::io::Error),
+    /// TLS was requested on a Unix socket
+    #[cfg(unix)]
+    #[error("TLS is not supported on a Unix socket")]
+    UnixTls,
+    /// A Unix socket was requested on a platform without them
+    #[cfg(not(unix))]
+    #[error("Unix sockets are not supported on this platform")]
+    NoUnixSocket,
+    /// A `wss://` listener has no TLS certificate
+    #[error("Listening with wss:// requires a TLS certificate")]
+    NoTlsIdentity,
+    /// A `SO_REUSEPORT` socket could not be bound
+    #[cfg(unix)]
+    #[error("Cannot listen with SO_REUSEPORT: {0}")]
+    ReusePort(std::io::Error),
+    /// `--acceptors` was used on a platform without `SO_REUSEPORT`
+    #[cfg(not(unix))]
+    #[error("--acceptors is not supported on this platform")]
+    NoReusePort,
+    /// A signal handler could not be registered
     #[error("Cannot register signal handler: {0}")]
     Signal(std::io::Error),
+    /// The HTTP server failed
     #[error("HTTP server error: {0}")]
     Hyper(#[from] hyper::Error),
+    /// The users file could not be loaded
+    #[error(transparent)]
+    Users(#[from] auth::Error),
+    /// The JWT key could not be loaded
+    #[error(transparent)]
+    Jwt(#[from] jwt::Error),
+    /// The access log could not be opened
+    #[error("Cannot open access log: {0}")]
+    AccessLog(std::io::Error),
+    /// The audit log could not be opened
+    #[error("Cannot open audit log: {0}")]
+    AuditLog(std::io::Error),
+    /// The TUN device could not be created
+    #[error("Cannot create TUN device: {0}")]
+    Tun(std::io::Error),
+}
+
+/// Where the server accepts connections
+enum Listener {
+    /// TCP, with TLS if `tls` is `Some(true)`, without if `Some(false)`,
+    /// or if TLS is configured when `None`
+    Tcp {
+        incoming: AddrIncoming,
+        tls: Option<bool>,
+    },
+    #[cfg(unix)]
+    Unix(unix::UnixIncoming),
+}
+
+impl Listener {
+    /// Listen on the `--listen` addresses, or as [`Listener::bind`] does
+    /// if there are none.
+    fn bind_all(args: &ServerArgs) -> Result<Vec<Self>, Error> {
+        if args.listen.is_empty() {
+            return Self::bind(args);
+        }
+        if systemd::take_listener()
+            .map_err(Error::SocketActivation)?
+            .is_some()
+        {
+            warn!("Not using the socket passed by systemd because --listen is set");
+        }
+        let mut listeners = Vec::new();
+        for addr in &args.listen {
+            match addr {
+                ListenAddr::Tcp { addr, tls } => {
+                    listeners.extend(Self::bind_tcp(addr, *tls, args)?);
+                }
+                #[cfg(unix)]
+                ListenAddr::Unix(path) => listeners.push(Self::Unix(
+                    unix::UnixIncoming::bind(path).map_err(Error::UnixSocket)?,
+                )),
+                #[cfg(not(unix))]
+                ListenAddr::Unix(_) => return Err(Error::NoUnixSocket),
+            }
+        }
+        Ok(listeners)
+    }
+
+    /// Listen on `addr` with `--acceptors` sockets.
+    fn bind_tcp(
+        addr: &SocketAddr,
+        tls: Option<bool>,
+        args: &ServerArgs,
+    ) -> Result<Vec<Self>, Error> {
+        let buffers = args.buffer_sizes();
+        if args.acceptors == 1 {
+            let listener = sockopt::tcp_listener(*addr, buffers, None).map_err(Error::Listen)?;
+            return Ok(vec![Self::Tcp {
+                incoming: AddrIncoming::from_listener(listener)?,
+                tls,
+            }]);
+        }
+        #[cfg(unix)]
+        {
+            (0..args.acceptors)
+                .map(|_| {
+                    let listener = reuseport::bind(addr, buffers).map_err(Error::ReusePort)?;
+                    Ok(Self::Tcp {
+                        incoming: AddrIncoming::from_listener(listener)?,
+                        tls,
+                    })
+                })
+                .collect()
+        }
+        #[cfg(not(unix))]
+        Err(Error::NoReusePort)
+    }
+
+    /// Listen on `--host` and `--port`, or on the socket passed by systemd.
+    fn bind(args: &ServerArgs) -> Result<Vec<Self>, Error> {
+        #[cfg(unix)]
+        if let Some(path) = args.host.strip_prefix("unix:") {
+            if args.tls_key.is_some() || args.tls_selfsign {
+                return Err(Error::UnixTls);
+            }
+            let incoming =
+                unix::UnixIncoming::bind(std::path::Path::new(path)).map_err(Error::UnixSocket)?;
+            return Ok(vec![Self::Unix(incoming)]);
+        }
+        if let Some(listener) = systemd::take_listener().map_err(Error::SocketActivation)? {
+            let listener =
+                tokio::net::TcpListener::from_std(listener).map_err(Error::SocketActivation)?;
+            info!("Using the socket passed by systemd");
+            return Ok(vec![Self::Tcp {
+                incoming: AddrIncoming::from_listener(listener)?,
+                tls: None,
+            }]);
+        }
+        let host = crate::parse_remote::remove_brackets(&args.host);
+        let sockaddr = (host.parse::<std::net::IpAddr>()?, args.port).into();
+        Self::bind_tcp(&sockaddr, None, args)
+    }
+
+    /// Serve `state` until a shutdown is requested.
+    fn serve(
+        self,
+        tls_config: Option<&TlsIdentity>,
+        state: State<'static>,
+        mut shutdown: ShutdownWatch,
+    ) -> Result<ServerFuture, Error> {
+        let signal = async move { shutdown.requested().await };
+        Ok(match self {
+            Self::Tcp { incoming, tls } => match (tls, tls_config) {
+                (Some(true) | None, Some(tls_config)) => {
+                    trace!("Enabling TLS");
+                    info!("Listening on wss://{}/ws", incoming.local_addr());
+                    Box::pin(
+                        Server::builder(TlsAcceptor::new(tls_config.dupe(), incoming))
+                            .serve(MakeStateService(state))
+                            .with_graceful_shutdown(signal),
+                    )
+                }
+                (Some(true), None) => return Err(Error::NoTlsIdentity),
+                (Some(false) | None, _) => {
+                    info!("Listening on ws://{}/ws", incoming.local_addr());
+                    Box::pin(
+                        Server::builder(incoming)
+                            .serve(MakeStateService(state))
+                            .with_graceful_shutdown(signal),
+                    )
+                }
+            },
+            #[cfg(unix)]
+            Self::Unix(incoming) => {
+                info!("Listening on unix:{}", incoming.path().display());
+                Box::pin(
+                    Server::builder(incoming)
+                        .serve(MakeStateService(state))
+                        .with_graceful_shutdown(signal),
+                )
+            }
+        })
+    }
+}
+
+/// A running HTTP server
+type ServerFuture = Pin<Box<dyn Future<Output = Result<(), hyper::Error>> + Send>>;
+
+/// Load the users, keys, and logs `args` refer to, and start the backend
+/// health checks.
+async fn make_state(
+    args: &'static ServerArgs,
+    shutdown: ShutdownWatch,
+    dump: DumpSignal,
+) -> Result<State<'static>, Error> {
+    let users = if let Some(path) = &args.users_file {
+        let users = UserDb::load(path).await?;
+        info!("Loaded {} users from {path}", users.len());
+        Some(Arc::new(users))
+    } else {
+        None
+    };
+    let jwt_issuer = args.jwt_issuer.as_deref();
+    let jwt_audience = args.jwt_audience.as_deref();
+    let jwt = if let Some(secret) = &args.jwt_secret {
+        Some(Arc::new(JwtValidator::from_secret(
+            secret.as_bytes(),
+            jwt_issuer,
+            jwt_audience,
+        )))
+    } else if let Some(path) = &args.jwt_jwks {
+        Some(Arc::new(
+            JwtValidator::load_jwks(path, jwt_issuer, jwt_audience).await?,
+        ))
+    } else {
+        None
+    };
+    let access_log = if let Some(path) = &args.access_log {
+        let access_log = AccessLog::open(path, args.access_log_format).map_err(Error::AccessLog)?;
+        Some(Arc::new(access_log))
+    } else {
+        None
+    };
+    let audit_log = if let Some(path) = &args.audit_log {
+        Some(Arc::new(AuditLog::open(path).map_err(Error::AuditLog)?))
+    } else {
+        None
+    };
+    if let Some(noise_key) = &args.noise_key {
+        info!(
+            "Clients connect with --noise-server-key {}",
+            noise_key.public()
+        );
+    }
+    let mut state = State::new(args, users, jwt, access_log, audit_log, shutdown, dump);
+    if let Some(name) = &args.tun {
+        let device = Tun::create(name, args.tun_mtu, &args.tun_address).map_err(Error::Tun)?;
+        info!("Forwarding IP packets through {}", device.name());
+        let router = Arc::new(TunRouter::new(
+            device,
+            &args.tun_address,
+            state.egress.dupe(),
+        ));
+        tokio::spawn(router.dupe().run());
+        state.tun = Some(router);
+    }
+    if state.backends.needs_health_check() {
+        tokio::spawn(state.backends.clone().health_check(state.client.dupe()));
+    }
+    Ok(state)
+}
+
+/// The tunnel endpoint of a server configured by `args`, to mount in
+/// another `hyper` application.
+///
+/// The returned [`WsRoute`] answers requests like `penguin server` does, so
+/// pass it the requests to `/ws` and keep the other paths for your own
+/// routes. Listening, TLS, and graceful shutdown are left to the
+/// application, so the related options in `args` are ignored.
+///
+/// ```no_run
+/// use hyper::service::{make_service_fn, service_fn, Service};
+/// use hyper::{Body, Request, Response, Server};
+/// use penguin::server::{ws_route, ServerArgs};
+/// use std::convert::Infallible;
+///
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let args = ServerArgs::try_parse_from(["--ws-psk", "secret"])?;
+/// let route = ws_route(Box::leak(Box::new(args))).await?;
+/// let make_service = make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+///     let route = route.clone().with_remote_addr(conn.remote_addr());
+///     async move {
+///         Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+///             let mut route = route.clone();
+///             async move {
+///                 if req.uri().path() == "/ws" {
+///                     route.call(req).await
+///                 } else {
+///                     Ok(Response::new(Body::from("Hello from my app")))
+///                 }
+///             }
+///         }))
+///     }
+/// });
+/// Server::bind(&([127, 0, 0, 1], 8080).into())
+///     .serve(make_service)
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub async fn ws_route(args: &'static ServerArgs) -> Result<WsRoute, Error> {
+    // Nothing asks the tunnels to shut down, and the application owns
+    // the signal handlers
+    let state = make_state(args, ShutdownWatch::default(), DumpSignal::default()).await?;
+    Ok(WsRoute::new(state))
 }
 
+/// Run `penguin server`.
 #[tracing::instrument(level = "trace")]
-pub async fn server_main(args: &'static ServerArgs) -> Result<(), Error> {
+pub(crate) async fn server_main(args: &'static ServerArgs) -> Result<(), Error> {
     let host = crate::parse_remote::remove_brackets(&args.host);
-    let sockaddr = (host.parse::<std::net::IpAddr>()?, args.port).into();
-    let incoming = AddrIncoming::bind(&sockaddr)?;
+    let listeners = Listener::bind_all(args)?;
+    let signal = shutdown::signal()?;
+    let dump = DumpSignal::listen().map_err(Error::Signal)?;
+    let (shutdown, shutdown_watch) = Shutdown::new(Duration::from_secs(args.shutdown_timeout));
+    let state = make_state(args, shutdown_watch, dump).await?;
 
-    let state = State::new(
-        args.backend.as_ref(),
-        args.ws_psk.as_ref(),
-        &args.not_found_resp,
-        args.obfs,
-    );
-
-    if let Some(tls_key) = &args.tls_key {
+    let tls_config = if let Some(tls_key) = &args.tls_key {
         // `expect`: `clap` ensures that both `--tls-cert` and `--tls-key` are
         // specified if either is specified.
         let tls_cert = args
             .tls_cert
             .as_ref()
             .expect("`tls_cert` is `None` (this is a bug)");
-        trace!("Enabling TLS");
-        info!("Listening on wss://{sockaddr}/ws");
-        let tls_config = make_tls_identity(tls_cert, tls_key, args.tls_ca.as_deref()).await?;
+        let tls_config = make_tls_identity(
+            tls_cert,
+            tls_key,
+            args.tls_ca.as_deref(),
+            args.tls_client_auth_optional,
+        )
+        .await?;
         #[cfg(unix)]
         {
             let mut sigusr1 =
@@ -65,23 +407,61 @@ pub async fn server_main(args: &'static ServerArgs) -> Result<(), Error> {
             tokio::spawn(async move {
                 while sigusr1.recv().await == Some(()) {
                     info!("Reloading TLS certificate");
-                    if let Err(err) =
-                        reload_tls_identity(&tls_config, tls_cert, tls_key, args.tls_ca.as_deref())
-                            .await
+                    if let Err(err) = reload_tls_identity(
+                        &tls_config,
+                        tls_cert,
+                        tls_key,
+                        args.tls_ca.as_deref(),
+                        args.tls_client_auth_optional,
+                    )
+                    .await
                     {
                         error!("Cannot reload TLS certificate: {err}");
                     }
                 }
             });
         }
-        Server::builder(TlsAcceptor::new(tls_config, incoming))
-            .serve(MakeStateService(state))
-            .await?;
+        Some(tls_config)
+    } else if args.tls_selfsign {
+        let (tls_config, pin) = make_self_signed_tls_identity(
+            vec![host.to_string()],
+            args.tls_ca.as_deref(),
+            args.tls_client_auth_optional,
+        )
+        .await?;
+        info!("Generated self-signed certificate with --tls-pin {pin}");
+        Some(tls_config)
     } else {
-        info!("Listening on ws://{sockaddr}/ws");
-        Server::builder(incoming)
-            .serve(MakeStateService(state))
-            .await?;
+        None
+    };
+
+    let servers = listeners
+        .into_iter()
+        .map(|listener| listener.serve(tls_config.as_ref(), state.dupe(), state.shutdown.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Only the servers and tunnels may hold a `ShutdownWatch` now
+    drop(state);
+    let mut servers = futures_util::future::try_join_all(servers);
+    systemd::notify_ready();
+    tokio::select! {
+        result = &mut servers => {
+            result?;
+        }
+        () = signal => {
+            info!("Shutting down");
+            systemd::notify_stopping();
+            shutdown.start();
+            servers.await?;
+        }
+    }
+    // Tunnels close on their own after the grace period, but do not wait
+    // forever for a stuck one
+    let deadline = Duration::from_secs(args.shutdown_timeout) + Duration::from_secs(1);
+    if tokio::time::timeout(deadline, shutdown.drained())
+        .await
+        .is_err()
+    {
+        warn!("Some tunnels did not close in time");
     }
     Ok(())
 }
@@ -0,0 +1,86 @@
+//! Per-client rate limiting of WebSocket upgrades and the health endpoints.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Prune drained buckets when there are more than this many.
+const PRUNE_THRESHOLD: usize = 1 << 12;
+
+/// A leaky bucket.
+#[derive(Debug)]
+struct Bucket {
+    /// Requests in the bucket as of `last`
+    level: f64,
+    last: Instant,
+}
+
+/// Leaky-bucket rate limiter keyed by client address.
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    /// Requests leaking out per second
+    rate: f64,
+    /// Capacity of each bucket
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Level of `bucket` after leaking until `now`.
+    fn leaked(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        (bucket.level - elapsed * self.rate).max(0.0)
+    }
+
+    /// Add a request from `ip`. Returns `false` if its bucket is full.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.leaked(bucket, now) > 0.0);
+        }
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            level: 0.0,
+            last: now,
+        });
+        let level = self.leaked(bucket, now);
+        bucket.last = now;
+        if level + 1.0 > self.burst {
+            bucket.level = level;
+            false
+        } else {
+            bucket.level = level + 1.0;
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(20.0, 3);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert!(limiter.check(ip));
+        assert!(limiter.check(ip));
+        assert!(limiter.check(ip));
+        assert!(!limiter.check(ip));
+        assert!(limiter.check("192.0.2.2".parse().unwrap()));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(limiter.check(ip));
+    }
+}
@@ -0,0 +1,64 @@
+//! Several listening sockets on one address with `SO_REUSEPORT`, so that
+//! the kernel spreads new connections across their acceptors. Also UDP
+//! sockets that share a multicast port with other programs.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::sockopt::BufferSizes;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Listen on `addr`, allowing other sockets to do the same, with `buffers`
+/// for the connections it accepts.
+pub(super) fn bind(addr: &SocketAddr, buffers: BufferSizes) -> io::Result<TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(*addr),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    buffers.set_socket2(&socket)?;
+    socket.bind(&(*addr).into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind a UDP socket to `addr`, allowing other sockets to do the same.
+pub(super) fn bind_udp(addr: &SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(*addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&(*addr).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_twice() {
+        let first = bind(&"127.0.0.1:0".parse().unwrap(), BufferSizes::default()).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(&addr, BufferSizes::default()).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+        // Not without `SO_REUSEPORT`
+        std::net::TcpListener::bind(addr).unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_bind_udp_twice() {
+        let first = bind_udp(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind_udp(&addr).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+    }
+}
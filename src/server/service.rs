@@ -2,35 +2,57 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+use super::access_log::{AccessLog, Entry};
+use super::audit::{AuditLog, Auditor};
+use super::auth::{User, UserDb};
+use super::backend::{BackendClients, BackendPool};
+use super::ban::BanList;
+use super::conn_limit::ConnLimiter;
+use super::cors::Cors;
+use super::egress::Egress;
+use super::forwarded::{add_forwarded_headers, client_ip, proxied_client_ip};
+use super::jwt::JwtValidator;
+use super::rate_limit::RateLimiter;
+use super::shutdown::ShutdownWatch;
+use super::token::{self, PskToken};
+use super::tun::TunRouter;
+use super::udp_session::UdpSessionConfig;
 use super::websocket::handle_websocket;
-use crate::arg::BackendUrl;
-use crate::proto_version::PROTOCOL_VERSION;
-use crate::tls::make_client_https;
+use super::www;
+use super::WebSocket;
+use crate::arg::{BackendUrl, Camouflage, ServerArgs};
+use crate::challenge::{server_challenge, Challenge, ChallengeResponse};
+use crate::dump::DumpSignal;
+use crate::noise::{server_handshake, NoiseKey, NoiseTransport};
+use crate::proto_version;
+use crate::throughput::Throughput;
+use crate::tls::{TlsConnInfo, TlsStream};
+use crate::totp::TotpSecret;
 use crate::Dupe;
 use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
 use base64::Engine;
-use http::{header, HeaderValue, Method, Request, Response, StatusCode, Uri};
-use hyper::client::HttpConnector;
+use futures_util::SinkExt;
+use http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri};
+use hyper::server::conn::AddrStream;
 use hyper::service::Service;
 use hyper::upgrade::OnUpgrade;
-use hyper::{Body, Client};
-#[cfg(feature = "__rustls")]
-use hyper_rustls::HttpsConnector;
-#[cfg(feature = "nativetls")]
-use hyper_tls::HttpsConnector;
+use hyper::Body;
+use ipnet::IpNet;
+use penguin_mux::{Capabilities, Compression, Options as MuxOptions};
 use sha1::{Digest, Sha1};
 use std::convert::Infallible;
 use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use tokio_tungstenite::tungstenite::protocol::Role;
-use tokio_tungstenite::WebSocketStream;
-use tracing::{debug, error, warn};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tracing::{debug, debug_span, error, warn, Instrument};
 
 static UPGRADE: HeaderValue = HeaderValue::from_static("upgrade");
 static WEBSOCKET: HeaderValue = HeaderValue::from_static("websocket");
-static WANTED_PROTOCOL: HeaderValue = HeaderValue::from_static(PROTOCOL_VERSION);
 static WEBSOCKET_VERSION: HeaderValue = HeaderValue::from_static("13");
 
 macro_rules! header_matches {
@@ -53,59 +75,251 @@ fn make_sec_websocket_accept(key: &HeaderValue) -> HeaderValue {
     accept.parse().expect("Broken header value (this is a bug)")
 }
 
+/// Remove the port, if any, from a `Host` header value.
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        host.split_once(']').map_or(host, |(host, _)| &host[1..])
+    } else {
+        host.split_once(':').map_or(host, |(host, _)| host)
+    }
+}
+
 /// Required state for each request.
 #[derive(Clone, Debug)]
 pub(super) struct State<'a> {
-    /// Backend URL
-    pub backend: Option<&'a BackendUrl>,
+    /// Backends
+    pub backends: Arc<BackendPool>,
+    /// Directory of static files to serve
+    pub www: Option<&'a Path>,
     /// Websocket PSK
     pub ws_psk: Option<&'a HeaderValue>,
+    /// Secret to derive a rotating PSK from
+    pub ws_psk_totp: Option<&'a TotpSecret>,
+    /// Users allowed to upgrade to a WebSocket
+    pub users: Option<Arc<UserDb>>,
+    /// Validator for JWT bearer tokens
+    pub jwt: Option<Arc<JwtValidator>>,
+    /// Whether to fall back to the PSK challenge-response
+    pub ws_psk_challenge: bool,
+    /// Longest lifetime of the signed PSK tokens, in seconds
+    pub token_max_ttl: u64,
+    /// Noise private key clients must perform the handshake with
+    pub noise_key: Option<&'a NoiseKey>,
+    /// Noise public keys of the allowed clients. Empty allows all.
+    pub noise_client_keys: &'a [NoiseKey],
+    /// TLS information of this connection
+    pub tls_info: Option<Arc<TlsConnInfo>>,
+    /// Whether a verified client certificate is enough to authenticate
+    pub tls_client_cert_auth: bool,
+    /// Address of the peer of this connection
+    pub remote_addr: Option<SocketAddr>,
+    /// Whether the peer is a proxy on our Unix socket
+    pub unix_peer: bool,
+    /// Proxies whose forwarding headers are trusted
+    pub trusted_proxies: &'a [IpAddr],
+    /// Networks clients may upgrade to a WebSocket from. Empty allows all.
+    pub allowed_client_cidrs: &'a [IpNet],
+    /// Clients banned after failing to authenticate
+    pub bans: Option<Arc<BanList>>,
+    /// Rate limiter for upgrades and the health endpoints
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Limits on the number of open tunnels
+    pub conn_limiter: Option<Arc<ConnLimiter>>,
+    /// Log of plain HTTP requests
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Log of tunneled streams
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Limits on the data sent over streams
+    pub throughput: Throughput,
+    /// Settings of the multiplexor of each connection
+    pub mux_options: MuxOptions,
+    /// Settings of the UDP sessions of each connection
+    pub udp_config: UdpSessionConfig,
+    /// TUN device for the IP packets of the clients
+    pub tun: Option<Arc<TunRouter>>,
+    /// How the forwarder reaches destinations
+    pub egress: Egress,
     /// 404 response
     pub not_found_resp: &'a str,
+    /// `Content-Type` of the 404 response
+    pub not_found_content_type: Option<&'static str>,
+    /// Web server whose error pages to mimic
+    pub camouflage: Option<Camouflage>,
+    /// `Server` header to add to responses
+    pub server_header: Option<HeaderValue>,
+    /// CORS configuration
+    pub cors: Cors<'a>,
     /// Whether to obfuscate
     pub obfs: bool,
-    /// Hyper client
-    pub client: Arc<Client<HttpsConnector<HttpConnector>, Body>>,
+    /// Hyper clients
+    pub client: Arc<BackendClients>,
+    /// Notice of a graceful shutdown
+    pub shutdown: ShutdownWatch,
+    /// Notice of a request to log statistics
+    pub dump: DumpSignal,
 }
 
 impl<'a> Dupe for State<'a> {
     fn dupe(&self) -> Self {
         Self {
-            backend: self.backend,
+            backends: self.backends.clone(),
+            www: self.www,
             ws_psk: self.ws_psk,
+            ws_psk_totp: self.ws_psk_totp,
+            users: self.users.clone(),
+            jwt: self.jwt.clone(),
+            ws_psk_challenge: self.ws_psk_challenge,
+            token_max_ttl: self.token_max_ttl,
+            noise_key: self.noise_key,
+            noise_client_keys: self.noise_client_keys,
+            tls_info: self.tls_info.clone(),
+            tls_client_cert_auth: self.tls_client_cert_auth,
+            remote_addr: self.remote_addr,
+            unix_peer: self.unix_peer,
+            trusted_proxies: self.trusted_proxies,
+            allowed_client_cidrs: self.allowed_client_cidrs,
+            bans: self.bans.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            conn_limiter: self.conn_limiter.clone(),
+            access_log: self.access_log.clone(),
+            audit_log: self.audit_log.clone(),
+            throughput: self.throughput.dupe(),
+            mux_options: self.mux_options.clone(),
+            udp_config: self.udp_config,
+            tun: self.tun.clone(),
+            egress: self.egress.dupe(),
             not_found_resp: self.not_found_resp,
+            not_found_content_type: self.not_found_content_type,
+            camouflage: self.camouflage,
+            server_header: self.server_header.clone(),
+            cors: self.cors,
             obfs: self.obfs,
             client: self.client.dupe(),
+            shutdown: self.shutdown.clone(),
+            dump: self.dump.clone(),
         }
     }
 }
 impl State<'static> {
     /// Create a new `State`
     pub fn new(
-        backend: Option<&'static BackendUrl>,
-        ws_psk: Option<&'static HeaderValue>,
-        not_found_resp: &'static str,
-        obfs: bool,
+        args: &'static ServerArgs,
+        users: Option<Arc<UserDb>>,
+        jwt: Option<Arc<JwtValidator>>,
+        access_log: Option<Arc<AccessLog>>,
+        audit_log: Option<Arc<AuditLog>>,
+        shutdown: ShutdownWatch,
+        dump: DumpSignal,
     ) -> Self {
         Self {
-            backend,
-            ws_psk,
-            not_found_resp,
-            obfs,
-            client: Arc::new(Client::builder().build(make_client_https())),
+            backends: Arc::new(BackendPool::new(&args.backend, &args.backend_route)),
+            www: args.www.as_deref().map(Path::new),
+            ws_psk: args.ws_psk.as_ref(),
+            ws_psk_totp: args.ws_psk_totp.as_ref(),
+            users,
+            jwt,
+            ws_psk_challenge: args.ws_psk_challenge,
+            token_max_ttl: args.token_max_ttl,
+            noise_key: args.noise_key.as_ref(),
+            noise_client_keys: &args.noise_client_key,
+            tls_info: None,
+            tls_client_cert_auth: args.tls_client_cert_auth,
+            remote_addr: None,
+            unix_peer: false,
+            trusted_proxies: &args.trusted_proxy,
+            allowed_client_cidrs: &args.allow_client_cidr,
+            bans: args.ban_after.map(|threshold| {
+                Arc::new(BanList::new(threshold, Duration::from_secs(args.ban_time)))
+            }),
+            rate_limiter: args
+                .rate_limit
+                .map(|rate| Arc::new(RateLimiter::new(rate, args.rate_limit_burst))),
+            conn_limiter: (args.max_conns_per_ip.is_some() || args.max_conns.is_some())
+                .then(|| Arc::new(ConnLimiter::new(args.max_conns_per_ip, args.max_conns))),
+            access_log,
+            audit_log,
+            throughput: Throughput::new(args.limit_rate, args.limit_rate_total),
+            mux_options: MuxOptions {
+                keepalive_interval: (args.keepalive != 0)
+                    .then(|| Duration::from_secs(args.keepalive)),
+                max_streams: args.max_streams,
+                stream_idle_timeout: args.stream_idle_timeout.map(Duration::from_secs),
+                cork: args.cork.map(Duration::from_millis),
+                capabilities: Capabilities {
+                    max_frame_size: Some(args.max_frame_size),
+                    compression: if args.compress {
+                        Compression::supported()
+                    } else {
+                        0
+                    },
+                    ip_packets: args.tun.is_some(),
+                    tcp_options: true,
+                    ..Capabilities::default()
+                },
+                obfs_traffic: args.obfs_traffic,
+                datagram_overflow: args.datagram_overflow.into(),
+                ..MuxOptions::default()
+            },
+            udp_config: UdpSessionConfig {
+                max_sessions: args.udp_max_sessions,
+                idle_timeout: Duration::from_secs(args.udp_idle_timeout),
+                multicast: args.udp_multicast,
+            },
+            // Created by `make_state`, since it can fail
+            tun: None,
+            egress: Egress::new(args),
+            not_found_resp: args.not_found_resp.as_deref().unwrap_or_else(|| {
+                args.camouflage
+                    .map_or("Not found", Camouflage::not_found_page)
+            }),
+            not_found_content_type: args
+                .camouflage
+                .filter(|_| args.not_found_resp.is_none())
+                .map(Camouflage::error_content_type),
+            camouflage: args.camouflage,
+            server_header: args.server_header.clone().or_else(|| {
+                args.camouflage
+                    .map(|c| HeaderValue::from_static(c.server_header()))
+            }),
+            cors: Cors {
+                origins: &args.cors_origin,
+                allow_headers: args.cors_allow_headers.as_ref(),
+            },
+            obfs: args.obfs,
+            client: Arc::new(BackendClients::new()),
+            shutdown,
+            dump,
         }
     }
 
+    /// Choose the backend for the request by the TLS server name or the
+    /// `Host` header, falling back to `--backend`.
+    fn select_backend(&self, req: &Request<Body>) -> Option<&'static BackendUrl> {
+        let host = self
+            .tls_info
+            .as_ref()
+            .and_then(|info| info.server_name.get().map(String::as_str))
+            .or_else(|| {
+                let host = req.headers().get(header::HOST)?.to_str().ok()?;
+                Some(strip_port(host))
+            })
+            .or_else(|| req.uri().host());
+        self.backends.select(host)
+    }
+
     /// Reverse proxy and 404
     async fn backend_or_404_handler(
         self,
         mut req: Request<Body>,
     ) -> Result<Response<Body>, Infallible> {
-        if let Some(BackendUrl {
-            scheme,
-            authority,
-            path: backend_path,
-        }) = self.backend
+        if let Some(
+            backend @ BackendUrl {
+                scheme,
+                authority,
+                path: backend_path,
+                ..
+            },
+        ) = self.select_backend(&req)
         {
             let req_path = req.uri().path();
             let req_path_query = req
@@ -117,21 +331,41 @@ impl State<'static> {
                 // `expect`: `BackendUrl` is validated by clap.
                 .scheme(scheme.dupe())
                 .authority(authority.dupe())
-                .path_and_query(format!("{}{req_path_query}", backend_path.path()))
+                .path_and_query(format!(
+                    "{}{req_path_query}",
+                    backend_path.path().trim_end_matches('/')
+                ))
                 .build()
                 .expect("Failed to build URI for backend (this is a bug)");
             *req.uri_mut() = uri;
             // This may not be the best way to do this, but to avoid panicking if
             // we have a HTTP/2 request, but `backend` does not support h2, let's
             // downgrade to HTTP/1.1 and let them upgrade if they want to.
+            // `hyper` still uses HTTP/2 (over one pooled connection) if ALPN
+            // negotiates h2 or the backend is h2c.
             *req.version_mut() = http::version::Version::default();
-            match self.client.request(req).await {
+            if let Some(peer) = self.remote_addr {
+                let proto = if self.tls_info.is_some() {
+                    "https"
+                } else {
+                    "http"
+                };
+                add_forwarded_headers(req.headers_mut(), peer.ip(), proto, self.trusted_proxies);
+            }
+            match self.client.request(backend, req).await {
                 Ok(resp) => Ok(resp),
                 Err(e) => {
                     error!("Failed to proxy request to backend: {}", e);
+                    self.backends.report_failure(backend);
                     self.not_found_handler()
                 }
             }
+        } else if let Some(www) = self.www {
+            match www::serve(www, &req).await {
+                Some(resp) if resp.status().is_client_error() => Ok(self.camouflage_error(resp)),
+                Some(resp) => Ok(resp),
+                None => self.not_found_handler(),
+            }
         } else {
             self.not_found_handler()
         }
@@ -139,12 +373,211 @@ impl State<'static> {
 
     /// 404 handler
     fn not_found_handler(self) -> Result<Response<Body>, Infallible> {
-        Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
+        let mut resp = Response::builder().status(StatusCode::NOT_FOUND);
+        if let Some(content_type) = self.not_found_content_type {
+            resp = resp.header(header::CONTENT_TYPE, content_type);
+        }
+        Ok(resp
             .body(Body::from(self.not_found_resp))
             .expect("Failed to build 404 response (this is a bug)"))
     }
 
+    /// 429 handler, or 404 if obfuscating
+    fn too_many_requests_handler(self) -> Result<Response<Body>, Infallible> {
+        if self.obfs {
+            return self.not_found_handler();
+        }
+        let resp = Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .body(Body::empty())
+            .expect("Failed to build 429 response (this is a bug)");
+        Ok(self.camouflage_error(resp))
+    }
+
+    /// Replace the body of an error response of our own with the error page
+    /// of `--camouflage`, if any.
+    fn camouflage_error(&self, mut resp: Response<Body>) -> Response<Body> {
+        if let Some(camouflage) = self.camouflage {
+            resp.headers_mut().insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static(camouflage.error_content_type()),
+            );
+            *resp.body_mut() = Body::from(camouflage.error_page(resp.status()));
+        }
+        resp
+    }
+
+    /// Find the user named in the verified client certificate, if any.
+    /// Returns `Err(())` if there is a users file without that name.
+    fn client_cert_user(&self) -> Result<Option<Arc<User>>, ()> {
+        let Some(name) = self
+            .tls_info
+            .as_ref()
+            .and_then(|info| info.client_cert_name.get())
+        else {
+            return Ok(None);
+        };
+        match &self.users {
+            Some(users) => users.get(name).map(Some).ok_or(()),
+            // Without a users file, the CA decides who may connect
+            None => Ok(Some(Arc::new(User::unrestricted(name.clone())))),
+        }
+    }
+
+    /// Combine the identity in the client certificate, if any, with `user`
+    /// authenticated by other credentials. Returns `Err(())` if they name
+    /// different users, or else the user of the certificate, whose entry
+    /// in the users file applies whatever the other credentials were.
+    fn with_client_cert(&self, user: Option<Arc<User>>) -> Result<Option<Arc<User>>, ()> {
+        match (self.client_cert_user()?, user) {
+            (Some(cert_user), None) => Ok(Some(cert_user)),
+            (Some(cert_user), Some(user)) if cert_user.name == user.name => Ok(Some(cert_user)),
+            (Some(_), Some(_)) => Err(()),
+            (None, user) => Ok(user),
+        }
+    }
+
+    /// Check the presented credentials against the client certificate,
+    /// `--ws-psk`, `--ws-psk-totp`, the users file, and the JWT validator.
+    /// Returns `Err(())` if the request is not authorized, or the matching
+    /// user (`None` if unrestricted) otherwise.
+    fn authenticate(&self, headers: &HeaderMap) -> Result<Option<Arc<User>>, ()> {
+        if self.tls_client_cert_auth {
+            if let Some(user) = self.client_cert_user()? {
+                return Ok(Some(user));
+            }
+        }
+        self.authenticate_credentials(headers)
+            .and_then(|user| self.with_client_cert(user))
+    }
+
+    /// Check the credentials in the headers, ignoring the client certificate.
+    fn authenticate_credentials(&self, headers: &HeaderMap) -> Result<Option<Arc<User>>, ()> {
+        if self.ws_psk.is_none()
+            && self.ws_psk_totp.is_none()
+            && self.users.is_none()
+            && self.jwt.is_none()
+        {
+            return Ok(None);
+        }
+        let psk = headers.get("x-penguin-psk");
+        if let (Some(ws_psk), Some(psk)) = (self.ws_psk, psk) {
+            if bool::from(ws_psk.as_bytes().ct_eq(psk.as_bytes())) {
+                return Ok(None);
+            }
+        }
+        if let (Some(totp), Some(psk)) = (self.ws_psk_totp, psk) {
+            if totp.accepts(psk) {
+                return Ok(None);
+            }
+        }
+        let user = psk
+            .and_then(|psk| self.users.as_ref()?.authenticate(psk))
+            .or_else(|| {
+                self.jwt
+                    .as_ref()?
+                    .validate_headers(headers, self.users.as_deref())
+            });
+        user.map(Some).ok_or(())
+    }
+
+    /// Check a response to the PSK challenge against `--ws-psk`,
+    /// `--ws-psk-totp`, and the users file. Returns the same as `authenticate`.
+    fn verify_challenge(
+        &self,
+        challenge: &Challenge,
+        response: &ChallengeResponse,
+    ) -> Result<Option<Arc<User>>, ()> {
+        if let Some(user) = self
+            .users
+            .as_ref()
+            .and_then(|users| users.authenticate_challenge(challenge, response))
+        {
+            return Ok(Some(user));
+        }
+        if let Some(totp) = self.ws_psk_totp {
+            if totp
+                .accepted_psks()
+                .any(|psk| challenge.verify(psk.as_bytes(), response))
+            {
+                return Ok(None);
+            }
+        }
+        match self.ws_psk {
+            Some(psk) if challenge.verify(psk.as_bytes(), response) => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    /// Check a signed token from the query string or a cookie against
+    /// `--ws-psk`, `--ws-psk-totp`, and the users file. Returns the same as
+    /// `authenticate`.
+    fn verify_token(&self, token: &PskToken) -> Result<Option<Arc<User>>, ()> {
+        // Do not let clients make tokens that never expire
+        if !token.expires_within(self.token_max_ttl) {
+            return Err(());
+        }
+        if token.user.is_some() {
+            return self
+                .users
+                .as_ref()
+                .and_then(|users| users.authenticate_token(token))
+                .map(Some)
+                .ok_or(());
+        }
+        if let Some(totp) = self.ws_psk_totp {
+            if totp.accepted_psks().any(|psk| token.verify(psk.as_bytes())) {
+                return Ok(None);
+            }
+        }
+        match self.ws_psk {
+            Some(psk) if token.verify(psk.as_bytes()) => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    /// Perform the PSK challenge-response on a freshly upgraded `WebSocket`.
+    async fn challenge_websocket(&self, ws: &mut WebSocket) -> Result<Option<Arc<User>>, ()> {
+        match server_challenge(ws).await {
+            Ok((challenge, response)) => self
+                .verify_challenge(&challenge, &response)
+                .and_then(|user| self.with_client_cert(user)),
+            Err(err) => {
+                warn!("PSK challenge failed: {err}");
+                Err(())
+            }
+        }
+    }
+
+    /// The real client address, taking trusted proxies into account.
+    fn client_ip(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        if self.unix_peer {
+            return proxied_client_ip(headers, self.trusted_proxies);
+        }
+        self.remote_addr
+            .map(|peer| client_ip(peer.ip(), headers, self.trusted_proxies))
+    }
+
+    /// The real client address for logging.
+    fn client_addr(&self, headers: &HeaderMap) -> String {
+        self.client_ip(headers)
+            .map_or_else(|| "unknown client".to_string(), |ip| ip.to_string())
+    }
+
+    /// Whether the client is in one of the `--allow-client-cidr` networks.
+    fn client_allowed(&self, headers: &HeaderMap) -> bool {
+        if self.allowed_client_cidrs.is_empty() {
+            return true;
+        }
+        self.client_ip(headers).is_some_and(|ip| {
+            // Dual-stack listeners see IPv4 clients as `::ffff:a.b.c.d`
+            let ip = ip.to_canonical();
+            self.allowed_client_cidrs
+                .iter()
+                .any(|cidr| cidr.contains(&ip))
+        })
+    }
+
     /// Check the PSK and protocol version and upgrade to a WebSocket if the PSK matches (if required).
     pub async fn ws_handler(self, mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
         let on_upgrade = req.extensions_mut().remove::<OnUpgrade>();
@@ -154,55 +587,189 @@ impl State<'static> {
         let sec_websocket_key = headers.get(header::SEC_WEBSOCKET_KEY);
         let sec_websocket_protocol = headers.get(header::SEC_WEBSOCKET_PROTOCOL);
         let sec_websocket_version = headers.get(header::SEC_WEBSOCKET_VERSION);
-        let x_penguin_psk = headers.get("x-penguin-psk");
+        let client = self.client_addr(headers);
+        let client_ip = self.client_ip(headers);
+        let ban_key = self.bans.clone().zip(client_ip);
 
+        if ban_key
+            .as_ref()
+            .is_some_and(|(bans, ip)| bans.is_banned(*ip))
+        {
+            debug!("Rejecting WebSocket request from banned {client}");
+            return self.not_found_handler();
+        }
+        if !self.client_allowed(headers) {
+            warn!("Invalid WebSocket request from {client}: address not allowed");
+            return self.backend_or_404_handler(req).await;
+        }
         if req.method() != Method::GET {
-            warn!("Invalid WebSocket request: not a GET request");
+            warn!("Invalid WebSocket request from {client}: not a GET request");
             return self.backend_or_404_handler(req).await;
         }
-        if self.ws_psk.is_some() && x_penguin_psk != self.ws_psk {
-            warn!("Invalid WebSocket request: invalid PSK {x_penguin_psk:?}");
+        if !self.cors.allows_upgrade(headers) {
+            warn!(
+                "Invalid WebSocket request from {client}: origin {:?} not allowed",
+                headers.get(header::ORIGIN)
+            );
             return self.backend_or_404_handler(req).await;
         }
+        let authenticated = self.authenticate(headers).or_else(|()| {
+            PskToken::from_request(&req).map_or(Err(()), |token| {
+                self.verify_token(&token)
+                    .and_then(|user| self.with_client_cert(user))
+            })
+        });
+        let (user, needs_challenge) = match authenticated {
+            Ok(user) => {
+                if let Some((bans, ip)) = &ban_key {
+                    bans.record_success(*ip);
+                }
+                (user, false)
+            }
+            Err(()) if self.ws_psk_challenge => (None, true),
+            Err(()) => {
+                if let Some((bans, ip)) = &ban_key {
+                    bans.record_failure(*ip);
+                }
+                // Only the user name of the users file, never a secret
+                let user = self
+                    .users
+                    .as_ref()
+                    .and_then(|_| headers.get("x-penguin-psk")?.to_str().ok())
+                    .and_then(|credential| credential.split_once(':'));
+                match user {
+                    Some((name, _)) => warn!(
+                        "Invalid WebSocket request from {client}: authentication failed for user {name:?}"
+                    ),
+                    None => warn!("Invalid WebSocket request from {client}: authentication failed"),
+                }
+                return self.backend_or_404_handler(req).await;
+            }
+        };
         let Some(sec_websocket_key) = sec_websocket_key else {
-            warn!("Invalid WebSocket request: no `sec-websocket-key` header");
+            warn!("Invalid WebSocket request from {client}: no `sec-websocket-key` header");
             return self.backend_or_404_handler(req).await;
         };
         if !header_matches!(connection, UPGRADE)
             || !header_matches!(upgrade, WEBSOCKET)
             || !header_matches!(sec_websocket_version, WEBSOCKET_VERSION)
-            || !header_matches!(sec_websocket_protocol, WANTED_PROTOCOL)
         {
             return self.backend_or_404_handler(req).await;
         }
+        let Some((protocol, frame_version)) =
+            sec_websocket_protocol.and_then(|offered| proto_version::choose(offered.as_bytes()))
+        else {
+            warn!(
+                "Invalid WebSocket request from {client}: unsupported protocol {sec_websocket_protocol:?}"
+            );
+            return self.backend_or_404_handler(req).await;
+        };
         let Some(on_upgrade) = on_upgrade else {
             error!("Empty `on_upgrade`");
             return self.backend_or_404_handler(req).await;
         };
+        let conn_guard = match &self.conn_limiter {
+            Some(conn_limiter) => {
+                let Some(conn_guard) = conn_limiter.acquire(client_ip) else {
+                    warn!("Rejecting WebSocket request from {client}: too many connections");
+                    return self.too_many_requests_handler();
+                };
+                Some(conn_guard)
+            }
+            None => None,
+        };
 
         // Now we know it's a valid WebSocket request, so we can upgrade to a WebSocket.
-        debug!("Upgrading to WebSocket");
+        if let Some(user) = &user {
+            debug!(user = %user.name, peer = %client, "Upgrading to WebSocket");
+        } else {
+            debug!(peer = %client, "Upgrading to WebSocket");
+        }
 
         let sec_websocket_accept = make_sec_websocket_accept(sec_websocket_key);
+        let span = debug_span!(parent: None, "tunnel", %client);
+        crate::otel::set_parent(&span, headers);
 
         tokio::spawn(async move {
             match on_upgrade.await {
                 Ok(upgraded) => {
-                    let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
-                    handle_websocket(ws).await;
+                    let max_frame_size = self.mux_options.capabilities.max_frame_size;
+                    let mut ws = crate::ws::accept(upgraded, max_frame_size).await;
+                    let user = if needs_challenge {
+                        let Ok(user) = self.challenge_websocket(&mut ws).await else {
+                            if let Some((bans, ip)) = ban_key {
+                                bans.record_failure(ip);
+                            }
+                            warn!(
+                                "Invalid WebSocket request from {client}: wrong PSK challenge response"
+                            );
+                            ws.close().await.ok();
+                            return;
+                        };
+                        user
+                    } else {
+                        user
+                    };
+                    let cipher = match self.noise_key {
+                        Some(noise_key) => {
+                            match server_handshake(
+                                &mut ws,
+                                noise_key,
+                                self.noise_client_keys,
+                                protocol.as_bytes(),
+                            )
+                            .await
+                            {
+                                Ok(cipher) => Some(cipher),
+                                Err(err) => {
+                                    if let Some((bans, ip)) = ban_key {
+                                        bans.record_failure(ip);
+                                    }
+                                    warn!("Invalid WebSocket request from {client}: {err}");
+                                    ws.close().await.ok();
+                                    return;
+                                }
+                            }
+                        }
+                        None => None,
+                    };
+                    let ws = NoiseTransport::new(ws, cipher);
+                    let auditor = Auditor {
+                        user: user.as_ref().map(|user| user.name.clone()),
+                        client: client_ip,
+                        log: self.audit_log.clone(),
+                    };
+                    handle_websocket(
+                        ws,
+                        user,
+                        auditor,
+                        self.throughput.dupe(),
+                        MuxOptions {
+                            frame_version,
+                            ..self.mux_options.clone()
+                        },
+                        self.udp_config,
+                        self.tun.clone(),
+                        self.egress.dupe(),
+                        self.shutdown.clone(),
+                        self.dump.clone(),
+                    )
+                    .await;
                 }
                 Err(err) => {
                     error!("Failed to upgrade to WebSocket: {err}");
                 }
             };
-        });
+            // The tunnel is closed now
+            drop(conn_guard);
+        }.instrument(span));
 
         // Shouldn't panic
         Ok(Response::builder()
             .status(StatusCode::SWITCHING_PROTOCOLS)
             .header(header::CONNECTION, &UPGRADE)
             .header(header::UPGRADE, &WEBSOCKET)
-            .header(header::SEC_WEBSOCKET_PROTOCOL, &WANTED_PROTOCOL)
+            .header(header::SEC_WEBSOCKET_PROTOCOL, protocol)
             .header(header::SEC_WEBSOCKET_ACCEPT, sec_websocket_accept)
             .body(Body::empty())
             .expect("Failed to build WebSocket response (this is a bug)"))
@@ -219,7 +786,78 @@ impl Service<Request<Body>> for State<'static> {
     }
 
     /// Hyper service handler
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        token::take_from_query(&mut req);
+        let access_log = self.access_log.clone();
+        let entry = access_log
+            .as_ref()
+            .map(|_| Entry::new(&req, self.client_ip(req.headers())));
+        let fut = if self.rate_limited(&req) {
+            let resp = self.dupe().too_many_requests_handler();
+            Box::pin(async { resp })
+        } else {
+            self.cors_route(req)
+        };
+        let server_header = self.server_header.clone();
+        if server_header.is_none() && access_log.is_none() {
+            return fut;
+        }
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            if let Some(server_header) = server_header {
+                resp.headers_mut()
+                    .entry(header::SERVER)
+                    .or_insert(server_header);
+            }
+            if let (Some(access_log), Some(entry)) = (access_log, entry) {
+                if !Entry::is_tunnel(&resp) {
+                    access_log.log(&entry, &resp);
+                }
+            }
+            Ok(resp)
+        })
+    }
+}
+
+impl State<'static> {
+    /// Whether the request is to `/ws`, `/health`, or `/version` and the
+    /// client exceeded `--rate-limit`.
+    fn rate_limited(&self, req: &Request<Body>) -> bool {
+        let Some(limiter) = &self.rate_limiter else {
+            return false;
+        };
+        if !matches!(req.uri().path(), "/ws" | "/health" | "/version") {
+            return false;
+        }
+        self.client_ip(req.headers())
+            .is_some_and(|ip| !limiter.check(ip))
+    }
+
+    /// Answer CORS preflight requests and add CORS headers to the responses
+    /// of the endpoints browsers may call
+    fn cors_route(&self, req: Request<Body>) -> <Self as Service<Request<Body>>>::Future {
+        let path = req.uri().path();
+        let is_cors_endpoint =
+            path == "/ws" || (!self.obfs && (path == "/health" || path == "/version"));
+        if !is_cors_endpoint || !self.cors.is_enabled() {
+            return self.route(req);
+        }
+        if req.method() == Method::OPTIONS {
+            let resp = self.cors.preflight(&req);
+            return Box::pin(async { Ok(resp) });
+        }
+        let cors = self.cors;
+        let origin = cors.allowed_origin(req.headers());
+        let fut = self.route(req);
+        Box::pin(async move {
+            let mut resp = fut.await?;
+            cors.add_headers(origin, resp.headers_mut());
+            Ok(resp)
+        })
+    }
+
+    /// Dispatch the request to the right handler
+    fn route(&self, req: Request<Body>) -> <Self as Service<Request<Body>>>::Future {
         // Only allow `/health` and `/version` if not obfuscating
         if req.uri().path() == "/health" && !self.obfs {
             return Box::pin(async { Ok(Response::new(Body::from("OK"))) });
@@ -246,7 +884,7 @@ impl Dupe for MakeStateService {
     }
 }
 
-impl<T> Service<T> for MakeStateService {
+impl Service<&AddrStream> for MakeStateService {
     type Response = State<'static>;
     type Error = Infallible;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
@@ -255,15 +893,133 @@ impl<T> Service<T> for MakeStateService {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _: T) -> Self::Future {
-        let cloned_self = self.dupe();
-        Box::pin(async { Ok(cloned_self.0) })
+    fn call(&mut self, conn: &AddrStream) -> Self::Future {
+        let mut state = self.0.dupe();
+        state.remote_addr = Some(conn.remote_addr());
+        Box::pin(async { Ok(state) })
+    }
+}
+
+impl Service<&TlsStream> for MakeStateService {
+    type Response = State<'static>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, conn: &TlsStream) -> Self::Future {
+        let mut state = self.0.dupe();
+        state.tls_info = Some(conn.info());
+        state.remote_addr = Some(conn.remote_addr());
+        Box::pin(async { Ok(state) })
+    }
+}
+
+#[cfg(unix)]
+impl Service<&tokio::net::UnixStream> for MakeStateService {
+    type Response = State<'static>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _conn: &tokio::net::UnixStream) -> Self::Future {
+        let mut state = self.0.dupe();
+        state.unix_peer = true;
+        Box::pin(async { Ok(state) })
+    }
+}
+
+/// The tunnel endpoint as a `tower` [`Service`]. Create it with
+/// [`ws_route`](super::ws_route).
+#[derive(Clone, Debug)]
+pub struct WsRoute(State<'static>);
+
+impl Dupe for WsRoute {
+    fn dupe(&self) -> Self {
+        Self(self.0.dupe())
+    }
+}
+
+impl WsRoute {
+    pub(super) const fn new(state: State<'static>) -> Self {
+        Self(state)
+    }
+
+    /// Set the address of the peer of the HTTP connection, which bans,
+    /// rate limits, `--allow-client-cidr`, and the logs use.
+    #[must_use]
+    pub fn with_remote_addr(mut self, addr: SocketAddr) -> Self {
+        self.0.remote_addr = Some(addr);
+        self
+    }
+}
+
+impl Service<Request<Body>> for WsRoute {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        self.0.call(req)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::arg::BackendRoute;
+    use crate::server::auth::Proto;
+    use once_cell::sync::Lazy;
+    use std::str::FromStr;
+
+    fn test_state() -> State<'static> {
+        State {
+            backends: Arc::default(),
+            www: None,
+            ws_psk: None,
+            ws_psk_totp: None,
+            users: None,
+            jwt: None,
+            ws_psk_challenge: false,
+            token_max_ttl: crate::config::TOKEN_MAX_TTL,
+            noise_key: None,
+            noise_client_keys: &[],
+            tls_info: None,
+            tls_client_cert_auth: false,
+            remote_addr: None,
+            unix_peer: false,
+            trusted_proxies: &[],
+            allowed_client_cidrs: &[],
+            bans: None,
+            rate_limiter: None,
+            conn_limiter: None,
+            access_log: None,
+            audit_log: None,
+            throughput: Throughput::default(),
+            mux_options: MuxOptions::default(),
+            udp_config: UdpSessionConfig::default(),
+            tun: None,
+            egress: Egress::default(),
+            not_found_resp: "not found in the test",
+            not_found_content_type: None,
+            camouflage: None,
+            server_header: None,
+            cors: Cors::default(),
+            obfs: false,
+            client: Arc::new(BackendClients::new()),
+            shutdown: ShutdownWatch::default(),
+            dump: DumpSignal::default(),
+        }
+    }
 
     #[test]
     fn test_make_sec_websocket_accept() {
@@ -277,16 +1033,59 @@ mod test {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_select_backend() {
+        static BACKEND: Lazy<Vec<BackendUrl>> =
+            Lazy::new(|| vec!["http://default".parse().unwrap()]);
+        static ROUTES: Lazy<Vec<BackendRoute>> = Lazy::new(|| {
+            vec![
+                "blog.example.com=http://blog".parse().unwrap(),
+                "::1=http://v6".parse().unwrap(),
+            ]
+        });
+        let state = State {
+            backends: Arc::new(BackendPool::new(&BACKEND, &ROUTES)),
+            ..test_state()
+        };
+        let make_req = |host: &str| {
+            Request::builder()
+                .uri("/")
+                .header(header::HOST, host)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let selected =
+            |state: &State<'static>, req| state.select_backend(&req).unwrap().to_string();
+        assert_eq!(
+            selected(&state, make_req("blog.example.com")),
+            "http://blog/"
+        );
+        assert_eq!(
+            selected(&state, make_req("BLOG.example.com:443")),
+            "http://blog/"
+        );
+        assert_eq!(selected(&state, make_req("[::1]:80")), "http://v6/");
+        assert_eq!(selected(&state, make_req("example.com")), "http://default/");
+        // SNI takes precedence over `Host`
+        let tls_info = TlsConnInfo::default();
+        tls_info
+            .server_name
+            .set("blog.example.com".to_string())
+            .unwrap();
+        let state = State {
+            tls_info: Some(Arc::new(tls_info)),
+            ..state
+        };
+        assert_eq!(selected(&state, make_req("example.com")), "http://blog/");
+        assert!(test_state()
+            .select_backend(&make_req("blog.example.com"))
+            .is_none());
+    }
+
     #[tokio::test]
     async fn test_obfs_or_not() {
         // Test `/health` without obfuscation
-        let mut state = State {
-            ws_psk: None,
-            backend: None,
-            not_found_resp: "not found in the test",
-            obfs: false,
-            client: Arc::new(Client::builder().build(make_client_https())),
-        };
+        let mut state = test_state();
         let req = Request::builder()
             .method(Method::GET)
             .uri("http://example.com/health")
@@ -298,11 +1097,8 @@ mod test {
         assert_eq!(body_bytes, "OK");
         // Test `/health` with obfuscation
         let mut state = State {
-            ws_psk: None,
-            backend: None,
-            not_found_resp: "not found in the test",
             obfs: true,
-            client: Arc::new(Client::builder().build(make_client_https())),
+            ..test_state()
         };
         let req = Request::builder()
             .method(Method::GET)
@@ -314,13 +1110,7 @@ mod test {
         let body_bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
         assert_eq!(body_bytes, "not found in the test");
         // Test `/version` without obfuscation
-        let mut state = State {
-            ws_psk: None,
-            backend: None,
-            not_found_resp: "not found in the test",
-            obfs: false,
-            client: Arc::new(Client::builder().build(make_client_https())),
-        };
+        let mut state = test_state();
         let req = Request::builder()
             .method(Method::GET)
             .uri("http://example.com/version")
@@ -332,11 +1122,8 @@ mod test {
         assert_eq!(body_bytes, env!("CARGO_PKG_VERSION"));
         // Test `/version` with obfuscation
         let mut state = State {
-            ws_psk: None,
-            backend: None,
-            not_found_resp: "not found in the test",
             obfs: true,
-            client: Arc::new(Client::builder().build(make_client_https())),
+            ..test_state()
         };
         let req = Request::builder()
             .method(Method::GET)
@@ -349,6 +1136,113 @@ mod test {
         assert_eq!(body_bytes, "not found in the test");
     }
 
+    #[tokio::test]
+    async fn test_ban_after_failures() {
+        static PSK: HeaderValue = HeaderValue::from_static("correct PSK");
+        let mut state = State {
+            ws_psk: Some(&PSK),
+            bans: Some(Arc::new(BanList::new(2, Duration::from_secs(60)))),
+            remote_addr: Some("192.0.2.1:4711".parse().unwrap()),
+            ..test_state()
+        };
+        let make_req = |psk: &'static str| {
+            Request::get("http://example.com/ws")
+                .header("connection", "upgrade")
+                .header("upgrade", "websocket")
+                .header("sec-websocket-version", "13")
+                .header("sec-websocket-protocol", proto_version::PROTOCOL_VERSION)
+                .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
+                .header("x-penguin-psk", psk)
+                .body(Body::empty())
+                .unwrap()
+        };
+        for _ in 0..2 {
+            let resp = state.call(make_req("wrong PSK")).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+        // Banned, even with the right PSK
+        let resp = state.call(make_req("correct PSK")).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let bans = state.bans.as_ref().unwrap();
+        assert!(bans.is_banned("192.0.2.1".parse().unwrap()));
+        // Other clients are not affected
+        assert!(!bans.is_banned("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_client_allowed() {
+        static CIDRS: Lazy<Vec<IpNet>> = Lazy::new(|| {
+            vec![
+                "192.0.2.0/24".parse().unwrap(),
+                "2001:db8::/32".parse().unwrap(),
+            ]
+        });
+        let mut state = State {
+            allowed_client_cidrs: &CIDRS,
+            ..test_state()
+        };
+        let headers = HeaderMap::new();
+        // Unknown peer
+        assert!(!state.client_allowed(&headers));
+        state.remote_addr = Some("192.0.2.9:4711".parse().unwrap());
+        assert!(state.client_allowed(&headers));
+        state.remote_addr = Some("[2001:db8::1]:4711".parse().unwrap());
+        assert!(state.client_allowed(&headers));
+        state.remote_addr = Some("198.51.100.1:4711".parse().unwrap());
+        assert!(!state.client_allowed(&headers));
+        // IPv4-mapped IPv6 addresses match the IPv4 networks
+        state.remote_addr = Some("[::ffff:192.0.2.9]:4711".parse().unwrap());
+        assert!(state.client_allowed(&headers));
+        state.remote_addr = Some("[::ffff:198.51.100.1]:4711".parse().unwrap());
+        assert!(!state.client_allowed(&headers));
+        assert!(test_state().client_allowed(&headers));
+    }
+
+    #[tokio::test]
+    async fn test_camouflage() {
+        let mut state = State {
+            obfs: true,
+            not_found_resp: Camouflage::Nginx.not_found_page(),
+            not_found_content_type: Some(Camouflage::Nginx.error_content_type()),
+            camouflage: Some(Camouflage::Nginx),
+            server_header: Some(HeaderValue::from_static(Camouflage::Nginx.server_header())),
+            ..test_state()
+        };
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("http://example.com/version")
+            .body(Body::empty())
+            .unwrap();
+        let resp = state.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.headers()[header::SERVER], "nginx");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/html");
+        let body_bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(std::str::from_utf8(&body_bytes)
+            .unwrap()
+            .contains("<hr><center>nginx</center>"));
+        // Other errors get the error pages too
+        let state = State {
+            obfs: false,
+            ..state
+        };
+        let resp = state.too_many_requests_handler().unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/html");
+        let body_bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(std::str::from_utf8(&body_bytes)
+            .unwrap()
+            .contains("<h1>429 Too Many Requests</h1>"));
+        // No `Server` header by default
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("http://example.com/")
+            .body(Body::empty())
+            .unwrap();
+        let resp = test_state().call(req).await.unwrap();
+        assert!(resp.headers().get(header::SERVER).is_none());
+    }
+
     #[cfg(any(feature = "tests-real-internet4", feature = "tests-real-internet6"))]
     #[tokio::test]
     async fn test_backend() {
@@ -358,11 +1252,8 @@ mod test {
             Lazy::new(|| BackendUrl::from_str("http://httpbin.org").unwrap());
         // Test that the backend is actually working
         let mut state = State {
-            ws_psk: None,
-            backend: Some(&BACKEND),
-            not_found_resp: "not found in the test",
-            obfs: false,
-            client: Arc::new(Client::builder().build(make_client_https())),
+            backends: Arc::new(BackendPool::new(std::slice::from_ref(&BACKEND), &[])),
+            ..test_state()
         };
         let req = Request::builder()
             .method(Method::GET)
@@ -372,11 +1263,8 @@ mod test {
         let resp = state.call(req).await.unwrap();
         assert_eq!(resp.status(), StatusCode::OK);
         let mut state = State {
-            ws_psk: None,
-            backend: Some(&BACKEND),
-            not_found_resp: "not found in the test",
-            obfs: false,
-            client: Arc::new(Client::builder().build(make_client_https())),
+            backends: Arc::new(BackendPool::new(std::slice::from_ref(&BACKEND), &[])),
+            ..test_state()
         };
         let req = Request::builder()
             .method(Method::GET)
@@ -388,21 +1276,80 @@ mod test {
     }
 
     #[tokio::test]
-    async fn test_stealth_websocket_upgrade_from_request_parts() {
-        // Test missing upgrade header
+    async fn test_h2c_backend() {
+        use hyper::service::{make_service_fn, service_fn};
+        static BACKEND: Lazy<BackendUrl> = Lazy::new(|| "h2c://127.0.0.1:24372".parse().unwrap());
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+                let body = format!("{:?} {}", req.version(), req.uri().path());
+                Ok::<_, Infallible>(Response::new(Body::from(body)))
+            }))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 24372).into())
+            .http2_only(true)
+            .serve(make_svc);
+        let server_task = tokio::spawn(server);
         let mut state = State {
-            ws_psk: None,
-            backend: None,
-            not_found_resp: "not found in the test",
-            obfs: false,
-            client: Arc::new(Client::builder().build(make_client_https())),
+            backends: Arc::new(BackendPool::new(std::slice::from_ref(&BACKEND), &[])),
+            ..test_state()
+        };
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("http://example.com/grpc.Service/Method")
+            .body(Body::empty())
+            .unwrap();
+        let resp = state.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body_bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body_bytes, "HTTP/2.0 /grpc.Service/Method");
+        server_task.abort();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_backend() {
+        use hyper::service::service_fn;
+        let tmpdir = tempfile::tempdir().unwrap();
+        let socket = tmpdir.path().join("backend.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let server_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|req: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(Body::from(req.uri().to_string())))
+            });
+            hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .await
+                .unwrap();
+        });
+        let backend = BackendUrl::from_str(&format!("unix:{}", socket.display())).unwrap();
+        let backend: &'static BackendUrl = Box::leak(Box::new(backend));
+        let mut state = State {
+            backends: Arc::new(BackendPool::new(std::slice::from_ref(backend), &[])),
+            ..test_state()
         };
+        let req = Request::builder()
+            .method(Method::GET)
+            .uri("http://example.com/foo?bar")
+            .body(Body::empty())
+            .unwrap();
+        let resp = state.call(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body_bytes = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body_bytes, "/foo?bar");
+        server_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stealth_websocket_upgrade_from_request_parts() {
+        // Test missing upgrade header
+        let mut state = test_state();
         let req = Request::builder()
             .method(Method::GET)
             .header("connection", "UpGrAdE")
             .header("upgrade", "WEBSOCKET")
             .header("sec-websocket-version", "13")
-            .header("sec-websocket-protocol", &WANTED_PROTOCOL)
+            .header("sec-websocket-protocol", proto_version::PROTOCOL_VERSION)
             .body(Body::empty())
             .unwrap();
         let result = state.call(req).await.unwrap();
@@ -413,17 +1360,14 @@ mod test {
         static PSK: HeaderValue = HeaderValue::from_static("correct PSK");
         let mut state = State {
             ws_psk: Some(&PSK),
-            backend: None,
-            not_found_resp: "not found in the test",
-            obfs: false,
-            client: Arc::new(Client::builder().build(make_client_https())),
+            ..test_state()
         };
         let req = Request::builder()
             .method(Method::GET)
             .header("connection", "UpGrAdE")
             .header("upgrade", "WEBSOCKET")
             .header("sec-websocket-version", "13")
-            .header("sec-websocket-protocol", &WANTED_PROTOCOL)
+            .header("sec-websocket-protocol", proto_version::PROTOCOL_VERSION)
             .header("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ==")
             .header("x-penguin-psk", "wrong PSK")
             .body(Body::empty())
@@ -433,4 +1377,255 @@ mod test {
         let body_bytes = hyper::body::to_bytes(result.into_body()).await.unwrap();
         assert_eq!(body_bytes, "not found in the test");
     }
+
+    #[test]
+    fn test_authenticate() {
+        static PSK: HeaderValue = HeaderValue::from_static("correct PSK");
+        fn psk_headers(psk: &'static str) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-penguin-psk", HeaderValue::from_static(psk));
+            headers
+        }
+        let users: UserDb = "alice:s3cret example.com:443".parse().unwrap();
+        // No authentication configured
+        let state = test_state();
+        assert!(matches!(state.authenticate(&HeaderMap::new()), Ok(None)));
+        // Only `--ws-psk`
+        let state = State {
+            ws_psk: Some(&PSK),
+            ..test_state()
+        };
+        assert!(matches!(
+            state.authenticate(&psk_headers("correct PSK")),
+            Ok(None)
+        ));
+        assert!(state.authenticate(&HeaderMap::new()).is_err());
+        // Only `--ws-psk-totp`
+        let totp: &'static TotpSecret = Box::leak(Box::new("GEZDGNBVGY3TQOJQ".parse().unwrap()));
+        let state = State {
+            ws_psk_totp: Some(totp),
+            ..test_state()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-penguin-psk", totp.current_psk());
+        assert!(matches!(state.authenticate(&headers), Ok(None)));
+        assert!(state.authenticate(&psk_headers("correct PSK")).is_err());
+        let challenge = Challenge::new();
+        let response = challenge.respond(totp.current_psk().as_bytes());
+        assert!(matches!(
+            state.verify_challenge(&challenge, &response),
+            Ok(None)
+        ));
+        // Both `--ws-psk` and a users file
+        let state = State {
+            ws_psk: Some(&PSK),
+            users: Some(Arc::new(users)),
+            ..test_state()
+        };
+        assert!(matches!(
+            state.authenticate(&psk_headers("correct PSK")),
+            Ok(None)
+        ));
+        let user = state
+            .authenticate(&psk_headers("alice:s3cret"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.name, "alice");
+        assert!(state.authenticate(&psk_headers("alice:wrong")).is_err());
+        assert!(state.authenticate(&HeaderMap::new()).is_err());
+        // Only JWT
+        let state = State {
+            jwt: Some(Arc::new(JwtValidator::from_secret(b"secret", None, None))),
+            ..test_state()
+        };
+        assert!(state.authenticate(&HeaderMap::new()).is_err());
+        assert!(state.authenticate(&psk_headers("alice:s3cret")).is_err());
+        let mut headers = HeaderMap::new();
+        // {"sub": "carol", "exp": 4102444800} signed with "secret"
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static(
+                "Bearer eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.\
+                 eyJzdWIiOiJjYXJvbCIsImV4cCI6NDEwMjQ0NDgwMH0.\
+                 GUEFyjhuVpTtoM67QjXk1E_vmVceLA3IIQNd-_O3NwE",
+            ),
+        );
+        let user = state.authenticate(&headers).unwrap().unwrap();
+        assert_eq!(user.name, "carol");
+        // Client certificate with a matching user
+        let users: UserDb = "alice:s3cret example.com:443\nbob:hunter2".parse().unwrap();
+        let users = Arc::new(users);
+        let tls_info = Arc::new(TlsConnInfo::default());
+        let state = State {
+            ws_psk: Some(&PSK),
+            users: Some(users.clone()),
+            tls_info: Some(tls_info.clone()),
+            ..test_state()
+        };
+        assert!(state.authenticate(&HeaderMap::new()).is_err());
+        tls_info.client_cert_name.set("alice".to_string()).unwrap();
+        // The PSK is still required
+        assert!(state.authenticate(&HeaderMap::new()).is_err());
+        let mut headers = HeaderMap::new();
+        headers.insert("x-penguin-psk", PSK.clone());
+        let user = state.authenticate(&headers).unwrap().unwrap();
+        assert_eq!(user.name, "alice");
+        assert!(!user.may_connect("example.com", 80, Proto::Tcp));
+        let user = state
+            .authenticate(&psk_headers("alice:s3cret"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.name, "alice");
+        // The credentials must not name another user
+        assert!(state.authenticate(&psk_headers("bob:hunter2")).is_err());
+        // Unless the certificate is enough on its own
+        let state = State {
+            tls_client_cert_auth: true,
+            ..state
+        };
+        let user = state.authenticate(&HeaderMap::new()).unwrap().unwrap();
+        assert_eq!(user.name, "alice");
+        // Client certificate without a matching user
+        let tls_info = TlsConnInfo::default();
+        tls_info.client_cert_name.set("dave".to_string()).unwrap();
+        let state = State {
+            ws_psk: Some(&PSK),
+            users: Some(users),
+            tls_info: Some(Arc::new(tls_info)),
+            ..test_state()
+        };
+        assert!(state.authenticate(&headers).is_err());
+        let state = State {
+            tls_client_cert_auth: true,
+            ..state
+        };
+        assert!(state.authenticate(&HeaderMap::new()).is_err());
+        assert!(state.authenticate(&headers).is_err());
+    }
+
+    /// Headers with a bearer token for `sub`, signed with "secret"
+    fn bearer_headers(sub: &str) -> HeaderMap {
+        let claims = serde_json::json!({ "sub": sub, "exp": 4_102_444_800u64 });
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_authenticate_jwt_with_users() {
+        let users: UserDb = "alice:s3cret example.com:443".parse().unwrap();
+        let state = State {
+            users: Some(Arc::new(users)),
+            jwt: Some(Arc::new(JwtValidator::from_secret(b"secret", None, None))),
+            ..test_state()
+        };
+        // The token gets the restrictions of the users file
+        let user = state
+            .authenticate(&bearer_headers("alice"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.name, "alice");
+        assert!(!user.is_unrestricted());
+        assert!(user.may_connect("example.com", 443, Proto::Tcp));
+        assert!(!user.may_connect("example.com", 80, Proto::Tcp));
+        // Users not in the file are rejected
+        assert!(state.authenticate(&bearer_headers("carol")).is_err());
+    }
+
+    #[test]
+    fn test_authenticate_client_cert_and_jwt() {
+        let users: UserDb = "alice:s3cret example.com:443\nbob:hunter2".parse().unwrap();
+        let tls_info = TlsConnInfo::default();
+        tls_info.client_cert_name.set("alice".to_string()).unwrap();
+        let state = State {
+            users: Some(Arc::new(users)),
+            jwt: Some(Arc::new(JwtValidator::from_secret(b"secret", None, None))),
+            tls_info: Some(Arc::new(tls_info)),
+            ..test_state()
+        };
+        // The ACL of the certificate's user still applies
+        let user = state
+            .authenticate(&bearer_headers("alice"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.name, "alice");
+        assert!(user.may_connect("example.com", 443, Proto::Tcp));
+        assert!(!user.may_connect("example.com", 80, Proto::Tcp));
+        // The token must not name another user
+        assert!(state.authenticate(&bearer_headers("bob")).is_err());
+    }
+
+    #[test]
+    fn test_verify_challenge() {
+        static PSK: HeaderValue = HeaderValue::from_static("correct:PSK");
+        let users: UserDb = "alice:s3cret".parse().unwrap();
+        let state = State {
+            ws_psk: Some(&PSK),
+            users: Some(Arc::new(users)),
+            ws_psk_challenge: true,
+            ..test_state()
+        };
+        let challenge = Challenge::new();
+        let response = challenge.respond(b"correct:PSK");
+        assert!(matches!(
+            state.verify_challenge(&challenge, &response),
+            Ok(None)
+        ));
+        let response = challenge.respond(b"alice:s3cret");
+        let user = state
+            .verify_challenge(&challenge, &response)
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.name, "alice");
+        let response = challenge.respond(b"alice:wrong");
+        assert!(state.verify_challenge(&challenge, &response).is_err());
+        let response = Challenge::new().respond(b"correct:PSK");
+        assert!(state.verify_challenge(&challenge, &response).is_err());
+    }
+
+    #[test]
+    fn test_verify_token() {
+        use super::super::token::make_token;
+        static PSK: HeaderValue = HeaderValue::from_static("correct PSK");
+        let users: UserDb = "alice:s3cret".parse().unwrap();
+        let state = State {
+            ws_psk: Some(&PSK),
+            users: Some(Arc::new(users)),
+            ..test_state()
+        };
+        let expiry = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 60;
+        let token = make_token(None, b"correct PSK", expiry);
+        let token = PskToken::parse(&token).unwrap();
+        assert!(matches!(state.verify_token(&token), Ok(None)));
+        let token = make_token(Some("alice"), b"alice:s3cret", expiry);
+        let token = PskToken::parse(&token).unwrap();
+        let user = state.verify_token(&token).unwrap().unwrap();
+        assert_eq!(user.name, "alice");
+        let token = make_token(Some("alice"), b"correct PSK", expiry);
+        assert!(state
+            .verify_token(&PskToken::parse(&token).unwrap())
+            .is_err());
+        let token = make_token(None, b"wrong PSK", expiry);
+        assert!(state
+            .verify_token(&PskToken::parse(&token).unwrap())
+            .is_err());
+        // Too far in the future
+        let token = make_token(None, b"correct PSK", expiry + crate::config::TOKEN_MAX_TTL);
+        assert!(state
+            .verify_token(&PskToken::parse(&token).unwrap())
+            .is_err());
+    }
 }
@@ -0,0 +1,102 @@
+//! Graceful shutdown on `SIGTERM` or `SIGINT`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::Error;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Tells the tunnels to shut down and waits for them to finish.
+#[derive(Debug)]
+pub(super) struct Shutdown {
+    tx: watch::Sender<bool>,
+}
+
+/// Held by each tunnel to learn about a shutdown.
+#[derive(Clone, Debug)]
+pub(super) struct ShutdownWatch {
+    rx: watch::Receiver<bool>,
+    /// How long streams may take to finish after a shutdown is requested
+    pub grace_period: Duration,
+}
+
+impl Default for ShutdownWatch {
+    /// A watch that never sees a shutdown.
+    fn default() -> Self {
+        Shutdown::new(Duration::ZERO).1
+    }
+}
+
+impl Shutdown {
+    pub fn new(grace_period: Duration) -> (Self, ShutdownWatch) {
+        let (tx, rx) = watch::channel(false);
+        (Self { tx }, ShutdownWatch { rx, grace_period })
+    }
+
+    /// Ask the tunnels to shut down.
+    pub fn start(&self) {
+        self.tx.send_replace(true);
+    }
+
+    /// Wait until every `ShutdownWatch` is dropped, i.e. all tunnels are
+    /// closed and no new ones can be created.
+    pub async fn drained(&self) {
+        self.tx.closed().await;
+    }
+}
+
+impl ShutdownWatch {
+    /// Wait until a shutdown is requested.
+    pub async fn requested(&mut self) {
+        loop {
+            if *self.rx.borrow_and_update() {
+                return;
+            }
+            if self.rx.changed().await.is_err() {
+                // No one can request a shutdown anymore
+                futures_util::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Wait for `SIGTERM` or `SIGINT`.
+/// The returned future is created after the handlers are registered.
+pub(super) fn signal() -> Result<impl std::future::Future<Output = ()>, Error> {
+    #[cfg(unix)]
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .map_err(Error::Signal)?;
+    Ok(async move {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+        #[cfg(not(unix))]
+        if tokio::signal::ctrl_c().await.is_err() {
+            // No console to receive it from, e.g. as a Windows service
+            futures_util::future::pending::<()>().await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (shutdown, watch) = Shutdown::new(Duration::from_secs(1));
+        let mut tunnel = watch.clone();
+        let tunnel = tokio::spawn(async move {
+            tunnel.requested().await;
+        });
+        // The watch `server_main` holds does not count once dropped
+        drop(watch);
+        shutdown.start();
+        tokio::time::timeout(Duration::from_secs(1), shutdown.drained())
+            .await
+            .unwrap();
+        tunnel.await.unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+//! Socket activation and readiness notification for systemd.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::time::Duration;
+#[cfg(unix)]
+use tracing::warn;
+
+/// Take the listening socket passed by systemd (`LISTEN_FDS`), if any.
+pub(super) fn take_listener() -> std::io::Result<Option<TcpListener>> {
+    let listener = listenfd::ListenFd::from_env().take_tcp_listener(0)?;
+    if let Some(listener) = &listener {
+        listener.set_nonblocking(true)?;
+    }
+    Ok(listener)
+}
+
+/// Tell systemd that we are ready, and keep its watchdog happy if it has
+/// one. Does nothing when not running under systemd.
+#[cfg(unix)]
+pub(super) fn notify_ready() {
+    use sd_notify::NotifyState;
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Cannot notify systemd: {err}");
+    }
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        // Ping twice per period, as recommended by `sd_watchdog_enabled(3)`
+        let mut interval = tokio::time::interval(Duration::from_micros(usec) / 2);
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                if let Err(err) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                    warn!("Cannot notify systemd watchdog: {err}");
+                }
+            }
+        });
+    }
+}
+
+/// Tell systemd that we are shutting down.
+#[cfg(unix)]
+pub(super) fn notify_stopping() {
+    sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]).ok();
+}
+
+#[cfg(not(unix))]
+pub(super) fn notify_ready() {}
+
+#[cfg(not(unix))]
+pub(super) fn notify_stopping() {}
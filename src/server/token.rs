@@ -0,0 +1,217 @@
+//! Signed PSK tokens for browser clients.
+//!
+//! Browsers cannot set custom headers on WebSocket connections, so the
+//! client may instead present a token in the `penguin-token` query
+//! parameter or cookie:
+//!
+//! ```text
+//! [<user>.]<expiry>.<mac>
+//! ```
+//!
+//! where `<expiry>` is a Unix timestamp in decimal, and `<mac>` is the
+//! unpadded base64url of `HMAC-SHA256(PSK, <expiry>)`. The `<user>` part
+//! names the user in the users file, whose PSK is `<user>:<secret>`.
+//!
+//! A token in the query string is moved out of the URI as soon as the
+//! request arrives, so that it is neither logged nor sent to backends.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64_URL_ENGINE;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use http::{header, Request, Uri};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Name of the query parameter and cookie.
+const TOKEN_NAME: &str = "penguin-token";
+
+/// A token taken out of the query string by [`take_from_query`].
+#[derive(Clone, Debug)]
+struct QueryToken(String);
+
+/// Move the token in the query string of `req`, if any, out of its URI.
+pub fn take_from_query<B>(req: &mut Request<B>) {
+    let Some(query) = req.uri().query() else {
+        return;
+    };
+    let mut token = None;
+    let rest = query
+        .split('&')
+        .filter(|pair| match pair.split_once('=') {
+            Some((name, value)) if name == TOKEN_NAME => {
+                token = Some(value.to_string());
+                false
+            }
+            _ => true,
+        })
+        .collect::<Vec<_>>()
+        .join("&");
+    let Some(token) = token else {
+        return;
+    };
+    let path_and_query = if rest.is_empty() {
+        req.uri().path().to_string()
+    } else {
+        format!("{}?{rest}", req.uri().path())
+    };
+    let mut parts = req.uri().clone().into_parts();
+    // `expect`: made of parts of a valid URI
+    parts.path_and_query = Some(
+        path_and_query
+            .parse()
+            .expect("Invalid path and query (this is a bug)"),
+    );
+    *req.uri_mut() = Uri::from_parts(parts).expect("Invalid URI (this is a bug)");
+    req.extensions_mut().insert(QueryToken(token));
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// A parsed token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PskToken<'a> {
+    /// User named in the token
+    pub user: Option<&'a str>,
+    expiry: &'a str,
+    mac: Vec<u8>,
+}
+
+fn mac(psk: &[u8], expiry: &str) -> HmacSha256 {
+    // `expect`: HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_from_slice(psk).expect("Invalid HMAC key (this is a bug)");
+    mac.update(expiry.as_bytes());
+    mac
+}
+
+impl<'a> PskToken<'a> {
+    /// Parse a token.
+    pub fn parse(token: &'a str) -> Option<Self> {
+        let (rest, mac) = token.rsplit_once('.')?;
+        let (user, expiry) = match rest.rsplit_once('.') {
+            Some((user, expiry)) => (Some(user), expiry),
+            None => (None, rest),
+        };
+        expiry.parse::<u64>().ok()?;
+        Some(Self {
+            user,
+            expiry,
+            mac: B64_URL_ENGINE.decode(mac).ok()?,
+        })
+    }
+
+    /// Find the token taken out of the query string by [`take_from_query`]
+    /// or in the cookies of a request.
+    pub fn from_request<B>(req: &'a Request<B>) -> Option<Self> {
+        let from_query = req
+            .extensions()
+            .get::<QueryToken>()
+            .map(|token| token.0.as_str());
+        let from_cookie = || {
+            req.headers()
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .find_map(|cookie| {
+                    let (name, value) = cookie.trim().split_once('=')?;
+                    (name == TOKEN_NAME).then_some(value)
+                })
+        };
+        Self::parse(from_query.or_else(from_cookie)?)
+    }
+
+    fn expiry(&self) -> u64 {
+        // `expect`: checked in `parse`
+        self.expiry.parse().expect("Invalid expiry (this is a bug)")
+    }
+
+    /// Check that the token expires at most `max_ttl` seconds from now.
+    pub fn expires_within(&self, max_ttl: u64) -> bool {
+        self.expiry() <= now().saturating_add(max_ttl)
+    }
+
+    /// Check that the token has not expired and was made with `psk`.
+    pub fn verify(&self, psk: &[u8]) -> bool {
+        self.expiry() > now() && mac(psk, self.expiry).verify_slice(&self.mac).is_ok()
+    }
+}
+
+/// Make a token for `psk` that expires at `expiry`.
+#[cfg(test)]
+pub fn make_token(user: Option<&str>, psk: &[u8], expiry: u64) -> String {
+    let expiry = expiry.to_string();
+    let mac = B64_URL_ENGINE.encode(mac(psk, &expiry).finalize().into_bytes());
+    match user {
+        Some(user) => format!("{user}.{expiry}.{mac}"),
+        None => format!("{expiry}.{mac}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::Body;
+
+    #[test]
+    fn test_parse_and_verify() {
+        let token = make_token(None, b"s3cret", now() + 60);
+        let parsed = PskToken::parse(&token).unwrap();
+        assert!(parsed.user.is_none());
+        assert!(parsed.verify(b"s3cret"));
+        assert!(!parsed.verify(b"wrong"));
+        let token = make_token(Some("alice"), b"alice:s3cret", now() + 60);
+        let parsed = PskToken::parse(&token).unwrap();
+        assert_eq!(parsed.user, Some("alice"));
+        assert!(parsed.verify(b"alice:s3cret"));
+        // Expired
+        let token = make_token(None, b"s3cret", now() - 1);
+        assert!(!PskToken::parse(&token).unwrap().verify(b"s3cret"));
+        assert!(PskToken::parse("garbage").is_none());
+        assert!(PskToken::parse("soon.AAAA").is_none());
+    }
+
+    #[test]
+    fn test_expires_within() {
+        let token = make_token(None, b"s3cret", now() + 60);
+        let parsed = PskToken::parse(&token).unwrap();
+        assert!(parsed.expires_within(3600));
+        assert!(!parsed.expires_within(10));
+        let token = make_token(None, b"s3cret", u64::MAX);
+        assert!(!PskToken::parse(&token).unwrap().expires_within(3600));
+    }
+
+    #[test]
+    fn test_from_request() {
+        let token = make_token(None, b"s3cret", now() + 60);
+        let mut req = Request::builder()
+            .uri(format!("/ws?foo=bar&penguin-token={token}"))
+            .body(Body::empty())
+            .unwrap();
+        take_from_query(&mut req);
+        assert_eq!(req.uri(), "/ws?foo=bar");
+        assert!(PskToken::from_request(&req).unwrap().verify(b"s3cret"));
+        let mut req = Request::builder()
+            .uri(format!("http://example.com/ws?penguin-token={token}"))
+            .body(Body::empty())
+            .unwrap();
+        take_from_query(&mut req);
+        assert_eq!(req.uri(), "http://example.com/ws");
+        assert!(PskToken::from_request(&req).unwrap().verify(b"s3cret"));
+        let req = Request::builder()
+            .uri("/ws")
+            .header(header::COOKIE, format!("a=b; penguin-token={token}"))
+            .body(Body::empty())
+            .unwrap();
+        assert!(PskToken::from_request(&req).unwrap().verify(b"s3cret"));
+        let req = Request::builder().uri("/ws").body(Body::empty()).unwrap();
+        assert!(PskToken::from_request(&req).is_none());
+    }
+}
@@ -0,0 +1,285 @@
+//! Routing of IP packets between the server's TUN device and the tunnels.
+//!
+//! Each tunnel owns the source addresses of the packets its client sends,
+//! and the packets the device reads for an address go back to that
+//! tunnel. The kernel does the rest, e.g. NAT with iptables MASQUERADE.
+//!
+//! A client may only send from its user's `tun=` prefixes, or else from
+//! the networks of the device, and only from a few addresses. A tunnel's
+//! addresses are freed when it closes. Like streams and UDP, the packets
+//! may only go where `--egress-allow` lets them.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::auth::User;
+use super::egress::Egress;
+use crate::config;
+use crate::tun::{self, Tun};
+use bytes::Bytes;
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use penguin_mux::DatagramFrame;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{debug, error, trace, warn};
+
+/// Addresses of the clients, and where to send the packets for them
+#[derive(Debug, Default)]
+struct Routes(HashMap<IpAddr, mpsc::Sender<DatagramFrame>>);
+
+impl Routes {
+    /// Let `tunnel` own `address` unless another open tunnel does, or
+    /// either `tunnel` or the table already has too many addresses.
+    fn learn(
+        &mut self,
+        address: IpAddr,
+        tunnel: &mpsc::Sender<DatagramFrame>,
+    ) -> Result<(), &'static str> {
+        match self.0.get(&address) {
+            Some(owner) if owner.same_channel(tunnel) => return Ok(()),
+            Some(owner) if !owner.is_closed() => return Err("belongs to another client"),
+            _ => {}
+        }
+        self.0.retain(|_, owner| !owner.is_closed());
+        if self.0.len() >= config::TUN_MAX_ROUTES {
+            return Err("cannot be routed, as the route table is full");
+        }
+        let owned = self.0.values().filter(|owner| owner.same_channel(tunnel));
+        if owned.count() >= config::TUN_ADDRESSES_PER_TUNNEL {
+            return Err("is one address too many for its client");
+        }
+        debug!("{address} is now routed to a tunnel");
+        self.0.insert(address, tunnel.clone());
+        Ok(())
+    }
+
+    /// The tunnel that owns `address`, forgetting it if it has closed.
+    fn lookup(&mut self, address: IpAddr) -> Option<mpsc::Sender<DatagramFrame>> {
+        let tunnel = self.0.get(&address)?;
+        if tunnel.is_closed() {
+            self.0.remove(&address);
+            return None;
+        }
+        Some(tunnel.clone())
+    }
+
+    /// Forget the addresses of `tunnel`.
+    fn forget(&mut self, tunnel: &mpsc::Sender<DatagramFrame>) {
+        self.0.retain(|_, owner| !owner.same_channel(tunnel));
+    }
+}
+
+/// Whether a client of `user` may send packets from `source` into a
+/// device with addresses `networks`.
+fn may_send_from(networks: &[IpNet], source: IpAddr, user: Option<&User>) -> bool {
+    // Never the device's own addresses
+    if networks.iter().any(|network| network.addr() == source) {
+        return false;
+    }
+    match user {
+        Some(user) if !user.tun_prefixes.is_empty() => user
+            .tun_prefixes
+            .iter()
+            .any(|prefix| prefix.contains(&source)),
+        _ => networks.iter().any(|network| network.contains(&source)),
+    }
+}
+
+/// The server's TUN device and the routes of its packets
+#[derive(Debug)]
+pub(super) struct TunRouter {
+    device: Tun,
+    /// Networks of the device, which clients without `tun=` prefixes may
+    /// send from
+    networks: Vec<IpNet>,
+    routes: Mutex<Routes>,
+    /// Where the packets may go
+    egress: Egress,
+}
+
+impl TunRouter {
+    /// Route packets through `device`, whose addresses are `networks`, to
+    /// the destinations that `egress` allows.
+    /// [`run`](Self::run) has to be spawned to send them back.
+    pub fn new(device: Tun, networks: &[IpNet], egress: Egress) -> Self {
+        Self {
+            device,
+            networks: networks.to_vec(),
+            routes: Mutex::new(Routes::default()),
+            egress,
+        }
+    }
+
+    /// Send a packet from the client of `tunnel`, which belongs to `user`,
+    /// to the device.
+    pub async fn forward(
+        &self,
+        packet: Bytes,
+        tunnel: &mpsc::Sender<DatagramFrame>,
+        user: Option<&User>,
+    ) {
+        let (Some((source, _)), Some(destination)) =
+            (tun::addresses(&packet), tun::destination(&packet))
+        else {
+            debug!("dropped an invalid IP packet");
+            return;
+        };
+        if !may_send_from(&self.networks, source, user) {
+            warn!("dropped an IP packet from {source}, which the client may not use");
+            return;
+        }
+        if !self.egress.allows(destination) {
+            warn!("dropped an IP packet to {destination}: not allowed by --egress-allow");
+            return;
+        }
+        if let Err(reason) = self.routes.lock().learn(source, tunnel) {
+            warn!("dropped an IP packet from {source}, which {reason}");
+            return;
+        }
+        if let Err(err) = self.device.send(&packet).await {
+            warn!("Failed to send IP packet to {}: {err}", self.device.name());
+        }
+    }
+
+    /// Stop routing packets to `tunnel`, which has closed.
+    pub fn forget(&self, tunnel: &mpsc::Sender<DatagramFrame>) {
+        self.routes.lock().forget(tunnel);
+    }
+
+    /// Send the packets the device reads to the clients they are for.
+    pub async fn run(self: Arc<Self>) {
+        let mut buf = vec![0; config::MAX_UDP_PACKET_SIZE];
+        loop {
+            let len = match self.device.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(err) => {
+                    error!(
+                        "Failed to receive IP packet from {}: {err}",
+                        self.device.name()
+                    );
+                    return;
+                }
+            };
+            let packet = &buf[..len];
+            let Some((_, destination)) = tun::addresses(packet) else {
+                continue;
+            };
+            let Some(tunnel) = self.routes.lock().lookup(destination) else {
+                trace!("no client has {destination}");
+                continue;
+            };
+            // Like a full link, drop packets if the tunnel is congested
+            if tunnel
+                .try_send(tun::packet_frame(Bytes::copy_from_slice(packet)))
+                .is_err()
+            {
+                trace!("dropped an IP packet for {destination}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::arg::EgressRule;
+    use once_cell::sync::Lazy;
+
+    #[test]
+    fn test_routes() {
+        let mut routes = Routes::default();
+        let address = IpAddr::from([10, 0, 0, 2]);
+        let (tunnel, rx) = mpsc::channel(1);
+        let (other, other_rx) = mpsc::channel(1);
+        assert!(routes.learn(address, &tunnel).is_ok());
+        assert!(routes.learn(address, &tunnel).is_ok());
+        assert!(routes.lookup(address).unwrap().same_channel(&tunnel));
+        // Another open tunnel cannot take the address
+        assert!(routes.learn(address, &other).is_err());
+        assert!(routes.lookup(IpAddr::from([10, 0, 0, 3])).is_none());
+        // But it can once the owner closes
+        drop(rx);
+        assert!(routes.learn(address, &other).is_ok());
+        assert!(routes.lookup(address).unwrap().same_channel(&other));
+        drop(other_rx);
+        assert!(routes.lookup(address).is_none());
+        assert!(routes.0.is_empty());
+    }
+
+    #[test]
+    fn test_may_send_from() {
+        let networks = ["10.0.0.1/24".parse().unwrap()];
+        assert!(may_send_from(&networks, IpAddr::from([10, 0, 0, 2]), None));
+        assert!(!may_send_from(&networks, IpAddr::from([10, 0, 0, 1]), None));
+        assert!(!may_send_from(&networks, IpAddr::from([10, 0, 1, 2]), None));
+        let mut user = User::unrestricted("alice".to_string());
+        user.tun_prefixes = vec!["10.0.0.8/30".parse().unwrap()];
+        assert!(may_send_from(
+            &networks,
+            IpAddr::from([10, 0, 0, 9]),
+            Some(&user)
+        ));
+        assert!(!may_send_from(
+            &networks,
+            IpAddr::from([10, 0, 0, 2]),
+            Some(&user)
+        ));
+        let user = User::unrestricted("bob".to_string());
+        assert!(may_send_from(
+            &networks,
+            IpAddr::from([10, 0, 0, 2]),
+            Some(&user)
+        ));
+    }
+
+    #[test]
+    fn test_egress() {
+        static ALLOW: Lazy<Vec<EgressRule>> = Lazy::new(|| {
+            vec![
+                "10.1.0.0/16:53".parse().unwrap(),
+                "10.2.0.0/16:*".parse().unwrap(),
+            ]
+        });
+        let egress = Egress {
+            allow: &ALLOW,
+            ..Default::default()
+        };
+        let packet = |protocol: u8, destination: [u8; 4], port: u16| {
+            let mut packet = [0u8; 28];
+            packet[0] = 0x45;
+            packet[9] = protocol;
+            packet[12..16].copy_from_slice(&[10, 0, 0, 2]);
+            packet[16..20].copy_from_slice(&destination);
+            packet[22..24].copy_from_slice(&port.to_be_bytes());
+            tun::destination(&packet).unwrap()
+        };
+        // UDP and TCP to an allowed port
+        assert!(egress.allows(packet(17, [10, 1, 0, 1], 53)));
+        assert!(egress.allows(packet(6, [10, 1, 0, 1], 53)));
+        assert!(!egress.allows(packet(17, [10, 1, 0, 1], 54)));
+        assert!(!egress.allows(packet(17, [10, 3, 0, 1], 53)));
+        // Other protocols only where every port is allowed
+        assert!(!egress.allows(packet(1, [10, 1, 0, 1], 53)));
+        assert!(egress.allows(packet(1, [10, 2, 0, 1], 53)));
+        assert!(Egress::default().allows(packet(1, [10, 3, 0, 1], 0)));
+    }
+
+    #[test]
+    fn test_routes_limits() {
+        let mut routes = Routes::default();
+        let (tunnel, _rx) = mpsc::channel(1);
+        let (other, _other_rx) = mpsc::channel(1);
+        for i in 0..config::TUN_ADDRESSES_PER_TUNNEL {
+            let address = IpAddr::from([10, 0, 1, u8::try_from(i).unwrap()]);
+            assert!(routes.learn(address, &tunnel).is_ok());
+        }
+        assert!(routes.learn(IpAddr::from([10, 0, 2, 0]), &tunnel).is_err());
+        assert!(routes.learn(IpAddr::from([10, 0, 2, 0]), &other).is_ok());
+        // A closed tunnel's addresses are freed
+        routes.forget(&tunnel);
+        assert_eq!(routes.0.len(), 1);
+        assert!(routes.learn(IpAddr::from([10, 0, 1, 0]), &other).is_ok());
+    }
+}
@@ -0,0 +1,292 @@
+//! UDP sessions of a tunnel: one socket for each client ID and destination,
+//! reused for all of the client's datagrams to it, closed when idle or when
+//! too many are open. Clients that ask for full-cone NAT get one socket for
+//! all their destinations instead.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::egress::Egress;
+use super::forwarder::{self, udp_forward_to};
+use crate::{config, Dupe};
+use bytes::Bytes;
+use penguin_mux::DatagramFrame;
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Settings of the UDP sessions of a tunnel
+#[derive(Clone, Copy, Debug)]
+pub struct UdpSessionConfig {
+    /// Sessions open at the same time. The least recently used one is
+    /// closed to make room for a new one.
+    pub max_sessions: usize,
+    /// Close sessions without datagrams in either direction for this long
+    pub idle_timeout: Duration,
+    /// Join the multicast groups and broadcast to the addresses clients
+    /// send datagrams to
+    pub multicast: bool,
+}
+
+impl Default for UdpSessionConfig {
+    fn default() -> Self {
+        Self {
+            max_sessions: config::UDP_MAX_SESSIONS,
+            idle_timeout: config::UDP_PRUNE_TIMEOUT,
+            multicast: false,
+        }
+    }
+}
+
+/// An open session
+#[derive(Debug)]
+struct Session {
+    /// Datagrams for the forwarder to send
+    data_tx: mpsc::Sender<DatagramFrame>,
+    /// When the client last sent a datagram in it
+    last_used: Instant,
+}
+
+/// Counters of the UDP sessions of a tunnel
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UdpSessionStats {
+    /// Sessions open now
+    pub open: usize,
+    /// Sessions opened so far
+    pub opened: u64,
+    /// Sessions closed to make room for new ones
+    pub evicted: u64,
+    /// Sessions that ended by themselves, e.g. when idle
+    pub expired: u64,
+}
+
+/// The UDP sessions of a tunnel by (client ID, destination host, port).
+/// Full-cone sessions have an empty host and port 0.
+#[derive(Debug)]
+pub struct UdpSessions {
+    config: UdpSessionConfig,
+    egress: Egress,
+    sessions: HashMap<(u32, Bytes, u16), Session>,
+    stats: UdpSessionStats,
+}
+
+impl UdpSessions {
+    pub fn new(config: UdpSessionConfig, egress: Egress) -> Self {
+        Self {
+            config,
+            egress,
+            sessions: HashMap::new(),
+            stats: UdpSessionStats::default(),
+        }
+    }
+
+    /// Send `datagram_frame` in its session. Returns the forwarder of a new
+    /// session to spawn, if there was none. Responses go to `datagram_tx`.
+    pub fn forward(
+        &mut self,
+        datagram_frame: DatagramFrame,
+        datagram_tx: &mpsc::Sender<DatagramFrame>,
+    ) -> Option<impl Future<Output = Result<(), forwarder::Error>>> {
+        let full_cone = datagram_frame.sid & config::UDP_FULL_CONE_FLAG != 0;
+        let key = if full_cone {
+            (datagram_frame.sid, Bytes::new(), 0)
+        } else {
+            (
+                datagram_frame.sid,
+                datagram_frame.host.dupe(),
+                datagram_frame.port,
+            )
+        };
+        let datagram_frame = match self.sessions.get_mut(&key) {
+            Some(session) => {
+                session.last_used = Instant::now();
+                match session.data_tx.try_send(datagram_frame) {
+                    Ok(()) => return None,
+                    Err(TrySendError::Full(_)) => {
+                        warn!("dropped datagram: UDP session queue full");
+                        return None;
+                    }
+                    // The forwarder has exited, so start over
+                    Err(TrySendError::Closed(datagram_frame)) => datagram_frame,
+                }
+            }
+            None => datagram_frame,
+        };
+        self.prune();
+        if self.sessions.len() >= self.config.max_sessions {
+            let lru = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, session)| session.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(lru) = lru {
+                debug!("closing the least recently used UDP session to make room");
+                // Dropping `data_tx` stops the forwarder
+                self.sessions.remove(&lru);
+                self.stats.evicted += 1;
+            }
+        }
+        let (data_tx, data_rx) = mpsc::channel(config::UDP_SESSION_QUEUE_SIZE);
+        self.sessions.insert(
+            key,
+            Session {
+                data_tx,
+                last_used: Instant::now(),
+            },
+        );
+        self.stats.opened += 1;
+        Some(udp_forward_to(
+            datagram_frame,
+            data_rx,
+            datagram_tx.dupe(),
+            self.config.idle_timeout,
+            full_cone,
+            self.config.multicast,
+            self.egress.dupe(),
+        ))
+    }
+
+    /// Forget the sessions whose forwarders have exited.
+    fn prune(&mut self) {
+        let before = self.sessions.len();
+        self.sessions
+            .retain(|_, session| !session.data_tx.is_closed());
+        self.stats.expired += (before - self.sessions.len()) as u64;
+    }
+
+    /// Counters of the sessions
+    pub fn stats(&mut self) -> UdpSessionStats {
+        self.prune();
+        UdpSessionStats {
+            open: self.sessions.len(),
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    fn frame(sid: u32, port: u16, data: &'static [u8]) -> DatagramFrame {
+        DatagramFrame {
+            sid,
+            host: Bytes::from_static(b"127.0.0.1"),
+            port,
+            data: Bytes::from_static(data),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_reused_and_evicted() {
+        let target = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let (datagram_tx, _datagram_rx) = mpsc::channel(4);
+        let mut sessions = UdpSessions::new(
+            UdpSessionConfig {
+                max_sessions: 2,
+                idle_timeout: config::UDP_PRUNE_TIMEOUT,
+                multicast: false,
+            },
+            Egress::default(),
+        );
+        let mut buf = [0; 16];
+
+        tokio::spawn(
+            sessions
+                .forward(frame(1, port, b"a"), &datagram_tx)
+                .unwrap(),
+        );
+        let (_, first) = target.recv_from(&mut buf).await.unwrap();
+        // Same client and destination: same socket
+        assert!(sessions
+            .forward(frame(1, port, b"b"), &datagram_tx)
+            .is_none());
+        let (len, addr) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], addr), (&b"b"[..], first));
+
+        tokio::spawn(
+            sessions
+                .forward(frame(2, port, b"c"), &datagram_tx)
+                .unwrap(),
+        );
+        let (_, second) = target.recv_from(&mut buf).await.unwrap();
+        assert_ne!(first, second);
+        // Client 1 is the least recently used
+        tokio::spawn(
+            sessions
+                .forward(frame(3, port, b"d"), &datagram_tx)
+                .unwrap(),
+        );
+        target.recv_from(&mut buf).await.unwrap();
+        assert!(sessions
+            .forward(frame(2, port, b"e"), &datagram_tx)
+            .is_none());
+        tokio::spawn(
+            sessions
+                .forward(frame(1, port, b"f"), &datagram_tx)
+                .unwrap(),
+        );
+        let (_, third) = target.recv_from(&mut buf).await.unwrap();
+        assert_ne!(first, third);
+        let stats = sessions.stats();
+        assert_eq!(stats.open, 2);
+        assert_eq!(stats.opened, 4);
+        assert_eq!(stats.evicted, 2);
+    }
+
+    #[tokio::test]
+    async fn test_full_cone_sessions_span_destinations() {
+        let target_1 = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let target_2 = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let port_1 = target_1.local_addr().unwrap().port();
+        let port_2 = target_2.local_addr().unwrap().port();
+        let (datagram_tx, _datagram_rx) = mpsc::channel(4);
+        let mut sessions = UdpSessions::new(UdpSessionConfig::default(), Egress::default());
+        let sid = 1 | config::UDP_FULL_CONE_FLAG;
+        let mut buf = [0; 16];
+        tokio::spawn(
+            sessions
+                .forward(frame(sid, port_1, b"a"), &datagram_tx)
+                .unwrap(),
+        );
+        let (_, from_1) = target_1.recv_from(&mut buf).await.unwrap();
+        assert!(sessions
+            .forward(frame(sid, port_2, b"b"), &datagram_tx)
+            .is_none());
+        let (_, from_2) = target_2.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from_1, from_2);
+        assert_eq!(sessions.stats().open, 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_sessions_expire() {
+        let target = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = target.local_addr().unwrap().port();
+        let (datagram_tx, _datagram_rx) = mpsc::channel(4);
+        let mut sessions = UdpSessions::new(
+            UdpSessionConfig {
+                max_sessions: 2,
+                idle_timeout: Duration::from_millis(100),
+                multicast: false,
+            },
+            Egress::default(),
+        );
+        let forwarder = tokio::spawn(
+            sessions
+                .forward(frame(1, port, b"a"), &datagram_tx)
+                .unwrap(),
+        );
+        forwarder.await.unwrap().unwrap();
+        let stats = sessions.stats();
+        assert_eq!(stats.open, 0);
+        assert_eq!(stats.expired, 1);
+        // A new datagram opens a new session
+        assert!(sessions
+            .forward(frame(1, port, b"b"), &datagram_tx)
+            .is_some());
+    }
+}
@@ -0,0 +1,94 @@
+//! Listening on a Unix socket behind a reverse proxy.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use hyper::server::accept::Accept;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Accepts connections on a Unix socket and removes it when dropped.
+#[derive(Debug)]
+pub(super) struct UnixIncoming {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl UnixIncoming {
+    /// Listen on `path`, replacing a socket left behind by a previous run.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let listener = match UnixListener::bind(path) {
+            Err(err)
+                if err.kind() == io::ErrorKind::AddrInUse
+                    && std::fs::symlink_metadata(path)
+                        .is_ok_and(|meta| meta.file_type().is_socket()) =>
+            {
+                if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                    // Another server is listening on it
+                    return Err(err);
+                }
+                std::fs::remove_file(path)?;
+                UnixListener::bind(path)?
+            }
+            result => result?,
+        };
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+
+    /// The path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Accept for UnixIncoming {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.listener
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    }
+}
+
+impl Drop for UnixIncoming {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::future::poll_fn;
+
+    #[tokio::test]
+    async fn test_unix_incoming() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("penguin.sock");
+        // A stale socket is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let mut incoming = UnixIncoming::bind(&path).unwrap();
+        let _client = UnixStream::connect(&path).await.unwrap();
+        poll_fn(|cx| Pin::new(&mut incoming).poll_accept(cx))
+            .await
+            .unwrap()
+            .unwrap();
+        // A live socket is left alone
+        let err = UnixIncoming::bind(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(incoming);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,157 @@
+//! Forwarding TCP streams with io_uring.
+//!
+//! `tokio-uring` needs a runtime of its own on each thread, so the streams
+//! are handed to a pool of threads running one each. If the kernel does not
+//! allow io_uring (e.g. in a container with a strict seccomp profile), the
+//! pool is empty and the forwarder copies as usual.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use super::websocket::MuxStream;
+use crate::config;
+use once_cell::sync::Lazy;
+use std::io;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot};
+use tokio_uring::buf::IoBuf;
+use tokio_uring::net::TcpStream;
+use tracing::{debug, warn};
+
+/// A stream to pipe and where to send the numbers of bytes it carried
+struct Job {
+    channel: MuxStream,
+    stream: std::net::TcpStream,
+    done: oneshot::Sender<io::Result<(u64, u64)>>,
+}
+
+/// Threads running `tokio-uring` runtimes, taking jobs in turn
+struct Pool {
+    workers: Vec<mpsc::UnboundedSender<Job>>,
+    next: AtomicUsize,
+}
+
+static POOL: Lazy<Pool> = Lazy::new(Pool::new);
+
+impl Pool {
+    /// Start one worker per CPU, or none if io_uring is not available.
+    fn new() -> Self {
+        let threads = std::thread::available_parallelism().map_or(1, usize::from);
+        let mut workers = Vec::with_capacity(threads);
+        for index in 0..threads {
+            match spawn_worker(index) {
+                Ok(worker) => workers.push(worker),
+                Err(err) => {
+                    warn!("io_uring is not available, forwarding without it: {err}");
+                    workers.clear();
+                    break;
+                }
+            }
+        }
+        debug!("started {} io_uring workers", workers.len());
+        Self {
+            workers,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Start a thread with a `tokio-uring` runtime that pipes the jobs sent to it.
+fn spawn_worker(index: usize) -> io::Result<mpsc::UnboundedSender<Job>> {
+    let (job_tx, mut job_rx) = mpsc::unbounded_channel::<Job>();
+    let (ready_tx, ready_rx) = std::sync::mpsc::sync_channel(1);
+    std::thread::Builder::new()
+        .name(format!("penguin-uring-{index}"))
+        .spawn(move || {
+            let runtime = match tokio_uring::Runtime::new(&tokio_uring::builder()) {
+                Ok(runtime) => runtime,
+                Err(err) => {
+                    ready_tx.send(Err(err)).ok();
+                    return;
+                }
+            };
+            ready_tx.send(Ok(())).ok();
+            runtime.block_on(async move {
+                while let Some(job) = job_rx.recv().await {
+                    tokio_uring::spawn(async move {
+                        let result = pipe(job.channel, job.stream).await;
+                        job.done.send(result).ok();
+                    });
+                }
+            });
+        })?;
+    ready_rx
+        .recv()
+        .map_err(|_| io::Error::other("io_uring worker exited"))??;
+    Ok(job_tx)
+}
+
+/// Whether there are io_uring workers to [`forward`] with
+pub(super) fn available() -> bool {
+    !POOL.workers.is_empty()
+}
+
+/// Pipe `stream` to and from `channel` on an io_uring worker, which there
+/// must be.
+///
+/// Returns the number of bytes sent to and received from `stream`.
+pub(super) async fn forward(
+    channel: MuxStream,
+    stream: tokio::net::TcpStream,
+) -> io::Result<(u64, u64)> {
+    let stream = stream.into_std()?;
+    // io_uring waits for the socket itself
+    stream.set_nonblocking(false)?;
+    let (done, result) = oneshot::channel();
+    let index = POOL.next.fetch_add(1, Ordering::Relaxed) % POOL.workers.len();
+    let job = Job {
+        channel,
+        stream,
+        done,
+    };
+    POOL.workers[index]
+        .send(job)
+        .map_err(|_| io::Error::other("io_uring worker exited"))?;
+    result
+        .await
+        .unwrap_or_else(|_| Err(io::Error::other("io_uring worker exited")))
+}
+
+/// Copy data both ways between `channel` and `stream` until both reach EOF.
+async fn pipe(channel: MuxStream, stream: std::net::TcpStream) -> io::Result<(u64, u64)> {
+    let stream = TcpStream::from_std(stream);
+    let (mut channel_rx, mut channel_tx) = tokio::io::split(channel);
+    let up = async {
+        let mut buf = vec![0; config::TCP_RELAY_BUFFER_SIZE];
+        let mut total = 0;
+        loop {
+            let read = channel_rx.read(&mut buf).await?;
+            if read == 0 {
+                stream.shutdown(Shutdown::Write)?;
+                return Ok::<_, io::Error>(total);
+            }
+            let (result, slice) = stream.write_all(buf.slice(..read)).await;
+            buf = slice.into_inner();
+            result?;
+            total += read as u64;
+        }
+    };
+    let down = async {
+        let mut buf = vec![0; config::TCP_RELAY_BUFFER_SIZE];
+        let mut total = 0;
+        loop {
+            let (result, returned) = stream.read(buf).await;
+            buf = returned;
+            let read = result?;
+            if read == 0 {
+                channel_tx.shutdown().await?;
+                return Ok(total);
+            }
+            channel_tx.write_all(&buf[..read]).await?;
+            channel_tx.flush().await?;
+            total += read as u64;
+        }
+    };
+    tokio::try_join!(up, down)
+}
@@ -2,24 +2,89 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
+use super::audit::{Auditor, Outcome};
+use super::auth::{Proto, User};
+use super::egress::Egress;
 use super::forwarder::tcp_forwarder_on_channel;
-use super::forwarder::udp_forward_to;
-use super::WebSocket;
-use crate::{config, Dupe};
-use hyper::upgrade::Upgraded;
-use penguin_mux::{DatagramFrame, Multiplexor, Role};
+use super::shutdown::ShutdownWatch;
+use super::tun::TunRouter;
+use super::udp_session::{UdpSessionConfig, UdpSessions};
+use crate::dump::DumpSignal;
+use crate::noise::NoiseTransport;
+use crate::throughput::Throughput;
+use crate::{config, tun, Dupe};
+use penguin_mux::{DatagramFrame, Multiplexor, Options, Role, SynFilter, TokenBucket};
+use std::sync::Arc;
 use tokio::{sync::mpsc, task::JoinSet};
-use tokio_tungstenite::WebSocketStream;
-use tracing::{debug, error, trace, warn};
+use tracing::{debug, debug_span, error, info, trace, warn, Instrument, Span};
 
-pub(super) type MuxStream = penguin_mux::MuxStream<WebSocketStream<Upgraded>>;
+/// The WebSocket of a tunnel, encrypted if the client used Noise
+pub(super) type Tunnel = NoiseTransport<super::WebSocket>;
+pub(super) type MuxStream = penguin_mux::MuxStream<Tunnel>;
+
+/// Check if `user` (`None` if unrestricted) may connect to the destination.
+fn may_connect(user: Option<&User>, host: &[u8], port: u16, proto: Proto) -> bool {
+    user.is_none_or(|user| {
+        std::str::from_utf8(host).is_ok_and(|host| user.may_connect(host, port, proto))
+    })
+}
+
+/// Whether a datagram of `len` bytes fits in `limit`, if any, charging it
+/// if so. Datagrams can be lost anyway, so they are dropped, not delayed.
+fn within_limit(limit: Option<&Arc<TokenBucket>>, len: usize) -> bool {
+    limit.is_none_or(|limit| limit.try_consume(len))
+}
+
+/// Reject the `Syn`s that `user` may not open, before any stream is created.
+fn syn_filter(user: Option<&Arc<User>>, auditor: &Auditor) -> Option<SynFilter> {
+    let user = user?.dupe();
+    let auditor = auditor.clone();
+    Some(Arc::new(move |host: &[u8], port: u16| {
+        if may_connect(Some(&user), host, port, Proto::Tcp) {
+            return Ok(());
+        }
+        auditor.start(host, port).finish(&Outcome::Denied);
+        warn!(
+            "Denied TCP connection to {:?} port {port}",
+            String::from_utf8_lossy(host)
+        );
+        Err(format!(
+            "destination {}:{port}/{} not allowed for user {}",
+            String::from_utf8_lossy(host),
+            Proto::Tcp,
+            user.name
+        ))
+    }))
+}
 
 /// Multiplex the `WebSocket` connection and handle the forwarding requests.
-#[tracing::instrument(skip(ws_stream), level = "debug")]
-pub async fn handle_websocket(ws_stream: WebSocket) {
-    let mux = Multiplexor::new(ws_stream, Role::Server, None, None);
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    skip(
+        ws_stream, auditor, throughput, options, udp_config, tun, egress, shutdown, dump
+    ),
+    level = "debug"
+)]
+pub async fn handle_websocket(
+    ws_stream: Tunnel,
+    user: Option<Arc<User>>,
+    auditor: Auditor,
+    throughput: Throughput,
+    mut options: Options,
+    udp_config: UdpSessionConfig,
+    tun: Option<Arc<TunRouter>>,
+    egress: Egress,
+    mut shutdown: ShutdownWatch,
+    mut dump: DumpSignal,
+) {
+    options.syn_filter = syn_filter(user.as_ref(), &auditor);
+    // `tcp_forwarder_on_channel` accepts a stream once it has connected
+    options.defer_syn_ack = true;
+    let mut mux_task = JoinSet::new();
+    let mux = Multiplexor::with_options(ws_stream, Role::Server, options, Some(&mut mux_task));
     debug!("WebSocket connection established");
     let mut jobs = JoinSet::new();
+    let mut udp_sessions = UdpSessions::new(udp_config, egress.dupe());
     // Channel for listeners to send UDP datagrams to the main loop
     let (datagram_send_tx, mut datagram_send_rx) =
         mpsc::channel::<DatagramFrame>(config::INCOMING_DATAGRAM_BUFFER_SIZE);
@@ -39,18 +104,120 @@ pub async fn handle_websocket(ws_stream: WebSocket) {
                 }
             }
             // Check if the multiplexor has received a new stream request
-            Ok(result) = mux.server_new_stream_channel() => {
-                jobs.spawn(tcp_forwarder_on_channel(result));
+            Ok(mut result) = mux.server_new_stream_channel() => {
+                // Disallowed destinations were already rejected by `syn_filter`
+                let record = auditor.start(&result.dest_host, result.dest_port);
+                throughput.apply(&mut result);
+                if let Some(user) = &user {
+                    // Reading from the stream is receiving from the client
+                    if let Some(limit) = &user.up_limit {
+                        result.limit_read(limit.dupe());
+                    }
+                    if let Some(limit) = &user.down_limit {
+                        result.limit_write(limit.dupe());
+                    }
+                }
+                // The forwarder joins the trace of the client's stream,
+                // linked to the tunnel
+                let span = debug_span!(
+                    "stream",
+                    host = %String::from_utf8_lossy(&result.dest_host),
+                    port = result.dest_port,
+                );
+                crate::otel::set_stream_parent(&span, &result.trace_context);
+                span.follows_from(Span::current());
+                let egress = egress.dupe();
+                jobs.spawn(async move {
+                    let transferred = tcp_forwarder_on_channel(result, &egress).await;
+                    match &transferred {
+                        Ok((up, down)) => record.finish(&Outcome::Closed { up: *up, down: *down }),
+                        Err(err) => record.finish(&Outcome::Failed(err)),
+                    }
+                    transferred.map(|_| ())
+                }.instrument(span));
             }
             // Check if the multiplexor has received a UDP datagram
             Ok(datagram_frame) = mux.get_datagram() => {
-                jobs.spawn(udp_forward_to(datagram_frame, datagram_send_tx.dupe()));
+                let up_limit = user.as_ref().and_then(|user| user.up_limit.as_ref());
+                if !within_limit(up_limit, datagram_frame.data.len()) {
+                    trace!("dropped a datagram over the user's upload limit");
+                } else if tun::is_packet(&datagram_frame) {
+                    match &tun {
+                        Some(tun) if user.as_deref().is_none_or(User::is_unrestricted) => {
+                            tun.forward(datagram_frame.data, &datagram_send_tx, user.as_deref()).await;
+                        }
+                        Some(_) => warn!("Denied IP packet from a restricted user"),
+                        None => debug!("Dropped IP packet without --tun"),
+                    }
+                } else if may_connect(user.as_deref(), &datagram_frame.host, datagram_frame.port, Proto::Udp) {
+                    if let Some(forwarder) = udp_sessions.forward(datagram_frame, &datagram_send_tx) {
+                        jobs.spawn(forwarder.in_current_span());
+                    }
+                } else {
+                    warn!("Denied UDP datagram to {:?} port {}", datagram_frame.host, datagram_frame.port);
+                }
             }
             // Check if any of the listeners have sent a UDP datagram
             Some(datagram_frame) = datagram_send_rx.recv() => {
-                mux.send_datagram(datagram_frame).await.unwrap_or_else(
-                    |err| error!("Failed to send datagram: {err}"),
+                let down_limit = user.as_ref().and_then(|user| user.down_limit.as_ref());
+                if within_limit(down_limit, datagram_frame.data.len()) {
+                    mux.send_datagram(datagram_frame).await.unwrap_or_else(
+                        |err| error!("Failed to send datagram: {err}"),
+                    );
+                } else {
+                    trace!("dropped a datagram over the user's download limit");
+                }
+            }
+            () = dump.requested() => {
+                let stats = mux.stats().await;
+                let udp_stats = udp_sessions.stats();
+                info!(
+                    streams = stats.streams.len(),
+                    forwarders = jobs.len(),
+                    udp_sessions = udp_stats.open,
+                    udp_sessions_opened = udp_stats.opened,
+                    udp_sessions_evicted = udp_stats.evicted,
+                    udp_sessions_expired = udp_stats.expired,
+                    datagram_queue = datagram_send_rx.len(),
+                    received_datagram_queue = stats.datagrams_queued,
+                    dropped_datagrams = stats.datagrams_dropped,
+                    dropped_port_queue = stats.dropped_ports_queued,
+                    ack_queue = stats.acks_queued,
+                    unsent_bytes = stats.bytes_unsent,
+                    "Tunnel statistics"
                 );
+                // Receiving is from the client
+                for stream in &stats.streams {
+                    info!(
+                        stream_id = stream.id,
+                        destination = format_args!(
+                            "{}:{}",
+                            String::from_utf8_lossy(&stream.dest_host),
+                            stream.dest_port
+                        ),
+                        bytes_up = stream.bytes_received,
+                        bytes_down = stream.bytes_sent,
+                        frames_up = stream.frames_received,
+                        frames_down = stream.frames_sent,
+                        frame_queue = stream.frames_queued,
+                        "Stream statistics"
+                    );
+                }
+                if let Some(rtt) = mux.rtt() {
+                    info!("Round-trip time to client: {rtt}");
+                }
+            }
+            () = shutdown.requested() => {
+                debug!("Shutting down, waiting for {} streams", jobs.len());
+                // Let the client open new streams elsewhere in the meantime
+                if let Err(err) = mux.go_away().await {
+                    warn!("Failed to send `GoAway`: {err}");
+                }
+                let drain = async { while jobs.join_next().await.is_some() {} };
+                if tokio::time::timeout(shutdown.grace_period, drain).await.is_err() {
+                    warn!("Closing {} streams that did not finish in time", jobs.len());
+                }
+                break;
             }
             else => {
                 // The multiplexor has closed for some reason
@@ -58,6 +225,18 @@ pub async fn handle_websocket(ws_stream: WebSocket) {
             }
         }
     }
-    debug!("WebSocket connection closed");
+    if let Some(rtt) = mux.rtt() {
+        debug!("WebSocket connection closed, round-trip time: {rtt}");
+    } else {
+        debug!("WebSocket connection closed");
+    }
     jobs.shutdown().await;
+    if let Some(tun) = &tun {
+        tun.forget(&datagram_send_tx);
+    }
+    // Let the multiplexor send `Close` before the tunnel counts as closed
+    drop(mux);
+    if let Some(Ok(Err(err))) = mux_task.join_next().await {
+        error!("Multiplexor task exited with error: {err}");
+    }
 }
@@ -0,0 +1,161 @@
+//! Static file serving for `--www`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use hyper::Body;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Decode `%XX` escapes in a URL path.
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Map a request path to a file under `root`, refusing to leave `root`.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode(path)?;
+    let mut resolved = root.to_path_buf();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => return None,
+            _ if segment.contains(['\\', '\0']) => return None,
+            _ => resolved.push(segment),
+        }
+    }
+    Some(resolved)
+}
+
+/// Guess the `Content-Type` from the file extension.
+fn content_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serve the file for the request from `root`. Returns `None` if there is
+/// no such file, so that the caller can respond with its 404.
+pub async fn serve(root: &Path, req: &Request<Body>) -> Option<Response<Body>> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Some(
+            Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header(header::ALLOW, "GET, HEAD")
+                .body(Body::empty())
+                .expect("Failed to build 405 response (this is a bug)"),
+        );
+    }
+    let mut path = resolve(root, req.uri().path())?;
+    if tokio::fs::metadata(&path).await.ok()?.is_dir() {
+        path.push("index.html");
+    }
+    let content = match tokio::fs::read(&path).await {
+        Ok(content) => content,
+        Err(err) => {
+            debug!("Cannot read {}: {err}", path.display());
+            return None;
+        }
+    };
+    let len = content.len();
+    let body = if req.method() == Method::HEAD {
+        Body::empty()
+    } else {
+        Body::from(content)
+    };
+    Some(
+        Response::builder()
+            .header(header::CONTENT_TYPE, content_type(&path))
+            .header(header::CONTENT_LENGTH, HeaderValue::from(len))
+            .body(body)
+            .expect("Failed to build static file response (this is a bug)"),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let root = Path::new("/srv/www");
+        assert_eq!(
+            resolve(root, "/a/./b%20c.html").unwrap(),
+            Path::new("/srv/www/a/b c.html")
+        );
+        assert_eq!(resolve(root, "/").unwrap(), root);
+        assert!(resolve(root, "/../etc/passwd").is_none());
+        assert!(resolve(root, "/a/%2e%2e/%2e%2e/etc/passwd").is_none());
+        assert!(resolve(root, "/a%5c..%5cb").is_none());
+        assert!(resolve(root, "/%zz").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        tokio::fs::write(tmpdir.path().join("index.html"), "<h1>Hi</h1>")
+            .await
+            .unwrap();
+        let make_req = |method, uri| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let resp = serve(tmpdir.path(), &make_req(Method::GET, "/"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()[header::CONTENT_TYPE],
+            "text/html; charset=utf-8"
+        );
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(body, "<h1>Hi</h1>");
+        let resp = serve(tmpdir.path(), &make_req(Method::HEAD, "/index.html"))
+            .await
+            .unwrap();
+        assert_eq!(resp.headers()[header::CONTENT_LENGTH], "11");
+        assert!(serve(tmpdir.path(), &make_req(Method::GET, "/missing"))
+            .await
+            .is_none());
+        let resp = serve(tmpdir.path(), &make_req(Method::POST, "/"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
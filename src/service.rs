@@ -0,0 +1,155 @@
+//! Running as a Windows service.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+// `define_windows_service!` expands to an `unsafe` block
+#![allow(unsafe_code)]
+
+use crate::arg::PenguinCli;
+use crate::Error;
+use once_cell::sync::OnceCell;
+use std::ffi::OsString;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+use windows_service::service::{
+    ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{
+    self, ServiceControlHandlerResult, ServiceStatusHandle,
+};
+use windows_service::{define_windows_service, service_dispatcher};
+
+/// Name to register the service under, e.g.
+/// `sc.exe create penguin binPath= "C:\penguin.exe --service server ..."`
+const SERVICE_NAME: &str = "penguin";
+
+/// The runtime `main` started, since the SCM calls `service_main`
+/// on a thread of its own
+static RUNTIME: OnceCell<Handle> = OnceCell::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Why the client or server stopped running
+enum Outcome {
+    Exited(Result<(), Error>),
+    Paused,
+    Stopped,
+}
+
+/// Hand this process over to the Service Control Manager and return when
+/// the service is stopped.
+pub async fn run() -> Result<(), Error> {
+    RUNTIME
+        .set(Handle::current())
+        .expect("`service::run` should not be called twice (this is a bug)");
+    tokio::task::spawn_blocking(|| service_dispatcher::start(SERVICE_NAME, ffi_service_main))
+        .await
+        .expect("Service dispatcher panicked (this is a bug)")?;
+    Ok(())
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        error!("Windows service error: {err}");
+    }
+}
+
+fn run_service() -> windows_service::Result<()> {
+    let runtime = RUNTIME
+        .get()
+        .expect("`service_main` called before `service::run` (this is a bug)");
+    let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Pause | ServiceControl::Continue => {
+                control_tx.send(control).ok();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+    set_status(
+        status_handle,
+        ServiceState::Running,
+        ServiceExitCode::NO_ERROR,
+    )?;
+    let exit_code = runtime.block_on(serve(status_handle, &mut control_rx))?;
+    set_status(status_handle, ServiceState::Stopped, exit_code)
+}
+
+/// Run the client or server until the service is stopped. Pausing the
+/// service stops it too, and continuing starts it afresh.
+async fn serve(
+    status_handle: ServiceStatusHandle,
+    control_rx: &mut mpsc::UnboundedReceiver<ServiceControl>,
+) -> windows_service::Result<ServiceExitCode> {
+    loop {
+        match run_until_control(control_rx).await {
+            Outcome::Exited(Ok(())) | Outcome::Stopped => return Ok(ServiceExitCode::NO_ERROR),
+            Outcome::Exited(Err(err)) => {
+                error!("{err}");
+                return Ok(ServiceExitCode::ServiceSpecific(1));
+            }
+            Outcome::Paused => {
+                info!("Service paused");
+                set_status(
+                    status_handle,
+                    ServiceState::Paused,
+                    ServiceExitCode::NO_ERROR,
+                )?;
+                loop {
+                    match control_rx.recv().await {
+                        Some(ServiceControl::Continue) => break,
+                        Some(ServiceControl::Stop) | None => return Ok(ServiceExitCode::NO_ERROR),
+                        Some(_) => {}
+                    }
+                }
+                info!("Service continued");
+                set_status(
+                    status_handle,
+                    ServiceState::Running,
+                    ServiceExitCode::NO_ERROR,
+                )?;
+            }
+        }
+    }
+}
+
+/// Run the client or server until it exits or the SCM asks us to stop or pause.
+async fn run_until_control(control_rx: &mut mpsc::UnboundedReceiver<ServiceControl>) -> Outcome {
+    let role = crate::run(&PenguinCli::get_global().subcommand);
+    tokio::pin!(role);
+    loop {
+        tokio::select! {
+            result = &mut role => return Outcome::Exited(result),
+            control = control_rx.recv() => match control {
+                Some(ServiceControl::Pause) => return Outcome::Paused,
+                Some(ServiceControl::Stop) | None => return Outcome::Stopped,
+                // `Continue` while running
+                Some(_) => {}
+            },
+        }
+    }
+}
+
+fn set_status(
+    status_handle: ServiceStatusHandle,
+    current_state: ServiceState,
+    exit_code: ServiceExitCode,
+) -> windows_service::Result<()> {
+    let controls_accepted = if current_state == ServiceState::Stopped {
+        ServiceControlAccept::empty()
+    } else {
+        ServiceControlAccept::STOP | ServiceControlAccept::PAUSE_CONTINUE
+    };
+    status_handle.set_service_status(ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state,
+        controls_accepted,
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    })
+}
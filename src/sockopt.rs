@@ -0,0 +1,231 @@
+//! Socket options for the connections penguin makes.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use penguin_mux::TcpOptions;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+/// Sizes of the kernel buffers of TCP sockets. `None` keeps the system's.
+/// They have to be set before connecting or listening, so that the window
+/// scale is chosen for them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferSizes {
+    /// `SO_RCVBUF`
+    pub recv: Option<u32>,
+    /// `SO_SNDBUF`
+    pub send: Option<u32>,
+}
+
+impl BufferSizes {
+    /// Set the sizes on `socket`.
+    pub fn set(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(recv) = self.recv {
+            socket.set_recv_buffer_size(recv)?;
+        }
+        if let Some(send) = self.send {
+            socket.set_send_buffer_size(send)?;
+        }
+        Ok(())
+    }
+
+    /// Set the sizes on a `socket2` `socket`.
+    #[cfg(unix)]
+    pub fn set_socket2(&self, socket: &socket2::Socket) -> io::Result<()> {
+        if let Some(recv) = self.recv {
+            socket.set_recv_buffer_size(recv as usize)?;
+        }
+        if let Some(send) = self.send {
+            socket.set_send_buffer_size(send as usize)?;
+        }
+        Ok(())
+    }
+}
+
+/// Listen on `addr` like [`TcpListener::bind`], with `buffers` for the
+/// connections it accepts, and only on the network `interface` if given
+/// (which is only possible on Linux).
+pub fn tcp_listener(
+    addr: SocketAddr,
+    buffers: BufferSizes,
+    interface: Option<&str>,
+) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(true)?;
+    buffers.set(&socket)?;
+    #[cfg(target_os = "linux")]
+    if let Some(interface) = interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    // The argument parser only accepts interfaces on Linux
+    #[cfg(not(target_os = "linux"))]
+    let _ = interface;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+/// Whether this build can mark packets with DSCP
+pub const DSCP_SUPPORTED: bool = cfg!(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+));
+
+/// Mark the packets `socket` sends with the DSCP value `dscp`, in the IPv4
+/// TOS or IPv6 traffic class field depending on the family of `socket`.
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+pub fn set_dscp<S: std::os::fd::AsFd>(socket: &S, dscp: u8) -> io::Result<()> {
+    let socket = socket2::SockRef::from(socket);
+    // The lower two bits are for ECN
+    let tos = u32::from(dscp) << 2;
+    if socket.local_addr()?.is_ipv6() {
+        socket.set_tclass_v6(tos)
+    } else {
+        socket.set_tos(tos)
+    }
+}
+
+/// Fail, since this build cannot mark packets with DSCP.
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+pub fn set_dscp<S>(_socket: &S, _dscp: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "DSCP marking is not supported on this platform",
+    ))
+}
+
+/// Set `options` on `stream`, except the ones this platform lacks: the
+/// keepalive parameters need a Unix-like OS, and the user timeout Linux.
+pub fn set_tcp_options(stream: &TcpStream, options: &TcpOptions) -> io::Result<()> {
+    if let Some(nodelay) = options.nodelay {
+        stream.set_nodelay(nodelay)?;
+    }
+    #[cfg(unix)]
+    {
+        use std::time::Duration;
+        let socket = socket2::SockRef::from(stream);
+        if options.keepalive() {
+            let mut keepalive = socket2::TcpKeepalive::new();
+            if let Some(idle) = options.keepalive_idle {
+                keepalive = keepalive.with_time(Duration::from_secs(idle.into()));
+            }
+            #[cfg(any(
+                target_os = "android",
+                target_os = "dragonfly",
+                target_os = "freebsd",
+                target_os = "fuchsia",
+                target_os = "illumos",
+                target_os = "ios",
+                target_os = "linux",
+                target_os = "macos",
+                target_os = "netbsd",
+            ))]
+            {
+                if let Some(interval) = options.keepalive_interval {
+                    keepalive = keepalive.with_interval(Duration::from_secs(interval.into()));
+                }
+                if let Some(count) = options.keepalive_count {
+                    keepalive = keepalive.with_retries(count);
+                }
+            }
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        if let Some(timeout) = options.user_timeout {
+            socket.set_tcp_user_timeout(Some(Duration::from_secs(timeout.into())))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_set_dscp() {
+        let socket = TcpSocket::new_v4().unwrap();
+        set_dscp(&socket, 46).unwrap();
+        assert_eq!(socket2::SockRef::from(&socket).tos().unwrap(), 46 << 2);
+        let socket = UdpSocket::bind(("::1", 0)).await.unwrap();
+        set_dscp(&socket, 10).unwrap();
+        assert_eq!(
+            socket2::SockRef::from(&socket).tclass_v6().unwrap(),
+            10 << 2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffer_sizes() {
+        // Below the default `rmem_max` and `wmem_max`
+        let buffers = BufferSizes {
+            recv: Some(1 << 16),
+            send: Some(1 << 15),
+        };
+        let listener = tcp_listener("127.0.0.1:0".parse().unwrap(), buffers, None).unwrap();
+        let socket = TcpSocket::new_v4().unwrap();
+        buffers.set(&socket).unwrap();
+        // Linux doubles them for its bookkeeping
+        assert_eq!(socket.recv_buffer_size().unwrap(), 2 << 16);
+        assert_eq!(socket.send_buffer_size().unwrap(), 2 << 15);
+        socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        // Accepted connections inherit them
+        let (accepted, _) = listener.accept().await.unwrap();
+        let accepted = socket2::SockRef::from(&accepted);
+        assert_eq!(accepted.recv_buffer_size().unwrap(), 2 << 16);
+        assert_eq!(accepted.send_buffer_size().unwrap(), 2 << 15);
+    }
+
+    #[tokio::test]
+    async fn test_set_tcp_options() {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let options = TcpOptions {
+            nodelay: Some(true),
+            keepalive_idle: Some(60),
+            keepalive_interval: Some(10),
+            keepalive_count: Some(3),
+            user_timeout: Some(30),
+        };
+        set_tcp_options(&stream, &options).unwrap();
+        assert!(stream.nodelay().unwrap());
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap().as_secs(), 60);
+        assert_eq!(socket.keepalive_interval().unwrap().as_secs(), 10);
+        assert_eq!(socket.keepalive_retries().unwrap(), 3);
+        assert_eq!(socket.tcp_user_timeout().unwrap().unwrap().as_secs(), 30);
+    }
+}
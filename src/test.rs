@@ -1,5 +1,5 @@
 use super::*;
-use crate::{arg::ServerUrl, parse_remote::Remote};
+use crate::{arg::ServerUrl, noise::NoiseKey, parse_remote::Remote};
 #[allow(unused_imports)]
 use once_cell::sync::{Lazy, OnceCell};
 use std::{
@@ -32,19 +32,75 @@ fn make_server_args(host: &str, port: u16) -> arg::ServerArgs {
     arg::ServerArgs {
         host: host.to_string(),
         port,
-        backend: None,
+        listen: vec![],
+        acceptors: 1,
+        backend: vec![],
+        backend_route: vec![],
+        trusted_proxy: vec![],
+        allow_client_cidr: vec![],
+        ban_after: None,
+        ban_time: 600,
+        rate_limit: None,
+        rate_limit_burst: 10,
+        max_conns_per_ip: None,
+        max_conns: None,
+        access_log: None,
+        access_log_format: crate::arg::AccessLogFormat::Combined,
+        audit_log: None,
+        limit_rate: None,
+        limit_rate_total: None,
+        max_streams: None,
+        stream_idle_timeout: None,
+        cork: None,
+        keepalive: 0,
+        max_frame_size: 1 << 20,
+        datagram_overflow: crate::arg::DatagramOverflow::DropNewest,
+        udp_max_sessions: crate::config::UDP_MAX_SESSIONS,
+        udp_idle_timeout: 10,
+        udp_multicast: false,
+        resolver: None,
+        egress_bind: vec![],
+        egress_interface: None,
+        egress_proxy: None,
+        egress_allow: vec![],
+        egress_dscp: None,
+        rcvbuf: None,
+        sndbuf: None,
+        tun: None,
+        tun_address: vec![],
+        tun_mtu: crate::config::TUN_MTU,
+        noise_key: None,
+        noise_client_key: vec![],
+        compress: false,
+        obfs_traffic: false,
+        shutdown_timeout: 30,
+        www: None,
         obfs: false,
-        not_found_resp: "404".to_string(),
+        not_found_resp: Some("404".to_string()),
+        camouflage: None,
+        server_header: None,
+        cors_origin: vec![],
+        cors_allow_headers: None,
         ws_psk: None,
+        ws_psk_totp: None,
+        ws_psk_challenge: false,
+        users_file: None,
+        jwt_secret: None,
+        jwt_jwks: None,
+        jwt_issuer: None,
+        jwt_audience: None,
         tls_ca: None,
+        tls_client_auth_optional: false,
+        tls_client_cert_auth: false,
+        token_max_ttl: crate::config::TOKEN_MAX_TTL,
         tls_cert: None,
+        tls_selfsign: false,
         tls_key: None,
         _pid: false,
         _socks5: false,
         _reverse: false,
         _auth: None,
         _authfile: None,
-        _keepalive: 0,
         _key: None,
     }
 }
@@ -53,18 +109,56 @@ fn make_client_args(servhost: &str, servport: u16, remotes: Vec<Remote>) -> arg:
     arg::ClientArgs {
         server: ServerUrl::from_str(&format!("ws://{servhost}:{servport}/ws")).unwrap(),
         remote: remotes,
+        remote_option: vec![],
+        remotes_file: None,
+        transparent: vec![],
+        local_forward: vec![],
+        dynamic_forward: vec![],
+        local_auth: None,
+        pac: false,
+        bypass_file: None,
+        hosts_file: None,
+        socks_dns: None,
+        listen_interface: None,
+        remote_forward: vec![],
+        tun: None,
+        tun_address: vec![],
+        tun_mtu: crate::config::TUN_MTU,
         ws_psk: None,
+        ws_psk_totp: None,
+        ws_psk_challenge: false,
         keepalive: 0,
+        keepalive_adaptive: false,
+        max_missed_pongs: 3,
+        compress: false,
+        obfs_traffic: false,
+        max_frame_size: 1 << 20,
+        datagram_overflow: crate::arg::DatagramOverflow::DropNewest,
+        noise_server_key: None,
+        noise_key: None,
         max_retry_count: 10,
         max_retry_interval: 10,
         proxy: None,
+        resolver: None,
         header: vec![],
         tls_ca: None,
         tls_cert: None,
         tls_key: None,
         tls_skip_verify: false,
+        dscp: None,
+        rcvbuf: None,
+        sndbuf: None,
+        tls_pin: vec![],
         hostname: Some(http::HeaderValue::from_static("localhost")),
         channel_timeout: 10,
+        stream_idle_timeout: None,
+        cork: None,
+        idle_timeout: None,
+        limit_rate: None,
+        limit_rate_total: None,
+        daemon: false,
+        pidfile: None,
+        control_socket: None,
         _pid: false,
         _fingerprint: None,
         _auth: None,
@@ -124,6 +218,106 @@ async fn test_it_works() {
     client_task.abort();
 }
 
+#[tokio::test]
+async fn test_it_works_noise() {
+    static SERVER_KEY: Lazy<NoiseKey> = Lazy::new(NoiseKey::generate);
+    static CLIENT_KEY: Lazy<NoiseKey> = Lazy::new(NoiseKey::generate);
+    static SERVER_ARGS: Lazy<arg::ServerArgs> = Lazy::new(|| arg::ServerArgs {
+        noise_key: Some(SERVER_KEY.clone()),
+        noise_client_key: vec![CLIENT_KEY.public()],
+        ..make_server_args("127.0.0.1", 30556)
+    });
+    static CLIENT_ARGS: Lazy<arg::ClientArgs> = Lazy::new(|| arg::ClientArgs {
+        noise_server_key: Some(SERVER_KEY.public()),
+        noise_key: Some(CLIENT_KEY.clone()),
+        ..make_client_args(
+            "127.0.0.1",
+            30556,
+            vec![Remote::from_str("127.0.0.1:21630:127.0.0.1:10809").unwrap()],
+        )
+    });
+
+    let input_bytes: Vec<u8> = (0..(1024 * 1024)).map(|_| rand::random::<u8>()).collect();
+    let input_len = input_bytes.len();
+    let second_task = tokio::spawn(async move {
+        let listener = TcpListener::bind("127.0.0.1:10809").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut output_bytes = vec![0u8; input_len];
+        stream.read_exact(&mut output_bytes).await.unwrap();
+        output_bytes
+    });
+
+    let client_task = tokio::spawn(crate::client::client_main(&CLIENT_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let mut sock = TcpStream::connect("127.0.0.1:21630").await.unwrap();
+    sock.write_all(&input_bytes).await.unwrap();
+    sock.shutdown().await.unwrap();
+    let output_bytes = second_task.await.unwrap();
+    assert_eq!(input_bytes, output_bytes);
+    server_task.abort();
+    client_task.abort();
+}
+
+#[tokio::test]
+async fn test_ws_route_mounted() {
+    use hyper::service::{make_service_fn, service_fn, Service};
+    use hyper::{Body, Request, Response};
+
+    static SERVER_ARGS: Lazy<arg::ServerArgs> = Lazy::new(|| make_server_args("127.0.0.1", 0));
+    static CLIENT_ARGS: Lazy<arg::ClientArgs> = Lazy::new(|| {
+        make_client_args(
+            "127.0.0.1",
+            30555,
+            vec![Remote::from_str("127.0.0.1:21629:127.0.0.1:10808").unwrap()],
+        )
+    });
+
+    // An application with its own route and the tunnel at `/ws`
+    let route = crate::server::ws_route(&SERVER_ARGS).await.unwrap();
+    let make_service = make_service_fn(move |conn: &hyper::server::conn::AddrStream| {
+        let route = route.clone().with_remote_addr(conn.remote_addr());
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |req: Request<Body>| {
+                let mut route = route.clone();
+                async move {
+                    if req.uri().path() == "/ws" {
+                        route.call(req).await
+                    } else {
+                        Ok(Response::new(Body::from("app")))
+                    }
+                }
+            }))
+        }
+    });
+    let app_task =
+        tokio::spawn(hyper::Server::bind(&([127, 0, 0, 1], 30555).into()).serve(make_service));
+
+    let second_task = tokio::spawn(async move {
+        let listener = TcpListener::bind("127.0.0.1:10808").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut output_bytes = vec![0u8; 5];
+        stream.read_exact(&mut output_bytes).await.unwrap();
+        output_bytes
+    });
+    let client_task = tokio::spawn(crate::client::client_main(&CLIENT_ARGS));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let mut sock = TcpStream::connect("127.0.0.1:21629").await.unwrap();
+    sock.write_all(b"hello").await.unwrap();
+    sock.shutdown().await.unwrap();
+    assert_eq!(second_task.await.unwrap(), b"hello");
+
+    // The application still serves its own routes
+    let resp = hyper::Client::new()
+        .get("http://127.0.0.1:30555/".parse().unwrap())
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&body[..], b"app");
+    app_task.abort();
+    client_task.abort();
+}
+
 #[tokio::test]
 async fn test_it_works_v6() {
     static SERVER_ARGS: Lazy<arg::ServerArgs> = Lazy::new(|| make_server_args("::1", 27254));
@@ -163,23 +357,13 @@ async fn test_it_works_v6() {
 async fn test_it_works_tls_simple() {
     static SERVER_ARGS: OnceCell<arg::ServerArgs> = OnceCell::new();
     static CLIENT_ARGS: Lazy<arg::ClientArgs> = Lazy::new(|| arg::ClientArgs {
-        server: ServerUrl::from_str(&format!("wss://127.0.0.1:20353/ws")).unwrap(),
-        remote: vec![Remote::from_str("127.0.0.1:24368:127.0.0.1:12034").unwrap()],
-        ws_psk: None,
-        keepalive: 0,
-        max_retry_count: 10,
-        max_retry_interval: 10,
-        proxy: None,
-        header: vec![],
-        tls_ca: None,
-        tls_cert: None,
-        tls_key: None,
+        server: ServerUrl::from_str("wss://127.0.0.1:20353/ws").unwrap(),
         tls_skip_verify: true,
-        hostname: Some(http::HeaderValue::from_static("localhost")),
-        channel_timeout: 10,
-        _pid: false,
-        _fingerprint: None,
-        _auth: None,
+        ..make_client_args(
+            "127.0.0.1",
+            20353,
+            vec![Remote::from_str("127.0.0.1:24368:127.0.0.1:12034").unwrap()],
+        )
     });
 
     let mut serv_cfg = make_server_args("127.0.0.1", 20353);
@@ -199,7 +383,7 @@ async fn test_it_works_tls_simple() {
     });
 
     let client_task = tokio::spawn(crate::client::client_main(&CLIENT_ARGS));
-    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS.get().unwrap()));
+    let server_task = tokio::spawn(crate::server::server_main(SERVER_ARGS.get().unwrap()));
     tokio::time::sleep(Duration::from_secs(2)).await;
     let mut sock = TcpStream::connect("127.0.0.1:24368").await.unwrap();
     sock.write_all(&input_bytes).await.unwrap();
@@ -354,7 +538,8 @@ async fn test_socks5_udp_v4v4() {
         let n = sock.read(&mut buf).await.unwrap();
         assert_eq!(n, 2);
         assert_eq!(&buf[..n], b"\x05\x00");
-        sock.write_all(b"\x05\x03\x00\x01\x7f\x00\x00\x01\x37\x27")
+        // DST.ADDR and DST.PORT are where we will send from, not known yet
+        sock.write_all(b"\x05\x03\x00\x01\x00\x00\x00\x00\x00\x00")
             .await
             .unwrap();
         let n = sock.read(&mut buf).await.unwrap();
@@ -435,7 +620,8 @@ async fn test_socks5_udp_v4v6() {
         let n = sock.read(&mut buf).await.unwrap();
         assert_eq!(n, 2);
         assert_eq!(&buf[..n], b"\x05\x00");
-        sock.write_all(b"\x05\x03\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x63\x03")
+        // DST.ADDR and DST.PORT are where we will send from, not known yet
+        sock.write_all(b"\x05\x03\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00")
             .await
             .unwrap();
         let n = sock.read(&mut buf).await.unwrap();
@@ -520,7 +706,8 @@ async fn test_socks5_udp_v6v6() {
         let n = sock.read(&mut buf).await.unwrap();
         assert_eq!(n, 2);
         assert_eq!(&buf[..n], b"\x05\x00");
-        sock.write_all(b"\x05\x03\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x5d\x7b")
+        // DST.ADDR and DST.PORT are where we will send from, not known yet
+        sock.write_all(b"\x05\x03\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00")
             .await
             .unwrap();
         let n = sock.read(&mut buf).await.unwrap();
@@ -569,6 +756,47 @@ async fn test_socks5_udp_v6v6() {
     client_task.abort();
 }
 
+#[tokio::test]
+async fn test_socks5_connect_refused() {
+    static SERVER_ARGS: Lazy<arg::ServerArgs> = Lazy::new(|| make_server_args("127.0.0.1", 29417));
+    static CLIENT_ARGS: Lazy<arg::ClientArgs> = Lazy::new(|| {
+        make_client_args(
+            "127.0.0.1",
+            29417,
+            vec![Remote::from_str("127.0.0.1:15383:socks").unwrap()],
+        )
+    });
+
+    let client_task = tokio::spawn(crate::client::client_main(&CLIENT_ARGS));
+    let server_task = tokio::spawn(crate::server::server_main(&SERVER_ARGS));
+    // A port nobody listens on
+    let closed_port = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let mut sock = TcpStream::connect("127.0.0.1:15383").await.unwrap();
+    sock.write_all(b"\x05\x01\x00").await.unwrap();
+    let mut buf = vec![0u8; 32];
+    let n = sock.read(&mut buf).await.unwrap();
+    assert_eq!(&buf[..n], b"\x05\x00");
+    sock.write_all(b"\x05\x01\x00\x01\x7f\x00\x00\x01")
+        .await
+        .unwrap();
+    sock.write_all(&closed_port.to_be_bytes()).await.unwrap();
+    let n = sock.read(&mut buf).await.unwrap();
+    assert!(n > 3);
+    // Connection refused, rather than success followed by EOF
+    assert_eq!(&buf[..3], b"\x05\x05\x00");
+
+    server_task.abort();
+    client_task.abort();
+}
+
 #[tokio::test]
 async fn test_socks4_works() {
     static SERVER_ARGS: Lazy<arg::ServerArgs> = Lazy::new(|| make_server_args("127.0.0.1", 10796));
@@ -0,0 +1,45 @@
+//! Throughput caps set with `--limit-rate` and `--limit-rate-total`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::arg::ByteRate;
+use crate::Dupe;
+use penguin_mux::{MuxStream, TokenBucket};
+use std::sync::Arc;
+
+/// Limits on the rate at which we send data over streams.
+#[derive(Clone, Debug, Default)]
+pub struct Throughput {
+    /// Bytes per second for each stream
+    per_stream: Option<u64>,
+    /// Bucket shared by all streams
+    total: Option<Arc<TokenBucket>>,
+}
+
+impl Dupe for Throughput {
+    fn dupe(&self) -> Self {
+        Self {
+            per_stream: self.per_stream,
+            total: self.total.clone(),
+        }
+    }
+}
+
+impl Throughput {
+    pub fn new(per_stream: Option<ByteRate>, total: Option<ByteRate>) -> Self {
+        Self {
+            per_stream: per_stream.map(|ByteRate(rate)| rate),
+            total: total.map(|ByteRate(rate)| Arc::new(TokenBucket::new(rate))),
+        }
+    }
+
+    /// Apply the limits to the `Psh` frames written to `stream`.
+    pub fn apply<S>(&self, stream: &mut MuxStream<S>) {
+        if let Some(rate) = self.per_stream {
+            stream.limit_write(Arc::new(TokenBucket::new(rate)));
+        }
+        if let Some(total) = &self.total {
+            stream.limit_write(total.dupe());
+        }
+    }
+}
@@ -2,7 +2,7 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use super::{TlsIdentity, TlsIdentityInner};
+use super::{TlsConnInfo, TlsIdentity, TlsIdentityInner};
 use futures_util::Future;
 use hyper::server::{
     accept::Accept,
@@ -10,6 +10,7 @@ use hyper::server::{
 };
 use std::{
     io,
+    net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{ready, Context, Poll},
@@ -36,10 +37,44 @@ enum State {
 
 pub struct TlsStream {
     state: State,
+    info: Arc<TlsConnInfo>,
+    remote_addr: SocketAddr,
 }
 
 impl TlsStream {
+    /// Address of the peer.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+
+    /// Information about the connection, set once the handshake is done.
+    pub fn info(&self) -> Arc<TlsConnInfo> {
+        self.info.clone()
+    }
+
+    #[cfg(feature = "__rustls")]
+    fn handshake_done(&self, stream: &tokio_rustls::server::TlsStream<AddrStream>) {
+        let conn = stream.get_ref().1;
+        if let Some(name) = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(super::rustls::client_cert_name)
+        {
+            self.info.client_cert_name.set(name).ok();
+        }
+        if let Some(name) = conn.server_name() {
+            self.info.server_name.set(name.to_string()).ok();
+        }
+    }
+
+    #[cfg(feature = "nativetls")]
+    #[allow(clippy::unused_self)]
+    fn handshake_done(&self, _stream: &tokio_native_tls::TlsStream<AddrStream>) {
+        // `native-tls` does not expose client certificates nor SNI on the server side
+    }
+
     fn new(stream: AddrStream, config: Arc<TlsIdentityInner>) -> Self {
+        let remote_addr = stream.remote_addr();
         #[cfg(feature = "__rustls")]
         let accept = tokio_rustls::TlsAcceptor::from(config).accept(stream);
         #[cfg(feature = "nativetls")]
@@ -51,6 +86,8 @@ impl TlsStream {
         });
         Self {
             state: State::Handshaking(accept),
+            info: Arc::default(),
+            remote_addr,
         }
     }
 }
@@ -65,6 +102,7 @@ impl AsyncRead for TlsStream {
         match pin.state {
             State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
                 Ok(mut stream) => {
+                    pin.handshake_done(&stream);
                     let result = Pin::new(&mut stream).poll_read(cx, buf);
                     pin.state = State::Streaming(stream);
                     result
@@ -86,6 +124,7 @@ impl AsyncWrite for TlsStream {
         match pin.state {
             State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
                 Ok(mut stream) => {
+                    pin.handshake_done(&stream);
                     let result = Pin::new(&mut stream).poll_write(cx, buf);
                     pin.state = State::Streaming(stream);
                     result
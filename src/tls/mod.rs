@@ -8,21 +8,43 @@ mod native;
 mod rustls;
 
 #[cfg(feature = "__rustls")]
-use self::rustls::{make_client_config, make_server_config, TlsIdentityInner};
+pub use self::rustls::{connect, ClientTlsStream};
+#[cfg(feature = "__rustls")]
+use self::rustls::{
+    make_client_config, make_server_config, make_server_config_from_pem, TlsIdentityInner,
+};
 use arc_swap::ArcSwap;
+use base64::engine::general_purpose::STANDARD as B64_STANDARD_ENGINE;
+use base64::Engine;
 use hyper::client::HttpConnector;
 #[cfg(feature = "__rustls")]
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 #[cfg(feature = "nativetls")]
 use hyper_tls::HttpsConnector;
 #[cfg(feature = "nativetls")]
-use native::{make_client_config, make_server_config, TlsIdentityInner};
+pub use native::{connect, ClientTlsStream};
+#[cfg(feature = "nativetls")]
+use native::{
+    make_client_config, make_server_config, make_server_config_from_pem, TlsIdentityInner,
+};
+use once_cell::sync::OnceCell;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
 use std::sync::Arc;
 use thiserror::Error;
 use tokio_tungstenite::Connector;
 
 pub use acceptor::{TlsAcceptor, TlsStream};
 
+/// Information about a TLS connection, set after the handshake.
+#[derive(Debug, Default)]
+pub struct TlsConnInfo {
+    /// Name of the client from its certificate
+    pub client_cert_name: OnceCell<String>,
+    /// Server name requested with SNI
+    pub server_name: OnceCell<String>,
+}
+
 /// A hot-swappable container for a TLS key and certificate.
 pub type TlsIdentity = Arc<ArcSwap<TlsIdentityInner>>;
 
@@ -43,6 +65,48 @@ pub enum Error {
     #[error("Unsupported private key type")]
     #[cfg(feature = "__rustls")]
     PrivateKeyNotSupported,
+    #[error("Cannot generate self-signed certificate: {0}")]
+    SelfSign(#[from] rcgen::RcgenError),
+    #[error("Certificate pinning is not supported with native-tls")]
+    #[cfg(feature = "nativetls")]
+    PinNotSupported,
+    #[error("TLS handshake failed: {0}")]
+    Handshake(std::io::Error),
+}
+
+/// SHA-256 fingerprint of the SubjectPublicKeyInfo of a certificate,
+/// written as `sha256//<base64>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TlsPin([u8; 32]);
+
+impl TlsPin {
+    /// Compute the pin of a DER-encoded SubjectPublicKeyInfo.
+    pub fn from_spki(spki_der: &[u8]) -> Self {
+        Self(Sha256::digest(spki_der).into())
+    }
+}
+
+impl FromStr for TlsPin {
+    type Err = &'static str;
+
+    /// Parse a pin with or without the `sha256//` prefix.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix("sha256//").unwrap_or(s);
+        let digest = B64_STANDARD_ENGINE
+            .decode(s)
+            .map_err(|_| "invalid base64 in TLS pin")?;
+        Ok(Self(
+            digest
+                .try_into()
+                .map_err(|_| "TLS pin is not a SHA-256 digest")?,
+        ))
+    }
+}
+
+impl std::fmt::Display for TlsPin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "sha256//{}", B64_STANDARD_ENGINE.encode(self.0))
+    }
 }
 
 #[cfg(feature = "rustls-native-roots")]
@@ -74,8 +138,9 @@ pub async fn make_tls_connector(
     tls_key: Option<&str>,
     tls_ca: Option<&str>,
     tls_insecure: bool,
+    tls_pins: &[TlsPin],
 ) -> Result<Connector, Error> {
-    let tls_config = make_client_config(tls_cert, tls_key, tls_ca, tls_insecure).await?;
+    let tls_config = make_client_config(tls_cert, tls_key, tls_ca, tls_insecure, tls_pins).await?;
     #[cfg(feature = "__rustls")]
     let result = Ok(Connector::Rustls(tls_config.into()));
     #[cfg(feature = "nativetls")]
@@ -87,18 +152,58 @@ pub async fn make_tls_identity(
     cert_path: &str,
     key_path: &str,
     client_ca_path: Option<&str>,
+    client_auth_optional: bool,
 ) -> Result<TlsIdentity, Error> {
-    let identity = make_server_config(cert_path, key_path, client_ca_path).await?;
+    let identity =
+        make_server_config(cert_path, key_path, client_ca_path, client_auth_optional).await?;
     Ok(Arc::new(ArcSwap::from_pointee(identity)))
 }
 
+/// A self-signed certificate and its private key in PEM
+pub struct SelfSigned {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub pin: TlsPin,
+}
+
+impl SelfSigned {
+    /// Generate a self-signed certificate for the given names.
+    pub fn generate(subject_alt_names: Vec<String>) -> Result<Self, Error> {
+        let cert = rcgen::generate_simple_self_signed(subject_alt_names)?;
+        Ok(Self {
+            cert_pem: cert.serialize_pem()?,
+            key_pem: cert.serialize_private_key_pem(),
+            pin: TlsPin::from_spki(&cert.get_key_pair().public_key_der()),
+        })
+    }
+}
+
+/// Make a `TlsIdentity` with a freshly generated self-signed certificate
+/// for the given names. Also returns the pin of the certificate.
+pub async fn make_self_signed_tls_identity(
+    subject_alt_names: Vec<String>,
+    client_ca_path: Option<&str>,
+    client_auth_optional: bool,
+) -> Result<(TlsIdentity, TlsPin), Error> {
+    let self_signed = SelfSigned::generate(subject_alt_names)?;
+    let identity = make_server_config_from_pem(
+        self_signed.cert_pem.as_bytes(),
+        self_signed.key_pem.as_bytes(),
+        client_ca_path,
+        client_auth_optional,
+    )
+    .await?;
+    Ok((Arc::new(ArcSwap::from_pointee(identity)), self_signed.pin))
+}
+
 pub async fn reload_tls_identity(
     identity: &TlsIdentity,
     cert_path: &str,
     key_path: &str,
     client_ca_path: Option<&str>,
+    client_auth_optional: bool,
 ) -> Result<(), Error> {
-    let new = make_server_config(cert_path, key_path, client_ca_path).await?;
+    let new = make_server_config(cert_path, key_path, client_ca_path, client_auth_optional).await?;
     identity.store(Arc::new(new));
     Ok(())
 }
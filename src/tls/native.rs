@@ -2,18 +2,45 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use super::Error;
+use super::{Error, TlsPin};
 use native_tls::{Identity, TlsAcceptor, TlsConnector};
+use tokio::net::TcpStream;
 
 /// Type alias for the inner TLS identity type.
 pub type TlsIdentityInner = tokio_native_tls::TlsAcceptor;
 
+/// Type alias for a TLS connection to a server.
+pub type ClientTlsStream = tokio_native_tls::TlsStream<TcpStream>;
+
+/// Start a TLS session with `host` over `stream`, trusting the system's
+/// roots.
+pub async fn connect(host: &str, stream: TcpStream) -> Result<ClientTlsStream, Error> {
+    let connector = make_client_config(None, None, None, false, &[]).await?;
+    tokio_native_tls::TlsConnector::from(connector)
+        .connect(host, stream)
+        .await
+        .map_err(|err| Error::Handshake(std::io::Error::other(err)))
+}
+
 pub async fn make_server_config(
     cert_path: &str,
     key_path: &str,
+    client_ca_path: Option<&str>,
+    client_auth_optional: bool,
+) -> Result<TlsIdentityInner, Error> {
+    let cert = tokio::fs::read(cert_path).await?;
+    let key = tokio::fs::read(key_path).await?;
+    make_server_config_from_pem(&cert, &key, client_ca_path, client_auth_optional).await
+}
+
+#[allow(clippy::unused_async)]
+pub async fn make_server_config_from_pem(
+    cert: &[u8],
+    key: &[u8],
     _client_ca_path: Option<&str>,
+    _client_auth_optional: bool,
 ) -> Result<TlsIdentityInner, Error> {
-    let identity = read_key_cert(key_path, cert_path).await?;
+    let identity = Identity::from_pkcs8(cert, key)?;
     // TODO: support client CA (sfackler/rust-native-tls#161)
     let raw_acceptor = TlsAcceptor::builder(identity).build()?;
     Ok(raw_acceptor.into())
@@ -24,7 +51,11 @@ pub async fn make_client_config(
     key_path: Option<&str>,
     ca_path: Option<&str>,
     tls_skip_verify: bool,
+    tls_pins: &[TlsPin],
 ) -> Result<TlsConnector, Error> {
+    if !tls_pins.is_empty() {
+        return Err(Error::PinNotSupported);
+    }
     let mut tls_config_builder = TlsConnector::builder();
     tls_config_builder
         .danger_accept_invalid_certs(tls_skip_verify)
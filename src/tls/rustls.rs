@@ -2,18 +2,39 @@
 //
 // SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
 
-use super::Error;
+use super::{Error, TlsPin};
 use rustls::{
     client::{ServerCertVerified, ServerCertVerifier, ServerName},
-    server::AllowAnyAuthenticatedClient,
-    Certificate, ClientConfig, RootCertStore, ServerConfig,
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+    Certificate, CertificateError, ClientConfig, RootCertStore, ServerConfig,
 };
 use rustls_pemfile::Item;
 use std::sync::Arc;
+use tokio::net::TcpStream;
+use x509_parser::extensions::GeneralName;
 
 /// Type alias for the inner TLS identity type.
 pub type TlsIdentityInner = ServerConfig;
 
+/// Type alias for a TLS connection to a server.
+pub type ClientTlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+/// Start a TLS session with `host` over `stream`, trusting the system's
+/// roots.
+pub async fn connect(host: &str, stream: TcpStream) -> Result<ClientTlsStream, Error> {
+    let config = make_client_config(None, None, None, false, &[]).await?;
+    let server_name = ServerName::try_from(host).map_err(|_| {
+        Error::Handshake(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid TLS server name",
+        ))
+    })?;
+    tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect(server_name, stream)
+        .await
+        .map_err(Error::Handshake)
+}
+
 /// Skip TLS verification
 pub struct EmptyVerifier;
 
@@ -31,22 +52,61 @@ impl ServerCertVerifier for EmptyVerifier {
     }
 }
 
+/// Accept only certificates whose public key matches one of the pins
+pub struct PinnedVerifier(Vec<TlsPin>);
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _: &[Certificate],
+        _: &ServerName,
+        _: &mut dyn Iterator<Item = &[u8]>,
+        _: &[u8],
+        _: std::time::SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let (_, cert) = x509_parser::parse_x509_certificate(&end_entity.0)
+            .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        let pin = TlsPin::from_spki(cert.public_key().raw);
+        if self.0.contains(&pin) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
 pub async fn make_server_config(
     cert_path: &str,
     key_path: &str,
     client_ca_path: Option<&str>,
+    client_auth_optional: bool,
+) -> Result<TlsIdentityInner, Error> {
+    let cert = tokio::fs::read(cert_path).await?;
+    let key = tokio::fs::read(key_path).await?;
+    make_server_config_from_pem(&cert, &key, client_ca_path, client_auth_optional).await
+}
+
+pub async fn make_server_config_from_pem(
+    cert: &[u8],
+    key: &[u8],
+    client_ca_path: Option<&str>,
+    client_auth_optional: bool,
 ) -> Result<TlsIdentityInner, Error> {
-    // Load certificate
-    // `expect`: we only get `None` if `key_path` and `cert_path` are `None`,
-    // which is not the case here.
-    let (certs, key) = try_load_certificate(Some(key_path), Some(cert_path))
-        .await?
-        .expect("`try_load_certificate` returned `None` (this is a bug)");
+    let (certs, key) = parse_key_cert(key, cert)?;
     // Build config
     let config = ServerConfig::builder().with_safe_defaults();
     let mut config = if let Some(client_ca_path) = client_ca_path {
         let store = load_ca_store(client_ca_path).await?;
-        config.with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(store)))
+        if client_auth_optional {
+            config.with_client_cert_verifier(Arc::new(AllowAnyAnonymousOrAuthenticatedClient::new(
+                store,
+            )))
+        } else {
+            config.with_client_cert_verifier(Arc::new(AllowAnyAuthenticatedClient::new(store)))
+        }
     } else {
         config.with_no_client_auth()
     }
@@ -60,28 +120,59 @@ pub async fn make_client_config(
     key_path: Option<&str>,
     ca_path: Option<&str>,
     tls_skip_verify: bool,
+    tls_pins: &[TlsPin],
 ) -> Result<ClientConfig, Error> {
     let config = ClientConfig::builder().with_safe_defaults();
     // Whether there is a custom CA store
     let roots = generate_rustls_rootcertstore(ca_path).await?;
     let client_certificate = try_load_certificate(key_path, cert_path).await?;
-    // Whether to skip TLS verification and whether there is a client certificate
-    let mut config = match (tls_skip_verify, client_certificate) {
-        (true, Some((cert_chain, key_der))) => config
-            .with_custom_certificate_verifier(Arc::new(EmptyVerifier {}))
+    // Whether to replace the CA verification
+    let verifier: Option<Arc<dyn ServerCertVerifier>> = if tls_skip_verify {
+        Some(Arc::new(EmptyVerifier {}))
+    } else if !tls_pins.is_empty() {
+        Some(Arc::new(PinnedVerifier(tls_pins.to_vec())))
+    } else {
+        None
+    };
+    // Whether to use a custom verifier and whether there is a client certificate
+    let mut config = match (verifier, client_certificate) {
+        (Some(verifier), Some((cert_chain, key_der))) => config
+            .with_custom_certificate_verifier(verifier)
             .with_client_auth_cert(cert_chain, key_der)?,
-        (true, None) => config
-            .with_custom_certificate_verifier(Arc::new(EmptyVerifier {}))
+        (Some(verifier), None) => config
+            .with_custom_certificate_verifier(verifier)
             .with_no_client_auth(),
-        (false, Some((cert_chain, key_der))) => config
+        (None, Some((cert_chain, key_der))) => config
             .with_root_certificates(roots)
             .with_client_auth_cert(cert_chain, key_der)?,
-        (false, None) => config.with_root_certificates(roots).with_no_client_auth(),
+        (None, None) => config.with_root_certificates(roots).with_no_client_auth(),
     };
     config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
     Ok(config)
 }
 
+/// Get a name for a client from its certificate: the subject CN if present,
+/// or the first DNS name, email address, or URI in the subject alternative
+/// names otherwise.
+pub fn client_cert_name(cert: &Certificate) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    if let Some(cn) = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+    {
+        return Some(cn.to_string());
+    }
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|name| match name {
+        GeneralName::DNSName(name) | GeneralName::RFC822Name(name) | GeneralName::URI(name) => {
+            Some((*name).to_string())
+        }
+        _ => None,
+    })
+}
+
 /// Load system certificates
 #[cfg(feature = "rustls-native-roots")]
 fn get_system_certs() -> Result<RootCertStore, Error> {
@@ -136,24 +227,31 @@ async fn try_load_certificate(
     tls_cert: Option<&str>,
 ) -> Result<Option<(Vec<Certificate>, rustls::PrivateKey)>, Error> {
     if let (Some(key), Some(cert)) = (tls_key, tls_cert) {
-        // Load certificate chain
-        let certs = tokio::fs::read(cert).await?;
-        let certs = rustls_pemfile::certs(&mut certs.as_ref())?;
-        let certs = certs.into_iter().map(Certificate).collect();
-        // Load private key
+        let cert = tokio::fs::read(cert).await?;
         let key = tokio::fs::read(key).await?;
-        let Some(Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key)) =
-            rustls_pemfile::read_one(&mut key.as_ref())?
-        else {
-            return Err(Error::PrivateKeyNotSupported);
-        };
-        let key = rustls::PrivateKey(key);
-        Ok(Some((certs, key)))
+        Ok(Some(parse_key_cert(&key, &cert)?))
     } else {
         Ok(None)
     }
 }
 
+/// Parse a PEM-encoded certificate chain and private key.
+fn parse_key_cert(
+    key: &[u8],
+    cert: &[u8],
+) -> Result<(Vec<Certificate>, rustls::PrivateKey), Error> {
+    // Load certificate chain
+    let certs = rustls_pemfile::certs(&mut &cert[..])?;
+    let certs = certs.into_iter().map(Certificate).collect();
+    // Load private key
+    let Some(Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key)) =
+        rustls_pemfile::read_one(&mut &key[..])?
+    else {
+        return Err(Error::PrivateKeyNotSupported);
+    };
+    Ok((certs, rustls::PrivateKey(key)))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -235,6 +333,7 @@ mod test {
             cert_path.to_str().unwrap(),
             key_path.to_str().unwrap(),
             None,
+            false,
         )
         .await
         .unwrap();
@@ -244,6 +343,44 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_client_cert_name() {
+        let mut params = rcgen::CertificateParams::new(vec!["alice.example.com".into()]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "alice");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let cert = Certificate(cert.serialize_der().unwrap());
+        assert_eq!(client_cert_name(&cert).unwrap(), "alice");
+        let mut params = rcgen::CertificateParams::new(vec!["bob.example.com".into()]);
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let cert = Certificate(cert.serialize_der().unwrap());
+        assert_eq!(client_cert_name(&cert).unwrap(), "bob.example.com");
+    }
+
+    #[test]
+    fn test_pinned_verifier() {
+        let cert = generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+        let pin = TlsPin::from_spki(&cert.get_key_pair().public_key_der());
+        assert_eq!(pin, pin.to_string().parse().unwrap());
+        let cert = Certificate(cert.serialize_der().unwrap());
+        let other = generate_simple_self_signed(vec!["example.com".into()]).unwrap();
+        let other_pin = TlsPin::from_spki(&other.get_key_pair().public_key_der());
+        let verify = |pins: Vec<TlsPin>| {
+            PinnedVerifier(pins).verify_server_cert(
+                &cert,
+                &[],
+                &ServerName::try_from("example.org").unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+        };
+        verify(vec![other_pin, pin]).unwrap();
+        verify(vec![other_pin]).unwrap_err();
+    }
+
     #[tokio::test]
     async fn test_client_config() {
         let tmpdir = tempdir().unwrap();
@@ -252,7 +389,7 @@ mod test {
         tokio::fs::write(&ca_path, custom_ca.serialize_pem().unwrap())
             .await
             .unwrap();
-        let config = make_client_config(None, None, Some(ca_path.to_str().unwrap()), true)
+        let config = make_client_config(None, None, Some(ca_path.to_str().unwrap()), true, &[])
             .await
             .unwrap();
         assert_eq!(
@@ -0,0 +1,182 @@
+//! TUN devices on Linux, created and configured with `ioctl`s.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+// The `ioctl`s have no safe wrappers
+#![allow(unsafe_code)]
+
+use ipnet::IpNet;
+use socket2::{Domain, Socket, Type};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use tokio::io::unix::AsyncFd;
+
+/// A TUN device, which goes away when dropped
+#[derive(Debug)]
+pub struct Tun {
+    file: AsyncFd<File>,
+    name: String,
+}
+
+impl Tun {
+    /// Create a TUN device named `name`, where a `%d` lets the kernel pick
+    /// a number, and bring it up with `mtu` and `addresses`.
+    pub fn create(name: &str, mtu: u16, addresses: &[IpNet]) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK | libc::O_CLOEXEC)
+            .open("/dev/net/tun")?;
+        let mut ifr = ifreq(name)?;
+        ifr.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        // SAFETY: `TUNSETIFF` takes an `ifreq`.
+        unsafe { ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut ifr)? };
+        // With the number filled in
+        let name = ifr_name(&ifr);
+        let control = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        ifr.ifr_ifru.ifru_mtu = mtu.into();
+        // SAFETY: `SIOCSIFMTU` takes an `ifreq`.
+        unsafe { ioctl(control.as_raw_fd(), libc::SIOCSIFMTU as _, &mut ifr)? };
+        for address in addresses {
+            match address {
+                IpNet::V4(net) => {
+                    ifr.ifr_ifru.ifru_addr = sockaddr(net.addr());
+                    // SAFETY: `SIOCSIFADDR` takes an `ifreq`.
+                    unsafe { ioctl(control.as_raw_fd(), libc::SIOCSIFADDR as _, &mut ifr)? };
+                    ifr.ifr_ifru.ifru_netmask = sockaddr(net.netmask());
+                    // SAFETY: `SIOCSIFNETMASK` takes an `ifreq`.
+                    unsafe { ioctl(control.as_raw_fd(), libc::SIOCSIFNETMASK as _, &mut ifr)? };
+                }
+                IpNet::V6(net) => {
+                    let control = Socket::new(Domain::IPV6, Type::DGRAM, None)?;
+                    // SAFETY: `SIOCGIFINDEX` takes an `ifreq`.
+                    unsafe { ioctl(control.as_raw_fd(), libc::SIOCGIFINDEX as _, &mut ifr)? };
+                    let mut ifr6 = libc::in6_ifreq {
+                        ifr6_addr: libc::in6_addr {
+                            s6_addr: net.addr().octets(),
+                        },
+                        ifr6_prefixlen: net.prefix_len().into(),
+                        // SAFETY: `SIOCGIFINDEX` has set it.
+                        ifr6_ifindex: unsafe { ifr.ifr_ifru.ifru_ifindex },
+                    };
+                    // SAFETY: `SIOCSIFADDR` takes an `in6_ifreq` on IPv6 sockets.
+                    unsafe { ioctl(control.as_raw_fd(), libc::SIOCSIFADDR as _, &mut ifr6)? };
+                }
+            }
+        }
+        // SAFETY: `SIOCGIFFLAGS` takes an `ifreq`.
+        unsafe { ioctl(control.as_raw_fd(), libc::SIOCGIFFLAGS as _, &mut ifr)? };
+        // SAFETY: `SIOCGIFFLAGS` has set it.
+        let flags = unsafe { ifr.ifr_ifru.ifru_flags };
+        ifr.ifr_ifru.ifru_flags = flags | (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        // SAFETY: `SIOCSIFFLAGS` takes an `ifreq`.
+        unsafe { ioctl(control.as_raw_fd(), libc::SIOCSIFFLAGS as _, &mut ifr)? };
+        Ok(Self {
+            file: AsyncFd::new(file)?,
+            name,
+        })
+    }
+
+    /// Name of the device
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Receive an IP packet.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.file.readable().await?;
+            if let Ok(result) = guard.try_io(|file| file.get_ref().read(buf)) {
+                return result;
+            }
+        }
+    }
+
+    /// Send an IP packet.
+    pub async fn send(&self, packet: &[u8]) -> io::Result<()> {
+        loop {
+            let mut guard = self.file.writable().await?;
+            if let Ok(result) = guard.try_io(|file| file.get_ref().write(packet)) {
+                return result.map(|_| ());
+            }
+        }
+    }
+}
+
+/// Run an `ioctl` on `fd`.
+///
+/// # Safety
+/// `arg` must be of the type `request` takes.
+unsafe fn ioctl<T>(fd: RawFd, request: libc::Ioctl, arg: &mut T) -> io::Result<()> {
+    // SAFETY: `arg` outlives the call, and the caller vouches for its type.
+    if unsafe { libc::ioctl(fd, request, std::ptr::from_mut(arg)) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// An `ifreq` for the device named `name`.
+fn ifreq(name: &str) -> io::Result<libc::ifreq> {
+    if name.len() >= libc::IFNAMSIZ || name.contains('\0') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid TUN device name",
+        ));
+    }
+    // SAFETY: all-zero is a valid `ifreq`.
+    let mut ifr: libc::ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in ifr.ifr_name.iter_mut().zip(name.bytes()) {
+        *dst = src as libc::c_char;
+    }
+    Ok(ifr)
+}
+
+/// The device name in `ifr`.
+fn ifr_name(ifr: &libc::ifreq) -> String {
+    let name = ifr
+        .ifr_name
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&name).into_owned()
+}
+
+/// `ip` as a `sockaddr`, as `ifreq` holds it.
+fn sockaddr(ip: Ipv4Addr) -> libc::sockaddr {
+    let addr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: 0,
+        sin_addr: libc::in_addr {
+            s_addr: u32::from_ne_bytes(ip.octets()),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: a `sockaddr_in` is an IPv4 `sockaddr` of the same size.
+    unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(addr) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ifreq_name() {
+        let ifr = ifreq("penguin%d").unwrap();
+        assert_eq!(ifr_name(&ifr), "penguin%d");
+        ifreq("a-name-too-long-for-linux").unwrap_err();
+        ifreq("nul\0").unwrap_err();
+    }
+
+    #[test]
+    fn test_sockaddr() {
+        let addr = sockaddr(Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(addr.sa_family, libc::AF_INET as libc::sa_family_t);
+        // The port comes first
+        assert_eq!(addr.sa_data[2..6], [10, 0, 0, 1]);
+    }
+}
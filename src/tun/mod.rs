@@ -0,0 +1,166 @@
+//! TUN devices, whose IP packets are forwarded as datagram frames with an
+//! empty host and port 0.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+#[cfg(all(feature = "tun", target_os = "linux"))]
+mod linux;
+#[cfg(not(all(feature = "tun", target_os = "linux")))]
+mod unsupported;
+
+use bytes::Bytes;
+use penguin_mux::DatagramFrame;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub use self::linux::Tun;
+#[cfg(not(all(feature = "tun", target_os = "linux")))]
+pub use self::unsupported::Tun;
+
+/// Whether this build can create TUN devices
+pub const SUPPORTED: bool = cfg!(all(feature = "tun", target_os = "linux"));
+
+/// Whether `frame` carries an IP packet instead of a UDP datagram.
+pub fn is_packet(frame: &DatagramFrame) -> bool {
+    frame.host.is_empty() && frame.port == 0
+}
+
+/// Wrap an IP packet in a datagram frame.
+pub fn packet_frame(packet: Bytes) -> DatagramFrame {
+    DatagramFrame {
+        host: Bytes::new(),
+        port: 0,
+        sid: 0,
+        data: packet,
+    }
+}
+
+/// Source and destination addresses of an IPv4 or IPv6 packet.
+pub fn addresses(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    match packet.first()? >> 4 {
+        4 if packet.len() >= 20 => {
+            let source: [u8; 4] = packet[12..16].try_into().ok()?;
+            let destination: [u8; 4] = packet[16..20].try_into().ok()?;
+            Some((
+                Ipv4Addr::from(source).into(),
+                Ipv4Addr::from(destination).into(),
+            ))
+        }
+        6 if packet.len() >= 40 => {
+            let source: [u8; 16] = packet[8..24].try_into().ok()?;
+            let destination: [u8; 16] = packet[24..40].try_into().ok()?;
+            Some((
+                Ipv6Addr::from(source).into(),
+                Ipv6Addr::from(destination).into(),
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Destination of an IPv4 or IPv6 packet, with the port of a TCP or UDP
+/// packet, or port 0 for other protocols and later fragments.
+pub fn destination(packet: &[u8]) -> Option<SocketAddr> {
+    let (_, address) = addresses(packet)?;
+    let (protocol, transport) = match address {
+        IpAddr::V4(_) => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            // Only the first fragment has the transport header
+            let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+            if fragment_offset != 0 {
+                return Some(SocketAddr::new(address, 0));
+            }
+            (packet[9], packet.get(header_len..))
+        }
+        // Extension headers are not followed
+        IpAddr::V6(_) => (packet[6], packet.get(40..)),
+    };
+    let port = match (protocol, transport) {
+        // TCP or UDP
+        (6 | 17, Some([_, _, high, low, ..])) => u16::from_be_bytes([*high, *low]),
+        _ => 0,
+    };
+    Some(SocketAddr::new(address, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_addresses() {
+        let mut v4 = [0u8; 20];
+        v4[0] = 0x45;
+        v4[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        v4[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        assert_eq!(
+            addresses(&v4),
+            Some(([10, 0, 0, 2].into(), [1, 1, 1, 1].into()))
+        );
+        assert_eq!(addresses(&v4[..19]), None);
+        let mut v6 = [0u8; 40];
+        v6[0] = 0x60;
+        v6[23] = 1;
+        v6[24] = 0xfd;
+        v6[39] = 2;
+        assert_eq!(
+            addresses(&v6),
+            Some((
+                Ipv6Addr::LOCALHOST.into(),
+                "fd00::2".parse::<Ipv6Addr>().unwrap().into()
+            ))
+        );
+        assert_eq!(addresses(&[0x50; 40]), None);
+        assert_eq!(addresses(&[]), None);
+    }
+
+    #[test]
+    fn test_destination() {
+        let mut udp = [0u8; 28];
+        udp[0] = 0x45;
+        udp[9] = 17;
+        udp[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        udp[22..24].copy_from_slice(&53u16.to_be_bytes());
+        assert_eq!(
+            destination(&udp),
+            Some(SocketAddr::from(([1, 1, 1, 1], 53)))
+        );
+        // Truncated transport header
+        assert_eq!(
+            destination(&udp[..21]),
+            Some(SocketAddr::from(([1, 1, 1, 1], 0)))
+        );
+        // Later fragment
+        udp[7] = 1;
+        assert_eq!(destination(&udp), Some(SocketAddr::from(([1, 1, 1, 1], 0))));
+        let mut icmp = [0u8; 28];
+        icmp[0] = 0x45;
+        icmp[9] = 1;
+        icmp[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        icmp[22] = 0xff;
+        assert_eq!(
+            destination(&icmp),
+            Some(SocketAddr::from(([1, 1, 1, 1], 0)))
+        );
+        let mut tcp6 = [0u8; 60];
+        tcp6[0] = 0x60;
+        tcp6[6] = 6;
+        tcp6[24] = 0xfd;
+        tcp6[39] = 2;
+        tcp6[42..44].copy_from_slice(&443u16.to_be_bytes());
+        assert_eq!(destination(&tcp6), Some("[fd00::2]:443".parse().unwrap()));
+        assert_eq!(destination(&[]), None);
+    }
+
+    #[test]
+    fn test_packet_frame() {
+        let frame = packet_frame(Bytes::from_static(&[0x45, 0, 0, 20]));
+        assert!(is_packet(&frame));
+        let datagram = DatagramFrame {
+            host: Bytes::from_static(b"127.0.0.1"),
+            port: 53,
+            sid: 0,
+            data: Bytes::new(),
+        };
+        assert!(!is_packet(&datagram));
+    }
+}
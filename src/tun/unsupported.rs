@@ -0,0 +1,35 @@
+//! Stand-in for builds that cannot create TUN devices.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use ipnet::IpNet;
+use std::io;
+
+/// A TUN device, which cannot exist in this build
+#[derive(Debug)]
+pub enum Tun {}
+
+impl Tun {
+    /// Fail, since this build does not support TUN devices.
+    pub fn create(_name: &str, _mtu: u16, _addresses: &[IpNet]) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TUN devices need Linux and the `tun` feature",
+        ))
+    }
+
+    /// Name of the device
+    pub fn name(&self) -> &str {
+        match *self {}
+    }
+
+    /// Receive an IP packet.
+    pub async fn recv(&self, _buf: &mut [u8]) -> io::Result<usize> {
+        match *self {}
+    }
+
+    /// Send an IP packet.
+    pub async fn send(&self, _packet: &[u8]) -> io::Result<()> {
+        match *self {}
+    }
+}
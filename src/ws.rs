@@ -0,0 +1,222 @@
+//! The `WebSocket` implementation tunnels run over: `tungstenite` by
+//! default, or the frame-level one of `penguin-mux` with `fast-ws`.
+//
+// SPDX-License-Identifier: Apache-2.0 OR GPL-3.0-or-later
+
+use crate::proto_version;
+use penguin_mux::ws::Role;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::tungstenite::handshake::client::{Request, Response};
+use tokio_tungstenite::tungstenite::Error;
+use tokio_tungstenite::{Connector, MaybeTlsStream};
+
+#[cfg(feature = "fast-ws")]
+pub use penguin_mux::fast_ws::WebSocket;
+#[cfg(not(feature = "fast-ws"))]
+pub use penguin_mux::ws::WebSocket;
+
+/// Wrap an upgraded server-side connection.
+pub async fn accept<RW>(io: RW, max_frame_size: Option<u32>) -> WebSocket<RW>
+where
+    RW: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    #[cfg(feature = "fast-ws")]
+    {
+        WebSocket::new(
+            io,
+            Role::Server,
+            max_frame_size.map(proto_version::max_message_size),
+        )
+    }
+    #[cfg(not(feature = "fast-ws"))]
+    {
+        let ws = tokio_tungstenite::WebSocketStream::from_raw_socket(
+            io,
+            Role::Server,
+            max_frame_size.map(proto_version::ws_config),
+        )
+        .await;
+        WebSocket::new(ws)
+    }
+}
+
+/// Perform the client handshake over `stream`, wrapping it in TLS
+/// first unless `connector` is `Connector::Plain`.
+#[cfg(not(feature = "fast-ws"))]
+pub async fn connect<S>(
+    request: Request,
+    stream: S,
+    max_frame_size: u32,
+    connector: Connector,
+) -> Result<(WebSocket<MaybeTlsStream<S>>, Response), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (ws, response) = tokio_tungstenite::client_async_tls_with_config(
+        request,
+        stream,
+        Some(proto_version::ws_config(max_frame_size)),
+        Some(connector),
+    )
+    .await?;
+    Ok((WebSocket::new(ws), response))
+}
+
+/// Perform the client handshake over `stream`, wrapping it in TLS
+/// first unless `connector` is `Connector::Plain`.
+#[cfg(feature = "fast-ws")]
+pub async fn connect<S>(
+    request: Request,
+    stream: S,
+    max_frame_size: u32,
+    connector: Connector,
+) -> Result<(WebSocket<MaybeTlsStream<S>>, Response), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    use tokio::io::AsyncWriteExt;
+    use tokio_tungstenite::tungstenite::handshake::client::generate_request;
+
+    let mut stream = wrap_tls(&request, stream, connector).await?;
+    let (request, key) = generate_request(request)?;
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+    let (response, leftover) = read_response(&mut stream).await?;
+    if response.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+        return Err(Error::Http(response));
+    }
+    verify_upgrade(&response, &key).map_err(Error::Protocol)?;
+    let ws = WebSocket::from_partially_read(
+        stream,
+        Role::Client,
+        Some(proto_version::max_message_size(max_frame_size)),
+        leftover,
+    );
+    Ok((ws, response))
+}
+
+/// Start TLS on `stream` with the host of the request as the server name.
+#[cfg(feature = "fast-ws")]
+async fn wrap_tls<S>(
+    request: &Request,
+    stream: S,
+    connector: Connector,
+) -> Result<MaybeTlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    #[cfg(any(feature = "__rustls", feature = "nativetls"))]
+    let host = request
+        .uri()
+        .host()
+        .ok_or(Error::Url(
+            tokio_tungstenite::tungstenite::error::UrlError::NoHostName,
+        ))?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    #[cfg(not(any(feature = "__rustls", feature = "nativetls")))]
+    let _ = request;
+    match connector {
+        Connector::Plain => Ok(MaybeTlsStream::Plain(stream)),
+        #[cfg(feature = "__rustls")]
+        Connector::Rustls(config) => {
+            use tokio_tungstenite::tungstenite::error::TlsError;
+            let server_name =
+                rustls::ServerName::try_from(host).map_err(|_| TlsError::InvalidDnsName)?;
+            let stream = tokio_rustls::TlsConnector::from(config)
+                .connect(server_name, stream)
+                .await?;
+            Ok(MaybeTlsStream::Rustls(stream))
+        }
+        #[cfg(feature = "nativetls")]
+        Connector::NativeTls(connector) => {
+            use tokio_tungstenite::tungstenite::error::TlsError;
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect(host, stream)
+                .await
+                .map_err(TlsError::Native)?;
+            Ok(MaybeTlsStream::NativeTls(stream))
+        }
+        // `Connector` is `non_exhaustive`
+        _ => unreachable!("TLS connector of a disabled implementation (this is a bug)"),
+    }
+}
+
+/// Read the handshake response. Returns it with whatever the server sent
+/// after it, which already belongs to the `WebSocket`.
+#[cfg(feature = "fast-ws")]
+async fn read_response<S>(stream: &mut S) -> Result<(Response, bytes::BytesMut), Error>
+where
+    S: AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt;
+    use tokio_tungstenite::tungstenite::error::{CapacityError, ProtocolError};
+
+    let mut buf = bytes::BytesMut::with_capacity(1 << 10);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(Error::Protocol(ProtocolError::HandshakeIncomplete));
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Response::new(&mut headers);
+        if let httparse::Status::Complete(size) = parsed.parse(&buf)? {
+            let mut response = Response::new(None);
+            *response.status_mut() = http::StatusCode::from_u16(
+                parsed
+                    .code
+                    .expect("`httparse` returned no status code (this is a bug)"),
+            )?;
+            for header in parsed.headers.iter() {
+                response.headers_mut().append(
+                    http::HeaderName::from_bytes(header.name.as_bytes())?,
+                    http::HeaderValue::from_bytes(header.value)?,
+                );
+            }
+            let _ = buf.split_to(size);
+            return Ok((response, buf));
+        }
+        if buf.len() >= crate::config::WS_MAX_RESPONSE_HEAD_SIZE {
+            return Err(Error::Capacity(CapacityError::MessageTooLong {
+                size: buf.len(),
+                max_size: crate::config::WS_MAX_RESPONSE_HEAD_SIZE,
+            }));
+        }
+    }
+}
+
+/// The header checks RFC 6455 section 4.1 asks of the client.
+#[cfg(feature = "fast-ws")]
+fn verify_upgrade(
+    response: &Response,
+    key: &str,
+) -> Result<(), tokio_tungstenite::tungstenite::error::ProtocolError> {
+    use tokio_tungstenite::tungstenite::error::ProtocolError;
+    use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+
+    let headers = response.headers();
+    let header_is = |name: &str, expected: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| {
+                value
+                    .split(',')
+                    .any(|token| token.trim().eq_ignore_ascii_case(expected))
+            })
+    };
+    if !header_is("upgrade", "websocket") {
+        return Err(ProtocolError::MissingUpgradeWebSocketHeader);
+    }
+    if !header_is("connection", "upgrade") {
+        return Err(ProtocolError::MissingConnectionUpgradeHeader);
+    }
+    let accept = derive_accept_key(key.as_bytes());
+    if headers
+        .get("sec-websocket-accept")
+        .map(http::HeaderValue::as_bytes)
+        != Some(accept.as_bytes())
+    {
+        return Err(ProtocolError::SecWebSocketAcceptKeyMismatch);
+    }
+    Ok(())
+}